@@ -3,7 +3,186 @@ use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+/// Feature combinations that exist within a product category
+///
+/// Derived from the datasheets of all MCUs in that category. Used to catch
+/// contradictory feature selections early, instead of ending up with missing
+/// pin implementations or a wrong memory layout.
+struct Category {
+    io: &'static str,
+    selected: bool,
+    subfamilies: &'static [&'static str],
+    eeprom: &'static [u32],
+    flash: &'static [u32],
+    ram: &'static [u32],
+}
+
+const CATEGORIES: &[Category] = &[
+    Category {
+        io: "io-STM32L021",
+        selected: cfg!(feature = "io-STM32L021"),
+        subfamilies: &["stm32l0x1"],
+        eeprom: &[128, 512],
+        flash: &[8, 16],
+        ram: &[2],
+    },
+    Category {
+        io: "io-STM32L031",
+        selected: cfg!(feature = "io-STM32L031"),
+        subfamilies: &["stm32l0x1"],
+        eeprom: &[256, 1024],
+        flash: &[16, 32],
+        ram: &[8],
+    },
+    Category {
+        io: "io-STM32L051",
+        selected: cfg!(feature = "io-STM32L051"),
+        subfamilies: &["stm32l0x1", "stm32l0x2", "stm32l0x3"],
+        eeprom: &[256, 2048],
+        flash: &[32, 64],
+        ram: &[8],
+    },
+    Category {
+        io: "io-STM32L071",
+        selected: cfg!(feature = "io-STM32L071"),
+        subfamilies: &["stm32l0x1", "stm32l0x2", "stm32l0x3"],
+        eeprom: &[512, 3072, 6144],
+        flash: &[64, 128, 192],
+        ram: &[20],
+    },
+];
+
+/// Returns the values of all selected features of a kind
+fn selected<T: Copy>(features: &[(T, bool)]) -> Vec<T> {
+    features
+        .iter()
+        .filter(|(_, f)| *f)
+        .map(|(v, _)| *v)
+        .collect()
+}
+
+/// Panics, if the selected features contradict each other
+fn check_consistency() {
+    let subfamilies = selected(&[
+        ("stm32l0x1", cfg!(feature = "stm32l0x1")),
+        ("stm32l0x2", cfg!(feature = "stm32l0x2")),
+        ("stm32l0x3", cfg!(feature = "stm32l0x3")),
+    ]);
+    let eeprom = selected(&[
+        (128, cfg!(feature = "eeprom-128")),
+        (256, cfg!(feature = "eeprom-256")),
+        (512, cfg!(feature = "eeprom-512")),
+        (1024, cfg!(feature = "eeprom-1024")),
+        (2048, cfg!(feature = "eeprom-2048")),
+        (3072, cfg!(feature = "eeprom-3072")),
+        (6144, cfg!(feature = "eeprom-6144")),
+    ]);
+    let flash = selected(&[
+        (8, cfg!(feature = "flash-8")),
+        (16, cfg!(feature = "flash-16")),
+        (32, cfg!(feature = "flash-32")),
+        (64, cfg!(feature = "flash-64")),
+        (128, cfg!(feature = "flash-128")),
+        (192, cfg!(feature = "flash-192")),
+    ]);
+    let ram = selected(&[
+        (2, cfg!(feature = "ram-2")),
+        (8, cfg!(feature = "ram-8")),
+        (20, cfg!(feature = "ram-20")),
+    ]);
+    let packages = selected(&[
+        ("ewlcsp49", cfg!(feature = "ewlcsp49")),
+        ("lqfp32", cfg!(feature = "lqfp32")),
+        ("lqfp48", cfg!(feature = "lqfp48")),
+        ("lqfp64", cfg!(feature = "lqfp64")),
+        ("lqfp100", cfg!(feature = "lqfp100")),
+        ("tfbga64", cfg!(feature = "tfbga64")),
+        ("tssop14", cfg!(feature = "tssop14")),
+        ("tssop20", cfg!(feature = "tssop20")),
+        ("ufbga64", cfg!(feature = "ufbga64")),
+        ("ufbga100", cfg!(feature = "ufbga100")),
+        ("ufqfpn20", cfg!(feature = "ufqfpn20")),
+        ("ufqfpn28", cfg!(feature = "ufqfpn28")),
+        ("ufqfpn32", cfg!(feature = "ufqfpn32")),
+        ("ufqfpn48", cfg!(feature = "ufqfpn48")),
+        ("wlcsp25", cfg!(feature = "wlcsp25")),
+        ("wlcsp36", cfg!(feature = "wlcsp36")),
+        ("wlcsp49", cfg!(feature = "wlcsp49")),
+    ]);
+
+    if eeprom.len() > 1 {
+        panic!(
+            "\n\nMust select at most one EEPROM size, but selected {:?}!\n\
+        Pick the mcu-feature that matches your MCU, for example 'mcu-STM32L071KBTx'\n\n",
+            eeprom
+        );
+    }
+    if packages.len() > 1 {
+        panic!(
+            "\n\nMust select at most one package, but selected {:?}!\n\
+        Pick the mcu-feature that matches your MCU, for example 'mcu-STM32L071KBTx'\n\n",
+            packages
+        );
+    }
+
+    let categories: Vec<&Category> = CATEGORIES.iter().filter(|c| c.selected).collect();
+    let category = match categories.as_slice() {
+        [] => return,
+        [category] => category,
+        _ => {
+            let io: Vec<&str> = categories.iter().map(|c| c.io).collect();
+            panic!(
+                "\n\nMust select at most one io-feature, but selected {:?}!\n\
+            Pick the mcu-feature that matches your MCU, for example 'mcu-STM32L071KBTx'\n\n",
+                io
+            );
+        }
+    };
+
+    let subfamilies: Vec<String> = subfamilies.iter().map(|s| s.to_string()).collect();
+    let allowed: Vec<String> = category.subfamilies.iter().map(|s| s.to_string()).collect();
+
+    check_allowed(category.io, "subfamily", &subfamilies, &allowed);
+    check_allowed(
+        category.io,
+        "EEPROM size",
+        &names("eeprom", &eeprom),
+        &names("eeprom", category.eeprom),
+    );
+    check_allowed(
+        category.io,
+        "flash size",
+        &names("flash", &flash),
+        &names("flash", category.flash),
+    );
+    check_allowed(
+        category.io,
+        "RAM size",
+        &names("ram", &ram),
+        &names("ram", category.ram),
+    );
+}
+
+/// Panics, if any of the selected features is not allowed with the io-feature
+fn check_allowed(io: &str, kind: &str, selected: &[String], allowed: &[String]) {
+    if let Some(feature) = selected.iter().find(|f| !allowed.contains(f)) {
+        panic!(
+            "\n\n'{}' contradicts '{}': No MCU of this product category has that {}!\n\
+        Choices: {:?}\n\
+        Pick the mcu-feature that matches your MCU, for example 'mcu-STM32L071KBTx'\n\n",
+            feature, io, kind, allowed
+        );
+    }
+}
+
+/// Converts sizes into feature names, like `flash-64`
+fn names(prefix: &str, values: &[u32]) -> Vec<String> {
+    values.iter().map(|v| format!("{}-{}", prefix, v)).collect()
+}
+
 fn main() {
+    check_consistency();
+
     // Put the linker script somewhere the linker can find it
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
 
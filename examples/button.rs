@@ -36,6 +36,6 @@ fn main() -> ! {
             _ => unreachable!(),
         };
         delay.delay(wait);
-        led.toggle().unwrap();
+        led.toggle();
     }
 }
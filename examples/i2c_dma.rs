@@ -7,8 +7,6 @@
 
 extern crate panic_semihosting;
 
-use core::pin::Pin;
-
 use cortex_m::interrupt;
 use cortex_m_rt::entry;
 use stm32l0xx_hal::{
@@ -43,11 +41,8 @@ fn main() -> ! {
     let mut tx_channel = dma.channels.channel2;
     let mut rx_channel = dma.channels.channel3;
 
-    // Create the buffer we're going to use for DMA.
-    // This is safe, since this is the main function, and it's only executed
-    // once. This means there is no other code accessing this `static`.
-    static mut BUFFER: [u8; 1] = [0; 1];
-    let mut buffer = Pin::new(unsafe { &mut BUFFER });
+    // Create the buffer we're going to use for DMA
+    let mut buffer = dma::buffer!([u8; 1]);
 
     let address = 0x52 >> 1;
 
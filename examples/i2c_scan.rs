@@ -0,0 +1,40 @@
+#![deny(warnings)]
+#![deny(unsafe_code)]
+#![no_main]
+#![no_std]
+
+extern crate panic_semihosting;
+
+use cortex_m_rt::entry;
+use cortex_m_semihosting::hprintln;
+use stm32l0xx_hal::{pac, prelude::*, rcc::Config};
+
+#[entry]
+fn main() -> ! {
+    let dp = pac::Peripherals::take().unwrap();
+
+    // Configure the clock.
+    let mut rcc = dp.RCC.freeze(Config::hsi16());
+
+    // Acquire the GPIOA peripheral. This also enables the clock for GPIOA in
+    // the RCC register.
+    let gpioa = dp.GPIOA.split(&mut rcc);
+
+    let sda = gpioa.pa10.into_open_drain_output();
+    let scl = gpioa.pa9.into_open_drain_output();
+
+    let mut i2c = dp.I2C1.i2c(sda, scl, 100.khz(), &mut rcc);
+
+    // Don't block forever, if the bus is stuck.
+    i2c.set_timeout(Some(25_000.microseconds()), &rcc);
+
+    let mut scan = i2c.scan();
+    for addr in &mut scan {
+        hprintln!("Found device at {:#04x}", addr);
+    }
+    if let Some(error) = scan.error() {
+        hprintln!("Scan failed: {}", error);
+    }
+
+    loop {}
+}
@@ -8,6 +8,10 @@ use core::fmt::Write;
 use rtic::app;
 use stm32l0xx_hal::prelude::*;
 use stm32l0xx_hal::{
+    gpio::{
+        gpiob::{PB6, PB7},
+        Floating, Input,
+    },
     pac,
     rcc::Config,
     serial::{self, Serial},
@@ -17,10 +21,12 @@ use stm32l0xx_hal::{
 
 const LOGGER_FREQUENCY: u32 = 2;
 
+type SerialPins = (PB6<Input<Floating>>, PB7<Input<Floating>>);
+
 #[app(device = stm32l0xx_hal::pac, peripherals = true)]
 const APP: () = {
     struct Resources {
-        serial: Serial<pac::USART1>,
+        serial: Serial<pac::USART1, SerialPins>,
         timer: Timer<pac::TIM6>,
         linked_tim2_tim3: LinkedTimerPair<pac::TIM2, pac::TIM3>,
         linked_tim21_tim22: LinkedTimerPair<pac::TIM21, pac::TIM22>,
@@ -108,7 +114,7 @@ const APP: () = {
 fn print_timer(
     name: &'static str,
     timer: &impl LinkedTimer,
-    serial: &mut Serial<pac::USART1>,
+    serial: &mut Serial<pac::USART1, SerialPins>,
     previous: &mut u32,
 ) {
     // Get the 32 bit counter
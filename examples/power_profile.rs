@@ -0,0 +1,52 @@
+#![no_main]
+#![no_std]
+
+extern crate panic_halt;
+
+use cortex_m::peripheral::NVIC;
+use cortex_m_rt::entry;
+use stm32l0xx_hal::{
+    exti::{ConfigurableLine, Exti, TriggerEdge},
+    pac,
+    power::Profile,
+    prelude::*,
+    pwr::PWR,
+    rcc,
+    rtc::{self, ClockSource, Rtc},
+};
+
+#[entry]
+fn main() -> ! {
+    let cp = pac::CorePeripherals::take().unwrap();
+    let dp = pac::Peripherals::take().unwrap();
+
+    let mut scb = cp.SCB;
+    let mut rcc = dp.RCC.freeze(rcc::Config::msi(rcc::MSIRange::Range0));
+    let mut exti = Exti::new(dp.EXTI);
+    let mut pwr = PWR::new(dp.PWR, &mut rcc);
+
+    // Use the RTC wakeup timer to wake up from Stop mode
+    let mut rtc = Rtc::new(dp.RTC, &mut rcc, &pwr, ClockSource::LSI, None).unwrap();
+    rtc.enable_interrupts(rtc::Interrupts {
+        wakeup_timer: true,
+        ..rtc::Interrupts::default()
+    });
+    exti.listen_configurable(ConfigurableLine::RtcWakeup, TriggerEdge::Rising);
+
+    let mut timer = rtc.wakeup_timer();
+
+    loop {
+        // Do some work in low-power run mode
+        let availability = Profile::LowPowerRun.apply(&mut pwr, &mut scb, &mut rcc);
+        assert!(availability.cpu);
+
+        // Then sleep for 5 seconds
+        timer.start(5u32);
+        Profile::Stop.apply(&mut pwr, &mut scb, &mut rcc);
+        timer.wait().unwrap(); // returns immediately; we just got woken up
+        Exti::unpend(ConfigurableLine::RtcWakeup);
+        NVIC::unpend(pac::Interrupt::RTC);
+
+        Profile::Run.apply(&mut pwr, &mut scb, &mut rcc);
+    }
+}
@@ -3,8 +3,6 @@
 
 extern crate panic_halt;
 
-use core::pin::Pin;
-
 use cortex_m::{asm, interrupt, peripheral::NVIC};
 use cortex_m_rt::entry;
 use stm32l0xx_hal::{
@@ -37,11 +35,8 @@ fn main() -> ! {
         .unwrap()
         .split();
 
-    // Create the buffer we're going to use for DMA.
-    // This is safe, since this is the main function, and it's only executed
-    // once. This means there is no other code accessing this `static`.
-    static mut BUFFER: [u8; 1] = [0; 1];
-    let mut buffer = Pin::new(unsafe { &mut BUFFER });
+    // Create the buffer we're going to use for DMA
+    let mut buffer = dma::buffer!([u8; 1]);
 
     loop {
         // Prepare read transfer
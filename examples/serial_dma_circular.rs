@@ -0,0 +1,49 @@
+//! Continuously receives bytes using a circular DMA buffer and echoes them
+
+#![no_main]
+#![no_std]
+
+extern crate panic_halt;
+
+use cortex_m_rt::entry;
+use nb::block;
+use stm32l0xx_hal::{
+    dma::{self, DMA},
+    pac,
+    prelude::*,
+    rcc::Config,
+    serial,
+};
+
+#[entry]
+fn main() -> ! {
+    let dp = pac::Peripherals::take().unwrap();
+
+    let mut rcc = dp.RCC.freeze(Config::hsi16());
+    let mut dma = DMA::new(dp.DMA1, &mut rcc);
+    let gpioa = dp.GPIOA.split(&mut rcc);
+
+    let (mut tx, rx) = dp
+        .USART2
+        .usart(
+            gpioa.pa2,
+            gpioa.pa3,
+            serial::Config::default().baudrate(115_200.Bd()),
+            &mut rcc,
+        )
+        .unwrap()
+        .split();
+
+    let buffer = dma::buffer!([u8; 64]);
+    let mut rx = rx.read_circular(&mut dma.handle, buffer, dma.channels.channel5);
+
+    loop {
+        for byte in rx.read_available() {
+            // An error means that bytes have been lost, because they weren't
+            // read in time. Just carry on with the next ones.
+            if let Ok(byte) = byte {
+                block!(tx.write(byte)).unwrap();
+            }
+        }
+    }
+}
@@ -1,22 +1,29 @@
 //! # Analog to Digital converter
 
-use core::{
-    ops::DerefMut,
-    pin::Pin,
-    sync::atomic::{compiler_fence, Ordering},
-};
+use core::{fmt, mem, ops::DerefMut, pin::Pin};
 
 use as_slice::AsMutSlice;
 
 use crate::{
+    calibration,
     gpio::*,
-    hal::adc::{Channel, OneShot},
+    hal::{
+        adc::{Channel, OneShot},
+        blocking::delay::DelayUs,
+    },
     pac::ADC,
     rcc::{Enable, Rcc},
 };
 
 use crate::dma::{self, Buffer as _};
 
+/// Start-up time of the temperature sensor and the VREFINT buffer for the ADC,
+/// in µs
+///
+/// See the datasheet, sections "Temperature sensor characteristics" and
+/// "Embedded internal reference voltage".
+const T_START_US: u8 = 10;
+
 pub trait AdcExt {
     fn constrain(self, rcc: &mut Rcc) -> Adc<Ready>;
 }
@@ -56,6 +63,83 @@ pub enum Precision {
     B_6 = 0b11,
 }
 
+impl Precision {
+    /// Returns the number of bits of a conversion result
+    fn bits(self) -> u32 {
+        match self {
+            Precision::B_12 => 12,
+            Precision::B_10 => 10,
+            Precision::B_8 => 8,
+            Precision::B_6 => 6,
+        }
+    }
+}
+
+/// Returns the right-aligned conversion result that corresponds to V_DDA
+fn full_scale(
+    precision: Precision,
+    oversampling: Option<(OversamplingRatio, OversamplingShift)>,
+) -> u32 {
+    let max = (1 << precision.bits()) - 1;
+    match oversampling {
+        Some((ratio, shift)) => max * ratio.factor() >> shift as u32,
+        None => max,
+    }
+}
+
+/// Returns `true`, if oversampled results fit into the 16-bit result register
+fn oversampling_fits(
+    precision: Precision,
+    ratio: OversamplingRatio,
+    shift: OversamplingShift,
+) -> bool {
+    // `ratio` is a power of two, with `ratio as u32 + 1` being its exponent
+    precision.bits() + (ratio as u32 + 1) <= 16 + shift as u32
+}
+
+/// ADC oversampling ratio
+///
+/// The number of conversions that are accumulated into one result.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OversamplingRatio {
+    X2 = 0b000,
+    X4 = 0b001,
+    X8 = 0b010,
+    X16 = 0b011,
+    X32 = 0b100,
+    X64 = 0b101,
+    X128 = 0b110,
+    X256 = 0b111,
+}
+
+impl OversamplingRatio {
+    /// Returns the number of accumulated conversions
+    fn factor(self) -> u32 {
+        2 << self as u32
+    }
+}
+
+/// Right shift applied to the accumulated oversampling result
+///
+/// The result register is 16 bits wide, so the shift needs to be large enough
+/// for the accumulated result to fit. For example, 256 12-bit conversions
+/// need a shift of at least 4.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OversamplingShift {
+    S0 = 0b0000,
+    S1 = 0b0001,
+    S2 = 0b0010,
+    S3 = 0b0011,
+    S4 = 0b0100,
+    S5 = 0b0101,
+    S6 = 0b0110,
+    S7 = 0b0111,
+    S8 = 0b1000,
+}
+
+/// Nominal analog supply voltage, in millivolts
+const VDDA_NOMINAL_MV: u16 = 3000;
+
 /// ADC Sampling time
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum SampleTime {
@@ -90,6 +174,9 @@ pub struct Adc<State> {
     sample_time: SampleTime,
     align: Align,
     precision: Precision,
+    oversampling: Option<(OversamplingRatio, OversamplingShift)>,
+    /// V_DDA measured by `read_vdda`, in millivolts
+    vdda_mv: u16,
     _state: State,
 }
 
@@ -104,6 +191,8 @@ impl Adc<Ready> {
             sample_time: SampleTime::T_1_5,
             align: Align::Right,
             precision: Precision::B_12,
+            oversampling: None,
+            vdda_mv: VDDA_NOMINAL_MV,
             _state: Ready,
         }
     }
@@ -123,6 +212,37 @@ impl Adc<Ready> {
         self.precision = precision;
     }
 
+    /// Enables hardware oversampling
+    ///
+    /// Every result is the sum of `ratio` conversions, shifted right by
+    /// `shift`. This averages out noise without any CPU involvement, and can
+    /// increase the resolution up to 16 bits. For example, 256 12-bit
+    /// conversions shifted by 4 result in a 16-bit value. Applies to `read`
+    /// and to continuous conversions started with [`Adc::start`].
+    ///
+    /// Oversampled results are always right aligned. The configured alignment
+    /// is ignored.
+    ///
+    /// Returns `Error::InvalidOversampling` and leaves the configuration
+    /// unchanged, if `shift` is too small for the result to fit into 16 bits
+    /// at the configured precision. Set the precision first.
+    pub fn set_oversampling(
+        &mut self,
+        ratio: OversamplingRatio,
+        shift: OversamplingShift,
+    ) -> Result<(), Error> {
+        if !oversampling_fits(self.precision, ratio, shift) {
+            return Err(Error::InvalidOversampling);
+        }
+        self.oversampling = Some((ratio, shift));
+        Ok(())
+    }
+
+    /// Disables hardware oversampling
+    pub fn disable_oversampling(&mut self) {
+        self.oversampling = None;
+    }
+
     /// Trigger internal ADC calibration
     ///
     /// This process is documented in Reference Manual RM377, section 13.3.3
@@ -140,6 +260,132 @@ impl Adc<Ready> {
         Ok(())
     }
 
+    /// Measures the analog supply voltage V_DDA, in millivolts
+    ///
+    /// Converts the internal voltage reference and compares the result with
+    /// the factory calibration value, which was acquired at V_DDA = 3 V. The
+    /// internal reference channel is enabled for the duration of the
+    /// measurement, using [`Adc::read_vref_managed`], so `delay` is used to
+    /// wait for its start-up time. The configured sample time, alignment, and
+    /// precision are not affected.
+    ///
+    /// The result is also stored and used by [`Adc::to_millivolts`].
+    ///
+    /// Returns 0, if the conversion result is 0.
+    pub fn read_vdda<D>(&mut self, delay: &mut D) -> nb::Result<u16, ()>
+    where
+        D: DelayUs<u8>,
+    {
+        let raw = self.read_vref_managed(delay)?;
+        if raw == 0 {
+            return Ok(0);
+        }
+        self.vdda_mv = calibration::vdda_from_vrefint(raw);
+        Ok(self.vdda_mv)
+    }
+
+    /// Converts a conversion result to millivolts
+    ///
+    /// Takes the configured precision, alignment, and oversampling into
+    /// account. V_DDA, which is the reference voltage of the ADC, is taken
+    /// from the last call to [`Adc::read_vdda`] or [`Adc::read_millivolts`].
+    /// Until then, the nominal 3 V are assumed.
+    pub fn to_millivolts(&self, raw: u16) -> u16 {
+        let bits = self.precision.bits();
+        // Oversampled results are always right-aligned
+        let value = match (self.oversampling, &self.align) {
+            (None, Align::Left) => (raw >> (16 - bits)) as u32,
+            _ => raw as u32,
+        };
+        let full_scale = full_scale(self.precision, self.oversampling);
+        (value * self.vdda_mv as u32 / full_scale.max(1)) as u16
+    }
+
+    /// Converts `pin` and returns the result in millivolts
+    ///
+    /// Measures V_DDA first, using [`Adc::read_vdda`], so the result is
+    /// correct regardless of the supply voltage. This is useful on battery
+    /// powered devices, where V_DDA drops over time.
+    pub fn read_millivolts<PIN, D>(&mut self, pin: &mut PIN, delay: &mut D) -> nb::Result<u16, ()>
+    where
+        PIN: Channel<Adc<Ready>, ID = u8>,
+        D: DelayUs<u8>,
+    {
+        self.read_vdda(delay)?;
+        let raw: u16 = self.read(pin)?;
+        Ok(self.to_millivolts(raw))
+    }
+
+    /// Converts the internal temperature sensor, waiting for it to settle
+    ///
+    /// If the temperature sensor isn't enabled yet, it is enabled, and
+    /// `delay` is used to wait for its start-up time. Afterwards, it is
+    /// disabled again. The conversion uses the longest sample time, to meet
+    /// the minimum sample time of the sensor. The configured sample time,
+    /// alignment, and precision are not affected.
+    ///
+    /// Returns the raw, right-aligned 12-bit result, which can be compared
+    /// with the factory calibration values in [`crate::calibration`].
+    pub fn read_temp_managed<D>(&mut self, delay: &mut D) -> nb::Result<u16, ()>
+    where
+        D: DelayUs<u8>,
+    {
+        let tsen = self.rb.ccr.read().tsen().bit_is_set();
+        let mut vtemp = VTemp::new();
+        if !tsen {
+            vtemp.enable(self);
+            delay.delay_us(T_START_US);
+        }
+        let raw = self.read_internal(&mut vtemp);
+        if !tsen {
+            vtemp.disable(self);
+        }
+        raw
+    }
+
+    /// Converts the internal voltage reference, waiting for it to settle
+    ///
+    /// Like [`Adc::read_temp_managed`], but for VREFINT.
+    pub fn read_vref_managed<D>(&mut self, delay: &mut D) -> nb::Result<u16, ()>
+    where
+        D: DelayUs<u8>,
+    {
+        let vrefen = self.rb.ccr.read().vrefen().bit_is_set();
+        let mut vref = VRef::new();
+        if !vrefen {
+            vref.enable(self);
+            delay.delay_us(T_START_US);
+        }
+        let raw = self.read_internal(&mut vref);
+        if !vrefen {
+            vref.disable(self);
+        }
+        raw
+    }
+
+    /// Converts an internal channel with the longest sample time, as a
+    /// right-aligned 12-bit value
+    ///
+    /// Restores the configured settings afterwards.
+    fn read_internal<C>(&mut self, channel: &mut C) -> nb::Result<u16, ()>
+    where
+        C: Channel<Adc<Ready>, ID = u8>,
+    {
+        let sample_time = mem::replace(&mut self.sample_time, SampleTime::T_160_5);
+        let align = mem::replace(&mut self.align, Align::Right);
+        let precision = mem::replace(&mut self.precision, Precision::B_12);
+        let oversampling = self.oversampling.take();
+
+        let raw = self.read(channel);
+
+        self.sample_time = sample_time;
+        self.align = align;
+        self.precision = precision;
+        self.oversampling = oversampling;
+
+        raw
+    }
+
     /// Starts a continuous conversion process
     ///
     /// The `channel` argument specifies which channel should be converted.
@@ -188,14 +434,7 @@ impl Adc<Ready> {
 
         // The cast to `u16` could truncate the value, but if it does,
         // `Transfer::new` is going to panic anyway.
-        let buffer_unsafe = Buffer {
-            ptr: buffer.as_ptr(),
-            len: buffer.len() as u16,
-            pos: 0,
-            dma_pos: 0,
-
-            r_gt_w: false,
-        };
+        let buffer_unsafe = dma::CircularBuffer::new(buffer.as_ptr(), buffer.len() as u16);
 
         // Safe, because the trait bounds of this method guarantee that the
         // buffer can be written to.
@@ -224,9 +463,12 @@ impl Adc<Ready> {
             sample_time: self.sample_time,
             align: self.align,
             precision: self.precision,
+            oversampling: self.oversampling,
+            vdda_mv: self.vdda_mv,
             _state: Active {
                 buffer: buffer_unsafe,
                 transfer,
+                seq_start: 0,
             },
         }
     }
@@ -253,6 +495,166 @@ where
             transfer: &mut self._state.transfer,
         })
     }
+
+    /// Returns an iterator over all currently available values, together with
+    /// their channel
+    ///
+    /// Works like [`Adc::read_available`], but yields `(channel, value)`
+    /// pairs. The channel is derived from the position of the value within
+    /// the scanned sequence, as channels are scanned in ascending order. For
+    /// this to work, the length of the buffer must be a multiple of the
+    /// number of scanned channels.
+    ///
+    /// If the ADC overran, values are missing from the buffer, and the channel
+    /// of the following values can't be known. The scan is restarted in that
+    /// case, so the next value belongs to the first channel of a sequence
+    /// again, and `Error::AdcOverrun` is returned.
+    pub fn read_available_by_channel(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<(u8, u16), Error>> + '_, Error> {
+        if self.rb.isr.read().ovr().is_overrun() {
+            self.resync();
+            return Err(Error::AdcOverrun);
+        }
+
+        let channels = self.rb.chselr.read().bits();
+
+        Ok(ReadAvailableByChannel {
+            buffer: &mut self._state.buffer,
+            transfer: &mut self._state.transfer,
+            channels,
+            seq_start: self._state.seq_start,
+        })
+    }
+
+    /// Restarts the scan, so the next value is the first of a sequence
+    fn resync(&mut self) {
+        self.rb.cr.modify(|_, w| w.adstp().set_bit());
+        while self.rb.cr.read().adstart().bit_is_set() {}
+
+        self.rb.isr.write(|w| w.ovr().clear());
+
+        let (remaining, _, _) = self._state.transfer.state();
+        self._state.seq_start = (self._state.buffer.len() - remaining) % self._state.buffer.len();
+
+        self.rb.isr.modify(|_, w| w.eos().set_bit());
+        self.rb.cr.modify(|_, w| w.adstart().set_bit());
+    }
+
+    /// Converts a single channel, interrupting the running scan
+    ///
+    /// This can be used to get a value for an urgent channel (e.g. overcurrent
+    /// sense) without waiting for the scan to come around to it, or for a
+    /// channel that isn't part of the scan at all.
+    ///
+    /// The scan is stopped and the interrupted sequence is completed, so the
+    /// next value written to the buffer belongs to the first channel of the
+    /// sequence again. The urgent value is not written to the buffer. Then
+    /// the scan is resumed with its original configuration. Values that are
+    /// already in the buffer are not affected.
+    ///
+    /// The interrupted sequence can only be completed correctly, if the
+    /// length of the buffer is a multiple of the number of scanned channels.
+    pub fn convert_urgent<PIN>(&mut self, _: &mut PIN) -> u16
+    where
+        PIN: Channel<Adc<Ready>, ID = u8>,
+    {
+        // Stop the scan. The conversion in progress, if any, is aborted.
+        self.rb.cr.modify(|_, w| w.adstp().set_bit());
+        while self.rb.cr.read().adstart().bit_is_set() {}
+
+        let cfgr1 = self.rb.cfgr1.read().bits();
+        let chselr = self.rb.chselr.read().bits();
+
+        // Check how many channels of the current sequence have already been
+        // transferred to the buffer.
+        let (remaining, _, _) = self._state.transfer.state();
+        let len = u32::from(self._state.buffer.len());
+        let pos = u32::from(self._state.buffer.len() - remaining);
+        let converted = (pos + len - u32::from(self._state.seq_start)) % chselr.count_ones().max(1);
+
+        // Single software-triggered sequence
+        self.rb.cfgr1.modify(|_, w| {
+            w.cont().clear_bit();
+            w.exten().disabled()
+        });
+
+        if converted != 0 {
+            // Channels are scanned in ascending order. Convert the channels
+            // missing from the interrupted sequence.
+            let mut missing = chselr;
+            for _ in 0..converted {
+                missing &= missing - 1;
+            }
+
+            self.rb.chselr.write(|w| unsafe { w.bits(missing) });
+            self.convert_sequence();
+        }
+
+        // Convert the urgent channel, without generating a DMA request
+        self.rb.cfgr1.modify(|_, w| w.dmaen().clear_bit());
+        self.rb
+            .chselr
+            .write(|w| unsafe { w.bits(0x1 << PIN::channel()) });
+        self.convert_sequence();
+
+        let res = self.rb.dr.read().bits() as u16;
+        let val = if self.align == Align::Left
+            && self.precision == Precision::B_6
+            && self.oversampling.is_none()
+        {
+            res << 8
+        } else {
+            res
+        };
+
+        // Resume the scan
+        self.rb.cfgr1.write(|w| unsafe { w.bits(cfgr1) });
+        self.rb.chselr.write(|w| unsafe { w.bits(chselr) });
+        self.rb.isr.modify(|_, w| w.eos().set_bit());
+        self.rb.cr.modify(|_, w| w.adstart().set_bit());
+
+        val
+    }
+
+    /// Stops the conversions and returns to the `Ready` state
+    ///
+    /// The running conversion, if any, is aborted, the ADC is disabled, and
+    /// the DMA transfer is ended. Returns the ADC together with the DMA
+    /// channel and the buffer, so they can be reused, for example to start
+    /// conversions with a different configuration.
+    pub fn stop(mut self) -> (Adc<Ready>, DmaChan, Pin<Buffer>) {
+        self.rb.cr.modify(|_, w| w.adstp().set_bit());
+        while self.rb.cr.read().adstart().bit_is_set() {}
+
+        self.power_down();
+        self.rb
+            .cfgr1
+            .modify(|_, w| w.dmaen().clear_bit().cont().clear_bit());
+        self.rb.isr.write(|w| w.ovr().clear());
+
+        // The DMA token is dropped here, which allows `Adc::start` to create
+        // a new one.
+        let res = self._state.transfer.stop();
+
+        let adc = Adc {
+            rb: self.rb,
+            sample_time: self.sample_time,
+            align: self.align,
+            precision: self.precision,
+            oversampling: self.oversampling,
+            vdda_mv: self.vdda_mv,
+            _state: Ready,
+        };
+
+        (adc, res.channel, res.buffer)
+    }
+
+    fn convert_sequence(&mut self) {
+        self.rb.isr.modify(|_, w| w.eos().set_bit());
+        self.rb.cr.modify(|_, w| w.adstart().set_bit());
+        while self.rb.isr.read().eos().bit_is_clear() {}
+    }
 }
 
 impl<State> Adc<State> {
@@ -260,6 +662,38 @@ impl<State> Adc<State> {
         self.rb
     }
 
+    /// Enables the end of sampling interrupt
+    ///
+    /// The end of sampling (EOSMP) flag is set as soon as the sampling phase of
+    /// a conversion is finished, while the conversion itself is still running.
+    /// At that point, the input is no longer needed, so an external analog
+    /// multiplexer can already be switched to the next input. This leaves the
+    /// conversion time for the multiplexer output to settle, and allows
+    /// scanning many external inputs through a single ADC channel faster.
+    pub fn listen_end_of_sampling(&mut self) {
+        self.rb.ier.modify(|_, w| w.eosmpie().set_bit());
+    }
+
+    /// Disables the end of sampling interrupt
+    pub fn unlisten_end_of_sampling(&mut self) {
+        self.rb.ier.modify(|_, w| w.eosmpie().clear_bit());
+    }
+
+    /// Indicates whether the sampling phase of a conversion has finished
+    ///
+    /// See [`Adc::listen_end_of_sampling`]. The flag needs to be cleared with
+    /// [`Adc::clear_end_of_sampling`].
+    pub fn is_end_of_sampling(&self) -> bool {
+        self.rb.isr.read().eosmp().bit_is_set()
+    }
+
+    /// Clears the end of sampling flag
+    pub fn clear_end_of_sampling(&mut self) {
+        // The flags in ISR are cleared by writing 1, so writing only this bit
+        // leaves the others untouched.
+        self.rb.isr.write(|w| w.eosmp().set_bit());
+    }
+
     fn power_up(&mut self) {
         self.rb.isr.modify(|_, w| w.adrdy().set_bit());
         self.rb.cr.modify(|_, w| w.aden().set_bit());
@@ -292,6 +726,20 @@ impl<State> Adc<State> {
             w
         });
 
+        // Only allowed while no conversion is ongoing, which is the case here
+        #[allow(unused_unsafe)]
+        self.rb.cfgr2.modify(|_, w| match self.oversampling {
+            Some((ratio, shift)) => unsafe {
+                w.ovse()
+                    .set_bit()
+                    .ovsr()
+                    .bits(ratio as u8)
+                    .ovss()
+                    .bits(shift as u8)
+            },
+            None => w.ovse().clear_bit(),
+        });
+
         self.rb
             .smpr
             .modify(|_, w| w.smp().bits(self.sample_time as u8));
@@ -326,7 +774,10 @@ where
         while self.rb.isr.read().eos().bit_is_clear() {}
 
         let res = self.rb.dr.read().bits() as u16;
-        let val = if self.align == Align::Left && self.precision == Precision::B_6 {
+        let val = if self.align == Align::Left
+            && self.precision == Precision::B_6
+            && self.oversampling.is_none()
+        {
             res << 8
         } else {
             res
@@ -344,7 +795,9 @@ pub struct Ready;
 /// Indicates that the ADC peripheral is performing conversions
 pub struct Active<DmaChan, Buf> {
     transfer: dma::Transfer<DmaToken, DmaChan, Buf, dma::Started>,
-    buffer: Buffer,
+    buffer: dma::CircularBuffer<u16>,
+    /// Buffer position of the first value of a sequence
+    seq_start: u16,
 }
 
 /// A collection of channels
@@ -381,9 +834,28 @@ where
 }
 
 /// Hardware triggers that can start an ADC conversion
+///
+/// The trigger inputs are the same on all STM32L0 sub-families, but the
+/// timers behind some of them only exist in some product categories. The
+/// triggers of missing timers are left out, if the product category is
+/// selected with an `io-*` feature. See the "External triggers" table in the
+/// reference manual (RM0377 for STM32L0x1, RM0376 for STM32L0x2, RM0367 for
+/// STM32L0x3).
+///
+/// On Category 1 devices (e.g. STM32L011), TIM2 and EXTI line 11 are the only
+/// trigger sources. Category 2 devices (e.g. STM32L031) add TIM22.
+///
+/// The LPTIM is not connected to the ADC trigger inputs. To sample at the
+/// rate of the LPTIM, its output pin can be wired externally to a pin with
+/// the number 11, using the `EXTI11` trigger. Otherwise, use TIM2 or TIM21,
+/// which exist on all devices.
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Trigger {
     /// TRG0
+    ///
+    /// Only available on Category 3 and 5 devices (e.g. STM32L05x/L07x).
+    #[cfg(not(any(feature = "io-STM32L021", feature = "io-STM32L031")))]
     TIM6_TRGO = 0b000,
 
     /// TRG1
@@ -396,219 +868,87 @@ pub enum Trigger {
     TIM2_CH4 = 0b011,
 
     /// TRG4
+    ///
+    /// Only available on Category 2, 3 and 5 devices (e.g.
+    /// STM32L03x/L05x/L07x).
+    #[cfg(not(feature = "io-STM32L021"))]
     TIM22_TRGO = 0b100,
 
     /// TRG5
     ///
     /// Only available on Category 5 devices.
-    #[cfg(any(feature = "stm32l072", feature = "stm32l082"))]
+    #[cfg(any(feature = "io-STM32L071", feature = "stm32l072", feature = "stm32l082"))]
     TIM2_CH3 = 0b101,
 
     /// TRG6
+    ///
+    /// Only available on Category 5 devices (e.g. STM32L07x).
+    #[cfg(not(any(
+        feature = "io-STM32L021",
+        feature = "io-STM32L031",
+        feature = "io-STM32L051"
+    )))]
     TIM3_TRGO = 0b110,
 
     /// TRG7
+    ///
+    /// Rising edge on EXTI line 11. The line must be configured using the
+    /// [`exti`](crate::exti) API, for a GPIO pin with the number 11 (e.g.
+    /// `PA11` or `PB11`). Available on all devices, including Category 1
+    /// and 2 devices without TIM6/TIM3.
     EXTI11 = 0b111,
 }
 
-/// Provides access to the buffer that the DMA writes ADC values into
-///
-/// Since the DMA transfer takes ownership of the buffer, we need to access it
-/// with unsafe means. This struct is a safe wrapper around this unsafe access.
-struct Buffer {
-    ptr: *const u16,
-    len: u16,
-    pos: u16,
-    dma_pos: u16,
-
-    /// Indicates order of read and write indices
-    ///
-    /// This is initially `false`, indicating that the read position (the `pos`
-    /// field) is smaller than or equal to the write position (internally
-    /// managed by the DMA peripheral).
-    ///
-    /// Once the write position wraps around the buffer boundary, this becomes
-    /// `true` until the read position also wraps around.
-    r_gt_w: bool,
+/// Iterator over buffered ADC values
+pub struct ReadAvailable<'r, T, C, B> {
+    buffer: &'r mut dma::CircularBuffer<u16>,
+    transfer: &'r dma::Transfer<T, C, B, dma::Started>,
 }
 
-impl Buffer {
-    fn read<T, C, B>(
-        &mut self,
-        transfer: &dma::Transfer<T, C, B, dma::Started>,
-    ) -> Option<Result<u16, Error>>
-    where
-        C: dma::Channel,
-    {
-        let transfer_state = self.transfer_state(transfer);
-        if self.check_overrun(transfer_state) {
-            return Some(Err(Error::BufferOverrun));
-        }
-
-        if self.pos == transfer_state.pos {
-            // No overrun detected, but read and write positions are equal. This
-            // can only mean that the buffer is empty.
-            return None;
-        }
-
-        // Safe, as we know that `ptr` and `len` define a valid buffer, and we
-        // make sure that `pos <= len`. There's a race condition between this
-        // line and the DMA peripheral, of course, but we take care of that with
-        // these overrun checks.
-        //
-        // The cast is fine too. This is a 32-bit platform, so casting a `u16`
-        // to an `isize` will never truncate the value.
-        compiler_fence(Ordering::SeqCst);
-        let value = unsafe { *self.ptr.offset(self.pos as isize) };
-        compiler_fence(Ordering::SeqCst);
-
-        // At this point we know that there was no overrun before we started
-        // reading, but of course the DMA might have overtaken us since that
-        // check. Let's check again. If there's still no overrun, we know that
-        // our value is valid.
-        let transfer_state = self.transfer_state(transfer);
-        if self.check_overrun(transfer_state) {
-            // Strictly speaking, the overrun might have happened after our
-            // read, and `value` might be valid. No way to know for sure though,
-            // so let's assume overrun.
-            return Some(Err(Error::BufferOverrun));
-        }
-
-        // Now we know that the value we read is totally fine. Let's advance the
-        // read position to finish up here.
-        self.pos = self.pos.wrapping_add(1);
-        if self.pos == 0 || self.pos >= self.len {
-            // We advanced beyond the end of the buffer, which means we need to
-            // wrap around to the beginning.
-            self.pos = 0;
-            self.r_gt_w = false;
-        }
-
-        Some(Ok(value))
-    }
-
-    fn transfer_state<T, C, B>(
-        &self,
-        transfer: &dma::Transfer<T, C, B, dma::Started>,
-    ) -> TransferState
-    where
-        C: dma::Channel,
-    {
-        let (remaining, half, complete) = transfer.state();
-        transfer.clear_flags();
-
-        // Let's translate what we got from the DMA peripheral into a write
-        // position that we can compare with our read position.
-        let pos = self.len - remaining;
-
-        TransferState {
-            pos,
-            half,
-            complete,
-        }
-    }
-
-    fn check_overrun(&mut self, transfer_state: TransferState) -> bool {
-        let overrun = self.check_overrun_inner(transfer_state);
-        self.dma_pos = transfer_state.pos; // Update our state of the DMA
-
-        if overrun {
-            // An overrun occured, but that is not a catastrophic error. Values
-            // got lost, but that doesn't mean we can't read the new values
-            // starting now. Let's get the buffer into a consistent state to
-            // make that possible.
-            //
-            // There are various ways to go about this. What we're doing here is
-            // to throw away all values in the buffer and start again with an
-            // empty buffer, because that minimizes the likelihood of getting
-            // another overrun right away.
-            //
-            // Maybe doing the opposite, setting the read position so that the
-            // buffer is full, to minimize lost values, would be better. But
-            // then we should give the user the option to empty the buffer
-            // manually. I've chosen to go with the simpler option for now.
-            self.pos = transfer_state.pos;
-            self.r_gt_w = false;
-        }
-
-        overrun
-    }
-
-    fn check_overrun_inner(&mut self, transfer_state: TransferState) -> bool {
-        if transfer_state.half && transfer_state.complete {
-            // Each time we attempt a read, we clear both flags. If both flags
-            // are set, then basically anything could have happened in between,
-            // so we have to assume an overrun.
-            //
-            // Please note that it's possible that the DMA has written beyond
-            // the half point and wrapped around, causing both of the flags to
-            // be set, without passing our current reading position. However,
-            // there's no way to distinguish this case from the DMA having
-            // passed those marks multiple times, so we have to be conservative
-            // and assume an overrun.
-            return true;
-        }
-
-        if transfer_state.complete && self.dma_pos < transfer_state.pos {
-            // If the complete flag is set and our previous position is less than
-            // the current position then an overrun must have occurred
-            // This is because the DMA must have wrapped to 0 and then ran past us again
-            return true;
-        }
-
-        // Don't use the transfer complete flag to detect wrap (aside from the overrun above)
-        // There is a timing issue with reading and clearing it so depend on relative positions
-        if transfer_state.pos < self.dma_pos {
-            // The write has wrapped beyond the buffer boundary and started
-            // again at the beginning of the buffer. This is completely normal,
-            // but it affects how we detect an overrun.
-
-            if self.r_gt_w {
-                // The read position was greater than the write position, so if
-                // the write position wrapped, it must have overtaken the read
-                // position. This is an overrun.
-                return true;
-            }
-
-            // The write position has wrapped, so now the read position needs
-            // to be greater than the write position.
-            self.r_gt_w = true;
-        }
+impl<T, C, B> Iterator for ReadAvailable<'_, T, C, B>
+where
+    C: dma::Channel,
+{
+    type Item = Result<u16, Error>;
 
-        // At this point we know that everything _could_ be alright, judging
-        // from the combination of flags we checked so far. We still need to
-        // compare read and write positions to make sure that we don't actually
-        // have an overrun.
-        if self.r_gt_w {
-            self.pos <= transfer_state.pos
-        } else {
-            self.pos > transfer_state.pos
-        }
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buffer
+            .read(self.transfer)
+            .map(|result| result.map_err(|dma::Overrun| Error::BufferOverrun))
     }
 }
 
-/// Internal struct to represent the current state of the DMA transfer
-#[derive(Clone, Copy, Debug)]
-struct TransferState {
-    pos: u16,
-    half: bool,
-    complete: bool,
-}
-
-/// Iterator over buffered ADC values
-pub struct ReadAvailable<'r, T, C, B> {
-    buffer: &'r mut Buffer,
+/// Iterator over buffered ADC values and their channels
+pub struct ReadAvailableByChannel<'r, T, C, B> {
+    buffer: &'r mut dma::CircularBuffer<u16>,
     transfer: &'r dma::Transfer<T, C, B, dma::Started>,
+    channels: u32,
+    seq_start: u16,
 }
 
-impl<T, C, B> Iterator for ReadAvailable<'_, T, C, B>
+impl<T, C, B> Iterator for ReadAvailableByChannel<'_, T, C, B>
 where
     C: dma::Channel,
 {
-    type Item = Result<u16, Error>;
+    type Item = Result<(u8, u16), Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.buffer.read(self.transfer)
+        let pos = u32::from(self.buffer.pos());
+        let value = match self.buffer.read(self.transfer)? {
+            Ok(value) => value,
+            Err(dma::Overrun) => return Some(Err(Error::BufferOverrun)),
+        };
+
+        // Find the channel of this value in the sequence. Channels are
+        // scanned in ascending order.
+        let len = u32::from(self.buffer.len());
+        let index = (pos + len - u32::from(self.seq_start)) % self.channels.count_ones().max(1);
+        let mut channels = self.channels;
+        for _ in 0..index {
+            channels &= channels - 1;
+        }
+
+        Some(Ok((channels.trailing_zeros() as u8, value)))
     }
 }
 
@@ -636,6 +976,27 @@ pub enum Error {
 
     /// Invalid ADC state for requested operation
     InvalidAdcState,
+
+    /// V_DDA couldn't be measured, as the internal voltage reference read 0
+    ///
+    /// A reference that isn't buffered for the ADC doesn't necessarily read
+    /// 0, so this doesn't detect all cases in which V_DDA is wrong.
+    VddaUnavailable,
+
+    /// The oversampling ratio and shift would result in more than 16 bits
+    InvalidOversampling,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AdcOverrun => f.write_str("ADC overrun"),
+            Error::BufferOverrun => f.write_str("buffer overrun"),
+            Error::InvalidAdcState => f.write_str("invalid ADC state"),
+            Error::VddaUnavailable => f.write_str("V_DDA unavailable"),
+            Error::InvalidOversampling => f.write_str("invalid oversampling configuration"),
+        }
+    }
 }
 
 macro_rules! int_adc {
@@ -691,6 +1052,42 @@ int_adc! {
     VRef: (17, vrefen),
 }
 
+impl VTemp {
+    /// Measures the temperature of the die, in tenths of a degree Celsius
+    ///
+    /// Measures V_DDA first, then converts the temperature sensor using
+    /// [`Adc::read_temp_managed`] and applies the factory calibration, see
+    /// [`calibration::temp_tenths_from_raw`]. If VREFINT or the sensor isn't
+    /// enabled yet, it is enabled for the duration of the measurement, and
+    /// `delay` is used to wait for its start-up time.
+    ///
+    /// Both VREFINT and the sensor output are buffered for the ADC, so the
+    /// [`VrefBuffer::Adc`] and [`VrefBuffer::TemperatureSensor`] buffers need
+    /// to be enabled with [`SYSCFG::enable_vref_buffer`] first. A missing
+    /// buffer isn't reliably detected, and usually results in a wrong
+    /// temperature. Returns `Error::VddaUnavailable` only if VREFINT reads 0,
+    /// and `Error::InvalidAdcState`, if a conversion fails.
+    ///
+    /// [`VrefBuffer::Adc`]: crate::syscfg::VrefBuffer::Adc
+    /// [`VrefBuffer::TemperatureSensor`]: crate::syscfg::VrefBuffer::TemperatureSensor
+    /// [`SYSCFG::enable_vref_buffer`]: crate::syscfg::SYSCFG::enable_vref_buffer
+    pub fn read_temperature<D>(&mut self, adc: &mut Adc<Ready>, delay: &mut D) -> Result<i16, Error>
+    where
+        D: DelayUs<u8>,
+    {
+        let vdda_mv = match adc.read_vdda(delay) {
+            Ok(vdda_mv) if vdda_mv != 0 => vdda_mv,
+            _ => return Err(Error::VddaUnavailable),
+        };
+
+        let raw = adc
+            .read_temp_managed(delay)
+            .map_err(|_| Error::InvalidAdcState)?;
+
+        Ok(calibration::temp_tenths_from_raw(raw, vdda_mv))
+    }
+}
+
 adc_pins! {
     Channel0: (gpioa::PA0<Analog>, 0u8),
     Channel1: (gpioa::PA1<Analog>, 1u8),
@@ -768,3 +1165,43 @@ adc_pins! {
     Channel14: (gpioc::PC4<Analog>, 14u8),
     Channel15: (gpioc::PC5<Analog>, 15u8),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_scale_without_oversampling() {
+        assert_eq!(full_scale(Precision::B_12, None), 4095);
+        assert_eq!(full_scale(Precision::B_6, None), 63);
+    }
+
+    #[test]
+    fn full_scale_with_oversampling() {
+        use OversamplingRatio::*;
+        use OversamplingShift::*;
+
+        assert_eq!(full_scale(Precision::B_12, Some((X2, S1))), 4095);
+        assert_eq!(full_scale(Precision::B_12, Some((X16, S4))), 4095);
+        assert_eq!(full_scale(Precision::B_12, Some((X16, S0))), 65520);
+        assert_eq!(full_scale(Precision::B_12, Some((X256, S8))), 4095);
+        assert_eq!(full_scale(Precision::B_12, Some((X256, S4))), 65520);
+        assert_eq!(full_scale(Precision::B_6, Some((X4, S0))), 252);
+    }
+
+    #[test]
+    fn oversampling_result_width() {
+        use OversamplingRatio::*;
+        use OversamplingShift::*;
+
+        assert!(oversampling_fits(Precision::B_12, X16, S0));
+        assert!(oversampling_fits(Precision::B_12, X256, S4));
+        assert!(oversampling_fits(Precision::B_6, X256, S0));
+
+        // 20 bits
+        assert!(!oversampling_fits(Precision::B_12, X256, S0));
+        // 17 bits
+        assert!(!oversampling_fits(Precision::B_12, X32, S0));
+        assert!(!oversampling_fits(Precision::B_12, X256, S3));
+    }
+}
@@ -6,14 +6,14 @@
 //! See STM32L0x2 reference manual, chapter 18.
 
 use core::{
-    convert::TryInto,
+    convert::{Infallible, TryInto},
+    fmt,
     ops::{Deref, DerefMut},
     pin::Pin,
 };
 
 use as_slice::{AsMutSlice, AsSlice};
 use nb::block;
-use void::Void;
 
 use crate::{
     dma,
@@ -84,6 +84,53 @@ impl AES {
 
         Stream {
             aes: self,
+            key,
+            rx: Rx(()),
+            tx: Tx(()),
+        }
+    }
+
+    /// Resumes a stream that has been suspended using [`Stream::suspend`]
+    ///
+    /// Restores the key, the chaining mode, and the initialization vector or
+    /// counter, so processing continues with the block after the last one
+    /// processed before the suspension.
+    pub fn resume(self, context: Context) -> Stream {
+        let aes = &self.aes;
+
+        // Follows the procedure in the STM32L0x2 reference manual, section
+        // 18.4.8. The peripheral is already disabled.
+
+        // Safe, as the registers accept the full range of `u32`.
+        aes.keyr0.write(|w| w.bits(context.key[0]));
+        aes.keyr1.write(|w| w.bits(context.key[1]));
+        aes.keyr2.write(|w| w.bits(context.key[2]));
+        aes.keyr3.write(|w| w.bits(context.key[3]));
+
+        // Decryption modes need the derived key, which isn't saved
+        if context.mode == 0b10 {
+            derive_key(aes);
+            aes.cr.modify(|_, w| w.ccfc().set_bit());
+        }
+
+        aes.ivr0.write(|w| w.bits(context.init_vector[0]));
+        aes.ivr1.write(|w| w.bits(context.init_vector[1]));
+        aes.ivr2.write(|w| w.bits(context.init_vector[2]));
+        aes.ivr3.write(|w| w.bits(context.init_vector[3]));
+
+        aes.cr.modify(|_, w| {
+            // Safe, as the values have been read from the register before.
+            unsafe {
+                w.chmod().bits(context.chmod);
+                w.mode().bits(context.mode);
+                w.datatype().bits(0b10);
+            }
+            w.en().set_bit()
+        });
+
+        Stream {
+            aes: self,
+            key: context.key,
             rx: Rx(()),
             tx: Tx(()),
         }
@@ -95,6 +142,7 @@ impl AES {
 /// You can get an instance of this struct by calling [`AES::enable`].
 pub struct Stream {
     aes: AES,
+    key: [u32; 4],
 
     /// Can be used to write data to the AES peripheral
     pub tx: Tx,
@@ -113,11 +161,52 @@ impl Stream {
     /// selected when this `Stream` was created.
     pub fn process(&mut self, input: &Block) -> Result<Block, Error> {
         self.tx.write(input)?;
-        // Can't panic. Error value of `Rx::read` is `Void`.
+        // Can't panic. Error value of `Rx::read` is `Infallible`.
         let output = block!(self.rx.read()).unwrap();
         Ok(output)
     }
 
+    /// Suspends the stream, so the peripheral can be used for something else
+    ///
+    /// Saves the state of the computation and disables the peripheral. The
+    /// returned [`AES`] can be used to start another stream, for example to
+    /// compute a latency-critical MAC in the middle of a long decryption. Pass
+    /// the returned [`Context`] to [`AES::resume`] to continue later.
+    ///
+    /// Must be called between blocks. Returns [`Error::Busy`] together with
+    /// the stream, if a processed block hasn't been read yet. Please note that
+    /// a block that has been written, but whose processing hasn't finished
+    /// yet, can't be detected, so make sure to wait for it to be read.
+    pub fn suspend(self) -> Result<(AES, Context), (Self, Error)> {
+        let aes = &self.aes.aes;
+
+        if aes.sr.read().ccf().bit_is_set() {
+            return Err((self, Error::Busy));
+        }
+
+        // Follows the procedure in the STM32L0x2 reference manual, section
+        // 18.4.8. The IVR registers contain the updated chaining value or
+        // counter.
+        let init_vector = [
+            aes.ivr0.read().bits(),
+            aes.ivr1.read().bits(),
+            aes.ivr2.read().bits(),
+            aes.ivr3.read().bits(),
+        ];
+
+        let cr = aes.cr.read();
+        let context = Context {
+            key: self.key,
+            init_vector,
+            chmod: cr.chmod().bits(),
+            mode: cr.mode().bits(),
+        };
+
+        aes.cr.modify(|_, w| w.en().clear_bit());
+
+        Ok((self.aes, context))
+    }
+
     /// Disable the AES peripheral
     ///
     /// Consumes the stream and returns the disabled [`AES`] instance. Call this
@@ -131,6 +220,17 @@ impl Stream {
     }
 }
 
+/// The saved state of a suspended [`Stream`]
+///
+/// You can get an instance of this struct by calling [`Stream::suspend`], and
+/// resume the stream using [`AES::resume`].
+pub struct Context {
+    key: [u32; 4],
+    init_vector: [u32; 4],
+    chmod: u8,
+    mode: u8,
+}
+
 /// Can be used to write data to the AES peripheral
 ///
 /// You can access this struct via [`Stream`].
@@ -236,7 +336,7 @@ impl Tx {
 pub struct Rx(());
 
 impl Rx {
-    pub fn read(&mut self) -> nb::Result<Block, Void> {
+    pub fn read(&mut self) -> nb::Result<Block, Infallible> {
         // Get access to the registers. This is safe, because:
         // - We only use SR for an atomic read.
         // - `Rx` has exclusive access to DOUTR.
@@ -525,6 +625,14 @@ pub enum Error {
     Busy,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Busy => f.write_str("peripheral busy"),
+        }
+    }
+}
+
 /// Wrapper around a [`dma::Transfer`].
 ///
 /// This struct is required, because under the hood, the AES peripheral only
@@ -0,0 +1,384 @@
+//! Software (bit-banged) I2C and SPI
+//!
+//! These implementations work on any GPIO pins, for boards where the pins of a
+//! bus aren't connected to a suitable peripheral instance. They are a lot
+//! slower than the peripherals and keep the CPU busy for the whole transfer,
+//! but are otherwise drop-in replacements implementing the same embedded-hal
+//! traits.
+//!
+//! The bus timing is derived from a periodic timer (for example a [`Timer`] or
+//! an [`LpTimer`] in periodic mode), which is started at twice the bus
+//! frequency. Each timer period is one half of a clock cycle. Any interrupt
+//! that occurs during a transfer stretches the current half cycle, which is
+//! fine for both I2C and SPI slaves, as both buses are synchronous.
+//!
+//! [`Timer`]: crate::timer::Timer
+//! [`LpTimer`]: crate::lptim::LpTimer
+
+use core::fmt;
+
+use crate::hal::blocking::i2c::{Read, Write, WriteRead};
+use crate::hal::digital::v2::{InputPin, OutputPin};
+use crate::hal::spi::{FullDuplex, Mode, Phase, Polarity};
+use crate::hal::timer::{CountDown, Periodic};
+use embedded_time::rate::Hertz;
+
+/// I2C errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Accessing a pin failed
+    Bus(E),
+    /// The slave didn't acknowledge the address or a data byte
+    Nack,
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Bus(error) => write!(f, "accessing a pin failed: {:?}", error),
+            Error::Nack => f.write_str("no acknowledge"),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<E: fmt::Debug> embedded_hal_1::i2c::Error for Error<E> {
+    fn kind(&self) -> embedded_hal_1::i2c::ErrorKind {
+        use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource};
+
+        match self {
+            Error::Bus(_) => ErrorKind::Other,
+            Error::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+        }
+    }
+}
+
+/// Software I2C master
+///
+/// SCL and SDA must be open-drain outputs that can also be read, for example
+/// pins in `Output<OpenDrain>` mode, with pull-up resistors on the bus. Clock
+/// stretching by slaves is supported.
+pub struct I2c<SCL, SDA, TIM> {
+    scl: SCL,
+    sda: SDA,
+    timer: TIM,
+}
+
+impl<SCL, SDA, TIM, E> I2c<SCL, SDA, TIM>
+where
+    SCL: OutputPin<Error = E> + InputPin<Error = E>,
+    SDA: OutputPin<Error = E> + InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    /// Creates a new I2C master running at `freq`
+    ///
+    /// Releases both lines and starts `timer` at twice the bus frequency.
+    pub fn new(mut scl: SCL, mut sda: SDA, mut timer: TIM, freq: Hertz) -> Result<Self, Error<E>> {
+        scl.set_high().map_err(Error::Bus)?;
+        sda.set_high().map_err(Error::Bus)?;
+        timer.start(Hertz(freq.0 * 2));
+
+        Ok(I2c { scl, sda, timer })
+    }
+
+    /// Releases the pins and the timer
+    pub fn free(self) -> (SCL, SDA, TIM) {
+        (self.scl, self.sda, self.timer)
+    }
+
+    fn wait(&mut self) {
+        let _ = nb::block!(self.timer.wait());
+    }
+
+    /// Releases SCL and waits for slaves that stretch the clock
+    fn release_scl(&mut self) -> Result<(), Error<E>> {
+        self.scl.set_high().map_err(Error::Bus)?;
+        while self.scl.is_low().map_err(Error::Bus)? {}
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), Error<E>> {
+        // Also works as repeated start, where SCL is still low
+        self.sda.set_high().map_err(Error::Bus)?;
+        self.wait();
+        self.release_scl()?;
+        self.wait();
+
+        self.sda.set_low().map_err(Error::Bus)?;
+        self.wait();
+        self.scl.set_low().map_err(Error::Bus)
+    }
+
+    fn stop(&mut self) -> Result<(), Error<E>> {
+        self.sda.set_low().map_err(Error::Bus)?;
+        self.wait();
+        self.release_scl()?;
+        self.wait();
+
+        self.sda.set_high().map_err(Error::Bus)?;
+        self.wait();
+        Ok(())
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), Error<E>> {
+        if bit {
+            self.sda.set_high().map_err(Error::Bus)?;
+        } else {
+            self.sda.set_low().map_err(Error::Bus)?;
+        }
+        self.wait();
+        self.release_scl()?;
+        self.wait();
+        self.scl.set_low().map_err(Error::Bus)
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Error<E>> {
+        self.sda.set_high().map_err(Error::Bus)?;
+        self.wait();
+        self.release_scl()?;
+        self.wait();
+        let bit = self.sda.is_high().map_err(Error::Bus)?;
+        self.scl.set_low().map_err(Error::Bus)?;
+        Ok(bit)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Error<E>> {
+        for i in (0..8).rev() {
+            self.write_bit(byte & (1 << i) != 0)?;
+        }
+
+        // The slave pulls SDA low to acknowledge
+        if self.read_bit()? {
+            return Err(Error::Nack);
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self, ack: bool) -> Result<u8, Error<E>> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.read_bit()? as u8;
+        }
+        self.write_bit(!ack)?;
+        Ok(byte)
+    }
+
+    fn write_bytes(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error<E>> {
+        self.start()?;
+        self.write_byte(address << 1)?;
+        for &byte in bytes {
+            self.write_byte(byte)?;
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error<E>> {
+        self.start()?;
+        self.write_byte((address << 1) | 1)?;
+
+        let len = buffer.len();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            // Don't acknowledge the last byte, to end the transfer
+            *byte = self.read_byte(i + 1 < len)?;
+        }
+        Ok(())
+    }
+
+    /// Generates a stop condition after the transfer, also if it failed
+    fn finish(&mut self, result: Result<(), Error<E>>) -> Result<(), Error<E>> {
+        let stop = self.stop();
+        result.and(stop)
+    }
+}
+
+impl<SCL, SDA, TIM, E> Write for I2c<SCL, SDA, TIM>
+where
+    SCL: OutputPin<Error = E> + InputPin<Error = E>,
+    SDA: OutputPin<Error = E> + InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    type Error = Error<E>;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        let result = self.write_bytes(address, bytes);
+        self.finish(result)
+    }
+}
+
+impl<SCL, SDA, TIM, E> Read for I2c<SCL, SDA, TIM>
+where
+    SCL: OutputPin<Error = E> + InputPin<Error = E>,
+    SDA: OutputPin<Error = E> + InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    type Error = Error<E>;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.read_bytes(address, buffer);
+        self.finish(result)
+    }
+}
+
+impl<SCL, SDA, TIM, E> WriteRead for I2c<SCL, SDA, TIM>
+where
+    SCL: OutputPin<Error = E> + InputPin<Error = E>,
+    SDA: OutputPin<Error = E> + InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    type Error = Error<E>;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self
+            .write_bytes(address, bytes)
+            .and_then(|_| self.read_bytes(address, buffer));
+        self.finish(result)
+    }
+}
+
+/// Software SPI master
+///
+/// Data is transferred MSB first. `SCK` and `MOSI` must be outputs, `MISO` an
+/// input. The chip select line of the slave has to be controlled separately.
+pub struct Spi<SCK, MOSI, MISO, TIM> {
+    sck: SCK,
+    mosi: MOSI,
+    miso: MISO,
+    timer: TIM,
+    mode: Mode,
+    received: Option<u8>,
+}
+
+impl<SCK, MOSI, MISO, TIM, E> Spi<SCK, MOSI, MISO, TIM>
+where
+    SCK: OutputPin<Error = E>,
+    MOSI: OutputPin<Error = E>,
+    MISO: InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    /// Creates a new SPI master running at `freq`
+    ///
+    /// Sets SCK to its idle level and starts `timer` at twice the bus
+    /// frequency.
+    pub fn new(
+        sck: SCK,
+        mosi: MOSI,
+        miso: MISO,
+        mut timer: TIM,
+        mode: Mode,
+        freq: Hertz,
+    ) -> Result<Self, E> {
+        timer.start(Hertz(freq.0 * 2));
+
+        let mut spi = Spi {
+            sck,
+            mosi,
+            miso,
+            timer,
+            mode,
+            received: None,
+        };
+        spi.set_sck(false)?;
+        Ok(spi)
+    }
+
+    /// Releases the pins and the timer
+    pub fn free(self) -> (SCK, MOSI, MISO, TIM) {
+        (self.sck, self.mosi, self.miso, self.timer)
+    }
+
+    fn wait(&mut self) {
+        let _ = nb::block!(self.timer.wait());
+    }
+
+    /// Drives SCK to its active (`true`) or idle (`false`) level
+    fn set_sck(&mut self, active: bool) -> Result<(), E> {
+        if active == (self.mode.polarity == Polarity::IdleLow) {
+            self.sck.set_high()
+        } else {
+            self.sck.set_low()
+        }
+    }
+
+    fn set_mosi(&mut self, bit: bool) -> Result<(), E> {
+        if bit {
+            self.mosi.set_high()
+        } else {
+            self.mosi.set_low()
+        }
+    }
+
+    fn transfer_byte(&mut self, byte: u8) -> Result<u8, E> {
+        let mut received = 0;
+
+        for i in (0..8).rev() {
+            let bit = byte & (1 << i) != 0;
+
+            match self.mode.phase {
+                Phase::CaptureOnFirstTransition => {
+                    self.set_mosi(bit)?;
+                    self.wait();
+                    self.set_sck(true)?;
+                    received = (received << 1) | self.miso.is_high()? as u8;
+                    self.wait();
+                    self.set_sck(false)?;
+                }
+                Phase::CaptureOnSecondTransition => {
+                    self.set_sck(true)?;
+                    self.set_mosi(bit)?;
+                    self.wait();
+                    self.set_sck(false)?;
+                    received = (received << 1) | self.miso.is_high()? as u8;
+                    self.wait();
+                }
+            }
+        }
+
+        Ok(received)
+    }
+}
+
+impl<SCK, MOSI, MISO, TIM, E> FullDuplex<u8> for Spi<SCK, MOSI, MISO, TIM>
+where
+    SCK: OutputPin<Error = E>,
+    MOSI: OutputPin<Error = E>,
+    MISO: InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    type Error = E;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.received.take().ok_or(nb::Error::WouldBlock)
+    }
+
+    fn send(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        // The whole byte is transferred right away, so the received byte is
+        // available when `read` is called.
+        self.received = Some(self.transfer_byte(byte)?);
+        Ok(())
+    }
+}
+
+impl<SCK, MOSI, MISO, TIM, E> crate::hal::blocking::spi::transfer::Default<u8>
+    for Spi<SCK, MOSI, MISO, TIM>
+where
+    SCK: OutputPin<Error = E>,
+    MOSI: OutputPin<Error = E>,
+    MISO: InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+}
+
+impl<SCK, MOSI, MISO, TIM, E> crate::hal::blocking::spi::write::Default<u8>
+    for Spi<SCK, MOSI, MISO, TIM>
+where
+    SCK: OutputPin<Error = E>,
+    MOSI: OutputPin<Error = E>,
+    MISO: InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+}
@@ -2,6 +2,16 @@
 //!
 //! The STM32L0 contains a few read-only registers with factory calibration
 //! data that are written during production.
+//!
+//! Besides the raw values, this module provides helpers that apply them to ADC
+//! conversion results, using the formulas from the reference manual. All
+//! calculations use integer math.
+
+/// V_DDA at which the calibration values were acquired, in millivolts
+pub const CAL_VDDA_MV: u16 = 3000;
+
+/// Full scale of a 12-bit conversion result
+const FULL_SCALE: u32 = 4095;
 
 macro_rules! define_ptr_type {
     ($name: ident, $ptr: expr) => {
@@ -33,6 +43,11 @@ impl VrefintCal {
     pub fn read(&self) -> u16 {
         self.0
     }
+
+    /// Returns the voltage of the internal reference, in millivolts
+    pub fn millivolts(&self) -> u16 {
+        (u32::from(self.0) * u32::from(CAL_VDDA_MV) / FULL_SCALE) as u16
+    }
 }
 
 /// Temperature sensor calibration data acquired at 30 °C (TS_CAL1)
@@ -55,6 +70,9 @@ impl VrefintCal {
 pub struct VtempCal30(u16);
 define_ptr_type!(VtempCal30, 0x1FF8_007A);
 impl VtempCal30 {
+    /// Temperature at which the calibration value was acquired, in °C
+    pub const TEMPERATURE: i16 = 30;
+
     /// Read calibration value (TS_CAL1), acquired at temperature of 30 °C and V_DDA = 3 V.
     pub fn read(&self) -> u16 {
         self.0
@@ -81,8 +99,140 @@ impl VtempCal30 {
 pub struct VtempCal130(u16);
 define_ptr_type!(VtempCal130, 0x1FF8_007E);
 impl VtempCal130 {
+    /// Temperature at which the calibration value was acquired, in °C
+    pub const TEMPERATURE: i16 = 130;
+
     /// Read calibration value (TS_CAL2), acquired at temperature of 130 °C and V_DDA = 3 V.
     pub fn read(&self) -> u16 {
         self.0
     }
 }
+
+/// Calculates V_DDA from a conversion result of the internal voltage
+/// reference, in millivolts
+///
+/// `raw` is a right-aligned 12-bit result. Returns 0, if `raw` is 0.
+pub fn vdda_from_vrefint(raw: u16) -> u16 {
+    vdda_mv(VrefintCal::get().read(), raw)
+}
+
+/// Calculates the temperature from a conversion result of the temperature
+/// sensor, in °C
+///
+/// `raw` is a right-aligned 12-bit result, converted while V_DDA was
+/// `vdda_mv`, for example as measured by [`vdda_from_vrefint`]. The result is
+/// interpolated linearly between the two factory calibration points, and
+/// rounded to the nearest degree.
+pub fn temp_from_raw(raw: u16, vdda_mv: u16) -> i16 {
+    temperature(
+        VtempCal30::get().read(),
+        VtempCal130::get().read(),
+        raw,
+        vdda_mv,
+        1,
+    )
+}
+
+/// Calculates the temperature from a conversion result of the temperature
+/// sensor, in tenths of a degree Celsius
+///
+/// Like [`temp_from_raw`], but rounded to the nearest tenth of a degree.
+pub fn temp_tenths_from_raw(raw: u16, vdda_mv: u16) -> i16 {
+    temperature(
+        VtempCal30::get().read(),
+        VtempCal130::get().read(),
+        raw,
+        vdda_mv,
+        10,
+    )
+}
+
+fn vdda_mv(vrefint_cal: u16, raw: u16) -> u16 {
+    if raw == 0 {
+        return 0;
+    }
+    let vdda = u32::from(CAL_VDDA_MV) * u32::from(vrefint_cal) / u32::from(raw);
+    vdda.min(u32::from(u16::max_value())) as u16
+}
+
+/// Calculates the temperature in `1 / scale` degrees Celsius
+fn temperature(cal30: u16, cal130: u16, raw: u16, vdda_mv: u16, scale: i16) -> i16 {
+    let t1 = i64::from(VtempCal30::TEMPERATURE) * i64::from(scale);
+    let t2 = i64::from(VtempCal130::TEMPERATURE) * i64::from(scale);
+
+    let span = i64::from(cal130) - i64::from(cal30);
+    if span <= 0 {
+        // Invalid calibration data
+        return t1 as i16;
+    }
+
+    // Scale the result to V_DDA = 3 V, where the calibration values were
+    // acquired. To keep the precision, the factor of 3000 is carried through
+    // the calculation.
+    //
+    // T = (T2 - T1) / (CAL2 - CAL1) * (raw * V_DDA / 3000 - CAL1) + T1
+    let range = t2 - t1;
+    let offset = i64::from(raw) * i64::from(vdda_mv) - i64::from(cal30) * i64::from(CAL_VDDA_MV);
+    let num = range * offset;
+    let den = span * i64::from(CAL_VDDA_MV);
+
+    // Round to the nearest unit, away from zero
+    let delta = if num >= 0 {
+        (num + den / 2) / den
+    } else {
+        (num - den / 2) / den
+    };
+    (delta + t1) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vdda() {
+        assert_eq!(vdda_mv(1671, 1671), 3000);
+        assert_eq!(vdda_mv(1671, 1519), 3300);
+        assert_eq!(vdda_mv(1671, 2506), 2000);
+        assert_eq!(vdda_mv(1671, 0), 0);
+    }
+
+    #[test]
+    fn vrefint_millivolts() {
+        assert_eq!(VrefintCal(1671).millivolts(), 1224);
+    }
+
+    #[test]
+    fn temperature_at_calibration_points() {
+        assert_eq!(temperature(670, 870, 670, 3000, 1), 30);
+        assert_eq!(temperature(670, 870, 870, 3000, 1), 130);
+    }
+
+    #[test]
+    fn temperature_interpolated() {
+        assert_eq!(temperature(670, 870, 770, 3000, 1), 80);
+        assert_eq!(temperature(670, 870, 600, 3000, 1), -5);
+        // Half a degree is rounded away from zero
+        assert_eq!(temperature(670, 870, 671, 3000, 1), 31);
+        assert_eq!(temperature(670, 870, 573, 3000, 1), -19);
+    }
+
+    #[test]
+    fn temperature_compensates_vdda() {
+        // 700 at 3.3 V corresponds to 770 at 3 V
+        assert_eq!(temperature(670, 870, 700, 3300, 1), 80);
+    }
+
+    #[test]
+    fn temperature_tenths() {
+        assert_eq!(temperature(670, 870, 670, 3000, 10), 300);
+        assert_eq!(temperature(670, 870, 771, 3000, 10), 805);
+        assert_eq!(temperature(670, 870, 573, 3000, 10), -185);
+        assert_eq!(temperature(670, 870, 700, 3300, 10), 800);
+    }
+
+    #[test]
+    fn temperature_invalid_calibration() {
+        assert_eq!(temperature(870, 870, 700, 3000, 1), 30);
+    }
+}
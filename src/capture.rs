@@ -0,0 +1,267 @@
+//! PWM input mode
+//!
+//! Measures the period and pulse width of a PWM signal on channel 1 of a
+//! timer. The input is routed to both capture channels: channel 1 captures the
+//! counter on the rising edge, channel 2 on the falling edge. The slave mode
+//! controller resets the counter on every rising edge, so channel 1 holds the
+//! period and channel 2 the pulse width of the last complete period of the
+//! signal, without any CPU involvement.
+//!
+//! ``` ignore
+//! let mut input = dp.TIM2.pwm_input(gpioa.pa0, 100.Hz(), &mut rcc);
+//!
+//! loop {
+//!     if let Ok(measurement) = input.read() {
+//!         let frequency = measurement.frequency();
+//!         let duty = measurement.duty_cycle_permille();
+//!     }
+//! }
+//! ```
+
+use core::{fmt, marker::PhantomData};
+
+use cast::u16;
+use embedded_time::rate::Hertz;
+
+use crate::encoder::PinCh1;
+use crate::pac::{TIM2, TIM21};
+use crate::rcc::{Enable, Rcc, Reset};
+use crate::time::IntoHertz;
+use crate::timer::Filter;
+
+/// Extension trait to use a timer in PWM input mode
+pub trait PwmInputExt<TIM> {
+    /// Measures the PWM signal on `pin`
+    ///
+    /// `min_frequency` is the lowest frequency of the signal that needs to be
+    /// measured. The timer prescaler is chosen so the period of such a signal
+    /// still fits into the counter, which limits the resolution for faster
+    /// signals.
+    fn pwm_input<PIN>(
+        self,
+        pin: PIN,
+        min_frequency: impl IntoHertz,
+        rcc: &mut Rcc,
+    ) -> PwmInput<TIM, PIN>
+    where
+        PIN: PinCh1<TIM>;
+}
+
+/// A timer in PWM input mode
+pub struct PwmInput<TIM, PIN> {
+    tim: TIM,
+    tick_frequency: Hertz,
+    _pin: PhantomData<PIN>,
+}
+
+/// A measurement of a PWM signal
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Measurement {
+    /// The period of the signal, in timer ticks
+    pub period: u16,
+    /// The time the signal was high during the period, in timer ticks
+    pub pulse_width: u16,
+    /// The frequency of the timer ticks
+    pub tick_frequency: Hertz,
+}
+
+impl Measurement {
+    /// Returns the frequency of the signal, rounded to the nearest Hz
+    ///
+    /// Returns 0 Hz, if the period is 0.
+    pub fn frequency(&self) -> Hertz {
+        let period = u32::from(self.period);
+        if period == 0 {
+            return Hertz(0);
+        }
+        Hertz((self.tick_frequency.0 + period / 2) / period)
+    }
+
+    /// Returns the duty cycle of the signal, in 1/1000
+    ///
+    /// Returns 0, if the period is 0.
+    pub fn duty_cycle_permille(&self) -> u16 {
+        let period = u32::from(self.period);
+        if period == 0 {
+            return 0;
+        }
+        let pulse_width = u32::from(self.pulse_width).min(period);
+        ((pulse_width * 1000 + period / 2) / period) as u16
+    }
+}
+
+/// PWM input errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// No rising edge has been detected for a whole counter period
+    ///
+    /// Either there's no signal, it's constantly high or low, or it's slower
+    /// than the minimum frequency.
+    NoSignal,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoSignal => f.write_str("no signal"),
+        }
+    }
+}
+
+macro_rules! pwm_inputs {
+    ($($TIM:ident: $timclk:ident,)+) => {
+        $(
+            impl PwmInputExt<$TIM> for $TIM {
+                fn pwm_input<PIN>(
+                    self,
+                    pin: PIN,
+                    min_frequency: impl IntoHertz,
+                    rcc: &mut Rcc,
+                ) -> PwmInput<$TIM, PIN>
+                where
+                    PIN: PinCh1<$TIM>,
+                {
+                    PwmInput::<$TIM, PIN>::new(self, pin, min_frequency, rcc)
+                }
+            }
+
+            impl<PIN> PwmInput<$TIM, PIN>
+            where
+                PIN: PinCh1<$TIM>,
+            {
+                fn new(tim: $TIM, pin: PIN, min_frequency: impl IntoHertz, rcc: &mut Rcc) -> Self {
+                    <$TIM>::enable(rcc);
+                    <$TIM>::reset(rcc);
+
+                    pin.into_alt_mode();
+
+                    let clk = rcc.clocks.$timclk().0;
+                    let ticks = clk / min_frequency.into_hertz().0.max(1);
+                    let psc = u16(ticks >> 16).unwrap_or(0xffff);
+
+                    tim.psc.write(|w| w.psc().bits(psc));
+                    // This is only unsafe for some timers, so we need this to
+                    // suppress the warnings.
+                    #[allow(unused_unsafe)]
+                    tim.arr.write(|w| unsafe { w.arr().bits(0xffff) });
+
+                    // Capture TI1 on both channels
+                    #[allow(unused_unsafe)]
+                    tim.ccmr1_input().write(|w| unsafe { w.cc1s().bits(0b01).cc2s().bits(0b10) });
+                    // Channel 1 on the rising edge, channel 2 on the falling edge
+                    tim.ccer.write(|w| {
+                        w.cc1p()
+                            .clear_bit()
+                            .cc1np()
+                            .clear_bit()
+                            .cc2p()
+                            .set_bit()
+                            .cc2np()
+                            .clear_bit()
+                    });
+                    // Reset the counter on the rising edge of TI1 (TI1FP1)
+                    #[allow(unused_unsafe)]
+                    tim.smcr.write(|w| unsafe { w.ts().bits(0b101).sms().bits(0b100) });
+                    tim.ccer.modify(|_, w| w.cc1e().set_bit().cc2e().set_bit());
+
+                    // Only counter overflows set the update flag, not the resets
+                    // by the slave mode controller.
+                    tim.cr1.write(|w| w.urs().set_bit());
+                    tim.egr.write(|w| w.ug().set_bit());
+                    tim.sr.reset();
+                    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    PwmInput {
+                        tim,
+                        tick_frequency: Hertz(clk / (u32::from(psc) + 1)),
+                        _pin: PhantomData,
+                    }
+                }
+
+                /// Sets the digital filter applied to the input signal
+                ///
+                /// Note that the filter limits the highest frequency that can
+                /// be measured.
+                pub fn set_filter(&mut self, filter: Filter) {
+                    // The capture channels need to be disabled to write to
+                    // CCMR1.
+                    self.tim.ccer.modify(|_, w| w.cc1e().clear_bit().cc2e().clear_bit());
+                    #[allow(unused_unsafe)]
+                    self.tim.ccmr1_input().modify(|_, w| unsafe {
+                        w.ic1f().bits(filter as u8).ic2f().bits(filter as u8)
+                    });
+                    self.tim.ccer.modify(|_, w| w.cc1e().set_bit().cc2e().set_bit());
+                }
+
+                /// Returns the frequency of the timer ticks
+                pub fn tick_frequency(&self) -> Hertz {
+                    self.tick_frequency
+                }
+
+                /// Returns the measurement of the last complete period
+                ///
+                /// Returns `WouldBlock`, if no new period has been captured
+                /// since the last call, and [`Error::NoSignal`], if the
+                /// counter overflowed without a rising edge.
+                pub fn read(&mut self) -> nb::Result<Measurement, Error> {
+                    let sr = self.tim.sr.read();
+                    if sr.cc1if().bit_is_set() {
+                        let pulse_width = self.tim.ccr2.read().bits() as u16;
+                        // Reading CCR1 clears CC1IF
+                        let period = self.tim.ccr1.read().bits() as u16;
+                        return Ok(Measurement {
+                            period,
+                            pulse_width,
+                            tick_frequency: self.tick_frequency,
+                        });
+                    }
+                    if sr.uif().bit_is_set() {
+                        self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                        return Err(nb::Error::Other(Error::NoSignal));
+                    }
+                    Err(nb::Error::WouldBlock)
+                }
+
+                /// Stops the timer and releases it
+                pub fn release(self) -> $TIM {
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim.ccer.reset();
+                    self.tim.smcr.reset();
+                    self.tim
+                }
+            }
+        )+
+    }
+}
+
+pwm_inputs! {
+    TIM2: apb1_tim_clk,
+    TIM21: apb2_tim_clk,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measurement() {
+        let measurement = Measurement {
+            period: 16_000,
+            pulse_width: 4_000,
+            tick_frequency: Hertz(16_000_000),
+        };
+        assert_eq!(measurement.frequency(), Hertz(1_000));
+        assert_eq!(measurement.duty_cycle_permille(), 250);
+    }
+
+    #[test]
+    fn measurement_without_period() {
+        let measurement = Measurement {
+            period: 0,
+            pulse_width: 0,
+            tick_frequency: Hertz(16_000_000),
+        };
+        assert_eq!(measurement.frequency(), Hertz(0));
+        assert_eq!(measurement.duty_cycle_permille(), 0);
+    }
+}
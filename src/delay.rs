@@ -2,11 +2,10 @@
 
 use crate::hal::blocking::delay::{DelayMs, DelayUs};
 use crate::rcc::Clocks;
+use crate::time::IntoMicroseconds;
 use cast::u32;
-use core::convert::TryInto;
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m::peripheral::SYST;
-use embedded_time::duration::{Extensions, Microseconds};
 
 pub trait DelayExt {
     fn delay(self, clocks: Clocks) -> Delay;
@@ -39,11 +38,9 @@ impl Delay {
     /// Note that durations above `u32::MAX` microseconds will be clamped at `u32::MAX`.
     pub fn delay<T>(&mut self, delay: T)
     where
-        T: TryInto<Microseconds>,
+        T: IntoMicroseconds,
     {
-        let delay = delay.try_into().unwrap_or_else(|_| u32::MAX.microseconds());
-
-        self.delay_us(delay.0)
+        self.delay_us(delay.into_microseconds().0)
     }
 
     /// Releases the system timer (SysTick) resource
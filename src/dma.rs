@@ -12,13 +12,14 @@
 #![cfg_attr(not(feature = "stm32l082"), allow(dead_code, unused_imports))]
 
 use core::{
+    any::TypeId,
     fmt, mem,
-    ops::Deref,
+    ops::{Deref, DerefMut},
     pin::Pin,
     sync::atomic::{compiler_fence, Ordering},
 };
 
-use as_slice::AsSlice;
+use as_slice::{AsMutSlice, AsSlice};
 
 use crate::{
     adc,
@@ -26,23 +27,24 @@ use crate::{
     rcc::{Enable, Rcc, Reset},
 };
 
-#[cfg(any(feature = "io-STM32L051", feature = "io-STM32L071"))]
-use crate::pac::USART1;
+use crate::{
+    pac::{USART1, USART2},
+    serial,
+};
 
-#[cfg(any(
-    feature = "io-STM32L021",
-    feature = "io-STM32L031",
-    feature = "io-STM32L051",
-    feature = "io-STM32L071",
-))]
+#[cfg(feature = "stm32l0x2")]
 use crate::{
     i2c,
-    pac::{I2C1, I2C2, I2C3, USART2},
-    serial,
+    pac::{I2C1, I2C2, I2C3},
 };
 
 use crate::{pac::SPI1, spi};
 
+use crate::{
+    pac::{TIM2, TIM3},
+    pwm,
+};
+
 #[cfg(any(feature = "stm32l0x2", feature = "stm32l0x3"))]
 use crate::pac::SPI2;
 
@@ -204,6 +206,18 @@ where
         Ok(self.res)
     }
 
+    /// Aborts the transfer and returns the owned resources
+    ///
+    /// This is the only way to end a circular transfer. Data that has already
+    /// been transferred stays in the buffer.
+    pub fn stop(self) -> TransferResources<T, C, B> {
+        self.res.channel.stop();
+
+        compiler_fence(Ordering::SeqCst);
+
+        self.res
+    }
+
     /// Returns some transfer state
     ///
     /// The number of items to transfer, the half transfer flag, and the
@@ -219,6 +233,96 @@ where
     pub(crate) fn clear_flags(&self) {
         self.res.channel.clear_flags()
     }
+
+    /// Indicates whether a transfer error occurred, clearing the error flag
+    pub(crate) fn error_occured(&self) -> bool {
+        self.res.channel.error_occured()
+    }
+
+    /// Continues a completed transfer at `address`, with `len` more words
+    ///
+    /// Used to split transfers that are larger than a single DMA transfer
+    /// allows. Must only be called once the previous part has completed.
+    pub(crate) fn continue_at(&self, address: u32, len: u16) {
+        self.res.channel.clear_complete_flag();
+
+        compiler_fence(Ordering::SeqCst);
+
+        self.res.channel.restart(address, len);
+    }
+}
+
+impl<T, C, B> Transfer<T, C, B, Started>
+where
+    C: Channel,
+{
+    /// Returns the half of the buffer of a circular transfer that is done
+    ///
+    /// Intended for double buffering, for example of audio or waveform data:
+    /// Once the DMA has reached the middle of the buffer, it continues with
+    /// the second half, and the first half can be refilled (or, for
+    /// peripheral-to-memory transfers, processed). Once it has reached the
+    /// end, it continues with the first half, and the second half is done.
+    ///
+    /// Call this from the DMA interrupt handler, with the `half_transfer` and
+    /// `transfer_complete` interrupts enabled (see
+    /// [`Transfer::enable_interrupts`]). Clears the interrupt flags.
+    ///
+    /// Returns `WouldBlock`, if neither half is done yet. Returns [`Overrun`],
+    /// if both halves have been completed since the last call, which means
+    /// that the buffer wasn't serviced in time.
+    pub fn next_half(&self) -> nb::Result<NextHalf, Overrun> {
+        let (_, half, complete) = self.state();
+
+        let next = match (half, complete) {
+            (false, false) => return Err(nb::Error::WouldBlock),
+            (true, false) => Ok(NextHalf::First),
+            (false, true) => Ok(NextHalf::Second),
+            (true, true) => Err(nb::Error::Other(Overrun)),
+        };
+
+        self.clear_flags();
+        next
+    }
+
+    /// Calls `f` with the half of the buffer of a circular transfer that is
+    /// done
+    ///
+    /// Works like [`Transfer::next_half`], but also passes the half of the
+    /// buffer that is safe to access to `f`. If the length of the buffer is
+    /// odd, the second half is one word longer.
+    pub fn with_next_half<Word, R>(
+        &mut self,
+        f: impl FnOnce(NextHalf, &mut [Word]) -> R,
+    ) -> nb::Result<R, Overrun>
+    where
+        B: DerefMut,
+        B::Target: AsMutSlice<Element = Word> + Unpin,
+    {
+        let next = self.next_half()?;
+
+        compiler_fence(Ordering::SeqCst);
+        let buffer = self.res.buffer.as_mut().get_mut().as_mut_slice();
+        let (first, second) = buffer.split_at_mut(buffer.len() / 2);
+        let result = match next {
+            NextHalf::First => f(next, first),
+            NextHalf::Second => f(next, second),
+        };
+        compiler_fence(Ordering::SeqCst);
+
+        Ok(result)
+    }
+}
+
+/// The half of the buffer of a circular transfer that can be accessed
+///
+/// See [`Transfer::next_half`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NextHalf {
+    /// The first half of the buffer is done, while the DMA works on the second
+    First,
+    /// The second half of the buffer is done, while the DMA works on the first
+    Second,
 }
 
 pub struct TransferResources<T, C, B> {
@@ -237,6 +341,24 @@ impl<T, C, B> fmt::Debug for TransferResources<T, C, B> {
     }
 }
 
+impl<T, C, B> TransferResources<T, C, B> {
+    /// Splits the resources into target, channel, and buffer
+    pub fn into_parts(self) -> (T, C, Pin<B>) {
+        (self.target, self.channel, self.buffer)
+    }
+
+    /// Returns the channel to `pool` and the target and buffer to the caller
+    ///
+    /// See [`ChannelPool::reclaim`].
+    pub fn reclaim_channel(self, pool: &mut ChannelPool) -> (T, Pin<B>)
+    where
+        C: PooledChannel,
+    {
+        pool.reclaim(self.channel);
+        (self.target, self.buffer)
+    }
+}
+
 /// The priority of the DMA transfer
 pub struct Priority(cr::PL_A);
 
@@ -274,6 +396,41 @@ impl Direction {
 #[derive(Debug)]
 pub struct Error;
 
+/// Errors returned by [`ChannelPool::take_for`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PoolError {
+    /// The channel has already been taken from the pool
+    Taken,
+    /// Another channel taken from the pool already serves the target
+    ///
+    /// If two channels were set up for the same request, both of them would
+    /// react to it.
+    TargetInUse,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolError::Taken => f.write_str("channel already taken"),
+            PoolError::TargetInUse => f.write_str("target already served by another channel"),
+        }
+    }
+}
+
+/// A DMA channel that can be managed by a [`ChannelPool`]
+///
+/// Implemented for all channels of DMA1.
+pub trait PooledChannel: Channel {
+    /// The index of the channel, starting at 0 for channel 1
+    const INDEX: usize;
+
+    #[doc(hidden)]
+    fn slot(pool: &ChannelPool) -> &Option<Self>;
+
+    #[doc(hidden)]
+    fn slot_mut(pool: &mut ChannelPool) -> &mut Option<Self>;
+}
+
 pub trait Channel: Sized {
     fn select_target<T: Target<Self>>(&self, _: &mut Handle, target: &T);
     fn set_peripheral_address(&self, _: &mut Handle, address: u32);
@@ -284,11 +441,13 @@ pub trait Channel: Sized {
         Word: SupportedWordSize;
     fn enable_interrupts(&self, interrupts: Interrupts);
     fn start(&self);
+    fn stop(&self);
     fn is_active(&self) -> bool;
     fn clear_complete_flag(&self);
     fn error_occured(&self) -> bool;
     fn transfer_state(&self) -> (u16, bool, bool);
     fn clear_flags(&self);
+    fn restart(&self, address: u32, len: u16);
 }
 
 macro_rules! impl_channel {
@@ -303,7 +462,8 @@ macro_rules! impl_channel {
             $teif:ident,
             $chtif:ident,
             $ctcif:ident,
-            $cteif:ident;
+            $cteif:ident,
+            $index:expr;
         )*
     ) => {
         pub struct Channels {
@@ -322,8 +482,64 @@ macro_rules! impl_channel {
             pub fn new() -> Self {
                 Default::default()
             }
+
+            /// Moves all channels into a [`ChannelPool`]
+            pub fn into_pool(self) -> ChannelPool {
+                ChannelPool::new(self)
+            }
+        }
+
+        /// Hands out DMA channels at runtime and takes them back
+        ///
+        /// The seven channels of DMA1 are usually shared between more
+        /// peripherals than that. Instead of moving channels around manually,
+        /// applications can keep them in a pool, take a channel before setting
+        /// up a transfer, and put it back once the transfer has finished, for
+        /// example using [`TransferResources::reclaim_channel`].
+        ///
+        /// [`ChannelPool::take_for`] makes sure at compile time that the
+        /// channel can serve the target, and at runtime that no other channel
+        /// taken from the pool already serves the same target.
+        pub struct ChannelPool {
+            $($field: Option<$channel>,)*
+            targets: [Option<TypeId>; CHANNEL_COUNT],
+        }
+
+        impl ChannelPool {
+            /// Creates a pool that owns all channels
+            pub fn new(channels: Channels) -> Self {
+                Self {
+                    $($field: Some(channels.$field),)*
+                    targets: [None; CHANNEL_COUNT],
+                }
+            }
+
+            /// Returns the channels, once all of them are back in the pool
+            ///
+            /// Returns the pool, if any channel is still taken.
+            pub fn into_channels(self) -> Result<Channels, Self> {
+                if $(self.$field.is_some())&&* {
+                    Ok(Channels::new())
+                } else {
+                    Err(self)
+                }
+            }
         }
 
+        $(
+            impl PooledChannel for $channel {
+                const INDEX: usize = $index;
+
+                fn slot(pool: &ChannelPool) -> &Option<Self> {
+                    &pool.$field
+                }
+
+                fn slot_mut(pool: &mut ChannelPool) -> &mut Option<Self> {
+                    &mut pool.$field
+                }
+            }
+        )*
+
         $(
             pub struct $channel(());
 
@@ -410,6 +626,22 @@ macro_rules! impl_channel {
                     ccr.modify(|_, w| w.en().enabled());
                 }
 
+                fn stop(&self) {
+                    // This is safe, for the following reasons:
+                    // - This channel has exclusive access to CCRx.
+                    // - IFCR is a stateless register and we do one atomic
+                    //   write.
+                    let dma = unsafe { &*pac::DMA1::ptr() };
+
+                    dma.$chfield.cr.modify(|_, w| w.en().disabled());
+                    dma.ifcr.write(|w|
+                        w
+                            .$chtif().clear()
+                            .$ctcif().clear()
+                            .$cteif().set_bit()
+                    );
+                }
+
                 fn is_active(&self) -> bool {
                     // This is safe, for the following reasons:
                     // - We only do one atomic read of ISR.
@@ -461,6 +693,20 @@ macro_rules! impl_channel {
                     (data_remaining, half_transfer, transfer_complete)
                 }
 
+                fn restart(&self, address: u32, len: u16) {
+                    // Safe, because we're only accessing registers that this
+                    // channel has exclusive access to.
+                    let dma = unsafe { &*pac::DMA1::ptr() };
+
+                    // MAR and NDTR can only be written while the channel is
+                    // disabled.
+                    dma.$chfield.cr.modify(|_, w| w.en().disabled());
+                    // unsafe needed because of PAC. fine since ma takes all u32 values.
+                    dma.$chfield.mar.write(|w| unsafe { w.ma().bits(address) });
+                    dma.$chfield.ndtr.write(|w| w.ndt().bits(len));
+                    dma.$chfield.cr.modify(|_, w| w.en().enabled());
+                }
+
                 fn clear_flags(&self) {
                     // Safe, as we're only doing an atomic write to a stateless
                     // register.
@@ -479,21 +725,68 @@ macro_rules! impl_channel {
 
 impl_channel!(
     Channel1, channel1, ch1,
-        c1s, htif1, tcif1, teif1, chtif1, ctcif1, cteif1;
+        c1s, htif1, tcif1, teif1, chtif1, ctcif1, cteif1, 0;
     Channel2, channel2, ch2,
-        c2s, htif2, tcif2, teif2, chtif2, ctcif2, cteif2;
+        c2s, htif2, tcif2, teif2, chtif2, ctcif2, cteif2, 1;
     Channel3, channel3, ch3,
-        c3s, htif3, tcif3, teif3, chtif3, ctcif3, cteif3;
+        c3s, htif3, tcif3, teif3, chtif3, ctcif3, cteif3, 2;
     Channel4, channel4, ch4,
-        c4s, htif4, tcif4, teif4, chtif4, ctcif4, cteif4;
+        c4s, htif4, tcif4, teif4, chtif4, ctcif4, cteif4, 3;
     Channel5, channel5, ch5,
-        c5s, htif5, tcif5, teif5, chtif5, ctcif5, cteif5;
+        c5s, htif5, tcif5, teif5, chtif5, ctcif5, cteif5, 4;
     Channel6, channel6, ch6,
-        c6s, htif6, tcif6, teif6, chtif6, ctcif6, cteif6;
+        c6s, htif6, tcif6, teif6, chtif6, ctcif6, cteif6, 5;
     Channel7, channel7, ch7,
-        c7s, htif7, tcif7, teif7, chtif7, ctcif7, cteif7;
+        c7s, htif7, tcif7, teif7, chtif7, ctcif7, cteif7, 6;
 );
 
+/// The number of channels of DMA1
+const CHANNEL_COUNT: usize = 7;
+
+impl ChannelPool {
+    /// Takes a channel from the pool
+    ///
+    /// Returns `None`, if the channel has already been taken.
+    pub fn take<C: PooledChannel>(&mut self) -> Option<C> {
+        C::slot_mut(self).take()
+    }
+
+    /// Takes a channel from the pool, to be used for `target`
+    ///
+    /// Only compiles, if the channel can serve the target. Returns an error,
+    /// if the channel has already been taken, or if another channel taken
+    /// with this method still serves the target.
+    pub fn take_for<C, T>(&mut self, _target: &T) -> Result<C, PoolError>
+    where
+        C: PooledChannel,
+        T: Target<C> + 'static,
+    {
+        let target = TypeId::of::<T>();
+        if self.targets.contains(&Some(target)) {
+            return Err(PoolError::TargetInUse);
+        }
+
+        let channel = self.take::<C>().ok_or(PoolError::Taken)?;
+        self.targets[C::INDEX] = Some(target);
+        Ok(channel)
+    }
+
+    /// Puts a channel back into the pool
+    ///
+    /// The target the channel served, if any, is released as well. The
+    /// channel must not be used by a transfer anymore, which is guaranteed,
+    /// as transfers own their channel.
+    pub fn reclaim<C: PooledChannel>(&mut self, channel: C) {
+        self.targets[C::INDEX] = None;
+        *C::slot_mut(self) = Some(channel);
+    }
+
+    /// Indicates whether the channel is in the pool
+    pub fn is_available<C: PooledChannel>(&self) -> bool {
+        C::slot(self).is_some()
+    }
+}
+
 pub trait Target<Channel> {
     const REQUEST: u8;
 }
@@ -515,7 +808,6 @@ impl_target!(
     adc::DmaToken, Channel2, 0;
 );
 
-#[cfg(any(feature = "io-STM32L051", feature = "io-STM32L071"))]
 impl_target!(
     // USART1
     serial::Tx<USART1>, Channel2, 3;
@@ -524,12 +816,6 @@ impl_target!(
     serial::Rx<USART1>, Channel5, 3;
 );
 
-#[cfg(any(
-    feature = "io-STM32L021",
-    feature = "io-STM32L031",
-    feature = "io-STM32L051",
-    feature = "io-STM32L071",
-))]
 impl_target!(
     // USART2
     serial::Tx<USART2>, Channel4, 4;
@@ -539,12 +825,6 @@ impl_target!(
 );
 
 #[cfg(feature = "stm32l0x2")]
-#[cfg(any(
-    feature = "io-STM32L021",
-    feature = "io-STM32L031",
-    feature = "io-STM32L051",
-    feature = "io-STM32L071",
-))]
 impl_target!(
     // I2C1
     i2c::Tx<I2C1>, Channel2, 6;
@@ -587,6 +867,13 @@ impl_target!(
     spi::Rx<SPI2>, Channel6, 2;
 );
 
+impl_target!(
+    // TIM2_UP
+    pwm::UpdateToken<TIM2>, Channel2, 8;
+    // TIM3_UP
+    pwm::UpdateToken<TIM3>, Channel3, 10;
+);
+
 /// Indicates that a DMA transfer is ready
 pub struct Ready;
 
@@ -618,6 +905,10 @@ pub(crate) struct PtrBuffer<Word> {
     pub len: usize,
 }
 
+// NOTE(unsafe) `PtrBuffer` is only created for buffers that are owned by the
+// same transfer, so it can be sent along with them.
+unsafe impl<Word: Send> Send for PtrBuffer<Word> {}
+
 // Required to make in possible to put this in a `Pin`, in a way that satisfies
 // the requirements on `Transfer::new`.
 impl<Word> Deref for PtrBuffer<Word> {
@@ -638,6 +929,236 @@ impl<Word> Buffer<Word> for PtrBuffer<Word> {
     }
 }
 
+/// Provides access to the buffer of a circular peripheral-to-memory transfer
+///
+/// Since the DMA transfer takes ownership of the buffer, we need to access it
+/// with unsafe means. This struct is a safe wrapper around this unsafe access.
+pub(crate) struct CircularBuffer<Word> {
+    ptr: *const Word,
+    len: u16,
+    pos: u16,
+    dma_pos: u16,
+
+    /// Indicates order of read and write indices
+    ///
+    /// This is initially `false`, indicating that the read position (the `pos`
+    /// field) is smaller than or equal to the write position (internally
+    /// managed by the DMA peripheral).
+    ///
+    /// Once the write position wraps around the buffer boundary, this becomes
+    /// `true` until the read position also wraps around.
+    r_gt_w: bool,
+}
+
+// NOTE(unsafe) `CircularBuffer` only points into the buffer owned by the DMA
+// transfer that it's stored next to, so it can be sent along with it.
+unsafe impl<Word: Send> Send for CircularBuffer<Word> {}
+
+impl<Word> CircularBuffer<Word>
+where
+    Word: Copy,
+{
+    /// Creates a new, empty instance for the buffer at `ptr`
+    ///
+    /// The transfer writing into the buffer must not have been started yet.
+    pub(crate) fn new(ptr: *const Word, len: u16) -> Self {
+        Self {
+            ptr,
+            len,
+            pos: 0,
+            dma_pos: 0,
+
+            r_gt_w: false,
+        }
+    }
+
+    /// Returns the length of the buffer
+    pub(crate) fn len(&self) -> u16 {
+        self.len
+    }
+
+    /// Returns the read position
+    pub(crate) fn pos(&self) -> u16 {
+        self.pos
+    }
+
+    /// Reads the next value from the buffer
+    ///
+    /// Returns `None`, if the buffer is empty, or `Some(Err(Overrun))`, if the
+    /// DMA has overwritten values that haven't been read yet. In the latter
+    /// case, the buffer is emptied and reading can continue.
+    pub(crate) fn read<T, C, B>(
+        &mut self,
+        transfer: &Transfer<T, C, B, Started>,
+    ) -> Option<Result<Word, Overrun>>
+    where
+        C: Channel,
+    {
+        let transfer_state = self.transfer_state(transfer);
+        if self.check_overrun(transfer_state) {
+            return Some(Err(Overrun));
+        }
+
+        if self.pos == transfer_state.pos {
+            // No overrun detected, but read and write positions are equal. This
+            // can only mean that the buffer is empty.
+            return None;
+        }
+
+        // Safe, as we know that `ptr` and `len` define a valid buffer, and we
+        // make sure that `pos <= len`. There's a race condition between this
+        // line and the DMA peripheral, of course, but we take care of that with
+        // these overrun checks.
+        //
+        // The cast is fine too. This is a 32-bit platform, so casting a `u16`
+        // to an `isize` will never truncate the value.
+        compiler_fence(Ordering::SeqCst);
+        let value = unsafe { *self.ptr.offset(self.pos as isize) };
+        compiler_fence(Ordering::SeqCst);
+
+        // At this point we know that there was no overrun before we started
+        // reading, but of course the DMA might have overtaken us since that
+        // check. Let's check again. If there's still no overrun, we know that
+        // our value is valid.
+        let transfer_state = self.transfer_state(transfer);
+        if self.check_overrun(transfer_state) {
+            // Strictly speaking, the overrun might have happened after our
+            // read, and `value` might be valid. No way to know for sure though,
+            // so let's assume overrun.
+            return Some(Err(Overrun));
+        }
+
+        // Now we know that the value we read is totally fine. Let's advance the
+        // read position to finish up here.
+        self.pos = self.pos.wrapping_add(1);
+        if self.pos == 0 || self.pos >= self.len {
+            // We advanced beyond the end of the buffer, which means we need to
+            // wrap around to the beginning.
+            self.pos = 0;
+            self.r_gt_w = false;
+        }
+
+        Some(Ok(value))
+    }
+
+    fn transfer_state<T, C, B>(&self, transfer: &Transfer<T, C, B, Started>) -> TransferState
+    where
+        C: Channel,
+    {
+        let (remaining, half, complete) = transfer.state();
+        transfer.clear_flags();
+
+        // Let's translate what we got from the DMA peripheral into a write
+        // position that we can compare with our read position.
+        let pos = self.len - remaining;
+
+        TransferState {
+            pos,
+            half,
+            complete,
+        }
+    }
+
+    fn check_overrun(&mut self, transfer_state: TransferState) -> bool {
+        let overrun = self.check_overrun_inner(transfer_state);
+        self.dma_pos = transfer_state.pos; // Update our state of the DMA
+
+        if overrun {
+            // An overrun occured, but that is not a catastrophic error. Values
+            // got lost, but that doesn't mean we can't read the new values
+            // starting now. Let's get the buffer into a consistent state to
+            // make that possible.
+            //
+            // There are various ways to go about this. What we're doing here is
+            // to throw away all values in the buffer and start again with an
+            // empty buffer, because that minimizes the likelihood of getting
+            // another overrun right away.
+            //
+            // Maybe doing the opposite, setting the read position so that the
+            // buffer is full, to minimize lost values, would be better. But
+            // then we should give the user the option to empty the buffer
+            // manually. I've chosen to go with the simpler option for now.
+            self.pos = transfer_state.pos;
+            self.r_gt_w = false;
+        }
+
+        overrun
+    }
+
+    fn check_overrun_inner(&mut self, transfer_state: TransferState) -> bool {
+        if transfer_state.half && transfer_state.complete {
+            // Each time we attempt a read, we clear both flags. If both flags
+            // are set, then basically anything could have happened in between,
+            // so we have to assume an overrun.
+            //
+            // Please note that it's possible that the DMA has written beyond
+            // the half point and wrapped around, causing both of the flags to
+            // be set, without passing our current reading position. However,
+            // there's no way to distinguish this case from the DMA having
+            // passed those marks multiple times, so we have to be conservative
+            // and assume an overrun.
+            return true;
+        }
+
+        if transfer_state.complete && self.dma_pos < transfer_state.pos {
+            // If the complete flag is set and our previous position is less than
+            // the current position then an overrun must have occurred
+            // This is because the DMA must have wrapped to 0 and then ran past us again
+            return true;
+        }
+
+        // Don't use the transfer complete flag to detect wrap (aside from the overrun above)
+        // There is a timing issue with reading and clearing it so depend on relative positions
+        if transfer_state.pos < self.dma_pos {
+            // The write has wrapped beyond the buffer boundary and started
+            // again at the beginning of the buffer. This is completely normal,
+            // but it affects how we detect an overrun.
+
+            if self.r_gt_w {
+                // The read position was greater than the write position, so if
+                // the write position wrapped, it must have overtaken the read
+                // position. This is an overrun.
+                return true;
+            }
+
+            // The write position has wrapped, so now the read position needs
+            // to be greater than the write position.
+            self.r_gt_w = true;
+        }
+
+        // At this point we know that everything _could_ be alright, judging
+        // from the combination of flags we checked so far. We still need to
+        // compare read and write positions to make sure that we don't actually
+        // have an overrun.
+        if self.r_gt_w {
+            self.pos <= transfer_state.pos
+        } else {
+            self.pos > transfer_state.pos
+        }
+    }
+}
+
+/// Internal struct to represent the current state of the DMA transfer
+#[derive(Clone, Copy, Debug)]
+struct TransferState {
+    pos: u16,
+    half: bool,
+    complete: bool,
+}
+
+/// Indicates that the DMA has overtaken the code servicing a circular buffer
+///
+/// Values have been overwritten before they were read, or a part of the buffer
+/// has been transferred again before it was refilled.
+#[derive(Debug)]
+pub struct Overrun;
+
+impl fmt::Display for Overrun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DMA buffer overrun")
+    }
+}
+
 pub trait SupportedWordSize {
     fn size() -> cr::MSIZE_A;
 }
@@ -666,3 +1187,167 @@ pub struct Interrupts {
     pub half_transfer: bool,
     pub transfer_complete: bool,
 }
+
+/// Creates a static DMA buffer and returns a pinned reference to it
+///
+/// Transfers require a buffer that lives for the whole duration of the
+/// transfer and can't move, which in practice means a
+/// `Pin<&'static mut [Word; N]>`. This macro declares a `static` array,
+/// aligned to 4 bytes, and returns such a reference to it.
+///
+/// The buffer is zero-initialized, unless an initial value is given:
+///
+/// ``` ignore
+/// let rx_buffer = dma::buffer!([u8; 64]);
+/// let adc_buffer = dma::buffer!([u16; 256]);
+/// let tx_buffer = dma::buffer!([u8; 5] = *b"hello");
+/// ```
+///
+/// # Panics
+///
+/// Each invocation of the macro declares its own `static`, so a reference can
+/// only be returned once. Panics, if the same invocation is executed again,
+/// for example because it's in a loop or in a function that is called more
+/// than once.
+#[macro_export]
+macro_rules! dma_buffer {
+    ([$ty:ty; $len:expr]) => {
+        $crate::dma_buffer!([$ty; $len] = [0; $len])
+    };
+    ([$ty:ty; $len:expr] = $init:expr) => {{
+        #[repr(align(4))]
+        struct Aligned([$ty; $len]);
+
+        static mut BUFFER: Aligned = Aligned($init);
+        static mut TAKEN: bool = false;
+
+        // NOTE(unsafe) `TAKEN` makes sure that at most one reference to
+        // `BUFFER` is ever created.
+        unsafe {
+            assert!(
+                $crate::dma::claim_buffer(core::ptr::addr_of_mut!(TAKEN)),
+                "DMA buffer already taken"
+            );
+            core::pin::Pin::new(&mut (*core::ptr::addr_of_mut!(BUFFER)).0)
+        }
+    }};
+}
+
+pub use crate::dma_buffer as buffer;
+
+/// Used by `dma::buffer!` to claim a buffer
+///
+/// Returns `true`, if the buffer hasn't been claimed before.
+///
+/// # Safety
+///
+/// `taken` must point to a valid `bool` that is only accessed by this
+/// function.
+#[doc(hidden)]
+pub unsafe fn claim_buffer(taken: *mut bool) -> bool {
+    cortex_m::interrupt::free(|_| {
+        let claimed = !*taken;
+        *taken = true;
+        claimed
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_at(pos: u16, dma_pos: u16, r_gt_w: bool) -> CircularBuffer<u16> {
+        CircularBuffer {
+            ptr: core::ptr::null(),
+            len: 8,
+            pos,
+            dma_pos,
+            r_gt_w,
+        }
+    }
+
+    fn state(pos: u16, half: bool, complete: bool) -> TransferState {
+        TransferState {
+            pos,
+            half,
+            complete,
+        }
+    }
+
+    #[test]
+    fn no_overrun_while_dma_is_ahead() {
+        let mut buffer = buffer_at(0, 0, false);
+        assert!(!buffer.check_overrun(state(3, false, false)));
+        assert_eq!(buffer.dma_pos, 3);
+        assert_eq!(buffer.pos, 0);
+    }
+
+    #[test]
+    fn no_overrun_when_dma_wraps() {
+        let mut buffer = buffer_at(6, 7, false);
+        assert!(!buffer.check_overrun(state(2, false, true)));
+        assert!(buffer.r_gt_w);
+        assert_eq!(buffer.pos, 6);
+    }
+
+    #[test]
+    fn overrun_when_dma_passes_read_position() {
+        let mut buffer = buffer_at(6, 2, true);
+        assert!(buffer.check_overrun(state(7, true, false)));
+
+        // The buffer is emptied, starting at the write position
+        assert_eq!(buffer.pos, 7);
+        assert!(!buffer.r_gt_w);
+    }
+
+    #[test]
+    fn overrun_when_dma_wraps_twice() {
+        let mut buffer = buffer_at(6, 7, true);
+        assert!(buffer.check_overrun(state(2, false, false)));
+    }
+
+    #[test]
+    fn overrun_when_dma_laps_buffer() {
+        let mut buffer = buffer_at(2, 2, false);
+        assert!(buffer.check_overrun(state(3, false, true)));
+
+        let mut buffer = buffer_at(2, 2, false);
+        assert!(buffer.check_overrun(state(2, true, true)));
+    }
+
+    struct TestTarget;
+
+    impl Target<Channel1> for TestTarget {
+        const REQUEST: u8 = 0;
+    }
+
+    impl Target<Channel2> for TestTarget {
+        const REQUEST: u8 = 0;
+    }
+
+    #[test]
+    fn pool_hands_out_channels_once() {
+        let mut pool = Channels::new().into_pool();
+        let channel = pool.take::<Channel3>().unwrap();
+        assert!(!pool.is_available::<Channel3>());
+        assert!(pool.take::<Channel3>().is_none());
+
+        pool.reclaim(channel);
+        assert!(pool.is_available::<Channel3>());
+        assert!(pool.into_channels().is_ok());
+    }
+
+    #[test]
+    fn pool_rejects_target_served_by_other_channel() {
+        let mut pool = Channels::new().into_pool();
+        let channel = pool.take_for::<Channel1, _>(&TestTarget).unwrap();
+        assert_eq!(
+            pool.take_for::<Channel2, _>(&TestTarget).err(),
+            Some(PoolError::TargetInUse)
+        );
+
+        pool.reclaim(channel);
+        assert!(pool.take_for::<Channel2, _>(&TestTarget).is_ok());
+        assert!(pool.into_channels().is_err());
+    }
+}
@@ -1,8 +1,10 @@
 //! Encoder input using timers.
 
 use crate::gpio::{gpioa, gpiob, AltMode, Analog};
+use crate::hal;
 use crate::pac::{tim2, tim21, TIM2, TIM21};
 use crate::rcc::{Enable, Rcc, Reset};
+use crate::timer::Filter;
 use core::marker::PhantomData;
 
 pub trait Pins<TIM> {
@@ -178,6 +180,28 @@ macro_rules! encoders {
                     self.timer.arr.write(|w| unsafe { w.arr().bits(arr) });
                 }
 
+                /// Sets the digital filter on both encoder inputs
+                ///
+                /// Mechanical encoders bounce, which shows up as spurious
+                /// counts. The filter only accepts an edge once the input has
+                /// been stable for a number of samples, see [`Filter`].
+                pub fn set_filter(&mut self, filter: Filter) {
+                    // The capture/compare channels need to be disabled to
+                    // write CCxS, which they are, as the encoder never enables
+                    // them.
+                    #[allow(unused_unsafe)]
+                    self.timer.ccmr1_input().modify(|_, w| unsafe {
+                        w.cc1s()
+                            .bits(0b01)
+                            .ic1f()
+                            .bits(filter as u8)
+                            .cc2s()
+                            .bits(0b01)
+                            .ic2f()
+                            .bits(filter as u8)
+                    });
+                }
+
                 /// Listen for over/underflow interrupts
                 pub fn listen(&mut self) {
                     // Listen for over/underflow.
@@ -222,6 +246,25 @@ macro_rules! encoders {
                     self.timer.sr.reset();
                 }
             }
+
+            impl<PINS> hal::Qei for Encoder<$TIM, PINS>
+            where
+                PINS: Pins<$TIM>,
+            {
+                type Count = u16;
+
+                fn count(&self) -> u16 {
+                    self.timer.cnt.read().bits() as u16
+                }
+
+                fn direction(&self) -> hal::Direction {
+                    if self.timer.cr1.read().dir().bit_is_clear() {
+                        hal::Direction::Upcounting
+                    } else {
+                        hal::Direction::Downcounting
+                    }
+                }
+            }
         )+
     }
 }
@@ -8,7 +8,10 @@
 //! - STM32L0x2 reference manual (RM0376), chapter 3
 //! - STM32L0x3 reference manual (RM0367), chapter 3
 
+use core::fmt;
+
 use cortex_m::interrupt;
+use embedded_time::duration::Microseconds;
 
 use crate::{
     pac::{self, flash::acr::LATENCY_A},
@@ -41,6 +44,80 @@ pub const EEPROM_SIZE: usize = 128;
 pub const EEPROM_START_BANK1: usize = 0x0808_0000;
 pub const EEPROM_START_BANK2: usize = 0x0808_0C00;
 
+/// The worst-case duration of a single program or erase operation
+///
+/// The datasheets specify a programming time (`t_prog`) of 3.2 ms for erasing
+/// a page, writing a half-page, or writing a word. This is the typical value,
+/// rounded up to leave some margin for temperature and supply voltage.
+pub const T_PROG_MAX: Microseconds = Microseconds(4_000);
+
+/// A non-volatile memory operation
+///
+/// Used to compute worst-case durations, for example to verify that a
+/// sequence of operations can't trigger the watchdog. Any access to Flash or
+/// EEPROM on the same memory bank (including instruction fetches) is stalled
+/// for the whole duration of the operation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Erasing a page of Flash memory, see [`FLASH::erase_flash_page`]
+    ErasePage,
+
+    /// Writing a half-page of Flash memory, see [`FLASH::write_flash_half_page`]
+    WriteHalfPage,
+
+    /// Writing a word to Flash memory or EEPROM, see [`FLASH::write_word`]
+    ///
+    /// Takes twice as long, if EEPROM needs to be erased before writing.
+    WriteWord,
+
+    /// Writing a byte to EEPROM, see [`FLASH::write_byte`]
+    ///
+    /// Takes twice as long, if EEPROM needs to be erased before writing.
+    WriteByte,
+}
+
+impl Operation {
+    /// Returns the worst-case duration of the operation
+    pub const fn worst_case_duration(self) -> Microseconds {
+        match self {
+            Operation::ErasePage | Operation::WriteHalfPage => T_PROG_MAX,
+            // EEPROM is erased automatically, unless all bits are zero
+            Operation::WriteWord | Operation::WriteByte => Microseconds(2 * T_PROG_MAX.0),
+        }
+    }
+
+    /// Returns the worst-case duration of `count` consecutive operations
+    pub const fn worst_case_duration_of(self, count: u32) -> Microseconds {
+        Microseconds(self.worst_case_duration().0 * count)
+    }
+}
+
+/// Presets for the flash power options, see [`FLASH::set_power_profile`]
+///
+/// The flash is one of the larger contributors to the current consumption in
+/// Run and Sleep mode, so these options are a tradeoff between speed, current
+/// and wakeup time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlashPowerProfile {
+    /// Prefetch and pre-read enabled
+    ///
+    /// Fastest execution with 1 wait state, at the cost of a higher current
+    /// in Run mode.
+    Performance,
+
+    /// The reset configuration
+    ///
+    /// Prefetch and pre-read are disabled. The flash stays powered in Sleep
+    /// mode, so wakeup is as fast as possible.
+    Balanced,
+
+    /// Prefetch and pre-read disabled, flash powered down in Sleep mode
+    ///
+    /// Lowest current in Sleep and Low-power sleep mode, at the cost of a
+    /// longer wakeup time.
+    LowPower,
+}
+
 /// Entry point to the non-volatile memory (NVM) API
 pub struct FLASH {
     flash: pac::FLASH,
@@ -102,6 +179,80 @@ impl FLASH {
             .modify(|_, w| w.latency().variant(wait_states));
     }
 
+    /// Applies a set of flash power options
+    ///
+    /// See [`FlashPowerProfile`] for the tradeoffs of the different profiles.
+    pub fn set_power_profile(&mut self, profile: FlashPowerProfile) {
+        let (prefetch, pre_read, sleep_pd) = match profile {
+            FlashPowerProfile::Performance => (true, true, false),
+            FlashPowerProfile::Balanced => (false, false, false),
+            FlashPowerProfile::LowPower => (false, false, true),
+        };
+
+        self.flash.acr.modify(|_, w| {
+            w.prften().bit(prefetch);
+            w.pre_read().bit(pre_read);
+            w.disab_buf().clear_bit();
+            w.sleep_pd().bit(sleep_pd)
+        });
+    }
+
+    /// Enables or disables the prefetch buffer (`FLASH_ACR.PRFTEN`)
+    ///
+    /// Prefetching only helps with 1 wait state, where it hides most of the
+    /// wait state for sequential code, at the cost of additional flash reads.
+    pub fn set_prefetch(&mut self, enable: bool) {
+        self.flash.acr.modify(|_, w| w.prften().bit(enable));
+    }
+
+    /// Enables or disables the pre-read (`FLASH_ACR.PRE_READ`)
+    ///
+    /// If enabled, the next 64 bits are read from flash before they're
+    /// requested, which helps with wait states and literal pools, but costs
+    /// power for reads that end up being unused.
+    pub fn set_pre_read(&mut self, enable: bool) {
+        self.flash.acr.modify(|_, w| w.pre_read().bit(enable));
+    }
+
+    /// Enables or disables the buffer cache (`FLASH_ACR.DISAB_BUF`)
+    ///
+    /// The buffer is enabled by default. Disabling it forces every access to
+    /// read the flash, which increases power consumption and is only useful
+    /// to debug timing issues.
+    pub fn set_buffer_cache(&mut self, enable: bool) {
+        self.flash.acr.modify(|_, w| w.disab_buf().bit(!enable));
+    }
+
+    /// Powers down the flash in Sleep and Low-power sleep mode
+    /// (`FLASH_ACR.SLEEP_PD`)
+    ///
+    /// This reduces the current in those modes, but the flash needs to power
+    /// up again on wakeup, which adds to the wakeup time.
+    pub fn set_sleep_power_down(&mut self, enable: bool) {
+        self.flash.acr.modify(|_, w| w.sleep_pd().bit(enable));
+    }
+
+    /// Powers down the flash in Run and Low-power run mode
+    /// (`FLASH_ACR.RUN_PD`)
+    ///
+    /// The flash can't be read while it's powered down, so this is only useful
+    /// while executing code from RAM, for example in Low-power run mode.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that no code is executed from flash and no
+    /// data is read from flash, until the flash is powered up again. This
+    /// includes interrupt handlers and the vector table.
+    pub unsafe fn set_run_power_down(&mut self, enable: bool) {
+        if enable {
+            // RUN_PD can only be set after unlocking it
+            self.flash.pdkeyr.write(|w| w.pdkeyr().bits(0x04152637));
+            self.flash.pdkeyr.write(|w| w.pdkeyr().bits(0xFAFBFCFD));
+        }
+
+        self.flash.acr.modify(|_, w| w.run_pd().bit(enable));
+    }
+
     /// Erases a page of flash memory
     ///
     /// Attention: You must make sure that your program is not executed from the
@@ -151,6 +302,41 @@ impl FLASH {
         })
     }
 
+    /// Erases multiple consecutive pages of flash memory
+    ///
+    /// Erases `pages` pages, starting at `address`, one page at a time.
+    /// `on_yield` is called before each page is erased. Use it to feed the
+    /// watchdog (for example `|| watchdog.feed()`), or to do other work
+    /// between operations. Each page erase stalls the memory bank for up to
+    /// [`T_PROG_MAX`], so as long as `on_yield` feeds the watchdog, a watchdog
+    /// period longer than that is safe, regardless of the number of pages.
+    ///
+    /// Stops at the first error. All pages before the one that failed have
+    /// been erased.
+    ///
+    /// See [`FLASH::erase_flash_page`] for restrictions regarding the memory
+    /// bank the program is executed from.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic, unless all of the following is true:
+    /// - All pages are located in Flash memory
+    /// - `address` is aligned to a page boundary (32 words, 128 bytes)
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn erase_flash_pages(
+        &mut self,
+        address: *mut u32,
+        pages: usize,
+        mut on_yield: impl FnMut(),
+    ) -> Result {
+        for page in 0..pages {
+            on_yield();
+            self.erase_flash_page(address.wrapping_add(page * PAGE_SIZE / 4))?;
+        }
+
+        Ok(())
+    }
+
     /// Writes a word to Flash memory or EEPROM
     ///
     /// Please note that any access to Flash or EEPROM on the same memory bank
@@ -181,6 +367,54 @@ impl FLASH {
         })
     }
 
+    /// Starts writing a word to Flash memory or EEPROM, without waiting
+    ///
+    /// This works like [`FLASH::write_word`], but returns as soon as the write
+    /// operation has been started. The memory stays unlocked until the
+    /// operation has been completed, which needs to be checked by calling
+    /// [`FLASH::poll_write`] until it returns something other than
+    /// `WouldBlock`.
+    ///
+    /// This is useful in situations where only a limited amount of time is
+    /// available, for example from a watchdog early wakeup hook, as the CPU
+    /// can do other work while the (comparatively slow) EEPROM write is in
+    /// progress, as long as it isn't executing from the same memory bank.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `address` does not point to Flash memory or EEPROM.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn start_write_word(&mut self, address: *mut u32, word: u32) {
+        self.verify_address(address);
+
+        // Wait, while the memory interface is busy.
+        while self.flash.sr.read().bsy().is_active() {}
+
+        self.unlock_registers();
+
+        // Write memory
+        // Safe, as we know that this points to flash or EEPROM.
+        unsafe { address.write_volatile(word) }
+    }
+
+    /// Checks whether a write started by [`FLASH::start_write_word`] has
+    /// completed
+    ///
+    /// Once the operation has completed, the memory is locked again and the
+    /// result of the operation is returned.
+    pub fn poll_write(&mut self) -> nb::Result<(), Error> {
+        if self.flash.sr.read().bsy().is_active() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let result = self.check_errors();
+
+        // Reset operations and write protection
+        self.flash.pecr.reset();
+
+        result.map_err(nb::Error::Other)
+    }
+
     /// Writes a single byte to EEPROM
     ///
     /// Please note that any access to Flash or EEPROM on the same memory bank
@@ -285,6 +519,39 @@ impl FLASH {
         })
     }
 
+    /// Writes multiple consecutive half-pages of Flash memory
+    ///
+    /// Writes `words` to the memory starting at `address`, one half-page at a
+    /// time. `on_yield` is called before each half-page is written. Use it to
+    /// feed the watchdog (for example `|| watchdog.feed()`), or to do other
+    /// work between operations. Interrupts are only disabled while a single
+    /// half-page is being prepared, and the memory bank is stalled for up to
+    /// [`T_PROG_MAX`] per half-page.
+    ///
+    /// The memory written to must have been erased before, otherwise this
+    /// method will return an error. Stops at the first error.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic, unless all of the following is true:
+    /// - All of the written memory is located in Flash memory
+    /// - `address` is aligned to a half-page boundary (16 words, 64 bytes)
+    /// - The length of `words` is a multiple of 16
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn write_flash_half_pages(
+        &mut self,
+        address: *mut u32,
+        words: &[u32],
+        mut on_yield: impl FnMut(),
+    ) -> Result {
+        for (i, half_page) in words.chunks(16).enumerate() {
+            on_yield();
+            self.write_flash_half_page(address.wrapping_add(i * 16), half_page)?;
+        }
+
+        Ok(())
+    }
+
     /// Unlock everything that needs unlocking:
     ///
     /// - FLASH_PECR lock (PELOCK)
@@ -297,6 +564,17 @@ impl FLASH {
     ///
     /// - STM32L0x1 reference manual (RM0377), section 3.3.4 (Writing/erasing the NVM)
     fn unlock(&mut self, f: impl FnOnce(&mut Self) -> Result) -> Result {
+        self.unlock_registers();
+
+        let result = f(self);
+
+        // Reset operations and write protection
+        self.flash.pecr.reset();
+
+        result
+    }
+
+    fn unlock_registers(&mut self) {
         // FLASH_PECR lock
         self.flash.pekeyr.write(|w| w.pekeyr().bits(0x89ABCDEF));
         self.flash.pekeyr.write(|w| w.pekeyr().bits(0x02030405));
@@ -306,13 +584,6 @@ impl FLASH {
         // Option bytes lock
         self.flash.optkeyr.write(|w| w.optkeyr().bits(0xFBEAD9C8));
         self.flash.optkeyr.write(|w| w.optkeyr().bits(0x24252627));
-
-        let result = f(self);
-
-        // Reset operations and write protection
-        self.flash.pecr.reset();
-
-        result
     }
 
     fn verify_address<T>(&self, address: *mut T) -> Memory {
@@ -478,3 +749,17 @@ pub enum Error {
     /// See WRPERR in SR register.
     WriteProtection,
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AbortedByFetch => f.write_str("write/erase aborted by fetch"),
+            Error::NotErased => f.write_str("memory not erased"),
+            Error::ReadProtection => f.write_str("read protection violation"),
+            Error::ConfigMismatch => f.write_str("option byte configuration mismatch"),
+            Error::InvalidSize => f.write_str("invalid size"),
+            Error::InvalidAlignment => f.write_str("invalid alignment"),
+            Error::WriteProtection => f.write_str("write protection violation"),
+        }
+    }
+}
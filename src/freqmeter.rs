@@ -0,0 +1,363 @@
+//! Frequency measurement
+//!
+//! Measures the frequency of an external signal using TIM2. Two methods are
+//! supported:
+//!
+//! - **Input capture**: The period of the signal is timed against the timer
+//!   clock and averaged over a number of periods. This is very accurate for low
+//!   frequencies, but requires the CPU to service every captured edge.
+//! - **Gated counting**: The edges of the signal are counted by the timer
+//!   during a known gate time. This works up to a quarter of the timer clock
+//!   (more with the ETR prescaler), but the resolution is limited to one count
+//!   per gate time.
+//!
+//! [`FreqMeter::measure`] picks the method based on the expected frequency.
+//!
+//! Both methods use the same input pin: TIM2 channel 1 and the TIM2 external
+//! trigger (ETR) are available on `PA0`, `PA5` and `PA15`.
+//!
+//! All measurements are relative to the timer clock (or the delay provider
+//! used as gate). The error bound returned with each measurement only covers
+//! the quantization error and does not include the inaccuracy of that clock.
+
+use core::fmt;
+
+use crate::gpio::gpioa::{PA0, PA15, PA5};
+use crate::gpio::{AltMode, PinMode};
+use crate::hal::blocking::delay::DelayUs;
+use crate::pac::TIM2;
+use crate::rcc::{Enable, Rcc, Reset};
+use crate::timer::{EtrPin, EtrPrescaler, Filter};
+use cast::{u16, u64};
+use embedded_time::duration::Microseconds;
+use embedded_time::rate::Hertz;
+
+/// Minimum number of timer ticks per signal period for input capture
+///
+/// Below that, the CPU can't reliably keep up with the captured edges and the
+/// quantization error becomes larger than the one of gated counting.
+const CAPTURE_MIN_TICKS: u32 = 256;
+
+/// Gate time used by [`FreqMeter::measure`], if the counter doesn't overflow
+const MAX_GATE_US: u32 = 100_000;
+
+/// Maximum number of periods averaged by [`FreqMeter::measure`]
+const MAX_PERIODS: u32 = 100;
+
+/// Pins that can be used as frequency meter input
+///
+/// Gated counting connects the pin to the TIM2 external trigger input, using
+/// [`EtrPin::setup`].
+pub trait Pin: EtrPin<TIM2> {
+    /// Connects the pin to TIM2 channel 1
+    ///
+    /// That's AF2 on PA0, and AF5 on PA5 and PA15.
+    fn setup_capture(&self);
+}
+
+macro_rules! impl_pin {
+    ($($pin:ident: $capture:ident,)+) => {
+        $(
+            impl<MODE: PinMode> Pin for $pin<MODE> {
+                fn setup_capture(&self) {
+                    self.set_alt_mode(AltMode::$capture);
+                }
+            }
+        )+
+    };
+}
+
+impl_pin! {
+    PA0: AF2,
+    PA5: AF5,
+    PA15: AF5,
+}
+
+/// Method used for a frequency measurement
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Method {
+    /// The signal period was timed using input capture
+    InputCapture,
+    /// The signal edges were counted during a gate time
+    GatedCounting,
+}
+
+/// Result of a frequency measurement
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Measurement {
+    /// Measured frequency, rounded to the nearest Hz
+    pub frequency: Hertz,
+    /// Upper bound of the quantization error of `frequency`
+    pub error: Hertz,
+    /// Method used for the measurement
+    pub method: Method,
+}
+
+/// Frequency measurement errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// No edges have been detected on the input
+    NoSignal,
+    /// The signal is too fast for the selected method and settings
+    OutOfRange,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoSignal => f.write_str("no signal"),
+            Error::OutOfRange => f.write_str("signal out of range"),
+        }
+    }
+}
+
+/// Frequency meter on TIM2
+pub struct FreqMeter<PIN> {
+    tim: TIM2,
+    pin: PIN,
+    clk: u32,
+    filter: Filter,
+}
+
+impl<PIN> FreqMeter<PIN>
+where
+    PIN: Pin,
+{
+    /// Creates a new frequency meter measuring the signal on `pin`
+    pub fn tim2(tim: TIM2, pin: PIN, rcc: &mut Rcc) -> Self {
+        TIM2::enable(rcc);
+        TIM2::reset(rcc);
+
+        FreqMeter {
+            tim,
+            pin,
+            clk: rcc.clocks.apb1_tim_clk().0,
+            filter: Filter::NoFilter,
+        }
+    }
+
+    /// Sets the digital filter applied to the input signal
+    ///
+    /// Applies to both input capture and gated counting, starting with the
+    /// next measurement. Note that the filter limits the highest frequency
+    /// that can be measured. Defaults to [`Filter::NoFilter`].
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Measures the frequency, selecting the method based on `expected`
+    ///
+    /// Signals that are slow compared to the timer clock are measured using
+    /// input capture, averaging over up to 100 periods or 100 ms. Faster
+    /// signals are measured by gated counting, with a gate time of up to 100
+    /// ms. `delay` is used to time the gate.
+    pub fn measure<D>(&mut self, expected: Hertz, delay: &mut D) -> Result<Measurement, Error>
+    where
+        D: DelayUs<u32>,
+    {
+        let expected = expected.0.max(1);
+
+        if self.clk / expected >= CAPTURE_MIN_TICKS {
+            let periods = (expected / (1_000_000 / MAX_GATE_US)).clamp(1, MAX_PERIODS);
+            self.measure_capture(Hertz(expected), periods as u16)
+        } else {
+            // The ETR input must not toggle faster than a quarter of the timer
+            // clock after the prescaler.
+            let prescaler = match expected / (self.clk / 4) {
+                0 => EtrPrescaler::Div1,
+                1 => EtrPrescaler::Div2,
+                2 | 3 => EtrPrescaler::Div4,
+                _ => EtrPrescaler::Div8,
+            };
+            let divider: u32 = 1 << prescaler as u32;
+
+            // Leave a margin of 2 so a signal that's somewhat faster than
+            // expected doesn't overflow the counter.
+            let gate = u64(0x8000) * u64(divider) * 1_000_000 / u64(expected);
+            let gate = gate.min(u64(MAX_GATE_US)) as u32;
+
+            self.measure_gated(Microseconds(gate.max(1)), prescaler, delay)
+        }
+    }
+
+    /// Measures the frequency by timing `periods` periods of the signal
+    ///
+    /// The timer prescaler is chosen based on `expected`, so the signal must
+    /// not be more than twice as slow as expected. If no edge is detected
+    /// within about four times the expected period, `Error::NoSignal` is
+    /// returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `periods` is 0.
+    pub fn measure_capture(&mut self, expected: Hertz, periods: u16) -> Result<Measurement, Error> {
+        assert!(periods > 0);
+
+        // Choose the prescaler so that the expected period takes up at most
+        // half the counter range.
+        let ticks = self.clk / expected.0.max(1);
+        let psc = u16(ticks / 0x8000).unwrap_or(0xffff);
+        let tick_freq = self.clk / (u32::from(psc) + 1);
+
+        self.configure_capture(psc);
+
+        let result = self.capture_periods(periods);
+        self.stop();
+        let total = u64(result?);
+
+        if total == 0 {
+            return Err(Error::OutOfRange);
+        }
+
+        // The captured edges are each off by less than one tick, which
+        // results in an error of at most one tick for the whole measurement.
+        let numerator = u64(tick_freq) * u64(periods);
+        let frequency = (numerator + total / 2) / total;
+        let error = (numerator + total * total - 1) / (total * total);
+
+        Ok(Measurement {
+            frequency: Hertz(frequency as u32),
+            error: Hertz(error as u32),
+            method: Method::InputCapture,
+        })
+    }
+
+    /// Measures the frequency by counting edges during `gate`
+    ///
+    /// Returns `Error::OutOfRange`, if the counter overflows during the gate
+    /// time. Either shorten the gate time or increase `prescaler` in that
+    /// case.
+    pub fn measure_gated<D>(
+        &mut self,
+        gate: Microseconds,
+        prescaler: EtrPrescaler,
+        delay: &mut D,
+    ) -> Result<Measurement, Error>
+    where
+        D: DelayUs<u32>,
+    {
+        let gate_us = u64(gate.0.max(1));
+        let divider: u32 = 1 << prescaler as u32;
+
+        self.configure_counter(prescaler);
+        delay.delay_us(gate.0);
+        let count = self.tim.cnt.read().cnt().bits() as u16;
+        let overflow = self.tim.sr.read().uif().bit_is_set();
+        self.stop();
+
+        if overflow {
+            return Err(Error::OutOfRange);
+        }
+        if count == 0 {
+            return Err(Error::NoSignal);
+        }
+
+        // Only complete prescaler periods are counted, so the count can be
+        // off by one prescaler period.
+        let edges = u64(count) * u64(divider);
+        let frequency = (edges * 1_000_000 + gate_us / 2) / gate_us;
+        let error = (u64(divider) * 1_000_000 + gate_us - 1) / gate_us;
+
+        Ok(Measurement {
+            frequency: Hertz(frequency as u32),
+            error: Hertz(error as u32),
+            method: Method::GatedCounting,
+        })
+    }
+
+    /// Releases the timer and pin
+    pub fn release(mut self) -> (TIM2, PIN) {
+        self.stop();
+        (self.tim, self.pin)
+    }
+
+    fn configure_capture(&mut self, psc: u16) {
+        self.pin.setup_capture();
+
+        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+        self.tim.smcr.reset();
+        self.tim.psc.write(|w| w.psc().bits(psc));
+        #[allow(unused_unsafe)]
+        self.tim.arr.write(|w| unsafe { w.arr().bits(0xffff) });
+
+        // Capture every rising edge on TI1
+        let filter = self.filter as u8;
+        self.tim.ccer.modify(|_, w| w.cc1e().clear_bit());
+        #[allow(unused_unsafe)]
+        self.tim
+            .ccmr1_input()
+            .modify(|_, w| unsafe { w.cc1s().bits(0b01).ic1psc().bits(0b00).ic1f().bits(filter) });
+        self.tim
+            .ccer
+            .modify(|_, w| w.cc1p().clear_bit().cc1np().clear_bit().cc1e().set_bit());
+
+        self.tim.egr.write(|w| w.ug().set_bit());
+        self.tim.sr.reset();
+        self.tim.cr1.modify(|_, w| w.cen().set_bit());
+    }
+
+    fn configure_counter(&mut self, prescaler: EtrPrescaler) {
+        EtrPin::<TIM2>::setup(&self.pin);
+
+        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+        self.tim.ccer.modify(|_, w| w.cc1e().clear_bit());
+        self.tim.psc.write(|w| w.psc().bits(0));
+        #[allow(unused_unsafe)]
+        self.tim.arr.write(|w| unsafe { w.arr().bits(0xffff) });
+
+        // External clock mode 2: count edges on ETRF
+        #[allow(unused_unsafe)]
+        self.tim.smcr.write(|w| unsafe {
+            w.etp()
+                .clear_bit()
+                .etps()
+                .bits(prescaler as u8)
+                .etf()
+                .bits(self.filter as u8)
+                .ece()
+                .set_bit()
+        });
+
+        self.tim.egr.write(|w| w.ug().set_bit());
+        self.tim.cnt.reset();
+        self.tim.sr.reset();
+        self.tim.cr1.modify(|_, w| w.cen().set_bit());
+    }
+
+    /// Returns the number of ticks spanned by `periods` periods of the signal
+    fn capture_periods(&mut self, periods: u16) -> Result<u32, Error> {
+        let mut last = self.capture()?;
+        let mut total: u32 = 0;
+        for _ in 0..periods {
+            let now = self.capture()?;
+            total += u32::from(now.wrapping_sub(last));
+            last = now;
+        }
+        Ok(total)
+    }
+
+    /// Waits for the next captured edge and returns the captured counter value
+    fn capture(&mut self) -> Result<u16, Error> {
+        let mut overflows = 0;
+        loop {
+            let sr = self.tim.sr.read();
+            if sr.cc1if().bit_is_set() {
+                // Reading CCR1 clears CC1IF
+                return Ok(self.tim.ccr1.read().ccr().bits() as u16);
+            }
+            if sr.uif().bit_is_set() {
+                self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                overflows += 1;
+                if overflows > 2 {
+                    return Err(Error::NoSignal);
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+        self.tim.ccer.modify(|_, w| w.cc1e().clear_bit());
+        self.tim.smcr.reset();
+    }
+}
@@ -1,9 +1,19 @@
 //! General Purpose Input / Output
-
+//!
+//! Peripheral drivers take ownership of the pins they use and switch them to
+//! the right alternate function, using the `setup` method of the pin traits in
+//! their modules (e.g. `serial::TxPin` or `i2c::SDAPin`). The pins are only
+//! returned when the driver is released, so handing the same pin to two
+//! peripherals fails to compile.
+
+use core::cell::Cell;
 use core::convert::Infallible;
 use core::marker::PhantomData;
 
-use crate::rcc::Rcc;
+use cortex_m::interrupt::{self, Mutex};
+
+use crate::pac;
+use crate::rcc::{Enable, Rcc};
 
 /// Extension trait to split a GPIO peripheral in independent pins and registers
 pub trait GpioExt {
@@ -19,6 +29,7 @@ trait GpioRegExt {
     fn is_set_low(&self, pos: u8) -> bool;
     fn set_high(&self, pos: u8);
     fn set_low(&self, pos: u8);
+    fn set_mode(&self, pos: u8, pupdr: u8, moder: u8, otyper: Option<u8>);
 }
 
 /// Input mode (type state)
@@ -49,12 +60,23 @@ pub struct Output<MODE> {
 /// Push pull output (type state)
 pub struct PushPull;
 
+/// Alternate function mode (type state)
+///
+/// Only used for pins that are temporarily configured using `with_alt_mode`.
+pub struct Alternate;
+
 use embedded_hal::digital::v2::{toggleable, InputPin, OutputPin, StatefulOutputPin};
 
+pub use embedded_hal::digital::v2::PinState;
+
 /// Fully erased pin
+///
+/// Created from a typed pin using `downgrade`, or at runtime from a port and
+/// pin number using [`Pin::try_new`].
 pub struct Pin<MODE> {
     i: u8,
     port: *const dyn GpioRegExt,
+    port_id: Port,
     _mode: PhantomData<MODE>,
 }
 
@@ -64,15 +86,122 @@ unsafe impl<MODE> Sync for Pin<MODE> {}
 // threads
 unsafe impl<MODE> Send for Pin<MODE> {}
 
+impl<MODE: PinMode> Pin<MODE> {
+    /// Creates a pin from a port and pin number, and configures it for `MODE`
+    ///
+    /// This makes it possible to configure pins at runtime, for example from a
+    /// pin list stored in EEPROM. The pin is claimed, so it can't be created
+    /// again until it's released using [`Pin::release`].
+    ///
+    /// Returns `None`, if the pin doesn't exist, or if it has already been
+    /// claimed. All pins of a port are claimed when the port is split using
+    /// [`GpioExt::split`], so this only works for ports that haven't been
+    /// split, or for pins that have been released.
+    ///
+    /// Enables the port's clock, if necessary. Please note that whether a
+    /// port actually exists depends on the package, which isn't checked here.
+    pub fn try_new(port: Port, i: u8, rcc: &mut Rcc) -> Option<Self> {
+        if !port.has_pin(i) || !claim(port, 1 << i) {
+            return None;
+        }
+
+        port.enable(rcc);
+
+        // Safe, as the pin has been claimed above.
+        Some(unsafe { Self::new_unchecked(port, i) })
+    }
+
+    /// Creates a pin from a port and pin number, and configures it for `MODE`
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that the pin exists, that no other instance
+    /// of the pin is in use, and that the port's clock is enabled. Please
+    /// prefer [`Pin::try_new`], which checks all of this.
+    pub unsafe fn new_unchecked(port: Port, i: u8) -> Self {
+        let pin = Pin {
+            i,
+            port: port.registers(),
+            port_id: port,
+            _mode: PhantomData,
+        };
+        (*pin.port).set_mode(i, MODE::PUPDR, MODE::MODER, MODE::OTYPER);
+        pin
+    }
+
+    /// Configures the pin for mode `M`
+    pub fn into_mode<M: PinMode>(self) -> Pin<M> {
+        unsafe { (*self.port).set_mode(self.i, M::PUPDR, M::MODER, M::OTYPER) };
+        Pin {
+            i: self.i,
+            port: self.port,
+            port_id: self.port_id,
+            _mode: PhantomData,
+        }
+    }
+}
+
+impl<MODE> Pin<MODE> {
+    /// Returns the port this pin is part of
+    pub fn port(&self) -> Port {
+        self.port_id
+    }
+
+    /// Returns this pin's number inside its port
+    pub fn pin_number(&self) -> u8 {
+        self.i
+    }
+
+    /// Releases the pin, so it can be created again using [`Pin::try_new`]
+    ///
+    /// The pin keeps its current configuration.
+    pub fn release(self) {
+        unclaim(self.port_id, 1 << self.i);
+    }
+}
+
+impl<MODE> Pin<Output<MODE>> {
+    /// Drives the pin high or low, depending on `state`
+    #[inline(always)]
+    pub fn set_state(&mut self, state: PinState) {
+        match state {
+            PinState::High => unsafe { (*self.port).set_high(self.i) },
+            PinState::Low => unsafe { (*self.port).set_low(self.i) },
+        }
+    }
+
+    /// Toggles the pin
+    #[inline(always)]
+    pub fn toggle(&mut self) {
+        if self.is_set_low() {
+            self.set_state(PinState::High);
+        } else {
+            self.set_state(PinState::Low);
+        }
+    }
+
+    /// Checks if the pin is set to drive high
+    #[inline(always)]
+    pub fn is_set_high(&self) -> bool {
+        !self.is_set_low()
+    }
+
+    /// Checks if the pin is set to drive low
+    #[inline(always)]
+    pub fn is_set_low(&self) -> bool {
+        unsafe { (*self.port).is_set_low(self.i) }
+    }
+}
+
 impl<MODE> StatefulOutputPin for Pin<Output<MODE>> {
     #[inline(always)]
     fn is_set_high(&self) -> Result<bool, Self::Error> {
-        self.is_set_low().map(|v| !v)
+        Ok(Pin::is_set_high(self))
     }
 
     #[inline(always)]
     fn is_set_low(&self) -> Result<bool, Self::Error> {
-        Ok(unsafe { (*self.port).is_set_low(self.i) })
+        Ok(Pin::is_set_low(self))
     }
 }
 
@@ -122,10 +251,104 @@ impl<MODE> InputPin for Pin<Input<MODE>> {
     }
 }
 
+/// Read-only view of a pin's input level
+///
+/// Reading the input data register of a pin is possible in every mode except
+/// analog, including alternate function mode. This is useful to observe the
+/// line level of a pin that is owned by a peripheral, for example to detect
+/// activity on a UART RX line or whether an I2C bus is idle.
+///
+/// An `InputMonitor` can be created for any pin using `input_monitor`, before
+/// the pin is handed over to a peripheral.
+pub struct InputMonitor {
+    i: u8,
+    port: *const dyn GpioRegExt,
+}
+
+// NOTE(unsafe) The monitor only ever reads from IDR
+unsafe impl Sync for InputMonitor {}
+// NOTE(unsafe) The monitor only ever reads from IDR
+unsafe impl Send for InputMonitor {}
+
+impl InputPin for InputMonitor {
+    type Error = Infallible;
+
+    #[inline(always)]
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.is_low().map(|v| !v)
+    }
+
+    #[inline(always)]
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(unsafe { (*self.port).is_low(self.i) })
+    }
+}
+
+/// Input levels of all pins of a port, captured at the same time
+///
+/// Created by `Parts::snapshot`. Comparing two snapshots is an efficient way
+/// to detect input changes on many pins, for example when scanning a keypad.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    bits: u16,
+}
+
+impl Snapshot {
+    /// Creates a snapshot from the raw input levels
+    pub fn new(bits: u16) -> Self {
+        Snapshot { bits }
+    }
+
+    /// Returns the raw input levels, with bit `n` being the level of pin `n`
+    pub fn bits(&self) -> u16 {
+        self.bits
+    }
+
+    /// Checks if pin `i` was high
+    pub fn is_high(&self, i: u8) -> bool {
+        self.bits & (1 << i) != 0
+    }
+
+    /// Checks if pin `i` was low
+    pub fn is_low(&self, i: u8) -> bool {
+        !self.is_high(i)
+    }
+
+    /// Returns a mask of the pins that changed since `previous`
+    pub fn changed(&self, previous: Snapshot) -> u16 {
+        self.bits ^ previous.bits
+    }
+
+    /// Returns a mask of the pins that went from low to high since `previous`
+    pub fn rising(&self, previous: Snapshot) -> u16 {
+        self.bits & !previous.bits
+    }
+
+    /// Returns a mask of the pins that went from high to low since `previous`
+    pub fn falling(&self, previous: Snapshot) -> u16 {
+        !self.bits & previous.bits
+    }
+}
+
 mod sealed {
     pub trait Sealed {}
 }
 
+/// Pins that can temporarily be switched back to the mode of their type state
+///
+/// Peripherals switch their pins to an alternate function, without changing
+/// the type of the pin. This allows a driver to use such a pin as GPIO again
+/// for a moment, for example to recover a stuck bus.
+pub trait WithGpioMode {
+    /// Configures the pin according to its type state, calls `f`, and then
+    /// restores the previous mode
+    ///
+    /// The alternate function selection isn't touched, so a pin that was
+    /// switched to an alternate function before is switched back to the same
+    /// function afterwards.
+    fn with_gpio_mode<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R;
+}
+
 /// Marker trait for valid pin modes (type state).
 ///
 /// It can not be implemented by outside types.
@@ -210,6 +433,66 @@ pub enum Port {
     PH,
 }
 
+impl Port {
+    /// Indicates whether pin `i` exists on this port
+    fn has_pin(self, i: u8) -> bool {
+        match self {
+            Port::PH => matches!(i, 0 | 1 | 9 | 10),
+            _ => i < 16,
+        }
+    }
+
+    fn enable(self, rcc: &mut Rcc) {
+        match self {
+            Port::PA => pac::GPIOA::enable(rcc),
+            Port::PB => pac::GPIOB::enable(rcc),
+            Port::PC => pac::GPIOC::enable(rcc),
+            Port::PD => pac::GPIOD::enable(rcc),
+            Port::PE => pac::GPIOE::enable(rcc),
+            Port::PH => pac::GPIOH::enable(rcc),
+        }
+    }
+
+    fn registers(self) -> *const dyn GpioRegExt {
+        match self {
+            Port::PA => pac::GPIOA::ptr() as *const dyn GpioRegExt,
+            Port::PB => pac::GPIOB::ptr() as *const dyn GpioRegExt,
+            Port::PC => pac::GPIOC::ptr() as *const dyn GpioRegExt,
+            Port::PD => pac::GPIOD::ptr() as *const dyn GpioRegExt,
+            Port::PE => pac::GPIOE::ptr() as *const dyn GpioRegExt,
+            Port::PH => pac::GPIOH::ptr() as *const dyn GpioRegExt,
+        }
+    }
+}
+
+/// Claimed pins, one bit per pin, indexed by `Port`
+///
+/// Keeps track of the pins handed out by `split` and [`Pin::try_new`].
+static CLAIMED: Mutex<Cell<[u16; 6]>> = Mutex::new(Cell::new([0; 6]));
+
+/// Claims the pins in `mask`, if none of them have been claimed already
+fn claim(port: Port, mask: u16) -> bool {
+    interrupt::free(|cs| {
+        let claimed = CLAIMED.borrow(cs);
+        let mut pins = claimed.get();
+        if pins[port as usize] & mask != 0 {
+            return false;
+        }
+        pins[port as usize] |= mask;
+        claimed.set(pins);
+        true
+    })
+}
+
+fn unclaim(port: Port, mask: u16) {
+    interrupt::free(|cs| {
+        let claimed = CLAIMED.borrow(cs);
+        let mut pins = claimed.get();
+        pins[port as usize] &= !mask;
+        claimed.set(pins);
+    })
+}
+
 macro_rules! gpio_trait {
     ($gpiox:ident) => {
         impl GpioRegExt for crate::pac::$gpiox::RegisterBlock {
@@ -230,6 +513,25 @@ macro_rules! gpio_trait {
                 // NOTE(unsafe) atomic write to a stateless register
                 unsafe { self.bsrr.write(|w| w.bits(1 << (pos + 16))) }
             }
+
+            fn set_mode(&self, pos: u8, pupdr: u8, moder: u8, otyper: Option<u8>) {
+                let offset = 2 * pos;
+                unsafe {
+                    self.pupdr.modify(|r, w| {
+                        w.bits((r.bits() & !(0b11 << offset)) | (u32::from(pupdr) << offset))
+                    });
+
+                    if let Some(otyper) = otyper {
+                        self.otyper.modify(|r, w| {
+                            w.bits((r.bits() & !(0b1 << pos)) | (u32::from(otyper) << pos))
+                        });
+                    }
+
+                    self.moder.modify(|r, w| {
+                        w.bits((r.bits() & !(0b11 << offset)) | (u32::from(moder) << offset))
+                    });
+                }
+            }
         }
     };
 }
@@ -243,6 +545,7 @@ macro_rules! gpio {
     ]) => {
         /// GPIO
         pub mod $gpiox {
+            use core::convert::Infallible;
             use core::marker::PhantomData;
 
             use crate::hal::digital::v2::{toggleable, InputPin, OutputPin, StatefulOutputPin};
@@ -250,8 +553,8 @@ macro_rules! gpio {
             use crate::rcc::{Enable, Rcc};
             use super::{
                 Floating, GpioExt, Input, OpenDrain, Output, Speed,
-                PullDown, PullUp, PushPull, AltMode, Analog, Port,
-                PinMode, Pin, GpioRegExt
+                PullDown, PullUp, PushPull, AltMode, Alternate, Analog, Port,
+                PinMode, PinState, Pin, GpioRegExt, InputMonitor, Snapshot
             };
 
             /// GPIO parts
@@ -262,10 +565,32 @@ macro_rules! gpio {
                 )+
             }
 
+            impl Parts {
+                /// Reads the input levels of all pins of the port at once
+                ///
+                /// Bit `n` of the result is the level of pin `n`. This doesn't
+                /// require access to the pins, so it can still be used after
+                /// the pins have been moved out of `Parts`.
+                pub fn read_all() -> u16 {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).idr.read().bits() as u16 }
+                }
+
+                /// Captures the input levels of all pins of the port
+                pub fn snapshot() -> Snapshot {
+                    Snapshot::new(Self::read_all())
+                }
+            }
+
             impl GpioExt for $GPIOX {
                 type Parts = Parts;
 
                 fn split(self, rcc: &mut Rcc) -> Parts {
+                    // Pins created at runtime would alias the typed pins
+                    if !super::claim(Port::$PXx, 0xffff) {
+                        panic!("pins already claimed using `Pin::try_new`");
+                    }
+
                     <$GPIOX>::enable(rcc);
 
                     Parts {
@@ -300,6 +625,18 @@ macro_rules! gpio {
                     pub fn pin_number(&self) -> u8 {
                         $i
                     }
+
+                    /// Returns a read-only view of this pin's input level
+                    ///
+                    /// The monitor stays valid after the pin has been handed over to a
+                    /// peripheral, so it can be used to observe the line level of a pin in
+                    /// alternate function mode.
+                    pub fn input_monitor(&self) -> InputMonitor {
+                        InputMonitor {
+                            i: $i,
+                            port: $GPIOX::ptr() as *const dyn GpioRegExt,
+                        }
+                    }
                 }
 
                 impl<MODE: PinMode> $PXi<MODE> {
@@ -451,6 +788,22 @@ macro_rules! gpio {
                         }
                     }
 
+                    /// Configures the pin to operate as an open drain output pin, with the
+                    /// output initially set to `initial_state`.
+                    ///
+                    /// The output level is set before the pin is switched to output mode, so
+                    /// the pin doesn't glitch to the previous output level.
+                    pub fn into_open_drain_output_with_state(
+                        mut self,
+                        initial_state: PinState,
+                    ) -> $PXi<Output<OpenDrain>> {
+                        self.set_output_state(initial_state);
+                        self.mode::<Output<OpenDrain>>();
+                        $PXi {
+                            _mode: PhantomData
+                        }
+                    }
+
                     /// Temporarily configures this pin as an open drain output.
                     ///
                     /// The closure `f` is called with the reconfigured pin. After it returns,
@@ -472,6 +825,22 @@ macro_rules! gpio {
                         }
                     }
 
+                    /// Configures the pin to operate as an push-pull output pin, with the
+                    /// output initially set to `initial_state`.
+                    ///
+                    /// The output level is set before the pin is switched to output mode, so
+                    /// the pin doesn't glitch to the previous output level.
+                    pub fn into_push_pull_output_with_state(
+                        mut self,
+                        initial_state: PinState,
+                    ) -> $PXi<Output<PushPull>> {
+                        self.set_output_state(initial_state);
+                        self.mode::<Output<PushPull>>();
+                        $PXi {
+                            _mode: PhantomData
+                        }
+                    }
+
                     /// Temporarily configures this pin as a push-pull output.
                     ///
                     /// The closure `f` is called with the reconfigured pin. After it returns,
@@ -483,6 +852,48 @@ macro_rules! gpio {
                         self.with_mode(f)
                     }
 
+                    /// Temporarily configures this pin to the alternate function `mode`.
+                    ///
+                    /// The closure `f` is called with the reconfigured pin. After it returns,
+                    /// the pin will be configured back.
+                    pub fn with_alt_mode<R>(
+                        &mut self,
+                        mode: AltMode,
+                        f: impl FnOnce(&mut $PXi<Alternate>) -> R,
+                    ) -> R {
+                        struct ResetMode<'a, ORIG: PinMode> {
+                            pin: &'a mut $PXi<ORIG>,
+                        }
+
+                        impl<'a, ORIG: PinMode> Drop for ResetMode<'a, ORIG> {
+                            fn drop(&mut self) {
+                                self.pin.mode::<ORIG>();
+                            }
+                        }
+
+                        self.set_alt_mode(mode);
+
+                        // This will reset the pin back to the original mode when dropped.
+                        // (so either when `with_alt_mode` returns or when `f` unwinds)
+                        let _resetti = ResetMode { pin: self };
+
+                        let mut witness = $PXi {
+                            _mode: PhantomData
+                        };
+
+                        f(&mut witness)
+                    }
+
+                    /// Sets the output data register bit of the pin, without changing its mode.
+                    fn set_output_state(&mut self, state: PinState) {
+                        let bit = match state {
+                            PinState::High => 1 << $i,
+                            PinState::Low => 1 << ($i + 16),
+                        };
+                        // NOTE(unsafe) atomic write to a stateless register
+                        unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(bit)) };
+                    }
+
                     /// Set pin speed.
                     pub fn set_speed(self, speed: Speed) -> Self {
                         let offset = 2 * $i;
@@ -494,6 +905,19 @@ macro_rules! gpio {
                         self
                     }
 
+                    /// Sets the output type of the pin, without changing its mode
+                    ///
+                    /// Used by peripherals that need an open-drain output in alternate
+                    /// function mode.
+                    #[allow(dead_code)]
+                    pub(crate) fn set_output_type(&self, open_drain: bool) {
+                        unsafe {
+                            (*$GPIOX::ptr()).otyper.modify(|r, w| {
+                                w.bits((r.bits() & !(0b1 << $i)) | (u32::from(open_drain) << $i))
+                            });
+                        }
+                    }
+
                     #[allow(dead_code)]
                     pub(crate) fn set_alt_mode(&self, mode: AltMode) {
                         let mode = mode as u32;
@@ -517,6 +941,24 @@ macro_rules! gpio {
                     }
                 }
 
+                impl<MODE: PinMode> WithGpioMode for $PXi<MODE> {
+                    fn with_gpio_mode<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+                        let offset = 2 * $i;
+                        // NOTE(unsafe) atomic read with no side effects
+                        let moder = unsafe { ((*$GPIOX::ptr()).moder.read().bits() >> offset) & 0b11 };
+
+                        self.mode::<MODE>();
+                        let result = f(self);
+
+                        unsafe {
+                            (*$GPIOX::ptr()).moder.modify(|r, w| {
+                                w.bits((r.bits() & !(0b11 << offset)) | (moder << offset))
+                            });
+                        }
+                        result
+                    }
+                }
+
                 impl<MODE> $PXi<Output<MODE>> {
                     /// Erases the pin number from the type
                     ///
@@ -526,13 +968,39 @@ macro_rules! gpio {
                         Pin {
                             i: $i,
                             port: $GPIOX::ptr() as *const dyn GpioRegExt,
+                            port_id: Port::$PXx,
                             _mode: self._mode,
                         }
                     }
+
+                    /// Drives the pin high or low, depending on `state`
+                    pub fn set_state(&mut self, state: PinState) {
+                        self.set_output_state(state);
+                    }
+
+                    /// Toggles the pin
+                    pub fn toggle(&mut self) {
+                        if self.is_set_low() {
+                            self.set_output_state(PinState::High);
+                        } else {
+                            self.set_output_state(PinState::Low);
+                        }
+                    }
+
+                    /// Checks if the pin is set to drive high
+                    pub fn is_set_high(&self) -> bool {
+                        !self.is_set_low()
+                    }
+
+                    /// Checks if the pin is set to drive low
+                    pub fn is_set_low(&self) -> bool {
+                        // NOTE(unsafe) atomic read with no side effects
+                        unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << $i) == 0 }
+                    }
                 }
 
                 impl<MODE> OutputPin for $PXi<Output<MODE>> {
-                    type Error = void::Void;
+                    type Error = Infallible;
 
                     fn set_high(&mut self) -> Result<(), Self::Error> {
                         // NOTE(unsafe) atomic write to a stateless register
@@ -550,21 +1018,33 @@ macro_rules! gpio {
                 impl<MODE> StatefulOutputPin for $PXi<Output<MODE>> {
 
                     fn is_set_high(&self) -> Result<bool, Self::Error> {
-                        let is_set_high = !self.is_set_low()?;
-                        Ok(is_set_high)
+                        Ok($PXi::is_set_high(self))
                     }
 
                     fn is_set_low(&self) -> Result<bool, Self::Error> {
-                        // NOTE(unsafe) atomic read with no side effects
-                        let is_set_low = unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << $i) == 0 };
-                        Ok(is_set_low)
+                        Ok($PXi::is_set_low(self))
                     }
                 }
 
                 impl<MODE> toggleable::Default for $PXi<Output<MODE>> {}
 
                 impl<MODE> InputPin for $PXi<Output<MODE>> {
-                    type Error = void::Void;
+                    type Error = Infallible;
+
+                    fn is_high(&self) -> Result<bool, Self::Error> {
+                        let is_high = !self.is_low()?;
+                        Ok(is_high)
+                    }
+
+                    fn is_low(&self) -> Result<bool, Self::Error> {
+                        // NOTE(unsafe) atomic read with no side effects
+                        let is_low = unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << $i) == 0 };
+                        Ok(is_low)
+                    }
+                }
+
+                impl InputPin for $PXi<Alternate> {
+                    type Error = Infallible;
 
                     fn is_high(&self) -> Result<bool, Self::Error> {
                         let is_high = !self.is_low()?;
@@ -587,13 +1067,14 @@ macro_rules! gpio {
                         Pin {
                             i: $i,
                             port: $GPIOX::ptr() as *const dyn GpioRegExt,
+                            port_id: Port::$PXx,
                             _mode: self._mode,
                         }
                     }
                 }
 
                 impl<MODE> InputPin for $PXi<Input<MODE>> {
-                    type Error = void::Void;
+                    type Error = Infallible;
 
                     fn is_high(&self) -> Result<bool, Self::Error> {
                         let is_high = !self.is_low()?;
@@ -712,3 +1193,70 @@ gpio!(GPIOH, gpioh, PH, [
     PH9: (ph9, 9, Analog),
     PH10: (ph10, 10, Analog),
 ]);
+
+/// Switches a list of pins to their default states in one pass
+///
+/// Pins float between reset and their configuration. To keep that window as
+/// short as possible, for example for pins that drive relays or power stages,
+/// call this right after `split()`, before any other initialization. Outputs
+/// are switched with their initial level already set, so they don't glitch.
+///
+/// Each pin is followed by its default state:
+///
+/// - `output_low`, `output_high`: push-pull output
+/// - `open_drain_low`, `open_drain_high`: open-drain output
+/// - `pull_up`, `pull_down`, `floating`: input
+/// - `analog`: analog mode, the state with the lowest current consumption
+///
+/// The pins are configured with interrupts disabled, in the order they are
+/// listed, and returned as a tuple in the same order:
+///
+/// ``` ignore
+/// let gpioa = dp.GPIOA.split(&mut rcc);
+/// let gpiob = dp.GPIOB.split(&mut rcc);
+///
+/// let (relay, enable, button, _unused) = gpio::init_defaults!(
+///     gpioa.pa5 => output_low,
+///     gpiob.pb2 => open_drain_high,
+///     gpioa.pa0 => pull_up,
+///     gpioa.pa1 => analog,
+/// );
+/// ```
+#[macro_export]
+macro_rules! gpio_init_defaults {
+    ($($pin:expr => $state:ident),+ $(,)?) => {{
+        $crate::gpio::__critical(|| ($($crate::gpio_init_defaults!(@state $pin, $state),)+))
+    }};
+    (@state $pin:expr, output_low) => {
+        $pin.into_push_pull_output_with_state($crate::gpio::PinState::Low)
+    };
+    (@state $pin:expr, output_high) => {
+        $pin.into_push_pull_output_with_state($crate::gpio::PinState::High)
+    };
+    (@state $pin:expr, open_drain_low) => {
+        $pin.into_open_drain_output_with_state($crate::gpio::PinState::Low)
+    };
+    (@state $pin:expr, open_drain_high) => {
+        $pin.into_open_drain_output_with_state($crate::gpio::PinState::High)
+    };
+    (@state $pin:expr, pull_up) => {
+        $pin.into_pull_up_input()
+    };
+    (@state $pin:expr, pull_down) => {
+        $pin.into_pull_down_input()
+    };
+    (@state $pin:expr, floating) => {
+        $pin.into_floating_input()
+    };
+    (@state $pin:expr, analog) => {
+        $pin.into_analog()
+    };
+}
+
+pub use crate::gpio_init_defaults as init_defaults;
+
+/// Used by `gpio::init_defaults!` to configure the pins in a critical section
+#[doc(hidden)]
+pub fn __critical<R>(f: impl FnOnce() -> R) -> R {
+    interrupt::free(|_| f())
+}
@@ -1,6 +1,6 @@
 //! I2C
 
-use core::ops::Deref;
+use core::{cmp, fmt, mem, ops::Deref};
 
 #[cfg(feature = "stm32l0x2")]
 use core::{marker::PhantomData, ops::DerefMut, pin::Pin};
@@ -16,43 +16,110 @@ use crate::pac::i2c1::{
 };
 use crate::rcc::{Enable, Rcc, Reset};
 use cast::u8;
-use embedded_time::rate::Hertz;
+use embedded_time::{duration::Microseconds, rate::Hertz};
+
+use crate::gpio::WithGpioMode;
+use crate::hal::digital::v2::{InputPin, OutputPin};
 
 // I²C traits
-use crate::hal::blocking::i2c::{Read, Write, WriteRead};
+use crate::hal::blocking::i2c::{
+    Operation, Read, SevenBitAddress, TenBitAddress, Transactional, Write, WriteIterRead, WriteRead,
+};
 
 // I/O Imports
-use crate::gpio::{AltMode, OpenDrain, Output};
 #[cfg(feature = "io-STM32L051")]
-use crate::{
-    gpio::gpiob::{PB10, PB11, PB13, PB14, PB6, PB7, PB8, PB9},
-    pac::{I2C1, I2C2},
-};
+use crate::gpio::gpiob::{PB10, PB11, PB12, PB13, PB14, PB5, PB6, PB7, PB8, PB9};
 #[cfg(feature = "io-STM32L021")]
-use crate::{
-    gpio::{
-        gpioa::{PA10, PA13, PA4, PA9},
-        gpiob::{PB6, PB7, PB8},
-    },
-    pac::I2C1,
+use crate::gpio::{
+    gpioa::{PA10, PA13, PA4, PA9},
+    gpiob::{PB6, PB7, PB8},
 };
 #[cfg(feature = "io-STM32L071")]
-use crate::{
-    gpio::{
-        gpioa::{PA10, PA8, PA9},
-        gpiob::{PB10, PB11, PB13, PB14, PB4, PB6, PB7, PB8, PB9},
-        gpioc::{PC0, PC1, PC9},
-    },
-    pac::{I2C1, I2C2, I2C3},
+use crate::gpio::{
+    gpioa::{PA10, PA8, PA9},
+    gpiob::{PB10, PB11, PB12, PB13, PB14, PB4, PB5, PB6, PB7, PB8, PB9},
+    gpioc::{PC0, PC1, PC9},
 };
 #[cfg(feature = "io-STM32L031")]
-use crate::{
-    gpio::{
-        gpioa::{PA10, PA9},
-        gpiob::{PB6, PB7, PB8, PB9},
-    },
-    pac::I2C1,
+use crate::gpio::{
+    gpioa::{PA10, PA9},
+    gpiob::{PB6, PB7, PB8, PB9},
 };
+#[cfg(any(
+    feature = "io-STM32L021",
+    feature = "io-STM32L031",
+    feature = "io-STM32L051",
+    feature = "io-STM32L071",
+))]
+use crate::gpio::{AltMode, OpenDrain, Output};
+use crate::pac::{I2C1, I2C2, I2C3};
+
+/// Calculates the values of the TIMINGR register fields
+///
+/// Returns `(presc, scll, sclh, sdadel, scldel)` for an I2C kernel clock of
+/// `i2cclk` and a bus frequency of `freq`, both in Hz.
+fn calculate_timing(i2cclk: u32, freq: u32) -> (u8, u8, u8, u8, u8) {
+    assert!(freq <= 1_000_000);
+
+    // TODO review compliance with the timing requirements of I2C
+    // t_I2CCLK = 1 / PCLK1
+    // t_PRESC  = (PRESC + 1) * t_I2CCLK
+    // t_SCLL   = (SCLL + 1) * t_PRESC
+    // t_SCLH   = (SCLH + 1) * t_PRESC
+    //
+    // t_SYNC1 + t_SYNC2 > 4 * t_I2CCLK
+    // t_SCL ~= t_SYNC1 + t_SYNC2 + t_SCLL + t_SCLH
+    let ratio = i2cclk / freq - 4;
+    let (presc, scll, sclh, sdadel, scldel) = if freq >= 100_000 {
+        // fast-mode or fast-mode plus
+        // here we pick SCLL + 1 = 2 * (SCLH + 1)
+        let presc = ratio / 387;
+
+        let sclh = ((ratio / (presc + 1)) - 3) / 3;
+        let scll = 2 * (sclh + 1) - 1;
+
+        let (sdadel, scldel) = if freq > 400_000 {
+            // fast-mode plus
+            let sdadel = 0;
+            let scldel = i2cclk / 4_000_000 / (presc + 1) - 1;
+
+            (sdadel, scldel)
+        } else {
+            // fast-mode
+            let sdadel = i2cclk / 8_000_000 / (presc + 1);
+            let scldel = i2cclk / 2_000_000 / (presc + 1) - 1;
+
+            (sdadel, scldel)
+        };
+
+        (presc, scll, sclh, sdadel, scldel)
+    } else {
+        // standard-mode
+        // here we pick SCLL = SCLH
+        // SCLDEL has 4 bits, which needs a larger prescaler at higher
+        // clock frequencies
+        let presc = cmp::max(ratio / 514, i2cclk / 800_000 / 17);
+
+        let sclh = ((ratio / (presc + 1)) - 2) / 2;
+        let scll = sclh;
+
+        let sdadel = i2cclk / 2_000_000 / (presc + 1);
+        let scldel = i2cclk / 800_000 / (presc + 1) - 1;
+
+        (presc, scll, sclh, sdadel, scldel)
+    };
+
+    let presc = u8(presc).unwrap();
+    assert!(presc < 16);
+    let scldel = u8(scldel).unwrap();
+    assert!(scldel < 16);
+    let sdadel = u8(sdadel).unwrap();
+    assert!(sdadel < 16);
+    let sclh = u8(sclh).unwrap();
+    let scll = u8(scll).unwrap();
+
+    (presc, scll, sclh, sdadel, scldel)
+}
 
 /// I2C abstraction
 pub struct I2c<I2C, SDA, SCL> {
@@ -79,65 +146,7 @@ where
         // Reset I2C
         I::reset(rcc);
 
-        let freq = freq.0;
-
-        assert!(freq <= 1_000_000);
-
-        // TODO review compliance with the timing requirements of I2C
-        // t_I2CCLK = 1 / PCLK1
-        // t_PRESC  = (PRESC + 1) * t_I2CCLK
-        // t_SCLL   = (SCLL + 1) * t_PRESC
-        // t_SCLH   = (SCLH + 1) * t_PRESC
-        //
-        // t_SYNC1 + t_SYNC2 > 4 * t_I2CCLK
-        // t_SCL ~= t_SYNC1 + t_SYNC2 + t_SCLL + t_SCLH
-        let i2cclk = rcc.clocks.apb1_clk().0;
-        let ratio = i2cclk / freq - 4;
-        let (presc, scll, sclh, sdadel, scldel) = if freq >= 100_000 {
-            // fast-mode or fast-mode plus
-            // here we pick SCLL + 1 = 2 * (SCLH + 1)
-            let presc = ratio / 387;
-
-            let sclh = ((ratio / (presc + 1)) - 3) / 3;
-            let scll = 2 * (sclh + 1) - 1;
-
-            let (sdadel, scldel) = if freq > 400_000 {
-                // fast-mode plus
-                let sdadel = 0;
-                let scldel = i2cclk / 4_000_000 / (presc + 1) - 1;
-
-                (sdadel, scldel)
-            } else {
-                // fast-mode
-                let sdadel = i2cclk / 8_000_000 / (presc + 1);
-                let scldel = i2cclk / 2_000_000 / (presc + 1) - 1;
-
-                (sdadel, scldel)
-            };
-
-            (presc, scll, sclh, sdadel, scldel)
-        } else {
-            // standard-mode
-            // here we pick SCLL = SCLH
-            let presc = ratio / 514;
-
-            let sclh = ((ratio / (presc + 1)) - 2) / 2;
-            let scll = sclh;
-
-            let sdadel = i2cclk / 2_000_000 / (presc + 1);
-            let scldel = i2cclk / 800_000 / (presc + 1) - 1;
-
-            (presc, scll, sclh, sdadel, scldel)
-        };
-
-        let presc = u8(presc).unwrap();
-        assert!(presc < 16);
-        let scldel = u8(scldel).unwrap();
-        assert!(scldel < 16);
-        let sdadel = u8(sdadel).unwrap();
-        assert!(sdadel < 16);
-        let sclh = u8(sclh).unwrap();
-        let scll = u8(scll).unwrap();
+        let (presc, scll, sclh, sdadel, scldel) = calculate_timing(rcc.clocks.apb1_clk().0, freq.0);
 
         // Configure for "fast mode" (400 KHz)
         i2c.timingr.write(|w| {
@@ -164,6 +173,91 @@ where
         (self.i2c, self.sda, self.scl)
     }
 
+    /// Disables the peripheral and its clock, then releases it and the pins
+    ///
+    /// Use this instead of `release`, if the peripheral isn't needed for a
+    /// while, so its clock doesn't keep drawing power.
+    pub fn release_and_disable(self, rcc: &mut Rcc) -> (I, SDA, SCL) {
+        self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+        I::disable(rcc);
+        (self.i2c, self.sda, self.scl)
+    }
+
+    /// Recovers a bus that is blocked by a slave holding SDA low
+    ///
+    /// This can happen if a transfer was interrupted, for example by a reset
+    /// of the master. Disables the peripheral and switches SCL and SDA to
+    /// GPIO. Then clocks SCL up to 9 times, until the slave releases SDA, and
+    /// generates a STOP condition. Finally, resets the peripheral and restores
+    /// its configuration.
+    ///
+    /// Returns [`Error::BusError`], if SDA is still held low afterwards.
+    pub fn recover_bus(&mut self, rcc: &mut Rcc) -> Result<(), Error>
+    where
+        SDA: WithGpioMode + InputPin + OutputPin,
+        SCL: WithGpioMode + OutputPin,
+    {
+        let cr1 = self.i2c.cr1.read().bits();
+        let timingr = self.i2c.timingr.read().bits();
+        let timeoutr = self.i2c.timeoutr.read().bits();
+        let oar1 = self.i2c.oar1.read().bits();
+        let oar2 = self.i2c.oar2.read().bits();
+
+        self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+
+        // Half a clock period at 100 kHz
+        let half_period = cmp::max(rcc.clocks.sys_clk().0 / 200_000, 1);
+
+        // Release both lines before switching the pins to GPIO. Otherwise they
+        // would briefly be driven low, according to the reset value of ODR,
+        // which could look like a START condition or a clock pulse.
+        self.scl.set_high().ok();
+        self.sda.set_high().ok();
+
+        let sda = &mut self.sda;
+        let released = self.scl.with_gpio_mode(|scl| {
+            sda.with_gpio_mode(|sda| {
+                cortex_m::asm::delay(half_period);
+
+                for _ in 0..9 {
+                    if matches!(sda.is_high(), Ok(true)) {
+                        break;
+                    }
+                    scl.set_low().ok();
+                    cortex_m::asm::delay(half_period);
+                    scl.set_high().ok();
+                    cortex_m::asm::delay(half_period);
+                }
+
+                // STOP condition: SDA goes high while SCL is high
+                scl.set_low().ok();
+                cortex_m::asm::delay(half_period);
+                sda.set_low().ok();
+                cortex_m::asm::delay(half_period);
+                scl.set_high().ok();
+                cortex_m::asm::delay(half_period);
+                sda.set_high().ok();
+                cortex_m::asm::delay(half_period);
+
+                matches!(sda.is_high(), Ok(true))
+            })
+        });
+
+        I::reset(rcc);
+        // Safe, as the values were read from the same registers.
+        self.i2c.timingr.write(|w| unsafe { w.bits(timingr) });
+        self.i2c.timeoutr.write(|w| unsafe { w.bits(timeoutr) });
+        self.i2c.oar1.write(|w| unsafe { w.bits(oar1) });
+        self.i2c.oar2.write(|w| unsafe { w.bits(oar2) });
+        self.i2c.cr1.write(|w| unsafe { w.bits(cr1) });
+
+        if released {
+            Ok(())
+        } else {
+            Err(Error::BusError)
+        }
+    }
+
     fn check_errors(&self) -> Result<(), Error> {
         let isr = self.i2c.isr.read();
         if isr.berr().bit_is_set() {
@@ -175,12 +269,26 @@ where
         } else if isr.nackf().bit_is_set() {
             self.i2c.icr.write(|w| w.nackcf().set_bit());
             Err(Error::Nack)
+        } else if isr.pecerr().bit_is_set() {
+            self.i2c.icr.write(|w| w.peccf().set_bit());
+            Err(Error::PECError)
+        } else if isr.timeout().bit_is_set() {
+            self.i2c.icr.write(|w| w.timoutcf().set_bit());
+            Err(Error::Timeout)
         } else {
             Ok(())
         }
     }
 
-    fn start_transfer(&mut self, addr: u8, len: usize, direction: RD_WRN_A, autoend: AUTOEND_A) {
+    fn start_transfer(
+        &mut self,
+        addr: Address,
+        len: usize,
+        direction: RD_WRN_A,
+        reload: bool,
+        pec: bool,
+        autoend: AUTOEND_A,
+    ) {
         // Ensure that TX/RX buffers are empty
         self.i2c.isr.write(|w| w.txe().set_bit());
         while self.i2c.isr.read().rxne().bit_is_set() {
@@ -193,7 +301,20 @@ where
             // Set number of bytes to transfer
             w.nbytes().bits(len as u8);
             // Set address to transfer to/from
-            w.sadd().bits((addr << 1) as u16);
+            match addr {
+                Address::SevenBit(addr) => {
+                    w.add10().clear_bit();
+                    w.sadd().bits(((addr & 0x7f) as u16) << 1)
+                }
+                Address::TenBit(addr) => {
+                    w.add10().set_bit();
+                    w.sadd().bits(addr & 0x3ff)
+                }
+            };
+            // Will more bytes follow after `len`?
+            w.reload().bit(reload);
+            // Is the last of the `len` bytes the PEC?
+            w.pecbyte().bit(pec);
             // Set transfer direction
             w.rd_wrn().variant(direction);
             // should we end the transfer automatically?
@@ -258,9 +379,11 @@ where
     {
         assert!(buffer.len() >= num_words);
         self.start_transfer(
-            address,
+            Address::SevenBit(address),
             buffer.as_slice().len(),
             RD_WRN_A::Write,
+            false,
+            false,
             AUTOEND_A::Automatic,
         );
 
@@ -337,9 +460,11 @@ where
     {
         assert!(buffer.len() >= num_words);
         self.start_transfer(
-            address,
+            Address::SevenBit(address),
             buffer.as_slice().len(),
             RD_WRN_A::Read,
+            false,
+            false,
             AUTOEND_A::Automatic,
         );
 
@@ -373,99 +498,941 @@ where
     }
 }
 
-impl<I, SDA, SCL> WriteRead for I2c<I, SDA, SCL>
+impl<I, SDA, SCL> I2c<I, SDA, SCL>
 where
     I: Instance,
 {
-    type Error = Error;
-
-    fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
-        let writing = !bytes.is_empty();
-        let reading = !buffer.is_empty();
-
-        // wait for i2c device to be available
+    /// Waits until the bus is free
+    fn wait_idle(&self) -> Result<(), Error> {
         while self.i2c.isr.read().busy().is_busy() {
             self.check_errors()?;
         }
+        Ok(())
+    }
 
-        // if we are writing bytes
-        if writing {
-            // if the previous write has failed, we need to flush the TX
-            // buffer to prevent sending old data
-            self.i2c.isr.write(|w| w.txe().set_bit());
+    /// Writes `bytes` to `addr`, starting with a START or repeated START
+    ///
+    /// The bytes are sent in chunks of up to 255 bytes, which is the maximum
+    /// the hardware supports in a single transfer. If `stop` is `false`, the
+    /// transfer ends without a STOP condition, so a repeated START can follow.
+    fn write_bytes<B>(&mut self, addr: Address, bytes: B, stop: bool) -> Result<(), Error>
+    where
+        B: IntoIterator<Item = u8>,
+    {
+        let mut bytes = bytes.into_iter().peekable();
+        let autoend = if stop {
+            AUTOEND_A::Automatic
+        } else {
+            AUTOEND_A::Software
+        };
 
-            if reading {
-                self.start_transfer(addr, bytes.len(), RD_WRN_A::Write, AUTOEND_A::Software);
+        let mut chunk = [0; 255];
+        let mut first = true;
+        loop {
+            let mut len = 0;
+            while len < chunk.len() {
+                match bytes.next() {
+                    Some(byte) => chunk[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            let reload = bytes.peek().is_some();
+
+            if first {
+                // if the previous write has failed, we need to flush the TX
+                // buffer to prevent sending old data
+                self.i2c.isr.write(|w| w.txe().set_bit());
+                self.start_transfer(addr, len, RD_WRN_A::Write, reload, false, autoend);
+                first = false;
             } else {
-                self.start_transfer(addr, bytes.len(), RD_WRN_A::Write, AUTOEND_A::Automatic);
+                self.continue_transfer(len, reload)?;
             }
 
             // Send bytes
-            for c in bytes {
-                self.send_byte(*c)?;
+            for &byte in &chunk[..len] {
+                self.send_byte(byte)?;
             }
 
-            // if we are going to read afterwards, we need to wait for
-            // the tx to complete
-            if reading {
-                while self.i2c.isr.read().tc().is_not_complete() {
-                    self.check_errors()?;
-                }
+            if !reload {
+                break;
             }
         }
 
-        if reading {
-            // force a read of the rx data register, so that we dont
-            // get stale data from the last transaction (if there is
-            // anything left over)
-            self.i2c.rxdr.read();
+        // if we are going to restart afterwards, we need to wait for the
+        // transfer to complete
+        if !stop {
+            self.wait_transfer_complete()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads `len` bytes from `addr` into `buffer`, starting with a START or
+    /// repeated START
+    ///
+    /// See `write_bytes` for `stop`.
+    fn read_bytes<'b, B>(
+        &mut self,
+        addr: Address,
+        buffer: B,
+        mut len: usize,
+        stop: bool,
+    ) -> Result<(), Error>
+    where
+        B: IntoIterator<Item = &'b mut u8>,
+    {
+        let mut buffer = buffer.into_iter();
+        let autoend = if stop {
+            AUTOEND_A::Automatic
+        } else {
+            AUTOEND_A::Software
+        };
+
+        let mut first = true;
+        loop {
+            let chunk = cmp::min(len, 255);
+            len -= chunk;
+            let reload = len > 0;
 
-            //send a new start condition and transfer
-            self.start_transfer(addr, buffer.len(), RD_WRN_A::Read, AUTOEND_A::Automatic);
+            if first {
+                self.start_transfer(addr, chunk, RD_WRN_A::Read, reload, false, autoend);
+                first = false;
+            } else {
+                self.continue_transfer(chunk, reload)?;
+            }
 
             // Receive bytes into buffer
-            for c in buffer {
+            for c in buffer.by_ref().take(chunk) {
                 *c = self.recv_byte()?;
             }
+
+            if !reload {
+                break;
+            }
+        }
+
+        if !stop {
+            self.wait_transfer_complete()?;
         }
 
         Ok(())
     }
+
+    /// Continues a transfer with `RELOAD` set, after the previous chunk has
+    /// been transferred
+    fn continue_transfer(&mut self, len: usize, reload: bool) -> Result<(), Error> {
+        while self.i2c.isr.read().tcr().bit_is_clear() {
+            self.check_errors()?;
+        }
+
+        self.i2c.cr2.modify(|_, w| {
+            w.nbytes().bits(len as u8);
+            w.reload().bit(reload)
+        });
+
+        Ok(())
+    }
+
+    fn wait_transfer_complete(&self) -> Result<(), Error> {
+        while self.i2c.isr.read().tc().is_not_complete() {
+            self.check_errors()?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, addr: Address, operations: &mut [Operation<'_>]) -> Result<(), Error> {
+        self.wait_idle()?;
+
+        // Adjacent operations of the same type are merged into a single
+        // transfer, without a repeated START in between.
+        let mut operations = operations;
+        while !operations.is_empty() {
+            let reading = matches!(operations[0], Operation::Read(_));
+            let same = operations
+                .iter()
+                .take_while(|op| matches!(op, Operation::Read(_)) == reading)
+                .count();
+            let (current, rest) = mem::take(&mut operations).split_at_mut(same);
+            let stop = rest.is_empty();
+
+            if reading {
+                let len = current
+                    .iter()
+                    .map(|op| match op {
+                        Operation::Read(buffer) => buffer.len(),
+                        Operation::Write(_) => 0,
+                    })
+                    .sum();
+                let buffer = current.iter_mut().flat_map(|op| match op {
+                    Operation::Read(buffer) => buffer.iter_mut(),
+                    Operation::Write(_) => (&mut []).iter_mut(),
+                });
+                self.read_bytes(addr, buffer, len, stop)?;
+            } else {
+                let bytes = current.iter().flat_map(|op| match op {
+                    Operation::Write(bytes) => bytes.iter().copied(),
+                    Operation::Read(_) => (&[]).iter().copied(),
+                });
+                self.write_bytes(addr, bytes, stop)?;
+            }
+
+            operations = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether a device acknowledges `addr`
+    ///
+    /// Sends the address with an empty write, followed by a STOP condition.
+    /// Returns `Ok(false)`, if the address wasn't acknowledged, and an error
+    /// for any other problem on the bus.
+    ///
+    /// On a bus that is stuck, this blocks forever, unless a timeout has been
+    /// configured with [`I2c::set_timeout`].
+    pub fn probe<A>(&mut self, addr: A) -> Result<bool, Error>
+    where
+        A: Into<Address>,
+    {
+        self.wait_idle()?;
+        self.start_transfer(
+            addr.into(),
+            0,
+            RD_WRN_A::Write,
+            false,
+            false,
+            AUTOEND_A::Automatic,
+        );
+
+        // The STOP condition is generated automatically, after the address
+        // has been acknowledged or not.
+        let mut acknowledged = true;
+        while self.i2c.isr.read().stopf().bit_is_clear() {
+            match self.check_errors() {
+                Ok(()) => {}
+                Err(Error::Nack) => acknowledged = false,
+                Err(error) => return Err(error),
+            }
+        }
+        self.i2c.icr.write(|w| w.stopcf().set_bit());
+
+        // NACKF can be flagged together with the STOP condition.
+        match self.check_errors() {
+            Ok(()) => Ok(acknowledged),
+            Err(Error::Nack) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Scans the bus for devices
+    ///
+    /// Returns an iterator over the 7-bit addresses that acknowledge a probe,
+    /// see [`I2c::probe`]. Only the non-reserved addresses from 0x08 to 0x77
+    /// are probed.
+    ///
+    /// The iterator ends early, if an error other than a NACK occurs. Use
+    /// [`Scan::error`] to check for that afterwards.
+    pub fn scan(&mut self) -> Scan<'_, I, SDA, SCL> {
+        Scan {
+            i2c: self,
+            next: SCAN_FIRST,
+            error: None,
+        }
+    }
+}
+
+const SCAN_FIRST: u8 = 0x08;
+const SCAN_LAST: u8 = 0x77;
+
+/// Iterator over the addresses found by [`I2c::scan`]
+pub struct Scan<'a, I, SDA, SCL> {
+    i2c: &'a mut I2c<I, SDA, SCL>,
+    next: u8,
+    error: Option<Error>,
+}
+
+impl<I, SDA, SCL> Scan<'_, I, SDA, SCL> {
+    /// Returns the error that ended the scan early, if any
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<I, SDA, SCL> Iterator for Scan<'_, I, SDA, SCL>
+where
+    I: Instance,
+{
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.error.is_none() && self.next <= SCAN_LAST {
+            let addr = self.next;
+            self.next += 1;
+
+            match self.i2c.probe(addr) {
+                Ok(true) => return Some(addr),
+                Ok(false) => {}
+                Err(error) => self.error = Some(error),
+            }
+        }
+
+        None
+    }
 }
 
-impl<I, SDA, SCL> Write for I2c<I, SDA, SCL>
+/// SMBus support
+impl<I, SDA, SCL> I2c<I, SDA, SCL>
 where
     I: Instance,
 {
-    type Error = Error;
+    /// Enables or disables packet error checking (PEC)
+    ///
+    /// While enabled, the hardware computes the PEC over all bytes of a
+    /// transfer. Use [`I2c::write_pec`] and [`I2c::write_read_pec`] to send
+    /// and check it. The other transfer methods ignore the PEC.
+    pub fn set_pec(&mut self, enable: bool) {
+        // PECEN can only be changed while the peripheral is disabled.
+        self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+        self.i2c.cr1.modify(|_, w| w.pecen().bit(enable));
+        self.i2c.cr1.modify(|_, w| w.pe().set_bit());
+    }
+
+    /// Enables SMBus alert handling (host mode) on the `SMBA` pin
+    ///
+    /// Devices pull the `SMBA` line low to request the attention of the host.
+    /// Use [`I2c::is_alert_pending`] to check for that, or enable the error
+    /// interrupt with [`I2c::listen_alert`].
+    pub fn enable_alert<PIN>(&mut self, smba: PIN) -> PIN
+    where
+        PIN: SMBAPin<I>,
+    {
+        smba.setup();
+        self.i2c.cr1.modify(|_, w| w.alerten().set_bit());
+        smba
+    }
+
+    /// Disables SMBus alert handling
+    pub fn disable_alert(&mut self) {
+        self.i2c.cr1.modify(|_, w| w.alerten().clear_bit());
+    }
+
+    /// Enables the error interrupt, which is also triggered by an SMBus alert
+    pub fn listen_alert(&mut self) {
+        self.i2c.cr1.modify(|_, w| w.errie().set_bit());
+    }
+
+    /// Disables the error interrupt
+    pub fn unlisten_alert(&mut self) {
+        self.i2c.cr1.modify(|_, w| w.errie().clear_bit());
+    }
+
+    /// Returns whether an SMBus alert has been detected
+    pub fn is_alert_pending(&self) -> bool {
+        self.i2c.isr.read().alert().bit_is_set()
+    }
+
+    /// Clears the SMBus alert flag
+    pub fn clear_alert(&mut self) {
+        self.i2c.icr.write(|w| w.alertcf().set_bit());
+    }
+
+    /// Configures the SMBus clock low timeout (`tTIMEOUT`)
+    ///
+    /// A transfer fails with [`Error::Timeout`], if SCL is held low for
+    /// longer than `timeout`. SMBus specifies 25 ms. `None` disables the
+    /// timeout.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `timeout` is longer than the hardware supports, which is
+    /// 4096 * 2048 periods of the I2C clock.
+    pub fn set_timeout(&mut self, timeout: Option<Microseconds>, rcc: &Rcc) {
+        // TIMEOUTA can only be changed while the timeout is disabled.
+        self.i2c.timeoutr.modify(|_, w| w.timouten().clear_bit());
+
+        if let Some(timeout) = timeout {
+            let timeouta = calculate_timeout(rcc.clocks.apb1_clk().0, timeout.0);
+            #[allow(unused_unsafe)]
+            self.i2c.timeoutr.modify(|_, w| {
+                // Safe, as `calculate_timeout` returns a 12-bit value.
+                unsafe { w.timeouta().bits(timeouta) };
+                w.tidle().clear_bit();
+                w.timouten().set_bit()
+            });
+        }
+    }
+
+    /// Configures the SMBus cumulative clock extension timeout (`tLOW:MEXT`)
+    ///
+    /// A transfer fails with [`Error::Timeout`], if the master stretches the
+    /// clock for longer than `timeout` in total, from START to ACK, ACK to
+    /// ACK, or ACK to STOP. SMBus specifies 10 ms. `None` disables the
+    /// timeout.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `timeout` is longer than the hardware supports, which is
+    /// 4096 * 2048 periods of the I2C clock.
+    pub fn set_extended_timeout(&mut self, timeout: Option<Microseconds>, rcc: &Rcc) {
+        // TIMEOUTB can only be changed while the timeout is disabled.
+        self.i2c.timeoutr.modify(|_, w| w.texten().clear_bit());
+
+        if let Some(timeout) = timeout {
+            let timeoutb = calculate_timeout(rcc.clocks.apb1_clk().0, timeout.0);
+            #[allow(unused_unsafe)]
+            self.i2c.timeoutr.modify(|_, w| {
+                // Safe, as `calculate_timeout` returns a 12-bit value.
+                unsafe { w.timeoutb().bits(timeoutb) };
+                w.texten().set_bit()
+            });
+        }
+    }
+
+    /// Writes `bytes` to `addr`, followed by the PEC
+    ///
+    /// Requires PEC to be enabled using [`I2c::set_pec`].
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `bytes` is longer than 254 bytes.
+    pub fn write_pec(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        assert!(bytes.len() < 255);
+        self.wait_idle()?;
+
+        // if the previous write has failed, we need to flush the TX buffer to
+        // prevent sending old data
+        self.i2c.isr.write(|w| w.txe().set_bit());
+        self.start_transfer(
+            Address::SevenBit(addr),
+            bytes.len() + 1,
+            RD_WRN_A::Write,
+            false,
+            true,
+            AUTOEND_A::Automatic,
+        );
+
+        for &byte in bytes {
+            self.send_byte(byte)?;
+        }
+
+        // The PEC is sent by the hardware.
+        self.wait_stop()
+    }
+
+    /// Writes `bytes` to `addr`, then reads into `buffer` after a repeated
+    /// START, and checks the PEC sent by the device
+    ///
+    /// If `bytes` is empty, only the read is performed. Requires PEC to be
+    /// enabled using [`I2c::set_pec`]. Returns [`Error::PECError`], if the
+    /// PEC doesn't match.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `buffer` is longer than 254 bytes.
+    pub fn write_read_pec(
+        &mut self,
+        addr: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        assert!(buffer.len() < 255);
+        self.wait_idle()?;
+
+        let addr = Address::SevenBit(addr);
+        if !bytes.is_empty() {
+            self.write_bytes(addr, bytes.iter().copied(), false)?;
+        }
+
+        self.start_transfer(
+            addr,
+            buffer.len() + 1,
+            RD_WRN_A::Read,
+            false,
+            true,
+            AUTOEND_A::Automatic,
+        );
+
+        for c in buffer {
+            *c = self.recv_byte()?;
+        }
+
+        // The PEC is checked by the hardware.
+        self.recv_byte()?;
+        self.wait_stop()
+    }
+
+    fn wait_stop(&mut self) -> Result<(), Error> {
+        while self.i2c.isr.read().stopf().bit_is_clear() {
+            self.check_errors()?;
+        }
+        self.i2c.icr.write(|w| w.stopcf().set_bit());
 
-    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Self::Error> {
-        self.write_read(addr, bytes, &mut [])
+        // Errors can be flagged together with the STOP condition.
+        self.check_errors()
     }
 }
 
-impl<I, SDA, SCL> Read for I2c<I, SDA, SCL>
+/// Calculates the value of the TIMEOUTA/TIMEOUTB fields of TIMEOUTR
+///
+/// The timeout is `(TIMEOUTx + 1) * 2048` periods of the I2C kernel clock
+/// `i2cclk`, in Hz. `timeout` is in µs and is rounded down, with a minimum of
+/// one step.
+fn calculate_timeout(i2cclk: u32, timeout: u32) -> u16 {
+    let steps = u64::from(i2cclk) * u64::from(timeout) / 2048 / 1_000_000;
+    let value = steps.saturating_sub(1);
+    assert!(value <= 0xfff);
+    value as u16
+}
+
+impl<I, SDA, SCL> I2c<I, SDA, SCL>
 where
     I: Instance,
 {
-    type Error = Error;
+    /// Returns a handle to talk to devices with 10-bit addresses
+    ///
+    /// `I2c` only implements the embedded-hal traits for 7-bit addresses, so
+    /// integer literals passed as the address keep being inferred as `u8`. The
+    /// returned handle implements them for `TenBitAddress` instead.
+    pub fn ten_bit(&mut self) -> TenBit<'_, I, SDA, SCL> {
+        TenBit { i2c: self }
+    }
+
+    fn i2c_mut(&mut self) -> &mut Self {
+        self
+    }
+
+    fn write_iter_read_addr<B>(
+        &mut self,
+        addr: Address,
+        bytes: B,
+        buffer: &mut [u8],
+    ) -> Result<(), Error>
+    where
+        B: IntoIterator<Item = u8>,
+    {
+        self.wait_idle()?;
+
+        let mut bytes = bytes.into_iter().peekable();
+        let writing = bytes.peek().is_some();
+        let reading = !buffer.is_empty();
 
-    fn read(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
-        self.write_read(addr, &[], buffer)
+        if writing {
+            self.write_bytes(addr, bytes, !reading)?;
+        }
+        if reading {
+            let len = buffer.len();
+            self.read_bytes(addr, buffer.iter_mut(), len, true)?;
+        }
+
+        Ok(())
     }
 }
 
+/// Handle to talk to devices with 10-bit addresses
+///
+/// See [`I2c::ten_bit`].
+pub struct TenBit<'a, I, SDA, SCL> {
+    i2c: &'a mut I2c<I, SDA, SCL>,
+}
+
+impl<I, SDA, SCL> TenBit<'_, I, SDA, SCL> {
+    fn i2c_mut(&mut self) -> &mut I2c<I, SDA, SCL> {
+        self.i2c
+    }
+}
+
+macro_rules! i2c_traits {
+    ($(($Type:ty, [$($gen:tt)*], $A:ty),)+) => {
+        $(
+            impl<$($gen)*> WriteRead<$A> for $Type
+            where
+                I: Instance,
+            {
+                type Error = Error;
+
+                fn write_read(&mut self, addr: $A, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+                    self.i2c_mut().write_iter_read_addr(addr.into(), bytes.iter().copied(), buffer)
+                }
+            }
+
+            impl<$($gen)*> WriteIterRead<$A> for $Type
+            where
+                I: Instance,
+            {
+                type Error = Error;
+
+                fn write_iter_read<B>(
+                    &mut self,
+                    addr: $A,
+                    bytes: B,
+                    buffer: &mut [u8],
+                ) -> Result<(), Self::Error>
+                where
+                    B: IntoIterator<Item = u8>,
+                {
+                    self.i2c_mut().write_iter_read_addr(addr.into(), bytes, buffer)
+                }
+            }
+
+            impl<$($gen)*> Transactional<$A> for $Type
+            where
+                I: Instance,
+            {
+                type Error = Error;
+
+                /// Executes the operations in a single transaction
+                ///
+                /// Adjacent operations of the same type are merged, operations of
+                /// different types are separated by a repeated START. Empty operations
+                /// still address the device, which can be used to check for its presence.
+                fn exec(&mut self, addr: $A, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+                    self.i2c_mut().transfer(addr.into(), operations)
+                }
+            }
+
+            impl<$($gen)*> Write<$A> for $Type
+            where
+                I: Instance,
+            {
+                type Error = Error;
+
+                fn write(&mut self, addr: $A, bytes: &[u8]) -> Result<(), Self::Error> {
+                    self.i2c_mut().write_iter_read_addr(addr.into(), bytes.iter().copied(), &mut [])
+                }
+            }
+
+            impl<$($gen)*> Read<$A> for $Type
+            where
+                I: Instance,
+            {
+                type Error = Error;
+
+                fn read(&mut self, addr: $A, buffer: &mut [u8]) -> Result<(), Self::Error> {
+                    self.i2c_mut().write_iter_read_addr(addr.into(), core::iter::empty(), buffer)
+                }
+            }
+        )+
+    }
+}
+
+i2c_traits! {
+    (I2c<I, SDA, SCL>, [I, SDA, SCL], SevenBitAddress),
+    (TenBit<'a, I, SDA, SCL>, ['a, I, SDA, SCL], TenBitAddress),
+}
+
+/// An I2C slave address
+///
+/// Converts from `u8` for 7-bit addresses and `u16` for 10-bit addresses,
+/// which matches the `SevenBitAddress` and `TenBitAddress` address modes of
+/// the embedded-hal traits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// 7-bit address, the most significant bit is ignored
+    SevenBit(u8),
+    /// 10-bit address, the 6 most significant bits are ignored
+    TenBit(u16),
+}
+
+impl From<SevenBitAddress> for Address {
+    fn from(addr: SevenBitAddress) -> Self {
+        Address::SevenBit(addr)
+    }
+}
+
+impl From<TenBitAddress> for Address {
+    fn from(addr: TenBitAddress) -> Self {
+        Address::TenBit(addr)
+    }
+}
+
+/// I2C peripheral in slave mode
+///
+/// Responds to transfers addressed to its own address (and optionally a
+/// secondary address) by an external master. Clock stretching is enabled, so
+/// the master waits while an event is pending. Drive the slave by calling
+/// [`I2cSlave::poll_event`], either in a loop or from the interrupt handler,
+/// after enabling the interrupts with [`I2cSlave::listen`].
+pub struct I2cSlave<I2C, SDA, SCL> {
+    i2c: I2C,
+    sda: SDA,
+    scl: SCL,
+}
+
+impl<I, SDA, SCL> I2cSlave<I, SDA, SCL>
+where
+    I: Instance,
+{
+    /// Creates a slave that responds to the 7-bit `address`
+    ///
+    /// `freq` is the bus frequency used by the master. It determines the
+    /// data setup and hold times.
+    pub fn new(i2c: I, sda: SDA, scl: SCL, address: u8, freq: Hertz, rcc: &mut Rcc) -> Self
+    where
+        SDA: SDAPin<I>,
+        SCL: SCLPin<I>,
+    {
+        sda.setup();
+        scl.setup();
+
+        // Enable clock for I2C
+        I::enable(rcc);
+        // Reset I2C
+        I::reset(rcc);
+
+        let (presc, scll, sclh, sdadel, scldel) = calculate_timing(rcc.clocks.apb1_clk().0, freq.0);
+
+        // SCLL and SCLH aren't used in slave mode, but setting them anyway
+        // doesn't hurt.
+        i2c.timingr.write(|w| {
+            w.presc().bits(presc);
+            w.scll().bits(scll);
+            w.sclh().bits(sclh);
+            w.sdadel().bits(sdadel);
+            w.scldel().bits(scldel)
+        });
+
+        #[allow(unused_unsafe)]
+        i2c.oar1.write(|w| {
+            // Safe, as the address is masked to 7 bits.
+            unsafe { w.oa1().bits(((address & 0x7f) as u16) << 1) };
+            w.oa1mode().clear_bit();
+            w.oa1en().set_bit()
+        });
+
+        // Enable peripheral
+        i2c.cr1.write(|w| w.pe().set_bit());
+
+        I2cSlave { i2c, sda, scl }
+    }
+
+    /// Sets the secondary 7-bit address
+    ///
+    /// `mask` is the number of least significant bits of `address` that are
+    /// ignored when comparing. It is limited to 7, which matches all
+    /// addresses except the reserved ones. `None` disables the secondary
+    /// address.
+    pub fn set_secondary_address(&mut self, address: Option<(u8, u8)>) {
+        // OA2EN must be cleared while the address is changed.
+        self.i2c.oar2.write(|w| w.oa2en().clear_bit());
+
+        if let Some((address, mask)) = address {
+            #[allow(unused_unsafe)]
+            self.i2c.oar2.write(|w| {
+                // Safe, as both values are masked to the width of their
+                // fields.
+                unsafe {
+                    w.oa2().bits(address & 0x7f);
+                    w.oa2msk().bits(cmp::min(mask, 7));
+                }
+                w.oa2en().set_bit()
+            });
+        }
+    }
+
+    /// Enables or disables responding to the general call address (`0x00`)
+    pub fn set_general_call(&mut self, enable: bool) {
+        self.i2c.cr1.modify(|_, w| w.gcen().bit(enable));
+    }
+
+    /// Starts listening for an interrupt event
+    pub fn listen(&mut self, event: SlaveInterrupt) {
+        self.i2c.cr1.modify(|_, w| match event {
+            SlaveInterrupt::AddressMatch => w.addrie().set_bit(),
+            SlaveInterrupt::Rx => w.rxie().set_bit(),
+            SlaveInterrupt::Tx => w.txie().set_bit(),
+            SlaveInterrupt::Stop => w.stopie().set_bit(),
+            SlaveInterrupt::Nack => w.nackie().set_bit(),
+            SlaveInterrupt::Error => w.errie().set_bit(),
+        });
+    }
+
+    /// Stops listening for an interrupt event
+    pub fn unlisten(&mut self, event: SlaveInterrupt) {
+        self.i2c.cr1.modify(|_, w| match event {
+            SlaveInterrupt::AddressMatch => w.addrie().clear_bit(),
+            SlaveInterrupt::Rx => w.rxie().clear_bit(),
+            SlaveInterrupt::Tx => w.txie().clear_bit(),
+            SlaveInterrupt::Stop => w.stopie().clear_bit(),
+            SlaveInterrupt::Nack => w.nackie().clear_bit(),
+            SlaveInterrupt::Error => w.errie().clear_bit(),
+        });
+    }
+
+    /// Returns the next pending event
+    ///
+    /// Clears the flag of the returned event, which also releases the clock
+    /// stretching for address matches. Received bytes are read from the data
+    /// register and returned with the event. [`SlaveEvent::TxRequested`] must
+    /// be answered by calling [`I2cSlave::write_byte`] before the transfer can
+    /// continue.
+    ///
+    /// A NACK from the master, which ends every read transfer, is cleared
+    /// without returning an event.
+    pub fn poll_event(&mut self) -> nb::Result<SlaveEvent, Error> {
+        let isr = self.i2c.isr.read();
+
+        if isr.berr().bit_is_set() {
+            self.i2c.icr.write(|w| w.berrcf().set_bit());
+            return Err(nb::Error::Other(Error::BusError));
+        }
+        if isr.arlo().bit_is_set() {
+            self.i2c.icr.write(|w| w.arlocf().set_bit());
+            return Err(nb::Error::Other(Error::ArbitrationLost));
+        }
+        if isr.ovr().bit_is_set() {
+            self.i2c.icr.write(|w| w.ovrcf().set_bit());
+            return Err(nb::Error::Other(Error::Overrun));
+        }
+
+        // Received data must be read before handling a repeated START or a
+        // STOP, or it would be lost.
+        if isr.rxne().bit_is_set() {
+            let byte = self.i2c.rxdr.read().rxdata().bits();
+            return Ok(SlaveEvent::ByteReceived(byte));
+        }
+
+        if isr.addr().bit_is_set() {
+            let address = isr.addcode().bits();
+            let direction = if isr.dir().bit_is_set() {
+                // Flush data that is left over from the previous transfer.
+                self.i2c.isr.write(|w| w.txe().set_bit());
+                TransferDirection::Read
+            } else {
+                TransferDirection::Write
+            };
+            self.i2c.icr.write(|w| w.addrcf().set_bit());
+            return Ok(SlaveEvent::AddressMatch { address, direction });
+        }
+
+        if isr.txis().bit_is_set() {
+            return Ok(SlaveEvent::TxRequested);
+        }
+
+        if isr.nackf().bit_is_set() {
+            self.i2c.icr.write(|w| w.nackcf().set_bit());
+        }
+
+        if isr.stopf().bit_is_set() {
+            self.i2c.icr.write(|w| w.stopcf().set_bit());
+            // The byte that was written for the NACKed transfer is still in
+            // the data register.
+            self.i2c.isr.write(|w| w.txe().set_bit());
+            return Ok(SlaveEvent::Stop);
+        }
+
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Writes the byte that is sent to the master next
+    ///
+    /// Call this in response to [`SlaveEvent::TxRequested`].
+    pub fn write_byte(&mut self, byte: u8) {
+        self.i2c.txdr.write(|w| w.txdata().bits(byte));
+    }
+
+    /// Handles the next pending event using a [`RegisterDispatcher`]
+    ///
+    /// Returns the handled event.
+    pub fn dispatch<M>(
+        &mut self,
+        dispatcher: &mut RegisterDispatcher<M>,
+    ) -> nb::Result<SlaveEvent, Error>
+    where
+        M: RegisterMap,
+    {
+        let event = self.poll_event()?;
+        match event {
+            SlaveEvent::AddressMatch { direction, .. } => dispatcher.address_matched(direction),
+            SlaveEvent::ByteReceived(byte) => dispatcher.byte_received(byte),
+            SlaveEvent::TxRequested => {
+                let byte = dispatcher.byte_requested();
+                self.write_byte(byte);
+            }
+            SlaveEvent::Stop => dispatcher.stop(),
+        }
+        Ok(event)
+    }
+
+    pub fn release(self) -> (I, SDA, SCL) {
+        (self.i2c, self.sda, self.scl)
+    }
+
+    /// Disables the peripheral and its clock, then releases it and the pins
+    ///
+    /// Use this instead of `release`, if the peripheral isn't needed for a
+    /// while, so its clock doesn't keep drawing power.
+    pub fn release_and_disable(self, rcc: &mut Rcc) -> (I, SDA, SCL) {
+        self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+        I::disable(rcc);
+        (self.i2c, self.sda, self.scl)
+    }
+}
+
+/// Interrupt events of [`I2cSlave`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlaveInterrupt {
+    /// Own address matched (ADDRIE)
+    AddressMatch,
+    /// Byte received (RXIE)
+    Rx,
+    /// Byte requested by the master (TXIE)
+    Tx,
+    /// STOP condition detected (STOPIE)
+    Stop,
+    /// NACK received (NACKIE)
+    Nack,
+    /// Bus error, arbitration loss, or overrun (ERRIE)
+    Error,
+}
+
+/// An event returned by [`I2cSlave::poll_event`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlaveEvent {
+    /// A master addressed this device, with a START or a repeated START
+    AddressMatch {
+        /// The 7-bit address that matched
+        address: u8,
+        /// The direction of the transfer
+        direction: TransferDirection,
+    },
+
+    /// A byte was received from the master
+    ByteReceived(u8),
+
+    /// The master reads a byte, which must be provided using
+    /// [`I2cSlave::write_byte`]
+    TxRequested,
+
+    /// A STOP condition ended the transfer
+    Stop,
+}
+
 pub trait Instance: Deref<Target = RegisterBlock> + Enable + Reset {
     fn ptr() -> *const RegisterBlock;
 }
 
-// I2C SDA pin
+/// Pins that can be used as the data line (`I2Cx_SDA`)
 pub trait SDAPin<I2C> {
+    /// Switches the pin to the SDA alternate function of `I2C`
+    ///
+    /// The function is AF1, AF3, AF4, AF5, AF6, or AF7, depending on the pin.
     fn setup(&self);
 }
 
-// I2C SCL pin
+/// Pins that can be used as the clock line (`I2Cx_SCL`)
 pub trait SCLPin<I2C> {
+    /// Switches the pin to the SCL alternate function of `I2C`
+    ///
+    /// The function is AF1, AF3, AF4, AF5, AF6, or AF7, depending on the pin.
+    fn setup(&self);
+}
+
+/// Pins that can be used as the SMBus alert line (`I2Cx_SMBA`)
+pub trait SMBAPin<I2C> {
+    /// Switches the pin to the SMBA alternate function of `I2C`
+    ///
+    /// That's AF3 for I2C1 on PB5, and AF5 for I2C2 on PB12.
     fn setup(&self);
 }
 
@@ -477,6 +1444,37 @@ pub enum Error {
     PECError,
     BusError,
     ArbitrationLost,
+    /// SMBus clock low or cumulative clock extension timeout
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Overrun => f.write_str("overrun or underrun"),
+            Error::Nack => f.write_str("no acknowledge"),
+            Error::PECError => f.write_str("PEC mismatch"),
+            Error::BusError => f.write_str("bus error"),
+            Error::ArbitrationLost => f.write_str("arbitration lost"),
+            Error::Timeout => f.write_str("SMBus timeout"),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::i2c::Error for Error {
+    fn kind(&self) -> embedded_hal_1::i2c::ErrorKind {
+        use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource};
+
+        match self {
+            Error::Overrun => ErrorKind::Overrun,
+            Error::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            Error::PECError => ErrorKind::Other,
+            Error::BusError => ErrorKind::Bus,
+            Error::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            Error::Timeout => ErrorKind::Other,
+        }
+    }
 }
 
 pub trait I2cExt<I2C> {
@@ -487,6 +1485,43 @@ pub trait I2cExt<I2C> {
 }
 
 macro_rules! i2c {
+    ($($I2CX:ident,)+) => {
+        $(
+            impl I2cExt<$I2CX> for $I2CX {
+                fn i2c<SDA, SCL>(
+                    self,
+                    sda: SDA,
+                    scl: SCL,
+                    freq: Hertz,
+                    rcc: &mut Rcc,
+                ) -> I2c<$I2CX, SDA, SCL>
+                where
+                    SDA: SDAPin<$I2CX>,
+                    SCL: SCLPin<$I2CX>,
+                {
+                    I2c::new(self, sda, scl, freq, rcc)
+                }
+            }
+
+            impl Instance for $I2CX {
+                fn ptr() -> *const RegisterBlock {
+                    $I2CX::ptr()
+                }
+            }
+        )+
+    };
+}
+
+// All sub-families share the same I2C peripherals. Which of them are actually
+// available on a specific MCU is determined by the pins, which are only
+// implemented for the selected `io-*` feature.
+i2c!(I2C1, I2C2, I2C3,);
+
+/// Implements `SDAPin` / `SCLPin` for the pins of an I2C peripheral
+///
+/// Unused, if no `io-*` feature is selected.
+#[allow(unused_macros)]
+macro_rules! i2c_pins {
     ($I2CX:ident,
         sda: [ $(($PSDA:ty, $afsda:expr),)+ ],
         scl: [ $(($PSCL:ty, $afscl:expr),)+ ],
@@ -494,7 +1529,7 @@ macro_rules! i2c {
         $(
             impl SDAPin<$I2CX> for $PSDA {
                 fn setup(&self) {
-                    self.set_alt_mode($afsda)
+                    self.set_alt_mode($afsda);
                 }
             }
         )+
@@ -502,37 +1537,33 @@ macro_rules! i2c {
         $(
             impl SCLPin<$I2CX> for $PSCL {
                 fn setup(&self) {
-                    self.set_alt_mode($afscl)
+                    self.set_alt_mode($afscl);
                 }
             }
         )+
+    };
+}
 
-        impl I2cExt<$I2CX> for $I2CX {
-            fn i2c<SDA, SCL>(
-                self,
-                sda: SDA,
-                scl: SCL,
-                freq: Hertz,
-                rcc: &mut Rcc,
-            ) -> I2c<$I2CX, SDA, SCL>
-            where
-                SDA: SDAPin<$I2CX>,
-                SCL: SCLPin<$I2CX>,
-            {
-                I2c::new(self, sda, scl, freq, rcc)
-            }
-        }
-
-        impl Instance for $I2CX {
-            fn ptr() -> *const RegisterBlock {
-                $I2CX::ptr()
+macro_rules! smba_pins {
+    ($($I2CX:ident: ($PSMBA:ty, $af:expr),)+) => {
+        $(
+            impl SMBAPin<$I2CX> for $PSMBA {
+                fn setup(&self) {
+                    self.set_alt_mode($af);
+                }
             }
-        }
+        )+
     };
 }
 
+#[cfg(any(feature = "io-STM32L051", feature = "io-STM32L071"))]
+smba_pins!(
+    I2C1: (PB5<Output<OpenDrain>>, AltMode::AF3),
+    I2C2: (PB12<Output<OpenDrain>>, AltMode::AF5),
+);
+
 #[cfg(feature = "io-STM32L021")]
-i2c!(
+i2c_pins!(
     I2C1,
     sda: [
         (PA10<Output<OpenDrain>>, AltMode::AF1),
@@ -548,7 +1579,7 @@ i2c!(
 );
 
 #[cfg(feature = "io-STM32L031")]
-i2c!(
+i2c_pins!(
     I2C1,
     sda: [
         (PA10<Output<OpenDrain>>, AltMode::AF1),
@@ -563,7 +1594,7 @@ i2c!(
 );
 
 #[cfg(feature = "io-STM32L051")]
-i2c!(
+i2c_pins!(
     I2C1,
     sda: [
         (PB7<Output<OpenDrain>>, AltMode::AF1),
@@ -576,7 +1607,7 @@ i2c!(
 );
 
 #[cfg(feature = "io-STM32L051")]
-i2c!(
+i2c_pins!(
     I2C2,
     sda: [
         (PB11<Output<OpenDrain>>, AltMode::AF6),
@@ -589,7 +1620,7 @@ i2c!(
 );
 
 #[cfg(feature = "io-STM32L071")]
-i2c!(
+i2c_pins!(
     I2C1,
     sda: [
         (PA10<Output<OpenDrain>>, AltMode::AF6),
@@ -604,7 +1635,7 @@ i2c!(
 );
 
 #[cfg(feature = "io-STM32L071")]
-i2c!(
+i2c_pins!(
     I2C2,
     sda: [
         (PB11<Output<OpenDrain>>, AltMode::AF6),
@@ -617,7 +1648,7 @@ i2c!(
 );
 
 #[cfg(feature = "io-STM32L071")]
-i2c!(
+i2c_pins!(
     I2C3,
     sda: [
         (PB4<Output<OpenDrain>>, AltMode::AF7),
@@ -718,3 +1749,235 @@ where
         }
     }
 }
+
+/// A register map, as exposed by an I2C-controlled device
+///
+/// Implement this for the state of your device and hand it to
+/// [`RegisterDispatcher`], which takes care of the register pointer and the
+/// transaction state.
+pub trait RegisterMap {
+    /// Returns the value of `register`
+    ///
+    /// Called once for every byte the master reads.
+    fn read(&mut self, register: u8) -> u8;
+
+    /// Writes `value` to `register`
+    ///
+    /// Called once for every data byte the master writes.
+    fn write(&mut self, register: u8, value: u8);
+}
+
+/// The direction of a transfer, as seen from the master
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransferDirection {
+    /// The master writes to the device
+    Write,
+
+    /// The master reads from the device
+    Read,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DispatchState {
+    Idle,
+    Pointer,
+    Data,
+}
+
+/// Dispatches register-style I2C transfers to a [`RegisterMap`]
+///
+/// Implements the protocol most I2C sensors and actuators use: the first byte
+/// of a write transfer sets the register pointer, every following byte is
+/// written to the register the pointer refers to. Read transfers return the
+/// register the pointer refers to. The pointer is incremented after every
+/// data byte, wrapping around after `0xff`, so consecutive registers can be
+/// accessed in a single transfer. A write of only the pointer, followed by a
+/// repeated start and a read, reads from that register.
+///
+/// The dispatcher doesn't access the hardware. Feed it the events of an I2C
+/// peripheral in slave mode (typically from its interrupt handler): call
+/// [`RegisterDispatcher::address_matched`] on an address match,
+/// [`RegisterDispatcher::byte_received`] for every received byte,
+/// [`RegisterDispatcher::byte_requested`] whenever the master expects a byte,
+/// and [`RegisterDispatcher::stop`] when a STOP condition is detected.
+/// [`I2cSlave::dispatch`] does all of that for the I2C peripherals of this
+/// crate.
+#[derive(Debug)]
+pub struct RegisterDispatcher<M> {
+    map: M,
+    pointer: u8,
+    state: DispatchState,
+}
+
+impl<M> RegisterDispatcher<M>
+where
+    M: RegisterMap,
+{
+    /// Creates a new dispatcher, with the register pointer at `0`
+    pub fn new(map: M) -> Self {
+        Self {
+            map,
+            pointer: 0,
+            state: DispatchState::Idle,
+        }
+    }
+
+    /// Handles an address match, including that of a repeated START
+    pub fn address_matched(&mut self, direction: TransferDirection) {
+        self.state = match direction {
+            TransferDirection::Write => DispatchState::Pointer,
+            TransferDirection::Read => DispatchState::Data,
+        };
+    }
+
+    /// Handles a byte received from the master
+    ///
+    /// Bytes received outside of a write transfer are ignored.
+    pub fn byte_received(&mut self, byte: u8) {
+        match self.state {
+            DispatchState::Pointer => {
+                self.pointer = byte;
+                self.state = DispatchState::Data;
+            }
+            DispatchState::Data => {
+                self.map.write(self.pointer, byte);
+                self.pointer = self.pointer.wrapping_add(1);
+            }
+            DispatchState::Idle => {}
+        }
+    }
+
+    /// Returns the next byte to send to the master
+    pub fn byte_requested(&mut self) -> u8 {
+        let value = self.map.read(self.pointer);
+        self.pointer = self.pointer.wrapping_add(1);
+        value
+    }
+
+    /// Handles a STOP condition
+    ///
+    /// Ends the transfer. The register pointer is kept, so a following read
+    /// transfer continues where the last transfer left off.
+    pub fn stop(&mut self) {
+        self.state = DispatchState::Idle;
+    }
+
+    /// Returns the current register pointer
+    pub fn pointer(&self) -> u8 {
+        self.pointer
+    }
+
+    /// Returns a reference to the register map
+    pub fn map(&self) -> &M {
+        &self.map
+    }
+
+    /// Returns a mutable reference to the register map
+    pub fn map_mut(&mut self) -> &mut M {
+        &mut self.map
+    }
+
+    /// Releases the register map
+    pub fn free(self) -> M {
+        self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timing_fast_mode() {
+        assert_eq!(calculate_timing(16_000_000, 400_000), (0, 23, 11, 2, 7));
+    }
+
+    #[test]
+    fn timing_fast_mode_plus() {
+        assert_eq!(calculate_timing(32_000_000, 1_000_000), (0, 17, 8, 0, 7));
+    }
+
+    #[test]
+    fn timing_standard_mode() {
+        // Without a prescaler, SCLDEL would overflow at this clock frequency
+        assert_eq!(calculate_timing(16_000_000, 50_000), (1, 78, 78, 4, 9));
+    }
+
+    #[test]
+    fn smbus_timeout() {
+        // 25 ms at 16 MHz: 195.3 steps of 128 µs
+        assert_eq!(calculate_timeout(16_000_000, 25_000), 194);
+        // 10 ms at 2.097 MHz
+        assert_eq!(calculate_timeout(2_097_000, 10_000), 9);
+        // too short to be represented
+        assert_eq!(calculate_timeout(2_097_000, 100), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn smbus_timeout_too_long() {
+        calculate_timeout(32_000_000, 1_000_000);
+    }
+
+    #[test]
+    fn timing_low_clock() {
+        // Default MSI range 5
+        assert_eq!(calculate_timing(2_097_000, 100_000), (0, 9, 4, 0, 0));
+    }
+
+    struct Registers([u8; 256]);
+
+    impl RegisterMap for Registers {
+        fn read(&mut self, register: u8) -> u8 {
+            self.0[register as usize]
+        }
+
+        fn write(&mut self, register: u8, value: u8) {
+            self.0[register as usize] = value;
+        }
+    }
+
+    #[test]
+    fn dispatcher_write_then_read() {
+        let mut dispatcher = RegisterDispatcher::new(Registers([0; 256]));
+
+        dispatcher.address_matched(TransferDirection::Write);
+        dispatcher.byte_received(0x10);
+        dispatcher.byte_received(0xaa);
+        dispatcher.byte_received(0xbb);
+        dispatcher.stop();
+        assert_eq!(dispatcher.map().0[0x10..0x12], [0xaa, 0xbb]);
+
+        // Pointer write, repeated START, read
+        dispatcher.address_matched(TransferDirection::Write);
+        dispatcher.byte_received(0x11);
+        dispatcher.address_matched(TransferDirection::Read);
+        assert_eq!(dispatcher.byte_requested(), 0xbb);
+        assert_eq!(dispatcher.pointer(), 0x12);
+        dispatcher.stop();
+    }
+
+    #[test]
+    fn dispatcher_pointer_wraps() {
+        let mut dispatcher = RegisterDispatcher::new(Registers([0; 256]));
+
+        dispatcher.address_matched(TransferDirection::Write);
+        dispatcher.byte_received(0xff);
+        dispatcher.byte_received(1);
+        dispatcher.byte_received(2);
+        dispatcher.stop();
+
+        assert_eq!(dispatcher.map().0[0xff], 1);
+        assert_eq!(dispatcher.map().0[0x00], 2);
+        assert_eq!(dispatcher.pointer(), 1);
+    }
+
+    #[test]
+    fn dispatcher_ignores_bytes_when_idle() {
+        let mut dispatcher = RegisterDispatcher::new(Registers([0; 256]));
+
+        dispatcher.byte_received(0x42);
+        assert_eq!(dispatcher.pointer(), 0);
+        assert!(dispatcher.map().0.iter().all(|&b| b == 0));
+    }
+}
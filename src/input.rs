@@ -0,0 +1,571 @@
+//! Debounced input helpers
+//!
+//! [`Button`] turns a bouncing push button into press, long-press and release
+//! events. The pin is sampled in [`Button::poll`], which should be called at a
+//! regular interval, for example from a timer interrupt. All durations are
+//! given in those ticks.
+//!
+//! The EXTI line of the pin only serves to wake the application up. Once the
+//! button is idle again (see [`Button::is_idle`]), the tick timer can be
+//! stopped until the next EXTI interrupt, so nothing has to run while nobody
+//! touches the button:
+//!
+//! ``` ignore
+//! let line = GpioLine::from_raw_line(pb2.pin_number()).unwrap();
+//! let port = pb2.port();
+//! let mut button = Button::new(pb2.into_pull_up_input(), line, Config::new(3, 200));
+//! button.listen(&mut exti, &mut syscfg, port);
+//!
+//! // In the EXTI interrupt
+//! if button.on_interrupt() {
+//!     timer.listen();
+//! }
+//!
+//! // In the timer interrupt, every 5 ms
+//! match button.poll() {
+//!     Some(Event::Pressed) => { /* ... */ }
+//!     Some(Event::LongPressed) => { /* ... */ }
+//!     Some(Event::Released) | None => {}
+//! }
+//! if button.is_idle() {
+//!     timer.unlisten();
+//! }
+//! ```
+//!
+//! [`RotaryEncoder`] decodes a mechanical rotary encoder in software, for
+//! encoders on pins that aren't connected to a timer (see the
+//! [`encoder`](crate::encoder) module otherwise). It's updated from the EXTI
+//! interrupts of both pins, or by polling.
+
+use crate::{
+    exti::{Exti, GpioLine, TriggerEdge},
+    gpio::Port,
+    hal::digital::v2::InputPin,
+    syscfg::SYSCFG,
+};
+
+/// A button state change
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The button has been pressed
+    Pressed,
+    /// The button has been held down for the long-press duration
+    ///
+    /// Reported once per press, after [`Event::Pressed`].
+    LongPressed,
+    /// The button has been released
+    Released,
+}
+
+/// Configuration for [`Button`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Number of consecutive ticks the pin must be read in its new state
+    /// before a change is reported
+    pub debounce: u16,
+
+    /// Number of ticks the button must be held down before
+    /// [`Event::LongPressed`] is reported
+    ///
+    /// A value of 0 disables long-press detection.
+    pub long_press: u16,
+
+    /// Whether the button pulls the pin low when pressed
+    ///
+    /// This is the case for the usual wiring, with a pull-up resistor and the
+    /// button connecting the pin to ground.
+    pub active_low: bool,
+}
+
+impl Config {
+    /// Creates a configuration for an active-low button
+    pub fn new(debounce: u16, long_press: u16) -> Self {
+        Config {
+            debounce,
+            long_press,
+            active_low: true,
+        }
+    }
+
+    /// Configures the button as active-high
+    pub fn active_high(mut self) -> Self {
+        self.active_low = false;
+        self
+    }
+}
+
+/// A debounced push button
+///
+/// See the [module documentation](self) for an example.
+pub struct Button<PIN> {
+    pin: PIN,
+    line: GpioLine,
+    config: Config,
+    pressed: bool,
+    counter: u16,
+    held: u16,
+    active: bool,
+}
+
+impl<PIN> Button<PIN>
+where
+    PIN: InputPin,
+{
+    /// Creates a new button
+    ///
+    /// `line` is the EXTI line of the pin, which is the same as the pin
+    /// number. The button starts out released.
+    pub fn new(pin: PIN, line: GpioLine, config: Config) -> Self {
+        Button {
+            pin,
+            line,
+            config: Config {
+                debounce: config.debounce.max(1),
+                ..config
+            },
+            pressed: false,
+            counter: 0,
+            held: 0,
+            active: false,
+        }
+    }
+
+    /// Enables the EXTI interrupt on both edges of the button pin
+    ///
+    /// `port` must be the port of the pin passed to [`Button::new`].
+    pub fn listen(&mut self, exti: &mut Exti, syscfg: &mut SYSCFG, port: Port) {
+        exti.listen_gpio(syscfg, port, self.line, TriggerEdge::Both);
+    }
+
+    /// Disables the EXTI interrupt of the button pin
+    pub fn unlisten(&mut self, exti: &mut Exti) {
+        exti.unlisten(self.line);
+    }
+
+    /// Handles an EXTI interrupt
+    ///
+    /// Call this from the EXTI interrupt handler. Returns `true`, if the
+    /// interrupt was caused by the button, in which case the pending flag has
+    /// been cleared, and [`Button::poll`] needs to be called until the button
+    /// is idle again.
+    pub fn on_interrupt(&mut self) -> bool {
+        if !Exti::is_pending(self.line) {
+            return false;
+        }
+
+        Exti::unpend(self.line);
+        self.active = true;
+
+        true
+    }
+
+    /// Samples the pin and returns the resulting event, if any
+    ///
+    /// Needs to be called at a regular interval, as all durations in
+    /// [`Config`] are counted in calls to this method.
+    pub fn poll(&mut self) -> Option<Event> {
+        let down = self.is_down();
+
+        if down != self.pressed {
+            self.active = true;
+            self.counter += 1;
+            if self.counter >= self.config.debounce {
+                self.counter = 0;
+                self.held = 0;
+                self.pressed = down;
+                return Some(if down {
+                    Event::Pressed
+                } else {
+                    Event::Released
+                });
+            }
+            return None;
+        }
+
+        self.counter = 0;
+
+        if !self.pressed {
+            self.active = false;
+            return None;
+        }
+
+        if self.config.long_press > 0 && self.held < self.config.long_press {
+            self.held += 1;
+            if self.held == self.config.long_press {
+                return Some(Event::LongPressed);
+            }
+        }
+
+        None
+    }
+
+    /// Samples the pin and passes the resulting event to `on_event`
+    ///
+    /// Like [`Button::poll`], for callback-style event handling.
+    pub fn poll_with<F>(&mut self, on_event: F)
+    where
+        F: FnOnce(Event),
+    {
+        if let Some(event) = self.poll() {
+            on_event(event);
+        }
+    }
+
+    /// Indicates whether the button is pressed (debounced)
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Indicates whether the button is released and stable
+    ///
+    /// Once this returns `true`, [`Button::poll`] doesn't need to be called
+    /// until the next EXTI interrupt.
+    pub fn is_idle(&self) -> bool {
+        !self.active
+    }
+
+    /// Releases the pin
+    pub fn free(self) -> PIN {
+        self.pin
+    }
+
+    fn is_down(&self) -> bool {
+        let level = if self.config.active_low {
+            self.pin.is_low()
+        } else {
+            self.pin.is_high()
+        };
+        level.unwrap_or(false)
+    }
+}
+
+/// A detent step of a [`RotaryEncoder`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Pin A leads pin B (usually clockwise)
+    Increment,
+    /// Pin B leads pin A (usually counter-clockwise)
+    Decrement,
+}
+
+/// Number of quadrature transitions per detent of a [`RotaryEncoder`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// One full quadrature cycle per detent, resting with both pins high
+    ///
+    /// This is the most common type of detented encoder, with both contacts
+    /// open (pulled up) in the detent.
+    FullStep,
+    /// Half a quadrature cycle per detent, resting with both pins high or
+    /// both pins low
+    HalfStep,
+    /// A step for every transition, for encoders without detents
+    QuarterStep,
+}
+
+/// Quadrature transition table, indexed by `previous << 2 | current`
+///
+/// Invalid transitions, where both pins changed at once, count as 0.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// A software quadrature decoder for mechanical rotary encoders
+///
+/// Contact bounce shows up as transitions back and forth, which cancel out.
+/// Steps are only reported once the encoder reaches a detent after a
+/// complete sequence of valid transitions, so glitches that skip a state
+/// don't produce spurious steps.
+///
+/// ``` ignore
+/// let mut encoder = RotaryEncoder::new(pa8, pa9, line_a, line_b, Resolution::FullStep);
+/// encoder.listen(&mut exti, &mut syscfg, Port::PA, Port::PA);
+///
+/// // In the EXTI interrupt
+/// match encoder.on_interrupt() {
+///     Some(Step::Increment) => volume += 1,
+///     Some(Step::Decrement) => volume -= 1,
+///     None => {}
+/// }
+/// ```
+pub struct RotaryEncoder<A, B> {
+    a: A,
+    b: B,
+    line_a: GpioLine,
+    line_b: GpioLine,
+    resolution: Resolution,
+    state: u8,
+    steps: i8,
+    position: i32,
+}
+
+impl<A, B> RotaryEncoder<A, B>
+where
+    A: InputPin,
+    B: InputPin,
+{
+    /// Creates a new encoder
+    ///
+    /// `line_a` and `line_b` are the EXTI lines of the pins, which are the
+    /// same as the pin numbers. Swap the pins to invert the direction.
+    pub fn new(a: A, b: B, line_a: GpioLine, line_b: GpioLine, resolution: Resolution) -> Self {
+        let mut encoder = RotaryEncoder {
+            a,
+            b,
+            line_a,
+            line_b,
+            resolution,
+            state: 0,
+            steps: 0,
+            position: 0,
+        };
+        encoder.state = encoder.read_state();
+        encoder
+    }
+
+    /// Enables the EXTI interrupts on both edges of both pins
+    ///
+    /// `port_a` and `port_b` must be the ports of the pins passed to
+    /// [`RotaryEncoder::new`].
+    pub fn listen(&mut self, exti: &mut Exti, syscfg: &mut SYSCFG, port_a: Port, port_b: Port) {
+        exti.listen_gpio(syscfg, port_a, self.line_a, TriggerEdge::Both);
+        exti.listen_gpio(syscfg, port_b, self.line_b, TriggerEdge::Both);
+    }
+
+    /// Disables the EXTI interrupts of both pins
+    pub fn unlisten(&mut self, exti: &mut Exti) {
+        exti.unlisten(self.line_a);
+        exti.unlisten(self.line_b);
+    }
+
+    /// Handles an EXTI interrupt
+    ///
+    /// Call this from the EXTI interrupt handler(s) of both pins. Clears the
+    /// pending flags of the encoder lines, then calls
+    /// [`RotaryEncoder::update`].
+    pub fn on_interrupt(&mut self) -> Option<Step> {
+        let mut pending = false;
+        for &line in &[self.line_a, self.line_b] {
+            if Exti::is_pending(line) {
+                Exti::unpend(line);
+                pending = true;
+            }
+        }
+
+        if !pending {
+            return None;
+        }
+
+        self.update()
+    }
+
+    /// Samples both pins and returns the resulting step, if any
+    ///
+    /// Can be called from an interrupt handler, or polled often enough not to
+    /// miss any transitions.
+    pub fn update(&mut self) -> Option<Step> {
+        let state = self.read_state();
+        if state == self.state {
+            return None;
+        }
+
+        let transition = TRANSITIONS[usize::from((self.state << 2) | state)];
+        self.state = state;
+        self.steps += transition;
+
+        let (detent, threshold) = match self.resolution {
+            Resolution::FullStep => (state == 0b11, 4),
+            Resolution::HalfStep => (state == 0b00 || state == 0b11, 2),
+            Resolution::QuarterStep => (true, 1),
+        };
+        if !detent {
+            return None;
+        }
+
+        // Incomplete sequences are discarded when reaching a detent, so the
+        // decoder resynchronizes after a glitch.
+        let steps = self.steps;
+        self.steps = 0;
+        if steps >= threshold {
+            self.position = self.position.wrapping_add(1);
+            Some(Step::Increment)
+        } else if steps <= -threshold {
+            self.position = self.position.wrapping_sub(1);
+            Some(Step::Decrement)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of steps counted since creation or the last reset
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    /// Resets the position to 0
+    pub fn reset(&mut self) {
+        self.position = 0;
+    }
+
+    /// Releases the pins
+    pub fn free(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    fn read_state(&self) -> u8 {
+        let a = self.a.is_high().unwrap_or(false) as u8;
+        let b = self.b.is_high().unwrap_or(false) as u8;
+        (a << 1) | b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{cell::Cell, convert::Infallible};
+
+    use super::*;
+    use crate::exti::ExtiLine as _;
+
+    struct MockPin<'a>(&'a Cell<bool>);
+
+    impl InputPin for MockPin<'_> {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Self::Error> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&self) -> Result<bool, Self::Error> {
+            Ok(!self.0.get())
+        }
+    }
+
+    fn button(level: &Cell<bool>, config: Config) -> Button<MockPin> {
+        Button::new(MockPin(level), GpioLine::from_raw_line(0).unwrap(), config)
+    }
+
+    #[test]
+    fn debounces_press_and_release() {
+        let level = Cell::new(true);
+        let mut button = button(&level, Config::new(3, 0));
+
+        level.set(false);
+        assert_eq!(button.poll(), None);
+        // Bounce resets the debounce counter
+        level.set(true);
+        assert_eq!(button.poll(), None);
+        level.set(false);
+        assert_eq!(button.poll(), None);
+        assert_eq!(button.poll(), None);
+        assert_eq!(button.poll(), Some(Event::Pressed));
+        assert!(button.is_pressed());
+        assert!(!button.is_idle());
+
+        level.set(true);
+        assert_eq!(button.poll(), None);
+        assert_eq!(button.poll(), None);
+        assert_eq!(button.poll(), Some(Event::Released));
+        assert_eq!(button.poll(), None);
+        assert!(button.is_idle());
+    }
+
+    fn encoder<'a>(
+        a: &'a Cell<bool>,
+        b: &'a Cell<bool>,
+        resolution: Resolution,
+    ) -> RotaryEncoder<MockPin<'a>, MockPin<'a>> {
+        RotaryEncoder::new(
+            MockPin(a),
+            MockPin(b),
+            GpioLine::from_raw_line(0).unwrap(),
+            GpioLine::from_raw_line(1).unwrap(),
+            resolution,
+        )
+    }
+
+    /// Applies a sequence of `(a, b)` levels, returning the last step
+    fn apply(
+        encoder: &mut RotaryEncoder<MockPin, MockPin>,
+        a: &Cell<bool>,
+        b: &Cell<bool>,
+        sequence: &[(bool, bool)],
+    ) -> Option<Step> {
+        let mut step = None;
+        for &(level_a, level_b) in sequence {
+            a.set(level_a);
+            b.set(level_b);
+            if let Some(s) = encoder.update() {
+                assert!(step.is_none());
+                step = Some(s);
+            }
+        }
+        step
+    }
+
+    #[test]
+    fn decodes_full_steps() {
+        let (a, b) = (Cell::new(true), Cell::new(true));
+        let mut encoder = encoder(&a, &b, Resolution::FullStep);
+
+        let forward = [(false, true), (false, false), (true, false), (true, true)];
+        assert_eq!(apply(&mut encoder, &a, &b, &forward), Some(Step::Increment));
+        assert_eq!(apply(&mut encoder, &a, &b, &forward), Some(Step::Increment));
+
+        let backward = [(true, false), (false, false), (false, true), (true, true)];
+        assert_eq!(
+            apply(&mut encoder, &a, &b, &backward),
+            Some(Step::Decrement)
+        );
+
+        assert_eq!(encoder.position(), 1);
+    }
+
+    #[test]
+    fn ignores_bounce_and_glitches() {
+        let (a, b) = (Cell::new(true), Cell::new(true));
+        let mut encoder = encoder(&a, &b, Resolution::FullStep);
+
+        // Bouncing contact A in the detent
+        let bounce = [(false, true), (true, true), (false, true), (true, true)];
+        assert_eq!(apply(&mut encoder, &a, &b, &bounce), None);
+
+        // Skipping a state is not counted as a step
+        let glitch = [(false, true), (true, false), (true, true)];
+        assert_eq!(apply(&mut encoder, &a, &b, &glitch), None);
+
+        assert_eq!(encoder.position(), 0);
+    }
+
+    #[test]
+    fn decodes_half_steps() {
+        let (a, b) = (Cell::new(true), Cell::new(true));
+        let mut encoder = encoder(&a, &b, Resolution::HalfStep);
+
+        assert_eq!(
+            apply(&mut encoder, &a, &b, &[(false, true), (false, false)]),
+            Some(Step::Increment)
+        );
+        assert_eq!(
+            apply(&mut encoder, &a, &b, &[(true, false), (true, true)]),
+            Some(Step::Increment)
+        );
+        assert_eq!(
+            apply(&mut encoder, &a, &b, &[(true, false), (false, false)]),
+            Some(Step::Decrement)
+        );
+    }
+
+    #[test]
+    fn reports_long_press_once() {
+        let level = Cell::new(false);
+        let mut button = button(&level, Config::new(1, 3).active_high());
+
+        level.set(true);
+        assert_eq!(button.poll(), Some(Event::Pressed));
+        assert_eq!(button.poll(), None);
+        assert_eq!(button.poll(), None);
+        assert_eq!(button.poll(), Some(Event::LongPressed));
+        assert_eq!(button.poll(), None);
+
+        level.set(false);
+        assert_eq!(button.poll(), Some(Event::Released));
+    }
+}
@@ -0,0 +1,93 @@
+//! Sharing peripherals with interrupt handlers
+//!
+//! Using any of the `listen` APIs requires moving the peripheral handle into
+//! the interrupt handler, which usually means declaring a
+//! `Mutex<RefCell<Option<T>>>` static and borrowing it in a critical section.
+//! [`Handle`] wraps this pattern, and [`bind_interrupt!`] additionally
+//! generates the interrupt handler that calls a function with the handle.
+//!
+//! ``` ignore
+//! use stm32l0xx_hal::{bind_interrupt, pac, serial};
+//!
+//! bind_interrupt!(USART2 => on_rx(RX: serial::Rx<pac::USART2>));
+//!
+//! fn on_rx(rx: &mut serial::Rx<pac::USART2>) {
+//!     if let Ok(byte) = rx.read() {
+//!         // ...
+//!     }
+//! }
+//!
+//! // In `main`, after setting up the serial port
+//! rx.listen();
+//! RX.put(rx);
+//! unsafe { NVIC::unmask(pac::Interrupt::USART2) };
+//! ```
+//!
+//! [`bind_interrupt!`]: crate::bind_interrupt
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
+
+/// A value shared between the application and an interrupt handler
+///
+/// Meant to be used as a `static`. All accesses happen in a critical section.
+pub struct Handle<T>(Mutex<RefCell<Option<T>>>);
+
+impl<T> Handle<T> {
+    /// Creates an empty handle
+    pub const fn new() -> Self {
+        Handle(Mutex::new(RefCell::new(None)))
+    }
+
+    /// Moves `value` into the handle, returning the previous value
+    pub fn put(&self, value: T) -> Option<T> {
+        interrupt::free(|cs| self.0.borrow(cs).replace(Some(value)))
+    }
+
+    /// Moves the value out of the handle
+    pub fn take(&self) -> Option<T> {
+        interrupt::free(|cs| self.0.borrow(cs).take())
+    }
+
+    /// Calls `f` with the value in a critical section
+    ///
+    /// Returns `None` without calling `f`, if the handle is empty.
+    pub fn with<R, F>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        interrupt::free(|cs| self.0.borrow(cs).borrow_mut().as_mut().map(f))
+    }
+}
+
+impl<T> Default for Handle<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Binds an interrupt to a handler function
+///
+/// `bind_interrupt!(IRQ => handler(NAME: Type))` declares a
+/// `static NAME: Handle<Type>` and an interrupt handler for `IRQ`, which calls
+/// `handler(&mut Type)` with the value in `NAME`. The handler function isn't
+/// called, while `NAME` is still empty.
+///
+/// Requires the `rt` feature.
+#[cfg(feature = "rt")]
+#[macro_export]
+macro_rules! bind_interrupt {
+    ($irq:ident => $handler:ident($name:ident: $ty:ty)) => {
+        static $name: $crate::irq::Handle<$ty> = $crate::irq::Handle::new();
+
+        const _: () = {
+            use $crate::pac::interrupt;
+
+            #[interrupt]
+            fn $irq() {
+                $name.with($handler);
+            }
+        };
+    };
+}
@@ -0,0 +1,116 @@
+//! Keypad matrix scanner
+//!
+//! Scans a matrix keypad with `R` rows connected to outputs and `C` columns
+//! connected to inputs with pull-ups. Rows are driven low one at a time, and a
+//! key is detected as pressed if its column reads low while its row is driven.
+//! Idle rows are driven high, so open-drain outputs work as well.
+//!
+//! [`Keypad::tick`] performs one full scan and should be called at a regular
+//! interval, for example from a timer interrupt. A key only changes its state
+//! after it has been read in the new state for `debounce` consecutive ticks.
+//!
+//! The pins are taken as erased [`Pin`]s, so pins from different ports can be
+//! mixed freely:
+//!
+//! ``` ignore
+//! let mut keypad = Keypad::new(
+//!     [row0.downgrade(), row1.downgrade()],
+//!     [col0.downgrade(), col1.downgrade(), col2.downgrade()],
+//!     3,
+//! );
+//!
+//! // Every 5 ms
+//! keypad.tick(|event| match event {
+//!     Event::Pressed { row, col } => { /* ... */ }
+//!     Event::Released { .. } => {}
+//! });
+//! ```
+
+use crate::gpio::{Input, Output, Pin};
+use crate::hal::digital::v2::{InputPin, OutputPin};
+
+/// A key state change
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The key at `row` and `col` has been pressed
+    Pressed { row: usize, col: usize },
+    /// The key at `row` and `col` has been released
+    Released { row: usize, col: usize },
+}
+
+/// Debounced keypad matrix scanner
+pub struct Keypad<ROW, COL, const R: usize, const C: usize> {
+    rows: [Pin<Output<ROW>>; R],
+    cols: [Pin<Input<COL>>; C],
+    debounce: u8,
+    pressed: [[bool; C]; R],
+    counters: [[u8; C]; R],
+}
+
+impl<ROW, COL, const R: usize, const C: usize> Keypad<ROW, COL, R, C> {
+    /// Creates a new scanner
+    ///
+    /// `debounce` is the number of consecutive ticks a key must be read in
+    /// its new state before the change is reported. A value of 0 or 1
+    /// disables debouncing.
+    pub fn new(rows: [Pin<Output<ROW>>; R], cols: [Pin<Input<COL>>; C], debounce: u8) -> Self {
+        let mut keypad = Keypad {
+            rows,
+            cols,
+            debounce: debounce.max(1),
+            pressed: [[false; C]; R],
+            counters: [[0; C]; R],
+        };
+        for row in keypad.rows.iter_mut() {
+            let _ = row.set_high();
+        }
+        keypad
+    }
+
+    /// Scans the keypad once, calling `on_event` for every debounced change
+    pub fn tick<F>(&mut self, mut on_event: F)
+    where
+        F: FnMut(Event),
+    {
+        for (row, row_pin) in self.rows.iter_mut().enumerate() {
+            let _ = row_pin.set_low();
+
+            for (col, col_pin) in self.cols.iter().enumerate() {
+                let down = col_pin.is_low().unwrap_or(false);
+                let counter = &mut self.counters[row][col];
+
+                if down == self.pressed[row][col] {
+                    *counter = 0;
+                    continue;
+                }
+
+                *counter += 1;
+                if *counter >= self.debounce {
+                    *counter = 0;
+                    self.pressed[row][col] = down;
+                    on_event(if down {
+                        Event::Pressed { row, col }
+                    } else {
+                        Event::Released { row, col }
+                    });
+                }
+            }
+
+            let _ = row_pin.set_high();
+        }
+    }
+
+    /// Checks if the key at `row` and `col` is pressed (debounced)
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `row` or `col` are out of range.
+    pub fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.pressed[row][col]
+    }
+
+    /// Releases the row and column pins
+    pub fn release(self) -> ([Pin<Output<ROW>>; R], [Pin<Input<COL>>; C]) {
+        (self.rows, self.cols)
+    }
+}
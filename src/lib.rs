@@ -16,9 +16,14 @@ pub use stm32l0::stm32l0x2 as pac;
 #[cfg(feature = "stm32l0x3")]
 pub use stm32l0::stm32l0x3 as pac;
 
+#[cfg(feature = "rt")]
+pub use crate::pac::interrupt;
+
 pub mod adc;
 pub mod aes;
+pub mod bitbang;
 pub mod calibration;
+pub mod capture;
 pub mod crc;
 pub mod delay;
 pub mod dma;
@@ -34,16 +39,17 @@ pub mod exti;
     feature = "eeprom-6144",
 ))]
 pub mod flash;
+pub mod freqmeter;
 pub mod gpio;
-#[cfg(any(
-    feature = "io-STM32L021",
-    feature = "io-STM32L031",
-    feature = "io-STM32L051",
-    feature = "io-STM32L071",
-))]
 pub mod i2c;
+pub mod input;
+pub mod irq;
+pub mod keypad;
 pub mod lptim;
 pub mod mco;
+pub mod motion;
+pub mod onewire;
+pub mod power;
 pub mod prelude;
 pub mod pwm;
 pub mod pwr;
@@ -52,25 +58,73 @@ pub mod rcc;
 pub mod rng;
 #[cfg(feature = "rtc")]
 pub mod rtc;
-#[cfg(any(
-    feature = "io-STM32L021",
-    feature = "io-STM32L031",
-    feature = "io-STM32L051",
-    feature = "io-STM32L071",
-))]
+#[cfg(feature = "embedded-sdmmc")]
+pub mod sdmmc;
 pub mod serial;
 pub mod signature;
+pub mod softuart;
 pub mod spi;
 pub mod syscfg;
+pub mod time;
 pub mod timer;
+pub mod tone;
 #[cfg(all(
     feature = "stm32-usbd",
     any(feature = "stm32l0x2", feature = "stm32l0x3")
 ))]
 pub mod usb;
 pub mod watchdog;
+#[cfg(feature = "ws2812")]
+pub mod ws2812;
 
 mod sealed {
     pub trait Sealed {}
 }
 pub(crate) use sealed::Sealed;
+
+/// Maximum number of iterations when waiting for a hardware flag
+///
+/// Used for flags that are expected to change within microseconds or a few
+/// milliseconds, like oscillator ready flags. This is long enough for all of
+/// them at any system clock frequency, while making sure that absent or broken
+/// hardware results in an error instead of a hang.
+pub(crate) const WAIT_ITERATIONS: u32 = 1_000_000;
+
+/// Waits until `ready` returns `true`, or returns `error` after
+/// `WAIT_ITERATIONS` attempts
+pub(crate) fn wait_for<E>(mut ready: impl FnMut() -> bool, error: E) -> Result<(), E> {
+    for _ in 0..WAIT_ITERATIONS {
+        if ready() {
+            return Ok(());
+        }
+    }
+    Err(error)
+}
+
+/// Compile-time check that the peripheral wrappers are `Send`
+///
+/// This is required to move them into interrupt handlers or share them as RTIC
+/// resources. Never called, it only needs to compile.
+#[allow(dead_code)]
+fn assert_send() {
+    fn is_send<T: Send>() {}
+
+    is_send::<gpio::Pin<gpio::Output<gpio::PushPull>>>();
+    is_send::<gpio::gpioa::PA0<gpio::Analog>>();
+    is_send::<gpio::InputMonitor>();
+    is_send::<adc::Adc<adc::Ready>>();
+    is_send::<adc::Adc<adc::Active<dma::Channel1, &'static mut [u16]>>>();
+    is_send::<aes::Transfer<aes::Tx, dma::Channel1, &'static mut [u8], dma::Started>>();
+    is_send::<dma::Handle>();
+    is_send::<exti::Exti>();
+    is_send::<lptim::LpTimer<lptim::Periodic>>();
+    is_send::<pwm::Timer<pac::TIM2>>();
+    is_send::<spi::Spi<pac::SPI1, ()>>();
+    is_send::<timer::Timer<pac::TIM2>>();
+    is_send::<watchdog::IndependedWatchdog>();
+    is_send::<watchdog::Supervisor>();
+
+    is_send::<i2c::I2c<pac::I2C1, (), ()>>();
+    is_send::<serial::Serial<pac::USART2, ((), ())>>();
+    is_send::<serial::DmaLogger<pac::USART2, dma::Channel4>>();
+}
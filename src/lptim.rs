@@ -1,15 +1,21 @@
 //! Low-Power Timer (LPTIM) support.
 
+use crate::encoder;
 use crate::gpio::{self, gpiob};
 use crate::hal;
 use crate::pac::LPTIM;
 use crate::pwr::PWR;
-use crate::rcc::{Enable, Rcc, Reset};
+use crate::rcc::{self, Enable, Rcc, Reset};
+use crate::wait_for;
 use cast::{u32, u64};
 use core::convert::TryFrom;
+use core::fmt;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
 use embedded_time::duration::Microseconds;
+use embedded_time::fraction::Fraction;
 use embedded_time::rate::Hertz;
+use embedded_time::{clock, Clock, Instant};
 use void::Void;
 
 mod sealed {
@@ -25,9 +31,25 @@ pub enum Periodic {}
 /// Low-Power Timer in encoder mode.
 pub enum Encoder {}
 
+/// Low-Power Timer in PWM mode.
+pub enum Pwm {}
+
+/// Low-Power Timer used as a monotonic clock.
+pub enum Monotonic {}
+
+/// Low-Power Timer counting pulses on an external input.
+pub enum Counter {}
+
+/// Low-Power Timer counting pulses on an external input, which clocks the counter directly.
+pub enum ExternalCounter {}
+
 impl sealed::Sealed for OneShot {}
 impl sealed::Sealed for Periodic {}
 impl sealed::Sealed for Encoder {}
+impl sealed::Sealed for Pwm {}
+impl sealed::Sealed for Monotonic {}
+impl sealed::Sealed for Counter {}
+impl sealed::Sealed for ExternalCounter {}
 
 /// Marker trait for counter directions.
 pub trait CountMode: sealed::Sealed {}
@@ -35,6 +57,170 @@ pub trait CountMode: sealed::Sealed {}
 impl CountMode for OneShot {}
 impl CountMode for Periodic {}
 impl CountMode for Encoder {}
+impl CountMode for Pwm {}
+impl CountMode for Monotonic {}
+impl CountMode for Counter {}
+impl CountMode for ExternalCounter {}
+
+/// Marker trait for the modes that can be started by a trigger, which are all but `Encoder`.
+pub trait TriggerMode: CountMode {}
+
+impl TriggerMode for OneShot {}
+impl TriggerMode for Periodic {}
+impl TriggerMode for Pwm {}
+impl TriggerMode for Monotonic {}
+impl TriggerMode for Counter {}
+impl TriggerMode for ExternalCounter {}
+
+/// Marker trait for the modes that count pulses on `LPTIM1_IN1`.
+pub trait PulseCountMode: CountMode {}
+
+impl PulseCountMode for Counter {}
+impl PulseCountMode for ExternalCounter {}
+
+/// Pins that can be used as LPTIM output (`LPTIM1_OUT`).
+pub trait OutPin {
+    /// Switches the pin to `LPTIM1_OUT`: AF2 on PB2, AF0 on PC1.
+    fn setup(&self);
+}
+
+impl<MODE: gpio::PinMode> OutPin for gpiob::PB2<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF2);
+    }
+}
+
+#[cfg(feature = "io-STM32L071")]
+impl<MODE: gpio::PinMode> OutPin for gpio::gpioc::PC1<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF0);
+    }
+}
+
+/// Pins that can be used as LPTIM input 1 (`LPTIM1_IN1`).
+pub trait In1Pin {
+    /// Switches the pin to `LPTIM1_IN1`: AF2 on PB5, AF0 on PC0.
+    fn setup(&self);
+}
+
+/// Pins that can be used as LPTIM input 2 (`LPTIM1_IN2`).
+pub trait In2Pin {
+    /// Switches the pin to `LPTIM1_IN2`: AF2 on PB7, AF0 on PC2.
+    fn setup(&self);
+}
+
+impl<MODE: gpio::PinMode> In1Pin for gpiob::PB5<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF2);
+    }
+}
+
+impl<MODE: gpio::PinMode> In2Pin for gpiob::PB7<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF2);
+    }
+}
+
+#[cfg(feature = "io-STM32L071")]
+impl<MODE: gpio::PinMode> In1Pin for gpio::gpioc::PC0<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF0);
+    }
+}
+
+#[cfg(feature = "io-STM32L071")]
+impl<MODE: gpio::PinMode> In2Pin for gpio::gpioc::PC2<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF0);
+    }
+}
+
+/// Pins that can be used as LPTIM external trigger (`LPTIM1_ETR`).
+pub trait EtrPin {
+    /// Switches the pin to `LPTIM1_ETR`: AF2 on PB6, AF0 on PC3.
+    fn setup(&self);
+}
+
+impl<MODE: gpio::PinMode> EtrPin for gpiob::PB6<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF2);
+    }
+}
+
+#[cfg(feature = "io-STM32L071")]
+impl<MODE: gpio::PinMode> EtrPin for gpio::gpioc::PC3<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(gpio::AltMode::AF0);
+    }
+}
+
+/// Active edge of an external input.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Edge {
+    /// Rising edges are active.
+    Rising,
+    /// Falling edges are active.
+    Falling,
+    /// Both edges are active.
+    Both,
+}
+
+impl Edge {
+    /// Returns the encoding of the `CKPOL` field.
+    fn ckpol(self) -> u8 {
+        match self {
+            Edge::Rising => 0b00,
+            Edge::Falling => 0b01,
+            Edge::Both => 0b10,
+        }
+    }
+
+    /// Returns the encoding of the `TRIGEN` field.
+    fn trigen(self) -> u8 {
+        match self {
+            Edge::Rising => 0b01,
+            Edge::Falling => 0b10,
+            Edge::Both => 0b11,
+        }
+    }
+}
+
+/// Internal trigger sources, for use with `set_trigger`.
+///
+/// The external trigger pin is selected with `set_trigger_pin` instead.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TriggerSource {
+    /// RTC alarm A.
+    RtcAlarmA = 0b001,
+    /// RTC alarm B.
+    RtcAlarmB = 0b010,
+    /// RTC tamper 1 input.
+    RtcTamper1 = 0b011,
+    /// RTC tamper 2 input.
+    RtcTamper2 = 0b100,
+    /// RTC tamper 3 input.
+    RtcTamper3 = 0b101,
+    /// Comparator 1 output.
+    Comp1 = 0b110,
+    /// Comparator 2 output.
+    Comp2 = 0b111,
+}
+
+/// Digital filter for the encoder inputs.
+///
+/// A level change is only accepted after the input has been stable for the given number of
+/// counter clock periods.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Any level change is accepted.
+    None = 0b00,
+    /// The level has to be stable for 2 clock periods.
+    Clocks2 = 0b01,
+    /// The level has to be stable for 4 clock periods.
+    Clocks4 = 0b10,
+    /// The level has to be stable for 8 clock periods.
+    Clocks8 = 0b11,
+}
 
 /// Clock source selection for the Low-Power Timer `LPTIM`.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -58,6 +244,24 @@ pub enum ClockSrc {
     Lse = 0b11,
 }
 
+/// Low-Power Timer errors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The total period of a PWM pattern overflows `u32` microseconds.
+    PeriodOverflow,
+    /// The period can't be produced from the timer's input clock.
+    PeriodOutOfRange,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::PeriodOverflow => f.write_str("PWM period overflow"),
+            Error::PeriodOutOfRange => f.write_str("period out of range"),
+        }
+    }
+}
+
 /// Interrupt enable flags.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 pub struct Interrupts {
@@ -77,6 +281,29 @@ pub struct Interrupts {
     pub compare_match: bool,
 }
 
+/// Interrupt events, for use with `listen`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// CMP register matches current CNT value.
+    CompareMatch,
+    /// ARR register matches current CNT value.
+    AutoreloadMatch,
+    /// Valid edge on ext. trigger input.
+    ExtTrig,
+}
+
+impl Event {
+    fn interrupts(self) -> Interrupts {
+        let mut interrupts = Interrupts::default();
+        match self {
+            Event::CompareMatch => interrupts.compare_match = true,
+            Event::AutoreloadMatch => interrupts.autoreload_match = true,
+            Event::ExtTrig => interrupts.ext_trig = true,
+        }
+        interrupts
+    }
+}
+
 /// Low-Power Timer (`LPTIM`).
 ///
 /// The Low-Power Timer is a 16-bit timer with a prescaler of up to 128. It can run off of the APB1,
@@ -88,9 +315,14 @@ pub struct Interrupts {
 /// implemented and the `CountDown` implementation uses `Hertz` as the time unit. In one-shot mode,
 /// the `CountDown` implementation instead uses `Microseconds`, allowing for a multi-second timeout
 /// to be configured (with the tradeoff being a larger code size due to use of 64-bit arithmetic).
-pub struct LpTimer<M: CountMode> {
+///
+/// `PINS` are the pins owned by the timer. They are returned by `release`.
+///
+/// Initialization returns an error, if the selected clock source doesn't become ready.
+pub struct LpTimer<M: CountMode, PINS = ()> {
     lptim: LPTIM,
     input_freq: Hertz,
+    pins: PINS,
     _mode: PhantomData<M>,
 }
 
@@ -98,8 +330,13 @@ impl LpTimer<Periodic> {
     /// Initializes the Low-Power Timer in periodic mode.
     ///
     /// The timer needs to be started by calling `.start(freq)`.
-    pub fn init_periodic(lptim: LPTIM, pwr: &mut PWR, rcc: &mut Rcc, clk: ClockSrc) -> Self {
-        Self::init(lptim, pwr, rcc, clk)
+    pub fn init_periodic(
+        lptim: LPTIM,
+        pwr: &mut PWR,
+        rcc: &mut Rcc,
+        clk: ClockSrc,
+    ) -> Result<Self, rcc::Error> {
+        Self::init(lptim, pwr, rcc, clk, ())
     }
 }
 
@@ -107,36 +344,54 @@ impl LpTimer<OneShot> {
     /// Initializes the Low-Power Timer in one-shot mode.
     ///
     /// The timer needs to be started by calling `.start(freq)`.
-    pub fn init_oneshot(lptim: LPTIM, pwr: &mut PWR, rcc: &mut Rcc, clk: ClockSrc) -> Self {
-        Self::init(lptim, pwr, rcc, clk)
+    pub fn init_oneshot(
+        lptim: LPTIM,
+        pwr: &mut PWR,
+        rcc: &mut Rcc,
+        clk: ClockSrc,
+    ) -> Result<Self, rcc::Error> {
+        Self::init(lptim, pwr, rcc, clk, ())
     }
 }
 
-impl LpTimer<Encoder> {
-    /// Initializes the Low-Power Timer in encoder mode.
+impl<IN1: In1Pin, IN2: In2Pin> LpTimer<Encoder, (IN1, IN2)> {
+    /// Initializes the Low-Power Timer in encoder mode, counting the quadrature signal on the
+    /// given input pins.
+    ///
+    /// The `enable` method must be called to enable the encoder input.
     ///
-    /// The `start` method must be called to enable the encoder input.
+    /// The inputs are sampled with the selected clock, so the encoder signals must not change
+    /// faster than a quarter of its frequency. When clocked from LSE or LSI, the timer keeps
+    /// counting in Stop mode. Enable the `enc_dir_down`/`enc_dir_up` or `compare_match`
+    /// interrupts to wake the MCU up when the encoder is turned.
     pub fn init_encoder(
         lptim: LPTIM,
         pwr: &mut PWR,
         rcc: &mut Rcc,
         clk: ClockSrc,
-        (pb5, pb7): (gpiob::PB5<gpio::Analog>, gpiob::PB7<gpio::Analog>),
-    ) -> Self {
-        pb5.set_alt_mode(gpio::AltMode::AF2);
-        pb7.set_alt_mode(gpio::AltMode::AF2);
+        (in1, in2): (IN1, IN2),
+    ) -> Result<Self, rcc::Error> {
+        in1.setup();
+        in2.setup();
 
-        Self::init(lptim, pwr, rcc, clk)
+        Self::init(lptim, pwr, rcc, clk, (in1, in2))
     }
+}
 
-    // TODO: Dedupe this fn with configure() function in `impl<M: CountMode> LpTimer<M>`
+impl<PINS> LpTimer<Encoder, PINS> {
+    // TODO: Dedupe this fn with configure() function in `impl<M: CountMode, PINS> LpTimer<M, PINS>`
     fn configure_encoder(&mut self, arr: u16) {
         // Disable the timer. The prescaler can only be changed while it's disabled.
         self.lptim.cr.write(|w| w.enable().clear_bit());
 
+        // Keep the filter configured by `set_filter`
+        let ckflt = self.lptim.cfgr.read().ckflt().bits();
+
         // Configure in encoder mode
-        self.lptim.cfgr.write(|w| {
-            w
+        #[allow(unused_unsafe)]
+        self.lptim.cfgr.write(|w| unsafe {
+            w.ckflt()
+                .bits(ckflt)
                 // Make sure prescaler is disabled. Encoder mode forbids prescaling.
                 .presc()
                 .div1()
@@ -185,9 +440,41 @@ impl LpTimer<Encoder> {
         self.lptim.cr.write(|w| w.enable().clear_bit());
     }
 
+    /// Sets the digital filter applied to both encoder inputs.
+    ///
+    /// This disables the timer, as the configuration can only be changed while it's stopped. Call
+    /// `enable` afterwards to resume counting.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.lptim.cr.write(|w| w.enable().clear_bit());
+        #[allow(unused_unsafe)]
+        self.lptim
+            .cfgr
+            .modify(|_, w| unsafe { w.ckflt().bits(filter as u8) });
+    }
+
+    /// Returns the direction of the last counter direction change, clearing the flags.
+    ///
+    /// Returns `None`, if the direction hasn't changed since the last call. The direction change
+    /// flags are also set when the encoder wakes the MCU up from Stop mode, if the
+    /// `enc_dir_down`/`enc_dir_up` interrupts are enabled.
+    pub fn direction_change(&mut self) -> Option<encoder::Direction> {
+        let isr = self.lptim.isr.read();
+        let direction = if isr.down().bit_is_set() {
+            Some(encoder::Direction::Down)
+        } else if isr.up().bit_is_set() {
+            Some(encoder::Direction::Up)
+        } else {
+            None
+        };
+        self.lptim
+            .icr
+            .write(|w| w.downcf().set_bit().upcf().set_bit());
+        direction
+    }
+
     /// Get the current count of the encoder.
     pub fn count(&self) -> u16 {
-        (self.lptim.cnt.read().bits() & 0xffff) as u16
+        self.get_counter()
     }
 
     /// Clear all LPTIM interrupt flags
@@ -196,8 +483,347 @@ impl LpTimer<Encoder> {
     }
 }
 
-impl<M: CountMode> LpTimer<M> {
-    fn init(lptim: LPTIM, pwr: &mut PWR, rcc: &mut Rcc, clk: ClockSrc) -> Self {
+impl<PIN: In1Pin> LpTimer<Counter, PIN> {
+    /// Initializes the Low-Power Timer as a counter of the pulses on the given `LPTIM1_IN1` pin.
+    ///
+    /// The `enable` method must be called to start counting. The counter is incremented on every
+    /// `edge` of the input.
+    ///
+    /// The input is sampled with the selected clock, so pulses must be longer than one period of
+    /// it. When clocked from LSE or LSI, the timer keeps counting in Stop mode. Enable the
+    /// `autoreload_match` or `compare_match` interrupts to wake the MCU up after a number of
+    /// pulses.
+    pub fn init_counter(
+        lptim: LPTIM,
+        pwr: &mut PWR,
+        rcc: &mut Rcc,
+        clk: ClockSrc,
+        pin: PIN,
+        edge: Edge,
+    ) -> Result<Self, rcc::Error> {
+        pin.setup();
+
+        let timer = Self::init(lptim, pwr, rcc, clk, pin)?;
+
+        // The timer is still disabled after `init`, so CFGR can be written. Count the edges of
+        // IN1, sampled with the internal clock, which makes the digital filter available.
+        #[allow(unused_unsafe)]
+        timer
+            .lptim
+            .cfgr
+            .write(|w| unsafe { w.countmode().set_bit().ckpol().bits(edge.ckpol()) });
+
+        Ok(timer)
+    }
+}
+
+impl<PIN: In1Pin> LpTimer<ExternalCounter, PIN> {
+    /// Initializes the Low-Power Timer as an asynchronous counter, clocked directly by the pulses
+    /// on the given `LPTIM1_IN1` pin.
+    ///
+    /// The `enable` method must be called to start counting. The counter is incremented on every
+    /// `edge` of the input.
+    ///
+    /// Unlike `init_counter`, this doesn't need an internal clock to count, so the timer keeps
+    /// counting in Stop mode, even with all internal clocks stopped. The input signal is the
+    /// counter's kernel clock, though:
+    /// - The digital filter can't be used, as it needs an internal clock.
+    /// - Register writes, like the auto-reload value written by `enable`, only take effect after a
+    ///   few pulses on the input.
+    pub fn init_external_counter(lptim: LPTIM, rcc: &mut Rcc, pin: PIN, edge: Edge) -> Self {
+        pin.setup();
+
+        // Only the APB clock is needed, to access the registers
+        LPTIM::enable(rcc);
+        LPTIM::reset(rcc);
+
+        // Count the edges of IN1, which clocks the counter directly
+        #[allow(unused_unsafe)]
+        lptim
+            .cfgr
+            .write(|w| unsafe { w.cksel().set_bit().ckpol().bits(edge.ckpol()) });
+
+        Self {
+            lptim,
+            // Not used in counter mode
+            input_freq: Hertz(0),
+            pins: pin,
+            _mode: PhantomData,
+        }
+    }
+}
+
+impl<M: PulseCountMode, PINS> LpTimer<M, PINS> {
+    /// Enables the timer and begins counting pulses.
+    ///
+    /// The provided value is stored in the ARR (Auto Reload Register). The counter wraps to 0 after
+    /// it has reached this value.
+    pub fn enable(&mut self, arr: u16) {
+        self.lptim.cr.write(|w| w.enable().set_bit());
+
+        // "After setting the ENABLE bit, a delay of two counter clock is needed before the LPTIM is
+        // actually enabled."
+        // The slowest LPTIM clock source is LSE at 32768 Hz, the fastest CPU clock is ~80 MHz. At
+        // these conditions, one cycle of the LPTIM clock takes 2500 CPU cycles, so sleep for 5000.
+        cortex_m::asm::delay(5000);
+
+        // ARR can only be changed while the timer is *en*abled
+        self.lptim.arr.write(|w| w.arr().bits(arr));
+
+        // Start LPTIM in continuous mode.
+        self.lptim
+            .cr
+            .write(|w| w.enable().set_bit().cntstrt().set_bit());
+    }
+
+    /// Disables the timer. This resets the counter.
+    pub fn disable(&mut self) {
+        self.lptim.cr.write(|w| w.enable().clear_bit());
+    }
+
+    /// Returns the number of pulses counted since the last wraparound.
+    pub fn count(&self) -> u16 {
+        self.get_counter()
+    }
+}
+
+impl<PINS> LpTimer<Counter, PINS> {
+    /// Sets the digital filter applied to the input.
+    ///
+    /// This disables the timer, as the configuration can only be changed while it's stopped. Call
+    /// `enable` afterwards to resume counting.
+    ///
+    /// Not available for a counter created by `init_external_counter`, as the filter needs an
+    /// internal clock.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.lptim.cr.write(|w| w.enable().clear_bit());
+        #[allow(unused_unsafe)]
+        self.lptim
+            .cfgr
+            .modify(|_, w| unsafe { w.ckflt().bits(filter as u8) });
+    }
+}
+
+impl<PIN: OutPin> LpTimer<Pwm, PIN> {
+    /// Initializes the Low-Power Timer in PWM mode, driving the given output pin.
+    ///
+    /// The `set_pattern` method must be called to start the output.
+    ///
+    /// The output is generated by the timer alone, without any CPU involvement. When clocked from
+    /// LSE or LSI, the timer keeps running in Stop mode, which makes this useful to blink a
+    /// heartbeat LED on low-power devices.
+    pub fn init_pwm(
+        lptim: LPTIM,
+        pwr: &mut PWR,
+        rcc: &mut Rcc,
+        clk: ClockSrc,
+        pin: PIN,
+    ) -> Result<Self, rcc::Error> {
+        pin.setup();
+
+        Self::init(lptim, pwr, rcc, clk, pin)
+    }
+}
+
+impl<PINS> LpTimer<Pwm, PINS> {
+    /// Starts generating a waveform that is high for `on_time`, then low for `off_time`.
+    ///
+    /// Returns `Error::PeriodOverflow`, if the total period doesn't fit into a `u32` of
+    /// microseconds, and `Error::PeriodOutOfRange`, if it can't be produced from the timer's input
+    /// clock.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `on_time` or `off_time` are zero.
+    pub fn set_pattern(
+        &mut self,
+        on_time: Microseconds,
+        off_time: Microseconds,
+    ) -> Result<(), Error> {
+        assert!(on_time.0 > 0 && off_time.0 > 0);
+
+        let period = on_time
+            .0
+            .checked_add(off_time.0)
+            .map(Microseconds)
+            .ok_or(Error::PeriodOverflow)?;
+        let conf = TimeConf::calculate_period(self.input_freq, period)?;
+        let cmp = conf.compare_for(on_time, period);
+
+        // Disable the timer. The prescaler can only be changed while it's disabled.
+        self.lptim.cr.write(|w| w.enable().clear_bit());
+
+        // Keep the trigger configured by `set_trigger`
+        let cfgr = self.lptim.cfgr.read();
+        let (trigen, trigsel) = (cfgr.trigen().bits(), cfgr.trigsel().bits());
+
+        // PWM mode, the output is set on compare match and reset on autoreload match. ARR and CMP
+        // are preloaded, so later updates only take effect at the end of a period.
+        #[allow(unused_unsafe)]
+        self.lptim.cfgr.write(|w| unsafe {
+            w.presc()
+                .bits(conf.psc_encoded)
+                .trigen()
+                .bits(trigen)
+                .trigsel()
+                .bits(trigsel)
+                .wave()
+                .clear_bit()
+                .wavpol()
+                .clear_bit()
+                .preload()
+                .set_bit()
+        });
+
+        self.lptim.cr.write(|w| w.enable().set_bit());
+
+        // "After setting the ENABLE bit, a delay of two counter clock is needed before the LPTIM is
+        // actually enabled."
+        // The slowest LPTIM clock source is LSE at 32768 Hz, the fastest CPU clock is ~80 MHz. At
+        // these conditions, one cycle of the LPTIM clock takes 2500 CPU cycles, so sleep for 5000.
+        cortex_m::asm::delay(5000);
+
+        // ARR and CMP can only be changed while the timer is *en*abled
+        self.lptim.icr.write(|w| w.arrokcf().set_bit());
+        self.lptim.arr.write(|w| w.arr().bits(conf.arr));
+        while self.lptim.isr.read().arrok().bit_is_clear() {}
+        self.write_compare(cmp);
+
+        // Start LPTIM in continuous mode.
+        self.lptim
+            .cr
+            .write(|w| w.enable().set_bit().cntstrt().set_bit());
+
+        Ok(())
+    }
+
+    /// Changes the time the output is high, keeping the period set by `set_pattern`.
+    ///
+    /// The new value takes effect at the end of the current period, so the output never produces a
+    /// truncated or doubled pulse. This can safely be called while the timer is running, including
+    /// from an interrupt handler that was woken from Stop mode. Blocks until the new value has
+    /// been transferred to the timer, which takes up to 3 cycles of the timer clock.
+    ///
+    /// `on_time` is clamped to the period.
+    pub fn set_duty_synced(&mut self, on_time: Microseconds) {
+        let conf = TimeConf {
+            psc_encoded: self.lptim.cfgr.read().presc().bits(),
+            arr: self.lptim.arr.read().arr().bits(),
+        };
+        self.write_compare(conf.compare_at(self.input_freq, on_time));
+    }
+
+    fn write_compare(&mut self, cmp: u16) {
+        self.lptim.icr.write(|w| w.cmpokcf().set_bit());
+        self.lptim.cmp.write(|w| w.cmp().bits(cmp));
+        while self.lptim.isr.read().cmpok().bit_is_clear() {}
+    }
+
+    /// Stops the output.
+    pub fn disable(&mut self) {
+        self.lptim.cr.write(|w| w.enable().clear_bit());
+    }
+}
+
+/// Number of counter overflows of the monotonic clock, i.e. the upper 16 bits of the 32-bit
+/// timestamp.
+///
+/// There's only one LPTIM, so a static is enough. It's updated by
+/// `LpTimer::<Monotonic>::handle_interrupt`, within a critical section.
+static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+impl LpTimer<Monotonic> {
+    /// Tick rate of the monotonic clock.
+    pub const FREQUENCY: Hertz = Hertz(32_768);
+
+    /// Initializes the Low-Power Timer as a monotonic clock, running off of the LSE.
+    ///
+    /// The timer counts at 32.768 kHz, so it keeps running in Stop mode. The 16-bit counter is
+    /// extended to 32 bits in software, which results in a wraparound period of about 36 hours.
+    /// This requires the `LPTIM1` interrupt to be unmasked in the NVIC, with
+    /// [`LpTimer::handle_interrupt`] being called from its handler. The timer is started
+    /// immediately.
+    ///
+    /// Returns `rcc::Error::LseTimeout`, if the LSE doesn't become ready.
+    pub fn init_monotonic(lptim: LPTIM, pwr: &mut PWR, rcc: &mut Rcc) -> Result<Self, rcc::Error> {
+        let mut timer = Self::init(lptim, pwr, rcc, ClockSrc::Lse, ())?;
+        OVERFLOWS.store(0, Ordering::Relaxed);
+
+        // IER can only be modified when the timer is disabled, which it still is after `init`.
+        timer.lptim.ier.write(|w| w.arrmie().enabled());
+        timer.configure(TimeConf {
+            psc_encoded: 0,
+            arr: TimeConf::ARR_MAX,
+        });
+
+        // Start LPTIM in continuous mode.
+        timer
+            .lptim
+            .cr
+            .write(|w| w.enable().set_bit().cntstrt().set_bit());
+
+        Ok(timer)
+    }
+
+    /// Handles the autoreload match interrupt, which extends the counter to 32 bits.
+    ///
+    /// Call this from the `LPTIM1` interrupt handler.
+    pub fn handle_interrupt(&self) {
+        cortex_m::interrupt::free(|_| {
+            if self.lptim.isr.read().arrm().bit_is_set() {
+                self.lptim.icr.write(|w| w.arrmcf().set_bit());
+                let overflows = OVERFLOWS.load(Ordering::Relaxed);
+                OVERFLOWS.store(overflows.wrapping_add(1), Ordering::Relaxed);
+            }
+        })
+    }
+
+    /// Returns the current 32-bit tick count.
+    pub fn ticks(&self) -> u32 {
+        cortex_m::interrupt::free(|_| {
+            let overflows = OVERFLOWS.load(Ordering::Relaxed);
+            let counter = self.get_counter();
+            let pending = self.lptim.isr.read().arrm().bit_is_set();
+            extend_counter(overflows, counter, pending)
+        })
+    }
+}
+
+impl Clock for LpTimer<Monotonic> {
+    type T = u32;
+
+    const SCALING_FACTOR: Fraction = Fraction::new(1, 32_768);
+
+    fn try_now(&self) -> Result<Instant<Self>, clock::Error> {
+        Ok(Instant::new(self.ticks()))
+    }
+}
+
+/// Combines the overflow count and the counter value into a 32-bit tick count.
+///
+/// `pending` is the state of the ARRM flag, read after the counter. The flag is set when the
+/// counter matches ARR, one tick before the counter wraps around to 0. If it's set, but the
+/// counter has already wrapped, the overflow hasn't been counted yet. As the flag is read after
+/// the counter, it may also have been set just after reading a counter value close to ARR, which
+/// must not be counted as an overflow. Only the lower half of the counter range is treated as
+/// wrapped.
+fn extend_counter(overflows: u32, counter: u16, pending: bool) -> u32 {
+    let overflows = if pending && counter < 0x8000 {
+        overflows.wrapping_add(1)
+    } else {
+        overflows
+    };
+    (overflows << 16) | u32(counter)
+}
+
+impl<M: CountMode, PINS> LpTimer<M, PINS> {
+    fn init(
+        lptim: LPTIM,
+        pwr: &mut PWR,
+        rcc: &mut Rcc,
+        clk: ClockSrc,
+        pins: PINS,
+    ) -> Result<Self, rcc::Error> {
         // `pwr` is not used. It is used as a marker that guarantees that `PWR.CR` is set so this
         // function can set the `RCC.LSEON` bit, which is otherwise write protected.
         let _ = pwr;
@@ -210,7 +836,10 @@ impl<M: CountMode> LpTimer<M> {
                 rcc.rb.csr.modify(|_, w| w.lsion().set_bit());
 
                 // Wait for LSI to be ready
-                while rcc.rb.csr.read().lsirdy().bit_is_clear() {}
+                wait_for(
+                    || rcc.rb.csr.read().lsirdy().bit_is_set(),
+                    rcc::Error::LsiTimeout,
+                )?;
 
                 Hertz(37_000)
             }
@@ -219,7 +848,10 @@ impl<M: CountMode> LpTimer<M> {
                 rcc.rb.cr.modify(|_, w| w.hsi16on().set_bit());
 
                 // Wait for HSI16 to be ready
-                while rcc.rb.cr.read().hsi16rdyf().bit_is_clear() {}
+                wait_for(
+                    || rcc.rb.cr.read().hsi16rdyf().bit_is_set(),
+                    rcc::Error::HsiTimeout,
+                )?;
 
                 Hertz(16_000_000)
             }
@@ -227,8 +859,8 @@ impl<M: CountMode> LpTimer<M> {
                 // Turn on LSE
                 rcc.rb.csr.modify(|_, w| w.lseon().set_bit());
 
-                // Wait for LSE to be ready
-                while rcc.rb.csr.read().lserdy().bit_is_clear() {}
+                // Wait for LSE to be ready, which can take up to 2 seconds
+                rcc.wait_for_lse()?;
 
                 Hertz(32_768)
             }
@@ -241,11 +873,12 @@ impl<M: CountMode> LpTimer<M> {
 
         LPTIM::reset(rcc);
 
-        Self {
+        Ok(Self {
             lptim,
             input_freq,
+            pins,
             _mode: PhantomData,
-        }
+        })
     }
 
     /// Disables the timer and configures it so that starting it will make it fire at the given
@@ -254,9 +887,21 @@ impl<M: CountMode> LpTimer<M> {
         // Disable the timer. The prescaler can only be changed while it's disabled.
         self.lptim.cr.write(|w| w.enable().clear_bit());
 
-        self.lptim
-            .cfgr
-            .write(|w| w.presc().bits(conf.psc_encoded).timout().set_bit());
+        // Keep the trigger configured by `set_trigger`
+        let cfgr = self.lptim.cfgr.read();
+        let (trigen, trigsel) = (cfgr.trigen().bits(), cfgr.trigsel().bits());
+
+        #[allow(unused_unsafe)]
+        self.lptim.cfgr.write(|w| unsafe {
+            w.presc()
+                .bits(conf.psc_encoded)
+                .timout()
+                .set_bit()
+                .trigen()
+                .bits(trigen)
+                .trigsel()
+                .bits(trigsel)
+        });
 
         self.lptim.cr.write(|w| w.enable().set_bit());
 
@@ -270,10 +915,41 @@ impl<M: CountMode> LpTimer<M> {
         self.lptim.arr.write(|w| w.arr().bits(conf.arr));
     }
 
+    /// Returns the current value of the counter.
+    ///
+    /// The counter runs asynchronously to the APB clock, so a single read can return a corrupted
+    /// value. As recommended by the reference manual, the register is read until two consecutive
+    /// reads return the same value.
+    pub fn get_counter(&self) -> u16 {
+        loop {
+            let first = (self.lptim.cnt.read().bits() & 0xffff) as u16;
+            let second = (self.lptim.cnt.read().bits() & 0xffff) as u16;
+            if first == second {
+                return first;
+            }
+        }
+    }
+
+    fn write_trigger(&mut self, trigsel: u8, trigen: u8) {
+        // CFGR can only be modified when the timer is disabled
+        self.lptim.cr.write(|w| w.enable().clear_bit());
+        #[allow(unused_unsafe)]
+        self.lptim
+            .cfgr
+            .modify(|_, w| unsafe { w.trigsel().bits(trigsel).trigen().bits(trigen) });
+    }
+
+    /// Disables and destructs the timer, returning the raw `LPTIM` peripheral and the pins.
+    pub fn release(self) -> (LPTIM, PINS) {
+        self.lptim.cr.reset();
+        (self.lptim, self.pins)
+    }
+
     /// Disables and destructs the timer, returning the raw `LPTIM` peripheral.
+    ///
+    /// The pins owned by the timer, if any, are dropped. Use `release` to get them back.
     pub fn free(self) -> LPTIM {
-        self.lptim.cr.reset();
-        self.lptim
+        self.release().0
     }
 
     /// Disables the timer and enables the given interrupts.
@@ -306,6 +982,38 @@ impl<M: CountMode> LpTimer<M> {
         })
     }
 
+    /// Disables the timer and enables the interrupt for `event`.
+    ///
+    /// The timer has to be disabled, as the interrupt enable register can only be modified while
+    /// it's stopped. Call this before starting the timer.
+    pub fn listen(&mut self, event: Event) {
+        self.enable_interrupts(event.interrupts());
+    }
+
+    /// Disables the timer and disables the interrupt for `event`.
+    pub fn unlisten(&mut self, event: Event) {
+        self.disable_interrupts(event.interrupts());
+    }
+
+    /// Checks if the flag for `event` is set.
+    pub fn is_pending(&self, event: Event) -> bool {
+        let isr = self.lptim.isr.read();
+        match event {
+            Event::CompareMatch => isr.cmpm().bit_is_set(),
+            Event::AutoreloadMatch => isr.arrm().bit_is_set(),
+            Event::ExtTrig => isr.exttrig().bit_is_set(),
+        }
+    }
+
+    /// Clears the flag for `event`.
+    pub fn clear_irq(&mut self, event: Event) {
+        self.lptim.icr.write(|w| match event {
+            Event::CompareMatch => w.cmpmcf().set_bit(),
+            Event::AutoreloadMatch => w.arrmcf().set_bit(),
+            Event::ExtTrig => w.exttrigcf().set_bit(),
+        });
+    }
+
     /// Disables the timer and disables the given interrupts.
     pub fn disable_interrupts(&mut self, interrupts: Interrupts) {
         // IER can only be modified when the timer is disabled
@@ -337,6 +1045,38 @@ impl<M: CountMode> LpTimer<M> {
     }
 }
 
+impl<M: TriggerMode, PINS> LpTimer<M, PINS> {
+    /// Disables the timer and makes it start on `edge` of the given internal trigger source.
+    ///
+    /// Once started, the timer waits for the trigger before it begins counting. In periodic and
+    /// PWM mode, every following trigger restarts the counter.
+    pub fn set_trigger(&mut self, source: TriggerSource, edge: Edge) {
+        self.write_trigger(source as u8, edge.trigen());
+    }
+
+    /// Disables the timer and makes it start on `edge` of the external trigger pin.
+    ///
+    /// The timer takes ownership of the pin, which is returned by `release` together with the
+    /// other pins. See `set_trigger`.
+    pub fn set_trigger_pin<PIN: EtrPin>(self, pin: PIN, edge: Edge) -> LpTimer<M, (PINS, PIN)> {
+        pin.setup();
+
+        let mut timer = LpTimer {
+            lptim: self.lptim,
+            input_freq: self.input_freq,
+            pins: (self.pins, pin),
+            _mode: PhantomData,
+        };
+        timer.write_trigger(0b000, edge.trigen());
+        timer
+    }
+
+    /// Disables the timer and makes it start immediately when started by software again.
+    pub fn clear_trigger(&mut self) {
+        self.write_trigger(0b000, 0b00);
+    }
+}
+
 impl hal::timer::CountDown for LpTimer<Periodic> {
     type Time = Hertz;
 
@@ -371,7 +1111,11 @@ impl hal::timer::CountDown for LpTimer<OneShot> {
     where
         T: Into<Microseconds>,
     {
-        self.configure(TimeConf::calculate_period(self.input_freq, period.into()));
+        let conf = match TimeConf::calculate_period(self.input_freq, period.into()) {
+            Ok(conf) => conf,
+            Err(err) => panic!("{}", err),
+        };
+        self.configure(conf);
 
         // Start LPTIM in one-shot mode.
         self.lptim
@@ -435,7 +1179,10 @@ impl TimeConf {
 
     /// Calculates prescaler and autoreload value for producing overflows after every
     /// `output_period`.
-    fn calculate_period(input_freq: Hertz, output_period: Microseconds) -> Self {
+    ///
+    /// Returns `Error::PeriodOutOfRange`, if the period is too short or too long to be produced
+    /// from `input_freq`.
+    fn calculate_period(input_freq: Hertz, output_period: Microseconds) -> Result<Self, Error> {
         // Here, the `output_period` can be very long, resulting in an output frequency of < 1 Hz.
 
         // Fi  = Frequency of input clock
@@ -456,7 +1203,7 @@ impl TimeConf {
         //
         // We first calculate `psc` by assuming the largest `ARR` value, and round the result to the
         // next power of two. If that's > 128, the chosen period is too long for the timer and we
-        // panic. Otherwise we use that `psc` to calculate the real `ARR`.
+        // return an error. Otherwise we use that `psc` to calculate the real `ARR`.
 
         // First, calculate the product `Fi * Po`. Since `output_period` is in µs, we have to divide
         // it by 1_000_000 to get seconds, without losing much precision. We can divide either of
@@ -464,12 +1211,16 @@ impl TimeConf {
         // the least amount of rouding error, but might require 64-bit multiplication and division,
         // which is very expensive. Dividing either of the multiplicands by 1_000_000 can easily
         // result in significant rounding error that makes this API useless.
-        let fi_po = u32(u64(input_freq.0) * u64(output_period.0) / 1_000_000).unwrap();
+        let fi_po = u32(u64(input_freq.0) * u64(output_period.0) / 1_000_000)
+            .map_err(|_| Error::PeriodOutOfRange)?;
         // Add `ARR_MAX - 1` to round the result upwards
-        let psc = (fi_po + (u32(Self::ARR_MAX) - 1)) / u32(Self::ARR_MAX);
-        assert!(psc > 0); // if 0, the output period is too short to be produced from input_freq
-        let psc = psc.next_power_of_two(); // always >= 1
-        assert!(psc <= 128); // if > 128, the output period is too long to be produced from input_freq
+        let psc = (u64(fi_po) + u64(Self::ARR_MAX - 1)) / u64(Self::ARR_MAX);
+        // if 0, the output period is too short to be produced from input_freq
+        // if > 128, the output period is too long to be produced from input_freq
+        if psc == 0 || psc > 128 {
+            return Err(Error::PeriodOutOfRange);
+        }
+        let psc = (psc as u32).next_power_of_two(); // always >= 1
 
         // This calculation must be in u16 range because we assume the max. ARR value above ^
         let arr = (fi_po / psc) as u16;
@@ -477,7 +1228,26 @@ impl TimeConf {
         // PSC encoding is N where `psc = 2^N`
         let psc_encoded = psc.trailing_zeros() as u8;
 
-        Self { psc_encoded, arr }
+        Ok(Self { psc_encoded, arr })
+    }
+
+    /// Calculates the compare value that results in the output being high for `on_time` out of
+    /// `period`, if the timer is configured according to `self`.
+    ///
+    /// The output is set when the counter matches CMP and reset when it matches ARR, so the compare
+    /// value must be strictly smaller than ARR.
+    fn compare_for(&self, on_time: Microseconds, period: Microseconds) -> u16 {
+        let on_ticks = u64(self.arr) * u64(on_time.0) / u64(period.0);
+        let cmp = u64(self.arr) - on_ticks;
+        cmp.min(u64(self.arr - 1)) as u16
+    }
+
+    /// Calculates the compare value that results in the output being high for `on_time`, if the
+    /// timer is configured according to `self` and is run at `input_freq`.
+    fn compare_at(&self, input_freq: Hertz, on_time: Microseconds) -> u16 {
+        let on_ticks = (u64(input_freq.0) * u64(on_time.0) / 1_000_000) >> self.psc_encoded;
+        let cmp = u64(self.arr).saturating_sub(on_ticks);
+        cmp.min(u64(self.arr - 1)) as u16
     }
 }
 
@@ -540,13 +1310,13 @@ mod tests {
     #[test]
     fn calc_from_period() {
         // 1:1 ratio
-        let c = TimeConf::calculate_period(1_000.hz(), 1_000.us());
+        let c = TimeConf::calculate_period(1_000.hz(), 1_000.us()).unwrap();
         assert_eq!(c.psc(), 1);
         assert_eq!(c.arr, 1);
         assert_eq!(c.output_freq(1_000.hz()), 1_000.hz());
 
         // real-world test: go from 32.768 kHz to 10 s
-        let c = TimeConf::calculate_period(32_768.hz(), 10_000_000.us());
+        let c = TimeConf::calculate_period(32_768.hz(), 10_000_000.us()).unwrap();
         assert_eq!(c.psc(), 8);
         assert_eq!(c.arr, 40960);
         assert_eq!(c.output_freq(32_768.hz()), 0.hz());
@@ -554,8 +1324,57 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "assertion failed: psc > 0")]
-    fn period_too_short() {
-        TimeConf::calculate_period(1_000.hz(), 999.us());
+    fn period_out_of_range() {
+        // too short
+        assert!(matches!(
+            TimeConf::calculate_period(1_000.hz(), 999.us()),
+            Err(Error::PeriodOutOfRange)
+        ));
+
+        // too long, needs psc=256
+        assert!(matches!(
+            TimeConf::calculate_period(32_768.hz(), 513_000_000.us()),
+            Err(Error::PeriodOutOfRange)
+        ));
+
+        // Fi * Po overflows
+        assert!(matches!(
+            TimeConf::calculate_period(16_000_000.hz(), u32::MAX.us()),
+            Err(Error::PeriodOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn extend_counter_overflow() {
+        assert_eq!(extend_counter(0, 0x1234, false), 0x1234);
+        assert_eq!(extend_counter(2, 0x1234, false), 0x2_1234);
+
+        // ARRM is set on the last tick before the wraparound
+        assert_eq!(extend_counter(2, 0xffff, true), 0x2_ffff);
+
+        // ARRM was set after reading the counter
+        assert_eq!(extend_counter(2, 0xfffe, true), (2 << 16) | 0xfffe);
+
+        // counter wrapped, but the interrupt hasn't run yet
+        assert_eq!(extend_counter(2, 0x0001, true), 0x3_0001);
+
+        // the 32-bit value wraps around
+        assert_eq!(extend_counter(0xffff, 0x0001, true), 0x0001);
+    }
+
+    #[test]
+    fn calc_compare() {
+        // 50 ms on, 1950 ms off at 32.768 kHz
+        let c = TimeConf::calculate_period(32_768.hz(), 2_000_000.us()).unwrap();
+        assert_eq!(c.psc(), 2);
+        assert_eq!(c.arr, 32768);
+        assert_eq!(c.compare_for(50_000.us(), 2_000_000.us()), 32768 - 819);
+
+        // on time too short to be represented still produces a valid compare value
+        assert_eq!(c.compare_for(1.us(), 2_000_000.us()), 32767);
+
+        // same result when calculated from the input frequency
+        assert_eq!(c.compare_at(32_768.hz(), 50_000.us()), 32768 - 819);
+        assert_eq!(c.compare_at(32_768.hz(), 3_000_000.us()), 0);
     }
 }
@@ -0,0 +1,156 @@
+//! Closed-loop motor control
+//!
+//! Combines a quadrature encoder, a pair of PWM channels driving an H-bridge,
+//! and a periodic timer into a control loop that runs at a fixed rate. The
+//! control algorithm (for example a PID controller) is supplied by the user as
+//! a closure. The HAL takes care of the order of operations on every tick:
+//!
+//! 1. The timer's update flag is checked and cleared.
+//! 2. The encoder is sampled, and position and velocity are updated.
+//! 3. The control closure is called with the new [`State`].
+//! 4. The returned drive value is applied to the PWM channels.
+//!
+//! ``` ignore
+//! let encoder = dp.TIM2.encoder((pa0, pa1), encoder::Mode::Qei, u16::MAX, &mut rcc);
+//! let mut timer = dp.TIM6.timer(1.kHz(), &mut rcc);
+//! timer.listen();
+//!
+//! let mut motion = Motion::new(encoder, forward, reverse, timer);
+//!
+//! // In the TIM6 interrupt handler
+//! motion.on_interrupt(|state| {
+//!     let error = TARGET - state.position;
+//!     error * 20
+//! });
+//! ```
+
+use crate::hal::timer::{CountDown, Periodic};
+use crate::hal::{PwmPin, Qei};
+
+/// State of the motor, as measured by the encoder
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct State {
+    /// Position in encoder counts, relative to the start position
+    pub position: i32,
+    /// Change of the position since the last tick
+    pub velocity: i32,
+}
+
+/// Motor control loop
+///
+/// The encoder must count over its full 16-bit range (ARR set to `0xffff`),
+/// and must not move by more than 32767 counts per tick.
+pub struct Motion<ENC, FWD, REV, TIM> {
+    encoder: ENC,
+    forward: FWD,
+    reverse: REV,
+    timer: TIM,
+    last_count: u16,
+    state: State,
+}
+
+impl<ENC, FWD, REV, TIM> Motion<ENC, FWD, REV, TIM>
+where
+    ENC: Qei<Count = u16>,
+    FWD: PwmPin<Duty = u16>,
+    REV: PwmPin<Duty = u16>,
+    TIM: CountDown + Periodic,
+{
+    /// Creates a new control loop
+    ///
+    /// `forward` and `reverse` drive the two halves of the H-bridge and are
+    /// enabled with a duty cycle of 0. `timer` must already be running at the
+    /// control rate. To run the loop from an interrupt handler, the timer's
+    /// interrupt must be enabled as well.
+    pub fn new(encoder: ENC, mut forward: FWD, mut reverse: REV, timer: TIM) -> Self {
+        forward.set_duty(0);
+        reverse.set_duty(0);
+        forward.enable();
+        reverse.enable();
+
+        let last_count = encoder.count();
+
+        Motion {
+            encoder,
+            forward,
+            reverse,
+            timer,
+            last_count,
+            state: State {
+                position: 0,
+                velocity: 0,
+            },
+        }
+    }
+
+    /// Runs one iteration of the control loop, if the timer has elapsed
+    ///
+    /// Call this from the timer's interrupt handler, or poll it from the main
+    /// loop. `control` receives the current state and returns the drive value:
+    /// positive values drive forward, negative values in reverse. The value is
+    /// a duty cycle and clamped to the maximum duty of the PWM channels.
+    ///
+    /// Returns the state passed to `control`, or `None` if the timer hasn't
+    /// elapsed yet.
+    pub fn on_interrupt<F>(&mut self, control: F) -> Option<State>
+    where
+        F: FnOnce(State) -> i32,
+    {
+        if self.timer.wait().is_err() {
+            return None;
+        }
+
+        let count = self.encoder.count();
+        let delta = i32::from(count.wrapping_sub(self.last_count) as i16);
+        self.last_count = count;
+
+        self.state.position = self.state.position.wrapping_add(delta);
+        self.state.velocity = delta;
+
+        let drive = control(self.state);
+        self.drive(drive);
+
+        Some(self.state)
+    }
+
+    /// Drives the motor directly, bypassing the control loop
+    ///
+    /// The drive value is interpreted as the return value of the control
+    /// closure of `on_interrupt`. It stays in effect until the next tick.
+    pub fn drive(&mut self, drive: i32) {
+        let max = self.forward.get_max_duty().min(self.reverse.get_max_duty());
+        let duty = drive.unsigned_abs().min(u32::from(max)) as u16;
+
+        // Always switch off one side first, so the bridge is never shorted
+        if drive >= 0 {
+            self.reverse.set_duty(0);
+            self.forward.set_duty(duty);
+        } else {
+            self.forward.set_duty(0);
+            self.reverse.set_duty(duty);
+        }
+    }
+
+    /// Stops driving the motor
+    pub fn stop(&mut self) {
+        self.drive(0);
+    }
+
+    /// Returns the last measured state
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Sets the current position, for example after homing
+    pub fn set_position(&mut self, position: i32) {
+        self.state.position = position;
+    }
+
+    /// Stops the motor and releases the peripherals
+    pub fn free(mut self) -> (ENC, FWD, REV, TIM) {
+        self.stop();
+        self.forward.disable();
+        self.reverse.disable();
+        (self.encoder, self.forward, self.reverse, self.timer)
+    }
+}
@@ -0,0 +1,344 @@
+//! 1-Wire bus master
+//!
+//! Implements the Dallas/Maxim 1-Wire protocol at standard speed on any GPIO
+//! pin, using a delay provider for the slot timing. The pin must be an
+//! open-drain output that can also be read (for example a pin in
+//! `Output<OpenDrain>` mode), with a pull-up resistor (typically 4.7 kΩ) on the
+//! bus.
+//!
+//! Interrupts are disabled during each time slot (up to 70 µs, or 960 µs for
+//! a reset), as the slots are too timing-sensitive to be stretched.
+//!
+//! Finding all devices on a bus, for example DS18B20 temperature sensors:
+//!
+//! ``` ignore
+//! let mut bus = OneWire::new(pin)?;
+//! let mut search = Search::new();
+//! while let Some(rom) = bus.search(&mut search, &mut delay)? {
+//!     // `rom.family_code()` is 0x28 for a DS18B20
+//! }
+//! ```
+
+use core::fmt;
+
+use crate::hal::blocking::delay::DelayUs;
+use crate::hal::digital::v2::{InputPin, OutputPin};
+
+/// ROM command: Search ROM
+const SEARCH_ROM: u8 = 0xf0;
+/// ROM command: Read ROM
+const READ_ROM: u8 = 0x33;
+/// ROM command: Match ROM
+const MATCH_ROM: u8 = 0x55;
+/// ROM command: Skip ROM
+const SKIP_ROM: u8 = 0xcc;
+
+/// 1-Wire errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Accessing the pin failed
+    Bus(E),
+    /// The CRC of a received ROM code didn't match
+    CrcMismatch,
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Bus(error) => write!(f, "accessing the pin failed: {:?}", error),
+            Error::CrcMismatch => f.write_str("CRC mismatch"),
+        }
+    }
+}
+
+/// 64-bit ROM code, uniquely identifying a device on the bus
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    /// Returns the family code, which identifies the device type
+    pub fn family_code(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// Checks the CRC in the last byte of the ROM code
+    pub fn is_valid(&self) -> bool {
+        crc8(&self.0) == 0
+    }
+}
+
+/// State of a ROM search, to be passed to [`OneWire::search`]
+#[derive(Debug, Clone)]
+pub struct Search {
+    rom: [u8; 8],
+    last_discrepancy: u8,
+    done: bool,
+}
+
+impl Search {
+    /// Creates a new search, starting with the first device
+    pub fn new() -> Self {
+        Search {
+            rom: [0; 8],
+            last_discrepancy: 0,
+            done: false,
+        }
+    }
+
+    fn bit(&self, i: u8) -> bool {
+        self.rom[usize::from(i / 8)] & (1 << (i % 8)) != 0
+    }
+
+    fn set_bit(&mut self, i: u8, bit: bool) {
+        let byte = &mut self.rom[usize::from(i / 8)];
+        if bit {
+            *byte |= 1 << (i % 8);
+        } else {
+            *byte &= !(1 << (i % 8));
+        }
+    }
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 1-Wire bus master
+pub struct OneWire<PIN> {
+    pin: PIN,
+}
+
+impl<PIN, E> OneWire<PIN>
+where
+    PIN: OutputPin<Error = E> + InputPin<Error = E>,
+{
+    /// Creates a new bus master, releasing the bus
+    pub fn new(mut pin: PIN) -> Result<Self, Error<E>> {
+        pin.set_high().map_err(Error::Bus)?;
+        Ok(OneWire { pin })
+    }
+
+    /// Releases the pin
+    pub fn free(self) -> PIN {
+        self.pin
+    }
+
+    /// Sends a reset pulse, returning `true` if any device signaled presence
+    pub fn reset<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<bool, Error<E>> {
+        let pin = &mut self.pin;
+        let presence = cortex_m::interrupt::free(|_| -> Result<bool, E> {
+            pin.set_low()?;
+            delay.delay_us(480);
+            pin.set_high()?;
+            delay.delay_us(70);
+            pin.is_low()
+        })
+        .map_err(Error::Bus)?;
+        delay.delay_us(410);
+
+        Ok(presence)
+    }
+
+    /// Writes a single bit
+    pub fn write_bit<D: DelayUs<u16>>(&mut self, delay: &mut D, bit: bool) -> Result<(), Error<E>> {
+        let (low, high) = if bit { (6, 64) } else { (60, 10) };
+
+        let pin = &mut self.pin;
+        cortex_m::interrupt::free(|_| -> Result<(), E> {
+            pin.set_low()?;
+            delay.delay_us(low);
+            pin.set_high()?;
+            delay.delay_us(high);
+            Ok(())
+        })
+        .map_err(Error::Bus)
+    }
+
+    /// Reads a single bit
+    pub fn read_bit<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<bool, Error<E>> {
+        let pin = &mut self.pin;
+        cortex_m::interrupt::free(|_| -> Result<bool, E> {
+            pin.set_low()?;
+            delay.delay_us(6);
+            pin.set_high()?;
+            delay.delay_us(9);
+            let bit = pin.is_high()?;
+            delay.delay_us(55);
+            Ok(bit)
+        })
+        .map_err(Error::Bus)
+    }
+
+    /// Writes a byte, LSB first
+    pub fn write_byte<D: DelayUs<u16>>(&mut self, delay: &mut D, byte: u8) -> Result<(), Error<E>> {
+        for i in 0..8 {
+            self.write_bit(delay, byte & (1 << i) != 0)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a byte, LSB first
+    pub fn read_byte<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<u8, Error<E>> {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit(delay)? {
+                byte |= 1 << i;
+            }
+        }
+        Ok(byte)
+    }
+
+    /// Writes all bytes from `bytes`
+    pub fn write_bytes<D: DelayUs<u16>>(
+        &mut self,
+        delay: &mut D,
+        bytes: &[u8],
+    ) -> Result<(), Error<E>> {
+        for &byte in bytes {
+            self.write_byte(delay, byte)?;
+        }
+        Ok(())
+    }
+
+    /// Fills `buffer` with bytes read from the bus
+    pub fn read_bytes<D: DelayUs<u16>>(
+        &mut self,
+        delay: &mut D,
+        buffer: &mut [u8],
+    ) -> Result<(), Error<E>> {
+        for byte in buffer {
+            *byte = self.read_byte(delay)?;
+        }
+        Ok(())
+    }
+
+    /// Resets the bus and addresses all devices (Skip ROM)
+    ///
+    /// Returns `false`, if no device is present.
+    pub fn skip_rom<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<bool, Error<E>> {
+        if !self.reset(delay)? {
+            return Ok(false);
+        }
+        self.write_byte(delay, SKIP_ROM)?;
+        Ok(true)
+    }
+
+    /// Resets the bus and addresses the device with the ROM code `rom` (Match ROM)
+    ///
+    /// Returns `false`, if no device is present.
+    pub fn match_rom<D: DelayUs<u16>>(
+        &mut self,
+        delay: &mut D,
+        rom: &Rom,
+    ) -> Result<bool, Error<E>> {
+        if !self.reset(delay)? {
+            return Ok(false);
+        }
+        self.write_byte(delay, MATCH_ROM)?;
+        self.write_bytes(delay, &rom.0)?;
+        Ok(true)
+    }
+
+    /// Reads the ROM code of the only device on the bus (Read ROM)
+    ///
+    /// Returns `None`, if no device is present. If there's more than one
+    /// device, the result is most likely `Error::CrcMismatch`.
+    pub fn read_rom<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<Option<Rom>, Error<E>> {
+        if !self.reset(delay)? {
+            return Ok(None);
+        }
+        self.write_byte(delay, READ_ROM)?;
+
+        let mut rom = Rom([0; 8]);
+        self.read_bytes(delay, &mut rom.0)?;
+        if !rom.is_valid() {
+            return Err(Error::CrcMismatch);
+        }
+        Ok(Some(rom))
+    }
+
+    /// Finds the next device on the bus (Search ROM)
+    ///
+    /// Returns `None`, once all devices have been found. Pass the same
+    /// `search` to every call, and start with [`Search::new`] to find all
+    /// devices again.
+    pub fn search<D: DelayUs<u16>>(
+        &mut self,
+        search: &mut Search,
+        delay: &mut D,
+    ) -> Result<Option<Rom>, Error<E>> {
+        if search.done {
+            return Ok(None);
+        }
+        if !self.reset(delay)? {
+            *search = Search::new();
+            return Ok(None);
+        }
+        self.write_byte(delay, SEARCH_ROM)?;
+
+        // Bit positions are counted from 1, so 0 can be used for "none"
+        let mut last_zero = 0;
+        for position in 1..=64 {
+            let i = position - 1;
+            let id_bit = self.read_bit(delay)?;
+            let complement = self.read_bit(delay)?;
+
+            let direction = match (id_bit, complement) {
+                // No device responded
+                (true, true) => {
+                    *search = Search::new();
+                    return Ok(None);
+                }
+                // All remaining devices have the same bit here
+                (bit, _) if bit != complement => bit,
+                // Discrepancy: devices with both values are present
+                _ => {
+                    let direction = if position < search.last_discrepancy {
+                        search.bit(i)
+                    } else {
+                        position == search.last_discrepancy
+                    };
+                    if !direction {
+                        last_zero = position;
+                    }
+                    direction
+                }
+            };
+
+            search.set_bit(i, direction);
+            self.write_bit(delay, direction)?;
+        }
+
+        search.last_discrepancy = last_zero;
+        search.done = last_zero == 0;
+
+        let rom = Rom(search.rom);
+        if !rom.is_valid() {
+            *search = Search::new();
+            return Err(Error::CrcMismatch);
+        }
+        Ok(Some(rom))
+    }
+}
+
+/// Calculates the Dallas/Maxim CRC-8 of `data`
+///
+/// Used to check ROM codes and the scratchpad contents of many devices. The
+/// CRC over data including its CRC byte is 0.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0;
+    for &byte in data {
+        let mut byte = byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
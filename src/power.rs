@@ -0,0 +1,353 @@
+//! Power profile presets
+//!
+//! The knobs that influence current consumption are spread over several
+//! peripherals: the regulator and low-power modes in [`PWR`], the system clock
+//! in [`Rcc`], the flash power options, and the GPIO configuration. This module
+//! bundles them into a small set of presets, so applications can pick a
+//! profile instead of reverse-engineering the right combination from the
+//! reference manual.
+//!
+//! The current figures in the documentation of [`Profile`] are typical values
+//! from the STM32L0x1/L0x2/L0x3 datasheets (25 °C, V_DD = 3 V). They're meant
+//! to give an idea of the order of magnitude; the actual consumption depends on
+//! the device, the enabled peripherals, and the board. Note that the presets
+//! can't do anything about GPIOs: to actually get close to the datasheet
+//! figures, configure all unused pins as analog inputs and disable the clocks
+//! of unused GPIO ports.
+//!
+//! [`PWR`]: crate::pwr::PWR
+//! [`Rcc`]: crate::rcc::Rcc
+
+use cortex_m::peripheral::SCB;
+
+#[cfg(any(
+    feature = "eeprom-128",
+    feature = "eeprom-256",
+    feature = "eeprom-512",
+    feature = "eeprom-1024",
+    feature = "eeprom-2048",
+    feature = "eeprom-3072",
+    feature = "eeprom-6144",
+))]
+use crate::flash::{FlashPowerProfile, FLASH};
+use crate::{
+    adc::{Adc, Ready},
+    hal::blocking::delay::DelayUs,
+    pwr::{BorLevel, PowerMode as _, PvdState, StopModeConfig, PWR},
+    rcc::Rcc,
+};
+
+/// A power profile preset
+///
+/// See [`Profile::apply`] for how the presets are applied, and
+/// [`Profile::availability`] for what still works in each of them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Profile {
+    /// Run mode
+    ///
+    /// Everything is available. Leaves low-power run mode and switches off
+    /// ultra-low-power mode, so VREFINT stays on in all low-power modes.
+    ///
+    /// Typical current: down to 76 µA/MHz in Vcore range 3, up to roughly
+    /// 140 µA/MHz in range 1 at 32 MHz.
+    Run,
+
+    /// Low-power run mode
+    ///
+    /// The CPU keeps running from MSI range 0 or 1 (at most 131.072 kHz), with
+    /// the regulator in low-power mode. Requires the clocks to be configured
+    /// accordingly.
+    ///
+    /// Typical current: a few µA at 32–131 kHz.
+    LowPowerRun,
+
+    /// Sleep mode
+    ///
+    /// The CPU is stopped until an interrupt occurs. All clocks and
+    /// peripherals keep running, so DMA transfers continue.
+    ///
+    /// Typical current: roughly a quarter to a third of the Run mode figure at
+    /// the same frequency.
+    Sleep,
+
+    /// Low-power sleep mode
+    ///
+    /// Like [`Profile::Sleep`], but with the regulator in low-power mode and
+    /// VREFINT switched off. Requires the same clock configuration as
+    /// [`Profile::LowPowerRun`].
+    ///
+    /// Typical current: a few µA, lower when the flash is powered down.
+    LowPowerSleep,
+
+    /// Stop mode
+    ///
+    /// All high-speed clocks are stopped, SRAM and registers are retained.
+    /// LSE/LSI keep running, so the RTC, IWDG, LPTIM, and LPUART can wake the
+    /// MCU up. VREFINT is switched off and fast wakeup is enabled.
+    ///
+    /// Typical current: 0.4 µA, or 0.8 µA with the RTC running.
+    Stop,
+
+    /// Standby mode
+    ///
+    /// Everything except the RTC, IWDG, and the wakeup pins is powered down.
+    /// SRAM and register contents are lost, and the MCU resets on wakeup.
+    ///
+    /// Typical current: 0.27 µA, or 0.65 µA with the RTC running.
+    Standby,
+}
+
+impl Profile {
+    /// Returns what is available while this profile is active
+    pub const fn availability(self) -> Availability {
+        match self {
+            Profile::Run => Availability {
+                cpu: true,
+                flash: true,
+                high_speed_clocks: true,
+                bus_peripherals: true,
+                low_power_peripherals: true,
+                rtc: true,
+                vrefint: true,
+                retention: true,
+            },
+            Profile::LowPowerRun => Availability {
+                cpu: true,
+                flash: true,
+                high_speed_clocks: false,
+                bus_peripherals: true,
+                low_power_peripherals: true,
+                rtc: true,
+                vrefint: true,
+                retention: true,
+            },
+            Profile::Sleep => Availability {
+                cpu: false,
+                flash: true,
+                high_speed_clocks: true,
+                bus_peripherals: true,
+                low_power_peripherals: true,
+                rtc: true,
+                vrefint: true,
+                retention: true,
+            },
+            Profile::LowPowerSleep => Availability {
+                cpu: false,
+                // Unless powered down by `apply_with_flash`
+                flash: true,
+                high_speed_clocks: false,
+                bus_peripherals: true,
+                low_power_peripherals: true,
+                rtc: true,
+                vrefint: false,
+                retention: true,
+            },
+            Profile::Stop => Availability {
+                cpu: false,
+                flash: false,
+                high_speed_clocks: false,
+                bus_peripherals: false,
+                low_power_peripherals: true,
+                rtc: true,
+                vrefint: false,
+                retention: true,
+            },
+            Profile::Standby => Availability {
+                cpu: false,
+                flash: false,
+                high_speed_clocks: false,
+                bus_peripherals: false,
+                low_power_peripherals: false,
+                rtc: true,
+                vrefint: false,
+                retention: false,
+            },
+        }
+    }
+
+    /// Applies the profile
+    ///
+    /// [`Profile::Run`] and [`Profile::LowPowerRun`] only reconfigure the
+    /// regulator and return immediately. All other profiles enter the
+    /// respective low-power mode and block until something wakes the
+    /// microcontroller up again, so please make sure to configure a wakeup
+    /// source first, or this could block forever. [`Profile::Standby`] never
+    /// returns, as the microcontroller resets on wakeup.
+    ///
+    /// Returns the [`Availability`] of the applied profile.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if [`Profile::LowPowerRun`] or [`Profile::LowPowerSleep`] is
+    /// applied while the system clock is faster than 131.072 kHz, or if
+    /// [`Profile::Stop`] is applied while running from an external clock.
+    /// See [`PWR::enter_low_power_run_mode`], [`PWR::low_power_sleep_mode`],
+    /// and [`PWR::stop_mode`].
+    pub fn apply(self, pwr: &mut PWR, scb: &mut SCB, rcc: &mut Rcc) -> Availability {
+        match self {
+            Profile::Run => {
+                pwr.exit_low_power_run_mode();
+                pwr.set_ultra_low_power(false);
+                pwr.set_fast_wakeup(false);
+            }
+            Profile::LowPowerRun => {
+                pwr.enter_low_power_run_mode(rcc.clocks);
+            }
+            Profile::Sleep => {
+                pwr.sleep_mode(scb).enter();
+            }
+            Profile::LowPowerSleep => {
+                pwr.set_ultra_low_power(true);
+                pwr.set_fast_wakeup(true);
+                pwr.low_power_sleep_mode(scb, rcc).enter();
+            }
+            Profile::Stop => {
+                pwr.set_fast_wakeup(true);
+                pwr.stop_mode(
+                    scb,
+                    rcc,
+                    StopModeConfig {
+                        ultra_low_power: true,
+                    },
+                )
+                .enter();
+            }
+            Profile::Standby => {
+                pwr.set_ultra_low_power(true);
+                pwr.set_fast_wakeup(true);
+                pwr.standby_mode(scb).enter();
+            }
+        }
+
+        self.availability()
+    }
+
+    /// Applies the profile, including the flash power options
+    ///
+    /// Configures the flash according to [`Profile::flash_profile`], then
+    /// calls [`Profile::apply`]. In contrast to [`Profile::apply`], this powers
+    /// the flash down in [`Profile::LowPowerSleep`], which is reflected in the
+    /// returned [`Availability`].
+    #[cfg(any(
+        feature = "eeprom-128",
+        feature = "eeprom-256",
+        feature = "eeprom-512",
+        feature = "eeprom-1024",
+        feature = "eeprom-2048",
+        feature = "eeprom-3072",
+        feature = "eeprom-6144",
+    ))]
+    pub fn apply_with_flash(
+        self,
+        pwr: &mut PWR,
+        scb: &mut SCB,
+        rcc: &mut Rcc,
+        flash: &mut FLASH,
+    ) -> Availability {
+        flash.set_power_profile(self.flash_profile());
+        let availability = self.apply(pwr, scb, rcc);
+
+        Availability {
+            flash: availability.flash && self != Profile::LowPowerSleep,
+            ..availability
+        }
+    }
+
+    /// Returns the flash power options that match this profile
+    ///
+    /// Run mode uses the fastest options, while the low-power profiles power
+    /// down the flash in Sleep mode. Plain Sleep mode keeps the flash powered,
+    /// so wakeup stays fast.
+    #[cfg(any(
+        feature = "eeprom-128",
+        feature = "eeprom-256",
+        feature = "eeprom-512",
+        feature = "eeprom-1024",
+        feature = "eeprom-2048",
+        feature = "eeprom-3072",
+        feature = "eeprom-6144",
+    ))]
+    pub fn flash_profile(self) -> FlashPowerProfile {
+        match self {
+            Profile::Run => FlashPowerProfile::Performance,
+            Profile::Sleep => FlashPowerProfile::Balanced,
+            Profile::LowPowerRun | Profile::LowPowerSleep | Profile::Stop | Profile::Standby => {
+                FlashPowerProfile::LowPower
+            }
+        }
+    }
+}
+
+/// Describes what is available while a [`Profile`] is active
+///
+/// Returned by [`Profile::apply`] and [`Profile::availability`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Availability {
+    /// The CPU executes code
+    pub cpu: bool,
+
+    /// The flash is powered and can be read, for example by DMA
+    pub flash: bool,
+
+    /// HSI16, HSE, and the PLL can run
+    pub high_speed_clocks: bool,
+
+    /// Peripherals clocked from AHB/APB keep running
+    pub bus_peripherals: bool,
+
+    /// LPTIM, LPUART, and the I2C wakeup logic keep running from LSE/LSI/HSI16
+    pub low_power_peripherals: bool,
+
+    /// The RTC and the IWDG keep running
+    pub rtc: bool,
+
+    /// VREFINT, and with it the ADC, comparators, BOR, and PVD, are available
+    pub vrefint: bool,
+
+    /// SRAM and register contents are retained
+    pub retention: bool,
+}
+
+/// A snapshot of the supply voltage and the supply monitors
+///
+/// Combines a V_DD measurement with the brown-out reset threshold and the
+/// state of the programmable voltage detector. This can be used to reduce the
+/// load or save state before the battery is drained far enough to trigger a
+/// brown-out reset.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PowerStatus {
+    /// The supply voltage, in millivolts
+    ///
+    /// Measured as V_DDA, which is connected to V_DD on most boards.
+    pub vdd_mv: u16,
+
+    /// The brown-out reset threshold configured in the option bytes
+    pub bor_level: BorLevel,
+
+    /// The state of the programmable voltage detector
+    pub pvd_state: PvdState,
+}
+
+impl PowerStatus {
+    /// Takes a snapshot of the current power status
+    ///
+    /// Uses the ADC to measure the supply voltage, see [`Adc::read_vdda`].
+    pub fn read<D>(pwr: &PWR, adc: &mut Adc<Ready>, delay: &mut D) -> nb::Result<Self, ()>
+    where
+        D: DelayUs<u8>,
+    {
+        Ok(Self {
+            vdd_mv: adc.read_vdda(delay)?,
+            bor_level: BorLevel::read(),
+            pvd_state: pwr.pvd_state(),
+        })
+    }
+
+    /// Returns the margin between the supply voltage and the brown-out reset
+    /// threshold, in millivolts
+    ///
+    /// Returns 0, if the supply voltage is at or below the threshold.
+    pub fn bor_margin_mv(&self) -> u16 {
+        self.vdd_mv.saturating_sub(self.bor_level.threshold_mv())
+    }
+}
@@ -1,13 +1,17 @@
+use crate::dma::{self, Buffer as _};
+use crate::exti::{Exti, ExtiLine};
 use crate::gpio::gpioa::{PA0, PA1, PA2, PA3};
 use crate::gpio::{
     gpioa::{PA15, PA5},
     gpiob::PB3,
 };
-use crate::gpio::{AltMode, PinMode};
+use crate::gpio::{AltMode, PinMode, PinState};
 use crate::hal;
 use crate::pac::{tim2, TIM2, TIM3};
 use crate::rcc::{Enable, Rcc, Reset};
-use cast::{u16, u32};
+use crate::time::IntoHertz;
+use crate::timer::get_clock_config;
+use as_slice::AsSlice;
 use core::marker::PhantomData;
 use core::ops::Deref;
 use cortex_m::interrupt;
@@ -42,8 +46,8 @@ where
     I: Instance,
 {
     /// Create new timer instance that is automatically started with given frequency
-    pub fn new(timer: I, frequency: impl Into<Hertz>, rcc: &mut Rcc) -> Self {
-        let frequency = frequency.into();
+    pub fn new(timer: I, frequency: impl IntoHertz, rcc: &mut Rcc) -> Self {
+        let frequency = frequency.into_hertz();
 
         I::enable(rcc);
         I::reset(rcc);
@@ -61,12 +65,54 @@ where
 
     /// Starts the PWM timer
     pub fn start(&mut self) {
-        self.instance.cr1.write(|w| w.cen().set_bit());
+        self.instance.cr1.modify(|_, w| w.cen().set_bit());
     }
 
     /// Stops the PWM timer
     pub fn stop(&mut self) {
-        self.instance.cr1.write(|w| w.cen().clear_bit());
+        self.instance.cr1.modify(|_, w| w.cen().clear_bit());
+    }
+
+    /// Enables or disables one-pulse mode
+    ///
+    /// In one-pulse mode, the counter stops at the end of the period, so every
+    /// call to [`Timer::start`] produces a single pulse on the enabled
+    /// channels. The channels then use PWM mode 2: a channel becomes active
+    /// when the counter reaches its duty cycle, and inactive at the end of the
+    /// period. So the duty cycle sets the delay before the pulse, and the pulse
+    /// lasts for the rest of the period.
+    ///
+    /// Stops the timer.
+    pub fn set_one_pulse_mode(&mut self, enable: bool) {
+        self.stop();
+        self.instance.cr1.modify(|_, w| w.opm().bit(enable));
+        let mode = if enable { Mode::Mode2 } else { Mode::Mode1 };
+        C1::set_mode(&self.instance, mode);
+        C2::set_mode(&self.instance, mode);
+        C3::set_mode(&self.instance, mode);
+        C4::set_mode(&self.instance, mode);
+    }
+
+    /// Selects edge-aligned or center-aligned PWM
+    ///
+    /// In the center-aligned modes, the counter counts up to the auto-reload
+    /// value and back down, so the pulses of all channels are centered on the
+    /// same point in time. This reduces the current ripple in motor control
+    /// applications. As one period now takes twice as many ticks, this halves
+    /// the frequency; call [`Timer::set_frequency`] afterwards to restore it.
+    ///
+    /// The counter has to be stopped to change the alignment, so this restarts
+    /// the timer, if it was running.
+    pub fn set_alignment(&mut self, alignment: Alignment) {
+        let running = self.instance.cr1.read().cen().bit_is_set();
+        self.stop();
+        #[allow(unused_unsafe)]
+        self.instance
+            .cr1
+            .modify(|_, w| unsafe { w.cms().bits(alignment as u8) });
+        if running {
+            self.start();
+        }
     }
 
     /// Update frequency of the timer
@@ -74,26 +120,339 @@ where
     /// In order to do this operation properly the function stop the timer and then starts it again.
     /// The duty cycle that was set before for given pin needs to adjusted according to the
     /// frequency
-    pub fn set_frequency(&mut self, frequency: impl Into<Hertz>, rcc: &Rcc) {
-        let frequency = frequency.into();
+    pub fn set_frequency(&mut self, frequency: impl IntoHertz, rcc: &Rcc) {
+        let frequency = frequency.into_hertz();
         self.stop();
-        let (psc, arr) = get_clock_config(frequency.0, I::clock_frequency(rcc));
+        let (psc, arr) = clock_config(&self.instance, frequency, I::clock_frequency(rcc));
         self.instance.psc.write(|w| w.psc().bits(psc));
         self.instance.arr.write(|w| w.arr().bits(arr));
         self.start();
     }
 
+    /// Updates the frequency of the timer at the next update event
+    ///
+    /// Unlike [`Timer::set_frequency`], this doesn't stop the counter. The new
+    /// prescaler and auto-reload values are buffered in the preload registers
+    /// and take effect when the current period ends, so the outputs don't
+    /// glitch. This makes it suitable for frequency sweeps.
+    ///
+    /// The duty cycles are relative to the auto-reload value, so they need to
+    /// be adjusted according to the new frequency. The compare registers are
+    /// preloaded as well, so duty cycles set before the update event take
+    /// effect together with the new frequency.
+    pub fn set_frequency_buffered(&mut self, frequency: impl IntoHertz, rcc: &Rcc) {
+        let frequency = frequency.into_hertz();
+        let (psc, arr) = clock_config(&self.instance, frequency, I::clock_frequency(rcc));
+        self.set_clock_config_buffered(psc, arr);
+    }
+
+    /// Writes prescaler and auto-reload value, to be applied at the next
+    /// update event
+    pub(crate) fn set_clock_config_buffered(&mut self, psc: u16, arr: u16) {
+        // Disable update events while writing, so the period can't end between
+        // the two writes and apply the new prescaler with the old auto-reload
+        // value.
+        self.instance
+            .cr1
+            .modify(|_, w| w.arpe().set_bit().udis().set_bit());
+        self.instance.psc.write(|w| w.psc().bits(psc));
+        self.instance.arr.write(|w| w.arr().bits(arr));
+        self.instance.cr1.modify(|_, w| w.udis().clear_bit());
+    }
+
+    /// Updates the auto-reload value at the next update event, keeping the
+    /// prescaler
+    ///
+    /// `period` is in ticks of the prescaled timer clock. See
+    /// [`Timer::set_frequency_buffered`] for how the update is applied.
+    pub fn set_period_buffered(&mut self, period: u16) {
+        self.instance.cr1.modify(|_, w| w.arpe().set_bit());
+        self.instance.arr.write(|w| w.arr().bits(period));
+    }
+
+    /// Starts listening for an interrupt event
+    pub fn listen(&mut self, event: Event) {
+        self.instance.dier.modify(|_, w| match event {
+            Event::Update => w.uie().set_bit(),
+            Event::Compare1 => w.cc1ie().set_bit(),
+            Event::Compare2 => w.cc2ie().set_bit(),
+            Event::Compare3 => w.cc3ie().set_bit(),
+            Event::Compare4 => w.cc4ie().set_bit(),
+        });
+    }
+
+    /// Stops listening for an interrupt event
+    pub fn unlisten(&mut self, event: Event) {
+        self.instance.dier.modify(|_, w| match event {
+            Event::Update => w.uie().clear_bit(),
+            Event::Compare1 => w.cc1ie().clear_bit(),
+            Event::Compare2 => w.cc2ie().clear_bit(),
+            Event::Compare3 => w.cc3ie().clear_bit(),
+            Event::Compare4 => w.cc4ie().clear_bit(),
+        });
+    }
+
+    /// Returns `true`, if the flag of the given event is set
+    ///
+    /// The flags are set by hardware, whether the interrupt is enabled or not.
+    pub fn is_pending(&self, event: Event) -> bool {
+        self.instance.sr.read().bits() & event.mask() != 0
+    }
+
+    /// Clears the interrupt flag of the given event
+    pub fn clear_irq(&mut self, event: Event) {
+        // The flags are cleared by writing 0, writing 1 has no effect
+        self.instance.sr.write(|w| unsafe { w.bits(!event.mask()) });
+    }
+
+    /// Forces the reference signal of all channels to `level` and stops the
+    /// counter
+    ///
+    /// All channels are switched to forced output mode at once, so no channel
+    /// keeps running while the others are already stopped. Channels that are
+    /// disabled stay disabled.
+    ///
+    /// `level` is the level of the channels' reference signal (OCxREF). The
+    /// output polarity is still applied, so the pin of a channel with
+    /// inverted polarity is driven to the opposite level.
+    ///
+    /// This is an associated function, so it can be called from an interrupt
+    /// handler (for example for an overcurrent signal), without access to the
+    /// `Timer` or its channels. Pass the returned [`OutputModes`] to
+    /// [`Timer::resume`] to return to normal operation.
+    pub fn emergency_stop(level: PinState) -> OutputModes {
+        let mode = match level {
+            PinState::High => 0b101,
+            PinState::Low => 0b100,
+        };
+
+        interrupt::free(|_| {
+            // Safe, as the read-modify-writes are within the critical section
+            let tim = unsafe { &*I::ptr() };
+
+            let modes = OutputModes {
+                ccmr1: tim.ccmr1_output().read().bits() & OutputModes::MASK,
+                ccmr2: tim.ccmr2_output().read().bits() & OutputModes::MASK,
+            };
+
+            tim.ccmr1_output()
+                .modify(|_, w| w.oc1m().bits(mode).oc2m().bits(mode));
+            tim.ccmr2_output()
+                .modify(|_, w| w.oc3m().bits(mode).oc4m().bits(mode));
+            tim.cr1.modify(|_, w| w.cen().clear_bit());
+
+            modes
+        })
+    }
+
+    /// Returns to normal operation after [`Timer::emergency_stop`]
+    ///
+    /// Switches all channels back to the output modes they had before the
+    /// stop, and restarts the counter.
+    pub fn resume(modes: OutputModes) {
+        interrupt::free(|_| {
+            // Safe, as the read-modify-writes are within the critical section
+            let tim = unsafe { &*I::ptr() };
+
+            tim.ccmr1_output()
+                .modify(|r, w| unsafe { w.bits(r.bits() & !OutputModes::MASK | modes.ccmr1) });
+            tim.ccmr2_output()
+                .modify(|r, w| unsafe { w.bits(r.bits() & !OutputModes::MASK | modes.ccmr2) });
+            tim.cr1.modify(|_, w| w.cen().set_bit());
+        })
+    }
+
     /// Returns the timer, so it can be used by any else
     pub fn free(self) -> I {
         self.instance
     }
 }
 
-fn get_clock_config(freq: u32, clk: u32) -> (u16, u16) {
-    let ticks = clk / freq;
-    let psc = u16((ticks - 1) / (1 << 16)).unwrap();
-    let arr = u16(ticks / u32(psc + 1)).unwrap();
-    (psc, arr)
+/// Source of the OCREF clear signal of TIM2
+///
+/// The signal is routed to the external trigger input (ETR) of TIM2. While it
+/// is high, the outputs of all channels with OCREF clear enabled are forced
+/// low, until the next update event. This limits the current cycle by cycle,
+/// if the signal is a comparator watching a current sense resistor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OcrefClearSource {
+    /// The ETR pin, which needs to be set up using [`EtrPin`]
+    ///
+    /// [`EtrPin`]: crate::timer::EtrPin
+    EtrPin = 0b000,
+    /// COMP2 output
+    Comp2 = 0b100,
+    /// COMP1 output
+    Comp1 = 0b101,
+}
+
+impl Timer<TIM2> {
+    /// Selects the source of the OCREF clear signal
+    ///
+    /// Enable OCREF clear for individual channels using
+    /// [`Pwm::set_ocref_clear`].
+    pub fn set_ocref_clear_source(&mut self, source: OcrefClearSource) {
+        #[allow(unused_unsafe)]
+        self.instance
+            .or
+            .modify(|_, w| unsafe { w.etr_rmp().bits(source as u8) });
+
+        // Active high, no prescaler, no filter. The signal needs to act
+        // within the current PWM cycle, so it can't be filtered.
+        //
+        // OCCS connects OCREF_CLR_INT to ETRF instead of the OCREF_CLR input,
+        // which isn't routed anywhere on TIM2. See "Clearing the OCxREF signal
+        // on an external event" in the general-purpose timer chapter of
+        // RM0377.
+        self.instance.smcr.modify(|_, w| {
+            w.occs().set_bit();
+            w.etp().clear_bit();
+            w.etps().bits(0b00);
+            w.etf().bits(0b0000)
+        });
+    }
+}
+
+/// The output modes of all channels, saved by [`Timer::emergency_stop`]
+///
+/// Pass it to [`Timer::resume`] to restore them.
+#[derive(Debug, Copy, Clone)]
+pub struct OutputModes {
+    ccmr1: u32,
+    ccmr2: u32,
+}
+
+impl OutputModes {
+    /// The OCxM fields of both channels in CCMR1 and CCMR2
+    const MASK: u32 = 0x7070;
+}
+
+/// Fault input that stops a PWM timer
+///
+/// Forces all channels of the PWM timer `I` to a safe level, using
+/// [`Timer::emergency_stop`], as soon as the EXTI line `L` is triggered, for
+/// example by an overcurrent signal on a GPIO pin or by a comparator. The
+/// fault is latched until it is cleared using [`FaultInput::rearm`].
+///
+/// The EXTI line must be configured separately, using the [`Exti`] API, and
+/// its interrupt must be unmasked in the NVIC. [`FaultInput::on_interrupt`]
+/// must be called from the interrupt handler, which is what actually stops
+/// the timer.
+///
+/// ``` ignore
+/// let line = GpioLine::from_raw_line(pb4.pin_number()).unwrap();
+/// exti.listen_gpio(&mut syscfg, pb4.port(), line, TriggerEdge::Rising);
+///
+/// bind_interrupt!(EXTI4_15 => on_fault(FAULT: FaultInput<TIM2, GpioLine>));
+///
+/// fn on_fault(fault: &mut FaultInput<TIM2, GpioLine>) {
+///     fault.on_interrupt();
+/// }
+///
+/// FAULT.put(FaultInput::new(line, PinState::Low));
+/// ```
+pub struct FaultInput<I, L> {
+    line: L,
+    level: PinState,
+    /// The output modes before the fault, if a fault has occurred
+    modes: Option<OutputModes>,
+    timer: PhantomData<I>,
+}
+
+impl<I, L> FaultInput<I, L>
+where
+    I: Instance,
+    L: ExtiLine + Copy,
+{
+    /// Creates a new fault input, forcing the channels to `level` on a fault
+    pub fn new(line: L, level: PinState) -> Self {
+        FaultInput {
+            line,
+            level,
+            modes: None,
+            timer: PhantomData,
+        }
+    }
+
+    /// Handles the EXTI interrupt
+    ///
+    /// If the fault line is pending, stops the PWM timer, latches the fault,
+    /// and clears the pending line. Returns `true` in that case. Returns
+    /// `false` if the interrupt was caused by another line sharing the same
+    /// interrupt handler.
+    pub fn on_interrupt(&mut self) -> bool {
+        if !Exti::is_pending(self.line) {
+            return false;
+        }
+
+        let modes = Timer::<I>::emergency_stop(self.level);
+        // If the fault was already latched, the channels are in forced mode
+        // now, so keep the modes from before the first fault.
+        self.modes.get_or_insert(modes);
+        Exti::unpend(self.line);
+
+        true
+    }
+
+    /// Returns `true`, if a fault has occurred since the last re-arm
+    pub fn is_faulted(&self) -> bool {
+        self.modes.is_some()
+    }
+
+    /// Clears the latched fault and resumes PWM operation
+    ///
+    /// Make sure the cause of the fault has been removed before calling this,
+    /// as only a new edge on the fault line triggers the fault again. Returns
+    /// `false` without resuming, if the fault line has been triggered again
+    /// in the meantime.
+    pub fn rearm(&mut self) -> bool {
+        // Don't resume with a fault that hasn't been handled yet
+        if Exti::is_pending(self.line) {
+            self.on_interrupt();
+            return false;
+        }
+
+        if let Some(modes) = self.modes.take() {
+            Timer::<I>::resume(modes);
+        }
+        true
+    }
+
+    /// Releases the EXTI line
+    pub fn free(self) -> L {
+        self.line
+    }
+}
+
+/// PWM timer interrupt event
+///
+/// Duty cycle changes take effect on the next update event, so listening for it
+/// allows updates to be synchronized with the PWM period.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The counter has reached the end of the PWM period
+    Update,
+    /// The counter has matched the duty cycle of channel 1
+    Compare1,
+    /// The counter has matched the duty cycle of channel 2
+    Compare2,
+    /// The counter has matched the duty cycle of channel 3
+    Compare3,
+    /// The counter has matched the duty cycle of channel 4
+    Compare4,
+}
+
+impl Event {
+    /// Bit mask of the event in `SR`
+    fn mask(self) -> u32 {
+        match self {
+            Event::Update => 1 << 0,
+            Event::Compare1 => 1 << 1,
+            Event::Compare2 => 1 << 2,
+            Event::Compare3 => 1 << 3,
+            Event::Compare4 => 1 << 4,
+        }
+    }
 }
 
 pub trait Instance: Deref<Target = tim2::RegisterBlock> + Enable + Reset {
@@ -132,6 +491,58 @@ pub trait Channel {
     fn enable(_: &tim2::RegisterBlock);
     fn get_duty(_: &tim2::RegisterBlock) -> u16;
     fn set_duty(_: &tim2::RegisterBlock, duty: u16);
+    fn set_ocref_clear(_: &tim2::RegisterBlock, enable: bool);
+    fn set_mode(_: &tim2::RegisterBlock, mode: Mode);
+    fn set_polarity(_: &tim2::RegisterBlock, polarity: Polarity);
+    fn ccr_address(_: &tim2::RegisterBlock) -> u32;
+}
+
+/// Calculates prescaler and auto-reload value for a PWM frequency
+///
+/// In the center-aligned modes, the counter counts up and down during one
+/// period, so it needs to run at twice the frequency.
+fn clock_config(tim: &tim2::RegisterBlock, frequency: Hertz, clk: u32) -> (u16, u16) {
+    let center_aligned = tim.cr1.read().cms().bits() != Alignment::Edge as u8;
+    let frequency = if center_aligned {
+        frequency.0 * 2
+    } else {
+        frequency.0
+    };
+    get_clock_config(frequency, clk)
+}
+
+/// Counter alignment of a PWM timer (`TIMx_CR1.CMS`)
+///
+/// The center-aligned modes only differ in when the compare interrupt flags
+/// are set: while counting down, while counting up, or both.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Alignment {
+    /// The counter counts up, the outputs change at the end of the period
+    Edge = 0b00,
+    /// Center-aligned, compare flags are set while counting down
+    Center1 = 0b01,
+    /// Center-aligned, compare flags are set while counting up
+    Center2 = 0b10,
+    /// Center-aligned, compare flags are set while counting up and down
+    Center3 = 0b11,
+}
+
+/// PWM mode of a channel (`TIMx_CCMRx.OCxM`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    /// The channel is active while the counter is below the duty cycle
+    Mode1 = 0b110,
+    /// The channel is active while the counter is at or above the duty cycle
+    Mode2 = 0b111,
+}
+
+/// Output polarity of a channel (`TIMx_CCER.CCxP`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Polarity {
+    /// The output is high while the channel is active
+    ActiveHigh,
+    /// The output is low while the channel is active
+    ActiveLow,
 }
 
 macro_rules! impl_channel {
@@ -139,9 +550,11 @@ macro_rules! impl_channel {
         $(
             $name:ident,
             $ccxe:ident,
+            $ccxp:ident,
             $ccmr_output:ident,
             $ocxpe:ident,
             $ocxm:ident,
+            $ocxce:ident,
             $ccrx:ident;
         )*
     ) => {
@@ -154,9 +567,14 @@ macro_rules! impl_channel {
                 }
 
                 fn enable(tim: &tim2::RegisterBlock) {
-                    tim.$ccmr_output().modify(|_, w| {
+                    tim.$ccmr_output().modify(|r, w| {
                         w.$ocxpe().set_bit();
-                        w.$ocxm().bits(0b110)
+                        // Keep PWM mode 2, if it has been selected before.
+                        if r.$ocxm().bits() == Mode::Mode2 as u8 {
+                            w
+                        } else {
+                            w.$ocxm().bits(Mode::Mode1 as u8)
+                        }
                     });
                     tim.ccer.modify(|_, w| w.$ccxe().set_bit());
                 }
@@ -172,16 +590,32 @@ macro_rules! impl_channel {
                 fn set_duty(tim: &tim2::RegisterBlock, duty: u16) {
                     tim.$ccrx.write(|w| w.ccr().bits(duty.into()));
                 }
+
+                fn set_ocref_clear(tim: &tim2::RegisterBlock, enable: bool) {
+                    tim.$ccmr_output().modify(|_, w| w.$ocxce().bit(enable));
+                }
+
+                fn set_mode(tim: &tim2::RegisterBlock, mode: Mode) {
+                    tim.$ccmr_output().modify(|_, w| w.$ocxm().bits(mode as u8));
+                }
+
+                fn set_polarity(tim: &tim2::RegisterBlock, polarity: Polarity) {
+                    tim.ccer.modify(|_, w| w.$ccxp().bit(polarity == Polarity::ActiveLow));
+                }
+
+                fn ccr_address(tim: &tim2::RegisterBlock) -> u32 {
+                    &tim.$ccrx as *const _ as u32
+                }
             }
         )*
     }
 }
 
 impl_channel!(
-    C1, cc1e, ccmr1_output, oc1pe, oc1m, ccr1;
-    C2, cc2e, ccmr1_output, oc2pe, oc2m, ccr2;
-    C3, cc3e, ccmr2_output, oc3pe, oc3m, ccr3;
-    C4, cc4e, ccmr2_output, oc4pe, oc4m, ccr4;
+    C1, cc1e, cc1p, ccmr1_output, oc1pe, oc1m, oc1ce, ccr1;
+    C2, cc2e, cc2p, ccmr1_output, oc2pe, oc2m, oc2ce, ccr2;
+    C3, cc3e, cc3p, ccmr2_output, oc3pe, oc3m, oc3ce, ccr3;
+    C4, cc4e, cc4p, ccmr2_output, oc4pe, oc4m, oc4ce, ccr4;
 );
 
 pub struct Pwm<I, C, State> {
@@ -258,15 +692,230 @@ where
     ///
     /// **WARNING:**
     /// This changes the frequency for all channels associated with the PWM timer.
-    pub fn set_frequency(&mut self, frequency: Hertz, rcc: &Rcc) {
-        let (psc, arr) = get_clock_config(frequency.0, I::clock_frequency(rcc));
+    pub fn set_frequency(&mut self, frequency: impl IntoHertz, rcc: &Rcc) {
+        let frequency = frequency.into_hertz();
+        // Safe, as we're only doing an atomic read.
+        let (psc, arr) = clock_config(unsafe { &*I::ptr() }, frequency, I::clock_frequency(rcc));
         unsafe {
             (*I::ptr()).psc.write(|w| w.psc().bits(psc));
             (*I::ptr()).arr.write(|w| w.arr().bits(arr));
         }
     }
+
+    /// Enables or disables OCREF clear for this channel
+    ///
+    /// If enabled, the output is forced low while the OCREF clear signal is
+    /// high, until the next update event. See [`OcrefClearSource`].
+    pub fn set_ocref_clear(&mut self, enable: bool) {
+        interrupt::free(|_|
+            // Safe, as the read-modify-write within the critical section
+            C::set_ocref_clear(unsafe { &*I::ptr() }, enable))
+    }
+
+    /// Selects the PWM mode of this channel
+    ///
+    /// Channels start out in [`Mode::Mode1`]. The timers of this family don't
+    /// have complementary outputs, but driving a second channel with the same
+    /// duty cycle in [`Mode::Mode2`] gives the inverted signal, without dead
+    /// time.
+    pub fn set_mode(&mut self, mode: Mode) {
+        interrupt::free(|_|
+            // Safe, as the read-modify-write within the critical section
+            C::set_mode(unsafe { &*I::ptr() }, mode))
+    }
+
+    /// Selects the output polarity of this channel
+    pub fn set_polarity(&mut self, polarity: Polarity) {
+        interrupt::free(|_|
+            // Safe, as the read-modify-write within the critical section
+            C::set_polarity(unsafe { &*I::ptr() }, polarity))
+    }
+
+    /// Streams duty cycles from `buffer` to this channel, using DMA
+    ///
+    /// On every update event of the timer, the DMA writes the next value from
+    /// the buffer into the compare register of the channel. As the compare
+    /// registers are preloaded, each value takes effect one period after it
+    /// has been written. This plays back arbitrary waveforms without CPU
+    /// involvement, for example for audio or LED timing.
+    ///
+    /// If `circular` is `true`, the buffer is played in a loop, until the
+    /// transfer is stopped with [`Transfer::stop`]. Use
+    /// [`Transfer::next_half`] to refill one half of the buffer while the
+    /// other is being played.
+    ///
+    /// The channel is returned as the target of the finished transfer.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if the buffer is longer than 65535 words.
+    pub fn play<Channel, Buffer>(
+        self,
+        dma: &mut dma::Handle,
+        channel: Channel,
+        buffer: core::pin::Pin<Buffer>,
+        circular: bool,
+    ) -> Transfer<Self, UpdateToken<I>, Channel, Buffer, dma::Ready>
+    where
+        UpdateToken<I>: dma::Target<Channel>,
+        Channel: dma::Channel,
+        Buffer: Deref + 'static,
+        Buffer::Target: AsSlice<Element = u16>,
+    {
+        // Safe, as we're only taking the address of a register.
+        let address = C::ccr_address(unsafe { &*I::ptr() });
+        let num_words = buffer.len();
+
+        // Safe, because the trait bounds of this method guarantee that the
+        // buffer can be read from.
+        let inner = unsafe {
+            dma::Transfer::new(
+                dma,
+                UpdateToken(PhantomData),
+                channel,
+                buffer,
+                num_words,
+                address,
+                dma::Priority::high(),
+                dma::Direction::memory_to_peripheral(),
+                circular,
+            )
+        };
+
+        interrupt::free(|_|
+            // Safe, as the read-modify-write within the critical section
+            unsafe { &*I::ptr() }.dier.modify(|_, w| w.ude().set_bit()));
+
+        Transfer {
+            target: self,
+            inner,
+        }
+    }
+
+    /// Disables the channel output and releases the pin
+    ///
+    /// The pin is returned in its alternate function mode. Use one of its
+    /// `into_*` methods to turn it back into a regular GPIO.
+    pub fn unassign(self) -> (Pwm<I, C, Unassigned>, P) {
+        interrupt::free(|_|
+            // Safe, as the read-modify-write within the critical section
+            C::disable(unsafe { &*I::ptr() }));
+
+        let Assigned(pin) = self._state;
+        (Pwm::new(), pin)
+    }
+}
+/// Token used for DMA transfers
+///
+/// This is an implementation detail. The user doesn't have to deal with this
+/// directly.
+pub struct UpdateToken<I>(PhantomData<I>);
+
+/// Wrapper around a [`dma::Transfer`]
+///
+/// Returned by [`Pwm::play`].
+pub struct Transfer<Target, Token, Channel, Buffer, State> {
+    target: Target,
+    inner: dma::Transfer<Token, Channel, Buffer, State>,
+}
+
+impl<Target, I, Channel, Buffer> Transfer<Target, UpdateToken<I>, Channel, Buffer, dma::Ready>
+where
+    UpdateToken<I>: dma::Target<Channel>,
+    Channel: dma::Channel,
+{
+    /// Enables the provided interrupts
+    ///
+    /// This setting only affects this transfer. It doesn't affect transfer on
+    /// other channels, or subsequent transfers on the same channel.
+    pub fn enable_interrupts(&mut self, interrupts: dma::Interrupts) {
+        self.inner.enable_interrupts(interrupts);
+    }
+
+    /// Start the DMA transfer
+    ///
+    /// Consumes this instance of `Transfer` and returns a new one, with its
+    /// state changed to indicate that the transfer has been started.
+    pub fn start(self) -> Transfer<Target, UpdateToken<I>, Channel, Buffer, dma::Started> {
+        Transfer {
+            target: self.target,
+            inner: self.inner.start(),
+        }
+    }
+}
+
+impl<Target, I, Channel, Buffer> Transfer<Target, UpdateToken<I>, Channel, Buffer, dma::Started>
+where
+    I: Instance,
+    Channel: dma::Channel,
+{
+    /// Indicates whether the transfer is still ongoing
+    pub fn is_active(&self) -> bool {
+        self.inner.is_active()
+    }
+
+    /// Returns the half of the buffer of a circular transfer that has been
+    /// played
+    ///
+    /// See [`dma::Transfer::next_half`].
+    pub fn next_half(&self) -> nb::Result<dma::NextHalf, dma::Overrun> {
+        self.inner.next_half()
+    }
+
+    /// Waits for the transfer to finish and returns the owned resources
+    ///
+    /// This function will busily wait until the transfer is finished. If you
+    /// don't want this, please call this function only once you know that the
+    /// transfer has finished.
+    ///
+    /// This function will return immediately, if [`Transfer::is_active`]
+    /// returns `false`.
+    pub fn wait(self) -> dma::TransferResourcesResult<Target, Channel, Buffer> {
+        // Need to move `target` out of `self`, otherwise the closure captures
+        // `self` completely.
+        let target = self.target;
+
+        let map_resources = |res: dma::TransferResources<_, _, _>| dma::TransferResources {
+            target,
+            channel: res.channel,
+            buffer: res.buffer,
+        };
+
+        let result = self.inner.wait();
+        disable_update_dma::<I>();
+        match result {
+            Ok(res) => Ok(map_resources(res)),
+            Err((res, err)) => Err((map_resources(res), err)),
+        }
+    }
+
+    /// Aborts the transfer and returns the owned resources
+    ///
+    /// This is the only way to end a circular transfer. The channel keeps the
+    /// duty cycle that was written last.
+    pub fn stop(self) -> dma::TransferResources<Target, Channel, Buffer> {
+        let res = self.inner.stop();
+        disable_update_dma::<I>();
+        dma::TransferResources {
+            target: self.target,
+            channel: res.channel,
+            buffer: res.buffer,
+        }
+    }
+}
+
+/// Stops the timer from generating DMA requests on update events
+fn disable_update_dma<I: Instance>() {
+    interrupt::free(|_|
+        // Safe, as the read-modify-write within the critical section
+        unsafe { &*I::ptr() }.dier.modify(|_, w| w.ude().clear_bit()));
 }
+
+/// Pins that can be used as output of channel `C` of timer `I` (`TIMx_CHy`)
 pub trait Pin<I, C> {
+    /// Switches the pin to the alternate function of the channel
+    ///
+    /// The function is AF0, AF2, AF4, or AF5, depending on the pin.
     fn setup(&self);
 }
 
@@ -2,13 +2,34 @@
 //!
 //! See STM32L0x2 reference manual, chapter 6.
 
+use core::fmt;
+
 use cortex_m::{asm, peripheral::SCB};
 
 use crate::{
     pac,
     rcc::{ClockSrc, Clocks, Enable, PLLSource, Rcc},
+    wait_for,
 };
 
+/// PWR errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The regulator didn't finish switching the voltage range in time
+    VoltageScalingTimeout,
+    /// The wakeup flag of a previous wakeup couldn't be cleared in time
+    WakeupFlagTimeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::VoltageScalingTimeout => f.write_str("voltage scaling timeout"),
+            Error::WakeupFlagTimeout => f.write_str("wakeup flag timeout"),
+        }
+    }
+}
+
 /// Entry point to the PWR API
 pub struct PWR(pac::PWR);
 
@@ -37,18 +58,40 @@ impl PWR {
     /// Please note that switching Vcore has consequences, so please make sure
     /// you know what you're doing. See STM32L0x2 reference manual, sections
     /// 6.1.3 and following.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if the regulator doesn't become ready. See
+    /// [`PWR::try_switch_vcore_range`].
     pub fn switch_vcore_range(&mut self, range: VcoreRange) {
+        if self.try_switch_vcore_range(range).is_err() {
+            panic!("voltage scaling didn't finish");
+        }
+    }
+
+    /// Switch voltage range of internal regulator
+    ///
+    /// Returns `Error::VoltageScalingTimeout`, if the regulator doesn't
+    /// become ready.
+    pub fn try_switch_vcore_range(&mut self, range: VcoreRange) -> Result<(), Error> {
         // The STM32L0x2 reference manual, section 6.1.5 describes the procedure
         // being followed here.
 
-        while self.0.csr.read().vosf().bit_is_set() {}
+        let csr = &self.0.csr;
+        wait_for(
+            || csr.read().vosf().bit_is_clear(),
+            Error::VoltageScalingTimeout,
+        )?;
 
         // Safe, as `VcoreRange` only provides valid bit patterns.
         self.0
             .cr
             .modify(|_, w| unsafe { w.vos().bits(range as u8) });
 
-        while self.0.csr.read().vosf().bit_is_set() {}
+        wait_for(
+            || csr.read().vosf().bit_is_clear(),
+            Error::VoltageScalingTimeout,
+        )
     }
 
     /// Returns currently configured internal regulator voltage range
@@ -101,6 +144,64 @@ impl PWR {
         self.clear_lpsdsr();
     }
 
+    /// Switches off VREFINT in low-power modes (`PWR_CR.ULP`)
+    ///
+    /// When enabled, the internal voltage reference, and with it the BOR, PVD
+    /// and temperature sensor, are switched off in low-power modes. This saves
+    /// a few µA, but VREFINT needs up to 3 ms to start up again after wakeup.
+    /// See [`PWR::set_fast_wakeup`] for how that affects wakeup.
+    ///
+    /// Note that entering Stop mode using [`PWR::stop_mode`] overrides this
+    /// with [`StopModeConfig::ultra_low_power`].
+    pub fn set_ultra_low_power(&mut self, enable: bool) {
+        self.0.cr.modify(|_, w| w.ulp().bit(enable));
+    }
+
+    /// Configures fast wakeup (`PWR_CR.FWU`)
+    ///
+    /// Only has an effect, if ultra-low-power mode is enabled. When enabled,
+    /// the MCU doesn't wait for VREFINT to start up when exiting a low-power
+    /// mode, which makes wakeup a lot faster. Use [`PWR::vrefint_ready`] to
+    /// check if VREFINT (and thus the ADC, BOR, PVD and comparators) is
+    /// usable again.
+    pub fn set_fast_wakeup(&mut self, enable: bool) {
+        self.0.cr.modify(|_, w| w.fwu().bit(enable));
+    }
+
+    /// Returns `true`, if the internal voltage reference is ready
+    pub fn vrefint_ready(&self) -> bool {
+        self.0.csr.read().vrefintrdyf().bit_is_set()
+    }
+
+    /// Enables the programmable voltage detector (PVD)
+    ///
+    /// The PVD compares V_DD against `level`. Use [`PWR::pvd_state`] to read
+    /// the result. The PVD output is also connected to EXTI line 16, which can
+    /// generate an interrupt when V_DD crosses the threshold.
+    pub fn enable_pvd(&mut self, level: PvdLevel) {
+        // Safe, as `PvdLevel` only provides valid bit patterns.
+        #[allow(unused_unsafe)]
+        self.0
+            .cr
+            .modify(|_, w| unsafe { w.pls().bits(level as u8) }.pvde().set_bit());
+    }
+
+    /// Disables the programmable voltage detector (PVD)
+    pub fn disable_pvd(&mut self) {
+        self.0.cr.modify(|_, w| w.pvde().clear_bit());
+    }
+
+    /// Returns the state of the programmable voltage detector (PVD)
+    pub fn pvd_state(&self) -> PvdState {
+        if self.0.cr.read().pvde().bit_is_clear() {
+            PvdState::Disabled
+        } else if self.0.csr.read().pvdo().bit_is_set() {
+            PvdState::Below
+        } else {
+            PvdState::Above
+        }
+    }
+
     /// Returns a struct that can be used to enter Sleep mode
     pub fn sleep_mode<'r>(&'r mut self, scb: &'r mut SCB) -> SleepMode<'r> {
         SleepMode { pwr: self, scb }
@@ -189,10 +290,118 @@ impl VcoreRange {
     }
 }
 
+/// Threshold of the programmable voltage detector (`PWR_CR.PLS`)
+///
+/// The voltages are the typical falling-edge thresholds. The rising-edge
+/// thresholds are roughly 100 mV higher.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PvdLevel {
+    /// 1.9 V
+    V1_9 = 0b000,
+    /// 2.1 V
+    V2_1 = 0b001,
+    /// 2.3 V
+    V2_3 = 0b010,
+    /// 2.5 V
+    V2_5 = 0b011,
+    /// 2.7 V
+    V2_7 = 0b100,
+    /// 2.9 V
+    V2_9 = 0b101,
+    /// 3.1 V
+    V3_1 = 0b110,
+    /// The external PVD_IN pin (PB7), compared against VREFINT
+    External = 0b111,
+}
+
+/// State of the programmable voltage detector, see [`PWR::pvd_state`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PvdState {
+    /// The PVD is disabled
+    Disabled,
+    /// V_DD is above the threshold
+    Above,
+    /// V_DD is below the threshold
+    Below,
+}
+
+/// Brown-out reset (BOR) threshold, as configured in the option bytes
+/// (`FLASH_OPTR.BOR_LEV`)
+///
+/// When the BOR is off, the power-on/power-down reset (POR/PDR) still resets
+/// the MCU at about 1.5 V.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BorLevel {
+    /// BOR off, only POR/PDR are active
+    Off,
+    /// Level 1, about 1.8 V
+    Level1,
+    /// Level 2, about 2.0 V
+    Level2,
+    /// Level 3, about 2.5 V
+    Level3,
+    /// Level 4, about 2.7 V
+    Level4,
+    /// Level 5, about 3.0 V
+    Level5,
+}
+
+impl BorLevel {
+    /// Reads the configured BOR level from the option bytes
+    pub fn read() -> Self {
+        // NOTE(unsafe) atomic read of a read-only register
+        let bits = unsafe { (*pac::FLASH::ptr()).optr.read().bor_lev().bits() };
+        Self::from_bits(bits)
+    }
+
+    /// Creates a `BorLevel` instance from the value of the `BOR_LEV` field
+    ///
+    /// All values that don't select a level switch the BOR off.
+    pub fn from_bits(bits: u8) -> Self {
+        match bits {
+            0b1000 => BorLevel::Level1,
+            0b1001 => BorLevel::Level2,
+            0b1010 => BorLevel::Level3,
+            0b1011 => BorLevel::Level4,
+            0b1100 => BorLevel::Level5,
+            _ => BorLevel::Off,
+        }
+    }
+
+    /// Returns the approximate reset threshold, in millivolts
+    ///
+    /// For [`BorLevel::Off`], this is the threshold of the power-down reset.
+    pub fn threshold_mv(self) -> u16 {
+        match self {
+            BorLevel::Off => 1500,
+            BorLevel::Level1 => 1800,
+            BorLevel::Level2 => 2000,
+            BorLevel::Level3 => 2500,
+            BorLevel::Level4 => 2700,
+            BorLevel::Level5 => 3000,
+        }
+    }
+}
+
 /// Implemented for all low-power modes
 pub trait PowerMode {
     /// Enters the low-power mode
+    ///
+    /// # Panics
+    ///
+    /// Stop and Standby mode panic, if the wakeup flag can't be cleared. See
+    /// [`PowerMode::try_enter`].
     fn enter(&mut self);
+
+    /// Enters the low-power mode
+    ///
+    /// Returns `Error::WakeupFlagTimeout` without entering Stop or Standby
+    /// mode, if the wakeup flag of a previous wakeup can't be cleared.
+    fn try_enter(&mut self) -> Result<(), Error> {
+        self.enter();
+        Ok(())
+    }
 }
 
 /// Sleep mode
@@ -281,6 +490,12 @@ pub struct StopMode<'r> {
 
 impl PowerMode for StopMode<'_> {
     fn enter(&mut self) {
+        if let Err(err) = self.try_enter() {
+            panic!("{}", err);
+        }
+    }
+
+    fn try_enter(&mut self) -> Result<(), Error> {
         self.scb.set_sleepdeep();
 
         // Restore current clock source after waking up from Stop mode.
@@ -346,11 +561,14 @@ impl PowerMode for StopMode<'_> {
         });
 
         // Wait for WUF to be cleared
-        while self.pwr.0.csr.read().wuf().bit_is_set() {}
+        let csr = &self.pwr.0.csr;
+        wait_for(|| csr.read().wuf().bit_is_clear(), Error::WakeupFlagTimeout)?;
 
         // Enter Stop mode
         asm::dsb();
         asm::wfi();
+
+        Ok(())
     }
 }
 
@@ -384,6 +602,12 @@ pub struct StandbyMode<'r> {
 
 impl PowerMode for StandbyMode<'_> {
     fn enter(&mut self) {
+        if let Err(err) = self.try_enter() {
+            panic!("{}", err);
+        }
+    }
+
+    fn try_enter(&mut self) -> Result<(), Error> {
         // Configure Standby mode
         self.scb.set_sleepdeep();
         self.pwr.0.cr.modify(|_, w| {
@@ -394,10 +618,13 @@ impl PowerMode for StandbyMode<'_> {
         });
 
         // Wait for WUF to be cleared
-        while self.pwr.0.csr.read().wuf().bit_is_set() {}
+        let csr = &self.pwr.0.csr;
+        wait_for(|| csr.read().wuf().bit_is_clear(), Error::WakeupFlagTimeout)?;
 
         // Enter Standby mode
         asm::dsb();
         asm::wfi();
+
+        Ok(())
     }
 }
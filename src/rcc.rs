@@ -1,11 +1,19 @@
+use core::fmt;
+
 use crate::mco;
 use crate::pac::rcc::cfgr::{MCOPRE_A, MCOSEL_A};
-use crate::pac::RCC;
+use crate::pac::{RCC, TIM21};
 use crate::pwr::PWR;
+use crate::time::IntoHertz;
+use crate::timer::Tim21Ti1;
+use crate::wait_for;
 use embedded_time::rate::{Extensions, Hertz};
 
 #[cfg(any(feature = "stm32l0x2", feature = "stm32l0x3"))]
-use crate::{pac::CRS, syscfg::SYSCFG};
+use crate::{
+    pac::CRS,
+    syscfg::{VrefBuffer, SYSCFG},
+};
 
 mod enable;
 
@@ -15,6 +23,11 @@ pub enum ClockSrc {
     MSI(MSIRange),
     PLL(PLLSource, PLLMul, PLLDiv),
     HSE(Hertz),
+    /// External clock signal on OSC_IN, bypassing the HSE oscillator
+    ///
+    /// Use this when the MCU is clocked by a TCXO or another external clock
+    /// source, instead of a crystal.
+    HseBypass(Hertz),
     HSI16(HSI16Div),
 }
 
@@ -99,6 +112,8 @@ pub enum APBPrescaler {
 pub enum PLLSource {
     HSI16(HSI16Div),
     HSE(Hertz),
+    /// External clock signal on OSC_IN, bypassing the HSE oscillator
+    HseBypass(Hertz),
 }
 
 /// HSI speed
@@ -182,10 +197,23 @@ impl Config {
     #[inline]
     pub fn hse<T>(freq: T) -> Config
     where
-        T: Into<Hertz>,
+        T: IntoHertz,
+    {
+        Config {
+            mux: ClockSrc::HSE(freq.into_hertz()),
+            ahb_pre: AHBPrescaler::NotDivided,
+            apb1_pre: APBPrescaler::NotDivided,
+            apb2_pre: APBPrescaler::NotDivided,
+        }
+    }
+
+    #[inline]
+    pub fn hse_bypass<T>(freq: T) -> Config
+    where
+        T: IntoHertz,
     {
         Config {
-            mux: ClockSrc::HSE(freq.into()),
+            mux: ClockSrc::HseBypass(freq.into_hertz()),
             ahb_pre: AHBPrescaler::NotDivided,
             apb1_pre: APBPrescaler::NotDivided,
             apb2_pre: APBPrescaler::NotDivided,
@@ -193,10 +221,92 @@ impl Config {
     }
 }
 
+/// LSE oscillator drive capability
+///
+/// A higher drive capability helps crystals that are hard to start, at the
+/// cost of a higher current consumption.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LseDrive {
+    Low = 0b00,
+    MediumLow = 0b01,
+    MediumHigh = 0b10,
+    High = 0b11,
+}
+
+/// LSE configuration, for use with [`Rcc::try_enable_lse`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LseConfig {
+    drive: LseDrive,
+    bypass: bool,
+}
+
+impl Default for LseConfig {
+    fn default() -> Self {
+        LseConfig {
+            drive: LseDrive::Low,
+            bypass: false,
+        }
+    }
+}
+
+impl LseConfig {
+    /// Sets the drive capability of the oscillator
+    pub fn drive(mut self, drive: LseDrive) -> Self {
+        self.drive = drive;
+        self
+    }
+
+    /// Bypasses the oscillator, for an external clock signal on OSC32_IN
+    ///
+    /// Use this when the LSE is provided by a 32.768 kHz TCXO or another
+    /// external clock source, instead of a crystal.
+    pub fn bypass(mut self, bypass: bool) -> Self {
+        self.bypass = bypass;
+        self
+    }
+}
+
+/// RCC errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The MSI didn't become ready in time
+    MsiTimeout,
+    /// The HSI16 didn't become ready in time
+    HsiTimeout,
+    /// The HSE didn't become ready in time
+    HseTimeout,
+    /// The PLL didn't lock or unlock in time
+    PllTimeout,
+    /// The LSI didn't become ready in time
+    LsiTimeout,
+    /// The LSE didn't become ready in time
+    LseTimeout,
+    /// The HSI48 didn't become ready in time
+    Hsi48Timeout,
+    /// VREFINT didn't become ready in time
+    VrefintTimeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MsiTimeout => f.write_str("MSI didn't become ready"),
+            Error::HsiTimeout => f.write_str("HSI16 didn't become ready"),
+            Error::HseTimeout => f.write_str("HSE didn't become ready"),
+            Error::PllTimeout => f.write_str("PLL didn't lock or unlock"),
+            Error::LsiTimeout => f.write_str("LSI didn't become ready"),
+            Error::LseTimeout => f.write_str("LSE didn't become ready"),
+            Error::Hsi48Timeout => f.write_str("HSI48 didn't become ready"),
+            Error::VrefintTimeout => f.write_str("VREFINT didn't become ready"),
+        }
+    }
+}
+
 /// RCC peripheral
 pub struct Rcc {
     pub clocks: Clocks,
     pub(crate) rb: RCC,
+    shared_users: SharedUsers,
 }
 
 impl core::ops::Deref for Rcc {
@@ -210,6 +320,9 @@ impl core::ops::Deref for Rcc {
 
 impl Rcc {
     /// Enable the Low Speed External (LSE) clock.
+    ///
+    /// Blocks until the LSE is ready, which never happens if the crystal
+    /// doesn't start. Use [`Rcc::try_enable_lse`] to detect that case.
     pub fn enable_lse(&mut self, _: &PWR) -> LSE {
         self.rb.csr.modify(|_, w| {
             // Enable LSE clock
@@ -219,20 +332,250 @@ impl Rcc {
         LSE(())
     }
 
+    /// Enable the Low Speed External (LSE) clock with the given configuration.
+    ///
+    /// Returns `Error::LseTimeout`, if the LSE isn't ready within about 2
+    /// seconds, the maximum startup time of a crystal, and disables it again.
+    ///
+    /// The configuration can only be changed while the LSE is disabled. If it
+    /// is already running, for example because it keeps the RTC running over
+    /// resets, the configuration is left as is.
+    pub fn try_enable_lse(&mut self, _: &PWR, config: LseConfig) -> Result<LSE, Error> {
+        let csr = self.rb.csr.read();
+        if csr.lseon().bit_is_set() && csr.lserdy().bit_is_set() {
+            return Ok(LSE(()));
+        }
+
+        self.rb.csr.modify(|_, w| w.lseon().clear_bit());
+        #[allow(unused_unsafe)]
+        self.rb.csr.modify(|_, w| unsafe {
+            w.lsebyp()
+                .bit(config.bypass)
+                .lsedrv()
+                .bits(config.drive as u8)
+        });
+        self.rb.csr.modify(|_, w| w.lseon().set_bit());
+
+        if let Err(err) = self.wait_for_lse() {
+            self.rb.csr.modify(|_, w| w.lseon().clear_bit());
+            return Err(err);
+        }
+
+        Ok(LSE(()))
+    }
+
+    /// Waits for the LSE to become ready
+    ///
+    /// Returns `Error::LseTimeout`, if the LSE isn't ready within about 2
+    /// seconds, the maximum startup time of a crystal.
+    pub(crate) fn wait_for_lse(&self) -> Result<(), Error> {
+        // Every iteration takes several cycles, so waiting for as many
+        // iterations as the system clock runs cycles per second takes well
+        // over 2 seconds.
+        let mut timeout = self.clocks.sys_clk().0;
+        while self.rb.csr.read().lserdy().bit_is_clear() {
+            timeout -= 1;
+            if timeout == 0 {
+                return Err(Error::LseTimeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Enable the Low Speed Internal (LSI) clock.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if the LSI doesn't become ready. See [`Rcc::try_enable_lsi`].
+    pub fn enable_lsi(&mut self, pwr: &PWR) -> LSI {
+        match self.try_enable_lsi(pwr) {
+            Ok(lsi) => lsi,
+            Err(_) => panic!("LSI didn't become ready"),
+        }
+    }
+
     /// Enable the Low Speed Internal (LSI) clock.
-    pub fn enable_lsi(&mut self, _: &PWR) -> LSI {
+    ///
+    /// Returns `Error::LsiTimeout`, if the LSI doesn't become ready.
+    pub fn try_enable_lsi(&mut self, _: &PWR) -> Result<LSI, Error> {
         self.rb.csr.modify(|_, w| {
             // Enable LSI clock
             w.lsion().set_bit()
         });
-        while self.rb.csr.read().lsirdy().bit_is_clear() {}
-        LSI(())
+        wait_for(
+            || self.rb.csr.read().lsirdy().bit_is_set(),
+            Error::LsiTimeout,
+        )?;
+        Ok(LSI(()))
     }
 }
 
+impl Rcc {
+    /// Measures the frequency of the LSI clock against the TIM21 clock
+    ///
+    /// The LSI clock is connected to TIM21 channel 1 internally and its period
+    /// is timed using input capture. The result is only as accurate as the
+    /// APB2 timer clock, so this is most useful when running from HSI16 or HSE.
+    ///
+    /// The typical LSI frequency is 37 kHz, but it varies significantly
+    /// between devices and with temperature. The measured value can be used to
+    /// compute the actual IWDG timeout or to calibrate an RTC running from LSI.
+    ///
+    /// TIM21 is reset and disabled afterwards.
+    pub fn measure_lsi(&mut self, tim21: &mut TIM21, _: &LSI) -> Hertz {
+        let ticks = self.measure_tim21_ti1(tim21, Tim21Ti1::Lsi);
+
+        // Each capture covers 8 LSI periods
+        let clk = u64::from(self.clocks.apb2_tim_clk().0);
+        let freq = clk * 8 * TIM21_CAPTURES / ticks;
+        (freq as u32).Hz()
+    }
+
+    /// Measures the frequency of the LSI clock against the LSE clock
+    ///
+    /// Works like [`Rcc::measure_lsi`], but times both the LSI and the LSE
+    /// clock using TIM21, so the result is as accurate as the LSE crystal,
+    /// regardless of the system clock source.
+    pub fn measure_lsi_with_lse(&mut self, tim21: &mut TIM21, _: &LSI, _: &LSE) -> Hertz {
+        let lsi_ticks = self.measure_tim21_ti1(tim21, Tim21Ti1::Lsi);
+        let lse_ticks = self.measure_tim21_ti1(tim21, Tim21Ti1::Lse);
+
+        let freq = 32_768 * lse_ticks / lsi_ticks;
+        (freq as u32).Hz()
+    }
+
+    /// Returns the number of TIM21 clock ticks for `TIM21_CAPTURES` captures
+    /// of 8 periods each of the internal signal `input`
+    fn measure_tim21_ti1(&mut self, tim21: &mut TIM21, input: Tim21Ti1) -> u64 {
+        TIM21::enable(self);
+        TIM21::reset(self);
+
+        // Connect TI1 to the internal clock signal
+        #[allow(unused_unsafe)]
+        tim21
+            .or
+            .modify(|_, w| unsafe { w.ti1_rmp().bits(input as u8) });
+
+        #[allow(unused_unsafe)]
+        tim21.arr.write(|w| unsafe { w.arr().bits(0xffff) });
+
+        // Capture TI1 on every 8th rising edge
+        #[allow(unused_unsafe)]
+        tim21
+            .ccmr1_input()
+            .modify(|_, w| unsafe { w.cc1s().bits(0b01).ic1psc().bits(0b11) });
+        tim21.ccer.modify(|_, w| w.cc1e().set_bit());
+
+        tim21.cr1.modify(|_, w| w.cen().set_bit());
+
+        let capture = || {
+            while tim21.sr.read().cc1if().bit_is_clear() {}
+            // Reading CCR1 clears CC1IF
+            tim21.ccr1.read().ccr().bits() as u16
+        };
+
+        // The first capture might have been started before the counter was
+        // enabled, so discard it.
+        capture();
+        let mut last = capture();
+        let mut ticks = 0;
+        for _ in 0..TIM21_CAPTURES {
+            let now = capture();
+            ticks += u64::from(now.wrapping_sub(last));
+            last = now;
+        }
+
+        TIM21::reset(self);
+        TIM21::disable(self);
+
+        ticks
+    }
+}
+
+impl Rcc {
+    /// Enables a shared peripheral for one more user
+    ///
+    /// The peripheral is enabled and reset when the first user acquires it,
+    /// in which case `true` is returned. Each call needs to be matched by a
+    /// call to [`Rcc::release`].
+    pub fn acquire<P: Shared>(&mut self) -> bool {
+        if !self.shared_users.acquire(P::INDEX) {
+            return false;
+        }
+
+        P::enable(self);
+        P::reset(self);
+        true
+    }
+
+    /// Releases a shared peripheral
+    ///
+    /// The peripheral is only disabled once all users that acquired it have
+    /// released it again. Does nothing, if the peripheral hasn't been acquired
+    /// with [`Rcc::acquire`].
+    pub fn release<P: Shared>(&mut self) {
+        if self.shared_users.release(P::INDEX) {
+            P::disable(self);
+        }
+    }
+
+    /// Returns the number of users that currently hold a shared peripheral
+    pub fn shared_users<P: Shared>(&self) -> u8 {
+        self.shared_users.0[P::INDEX]
+    }
+}
+
+/// User counts of the peripherals that implement [`Shared`]
+struct SharedUsers([u8; SHARED_COUNT]);
+
+impl SharedUsers {
+    /// Adds a user, returning `true` if it's the first one
+    fn acquire(&mut self, index: usize) -> bool {
+        let users = &mut self.0[index];
+        *users = users
+            .checked_add(1)
+            .expect("too many shared peripheral users");
+        *users == 1
+    }
+
+    /// Removes a user, returning `true` if it was the last one
+    ///
+    /// Does nothing and returns `false`, if there are no users.
+    fn release(&mut self, index: usize) -> bool {
+        let users = &mut self.0[index];
+        if *users == 0 {
+            return false;
+        }
+        *users -= 1;
+        *users == 0
+    }
+}
+
+/// Number of captures averaged by `Rcc::measure_tim21_ti1`
+const TIM21_CAPTURES: u64 = 8;
+
 #[cfg(any(feature = "stm32l0x2", feature = "stm32l0x3"))]
 impl Rcc {
+    /// Enables the HSI48 clock, trimmed by the CRS using the LSE
+    ///
+    /// # Panics
+    ///
+    /// Panics, if the HSI48 doesn't become ready. See
+    /// [`Rcc::try_enable_hsi48`].
     pub fn enable_hsi48(&mut self, syscfg: &mut SYSCFG, crs: CRS) -> HSI48 {
+        match self.try_enable_hsi48(syscfg, crs) {
+            Ok(hsi48) => hsi48,
+            Err(_) => panic!("HSI48 didn't become ready"),
+        }
+    }
+
+    /// Enables the HSI48 clock, trimmed by the CRS using the LSE
+    ///
+    /// Returns `Error::VrefintTimeout`, if VREFINT, which the HSI48 needs,
+    /// doesn't become ready, and `Error::Hsi48Timeout`, if the HSI48 doesn't
+    /// become ready. The HSI48 buffer of VREFINT is disabled again in both
+    /// cases.
+    pub fn try_enable_hsi48(&mut self, syscfg: &mut SYSCFG, crs: CRS) -> Result<HSI48, Error> {
         // Enable CRS peripheral
         CRS::enable(self);
         // Reset CRS peripheral
@@ -247,18 +590,25 @@ impl Rcc {
 
         // Enable VREFINT reference for HSI48 oscillator
         syscfg
-            .syscfg
-            .cfgr3
-            .modify(|_, w| w.enref_hsi48().set_bit().en_vrefint().set_bit());
+            .enable_vref_buffer(VrefBuffer::Hsi48)
+            .map_err(|_| Error::VrefintTimeout)?;
 
         // Select HSI48 as USB clock
         self.rb.ccipr.modify(|_, w| w.hsi48msel().set_bit());
 
         // Enable dedicated USB clock
         self.rb.crrcr.modify(|_, w| w.hsi48on().set_bit());
-        while self.rb.crrcr.read().hsi48rdy().bit_is_clear() {}
+        let ready = wait_for(
+            || self.rb.crrcr.read().hsi48rdy().bit_is_set(),
+            Error::Hsi48Timeout,
+        );
+        if let Err(error) = ready {
+            self.rb.crrcr.modify(|_, w| w.hsi48on().clear_bit());
+            syscfg.disable_vref_buffer(VrefBuffer::Hsi48);
+            return Err(error);
+        }
 
-        HSI48(())
+        Ok(HSI48(()))
     }
 }
 
@@ -286,7 +636,30 @@ impl Rcc {
 
 /// Extension trait that freezes the `RCC` peripheral with provided clocks configuration
 pub trait RccExt {
+    /// Configures the clocks
+    ///
+    /// # Panics
+    ///
+    /// Panics, if one of the selected clocks doesn't become ready. See
+    /// [`RccExt::try_freeze`].
     fn freeze(self, config: Config) -> Rcc;
+
+    /// Configures the clocks
+    ///
+    /// Returns an error, if one of the selected clocks doesn't become ready,
+    /// for example because an HSE crystal is missing.
+    fn try_freeze(self, config: Config) -> Result<Rcc, Error>;
+}
+
+/// Enables HSE and waits for it to become ready
+///
+/// With `bypass`, the oscillator is bypassed and an external clock signal is
+/// expected on OSC_IN. HSEBYP can only be written while HSE is disabled, so it
+/// is set before HSEON.
+fn enable_hse(rcc: &RCC, bypass: bool) -> Result<(), Error> {
+    rcc.cr.write(|w| w.hsebyp().bit(bypass));
+    rcc.cr.write(|w| w.hsebyp().bit(bypass).hseon().set_bit());
+    wait_for(|| rcc.cr.read().hserdy().bit_is_set(), Error::HseTimeout)
 }
 
 impl RccExt for RCC {
@@ -295,6 +668,14 @@ impl RccExt for RCC {
     // This saves ~900 Bytes for the `pwr.rs` example.
     #[inline]
     fn freeze(self, cfgr: Config) -> Rcc {
+        match self.try_freeze(cfgr) {
+            Ok(rcc) => rcc,
+            Err(_) => panic!("clock didn't become ready"),
+        }
+    }
+
+    #[inline]
+    fn try_freeze(self, cfgr: Config) -> Result<Rcc, Error> {
         let (sys_clk, sw_bits) = match cfgr.mux {
             ClockSrc::MSI(range) => {
                 let range = range as u8;
@@ -303,7 +684,7 @@ impl RccExt for RCC {
 
                 // Enable MSI
                 self.cr.write(|w| w.msion().set_bit());
-                while self.cr.read().msirdy().bit_is_clear() {}
+                wait_for(|| self.cr.read().msirdy().bit_is_set(), Error::MsiTimeout)?;
 
                 let freq = 32_768 * (1 << (range + 1));
                 (freq, 0)
@@ -321,22 +702,28 @@ impl RccExt for RCC {
                         HSI_FREQ
                     }
                 };
-                while self.cr.read().hsi16rdyf().bit_is_clear() {}
+                wait_for(
+                    || self.cr.read().hsi16rdyf().bit_is_set(),
+                    Error::HsiTimeout,
+                )?;
                 (freq, 1)
             }
             ClockSrc::HSE(freq) => {
-                // Enable HSE
-                self.cr.write(|w| w.hseon().set_bit());
-                while self.cr.read().hserdy().bit_is_clear() {}
-
+                enable_hse(&self, false)?;
+                (freq.0, 2)
+            }
+            ClockSrc::HseBypass(freq) => {
+                enable_hse(&self, true)?;
                 (freq.0, 2)
             }
             ClockSrc::PLL(src, mul, div) => {
                 let (src_bit, freq) = match src {
                     PLLSource::HSE(freq) => {
-                        // Enable HSE
-                        self.cr.write(|w| w.hseon().set_bit());
-                        while self.cr.read().hserdy().bit_is_clear() {}
+                        enable_hse(&self, false)?;
+                        (true, freq.0)
+                    }
+                    PLLSource::HseBypass(freq) => {
+                        enable_hse(&self, true)?;
                         (true, freq.0)
                     }
                     PLLSource::HSI16(div) => {
@@ -352,14 +739,17 @@ impl RccExt for RCC {
                                 HSI_FREQ
                             }
                         };
-                        while self.cr.read().hsi16rdyf().bit_is_clear() {}
+                        wait_for(
+                            || self.cr.read().hsi16rdyf().bit_is_set(),
+                            Error::HsiTimeout,
+                        )?;
                         (false, freq)
                     }
                 };
 
                 // Disable PLL
                 self.cr.modify(|_, w| w.pllon().clear_bit());
-                while self.cr.read().pllrdy().bit_is_set() {}
+                wait_for(|| self.cr.read().pllrdy().bit_is_clear(), Error::PllTimeout)?;
 
                 let mul_bytes = mul as u8;
                 let div_bytes = div as u8;
@@ -394,7 +784,7 @@ impl RccExt for RCC {
 
                 // Enable PLL
                 self.cr.modify(|_, w| w.pllon().set_bit());
-                while self.cr.read().pllrdy().bit_is_clear() {}
+                wait_for(|| self.cr.read().pllrdy().bit_is_set(), Error::PllTimeout)?;
 
                 (freq, 3)
             }
@@ -444,7 +834,11 @@ impl RccExt for RCC {
             apb2_tim_clk: apb2_tim_freq.Hz(),
         };
 
-        Rcc { rb: self, clocks }
+        Ok(Rcc {
+            rb: self,
+            clocks,
+            shared_users: SharedUsers([0; SHARED_COUNT]),
+        })
     }
 }
 
@@ -578,6 +972,24 @@ pub trait SMEnable: RccBus {
     unsafe fn disable_in_sleep_mode_unchecked();
 }
 
+/// Peripheral that is shared by several drivers
+///
+/// Its clock is managed through [`Rcc::acquire`] and [`Rcc::release`], which
+/// count the users, so one driver can't disable the peripheral while another
+/// one still needs it.
+pub trait Shared: Enable + Reset {
+    /// Index of the user count in `Rcc`
+    #[doc(hidden)]
+    const INDEX: usize;
+}
+
+impl Shared for crate::pac::SYSCFG {
+    const INDEX: usize = 0;
+}
+
+/// Number of peripherals that implement [`Shared`]
+const SHARED_COUNT: usize = 1;
+
 /// Reset peripheral
 pub trait Reset: RccBus {
     /// Resets peripheral
@@ -623,3 +1035,42 @@ bus_struct! {
     APB2 => (APB2ENR, apb2enr, APB2SMENR, apb2smenr, APB2RSTR, apb2rstr, "Advanced Peripheral Bus 2 (APB2) registers"),
     IOP => (IOPENR, iopenr, IOPSMEN, iopsmen, IOPRSTR, ioprstr, "Input-Output Peripheral Bus (IOP) registers"),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_users_acquire_release() {
+        let mut users = SharedUsers([0; SHARED_COUNT]);
+
+        // Only the first user enables the peripheral
+        assert!(users.acquire(0));
+        assert!(!users.acquire(0));
+        assert_eq!(users.0[0], 2);
+
+        // Only the last user disables it
+        assert!(!users.release(0));
+        assert!(users.release(0));
+        assert_eq!(users.0[0], 0);
+
+        // Enabled again by the next user
+        assert!(users.acquire(0));
+    }
+
+    #[test]
+    fn shared_users_release_underflow() {
+        let mut users = SharedUsers([0; SHARED_COUNT]);
+
+        // Releasing a peripheral that wasn't acquired does nothing
+        assert!(!users.release(0));
+        assert_eq!(users.0[0], 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many shared peripheral users")]
+    fn shared_users_overflow() {
+        let mut users = SharedUsers([u8::max_value(); SHARED_COUNT]);
+        users.acquire(0);
+    }
+}
@@ -24,7 +24,7 @@
 //! See STM32L0x2 reference manual, chapter 26 or STM32L0x1 reference manual,
 //! chapter 22 for more details.
 
-use core::convert::TryInto;
+use core::{convert::TryInto, fmt};
 
 use embedded_time::rate::Extensions;
 use void::Void;
@@ -33,7 +33,8 @@ use crate::{
     hal::timer::{self, Cancel as _},
     pac,
     pwr::PWR,
-    rcc::Rcc,
+    rcc::{LseConfig, Rcc},
+    wait_for,
 };
 
 #[doc(no_inline)]
@@ -44,6 +45,29 @@ pub use rtcc::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
 pub enum Error {
     /// Invalid input data was used (e.g. a year outside the 2000-2099 range).
     InvalidInputData,
+    /// The selected clock source didn't become ready.
+    ClockTimeout,
+    /// The RTC didn't enter initialization mode.
+    InitTimeout,
+    /// The RTC is not clocked or its calendar has not been initialized.
+    NotInitialized,
+    /// The calendar registers didn't synchronize (RSF) in time.
+    SyncTimeout,
+    /// The wakeup timer configuration didn't become writable (WUTWF) in time.
+    WakeupTimerTimeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidInputData => f.write_str("invalid input data"),
+            Error::ClockTimeout => f.write_str("clock source didn't become ready"),
+            Error::InitTimeout => f.write_str("initialization mode timeout"),
+            Error::NotInitialized => f.write_str("RTC not initialized"),
+            Error::SyncTimeout => f.write_str("calendar synchronization timeout"),
+            Error::WakeupTimerTimeout => f.write_str("wakeup timer timeout"),
+        }
+    }
 }
 
 /// Low speed clock source to be used by the RTC.
@@ -54,6 +78,83 @@ pub enum ClockSource {
     LSI,
 }
 
+/// Signal routed to the RTC_OUT pin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputSignal {
+    /// 512 Hz calibration output, with the default prescaler settings
+    Calibration512Hz,
+    /// 1 Hz calibration output, with the default prescaler settings
+    Calibration1Hz,
+    /// Alarm A flag
+    AlarmA,
+    /// Alarm B flag
+    AlarmB,
+    /// Wakeup timer flag
+    Wakeup,
+}
+
+/// Pin used as RTC_OUT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputPin {
+    PC13,
+    PB14,
+}
+
+/// Level of the RTC_OUT pin while an alarm or wakeup flag is set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputPolarity {
+    High,
+    Low,
+}
+
+/// Output type of the RTC_OUT pin for the alarm and wakeup signals
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputType {
+    OpenDrain,
+    PushPull,
+}
+
+/// Configuration of the RTC_OUT pin
+///
+/// Polarity and output type only apply to the alarm and wakeup signals. The
+/// output type can only be selected on `PC13`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputConfig {
+    pin: OutputPin,
+    polarity: OutputPolarity,
+    output_type: OutputType,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            pin: OutputPin::PC13,
+            polarity: OutputPolarity::High,
+            output_type: OutputType::OpenDrain,
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Selects the pin used as RTC_OUT
+    pub fn pin(mut self, pin: OutputPin) -> Self {
+        self.pin = pin;
+        self
+    }
+
+    /// Sets the active level of the alarm and wakeup signals
+    pub fn polarity(mut self, polarity: OutputPolarity) -> Self {
+        self.polarity = polarity;
+        self
+    }
+
+    /// Sets the output type for the alarm and wakeup signals on `PC13`
+    pub fn output_type(mut self, output_type: OutputType) -> Self {
+        self.output_type = output_type;
+        self
+    }
+}
+
 /// Binary coded decimal with 2 bytes.
 struct Bcd2 {
     pub tens: u8,
@@ -68,6 +169,11 @@ struct Bcd2 {
 /// The following helper functions encode into BCD format from integer and
 /// decode to an integer from a BCD value respectively.
 fn bcd2_encode(word: u32) -> Result<Bcd2, Error> {
+    // Two BCD digits can't hold more than that
+    if word > 99 {
+        return Err(Error::InvalidInputData);
+    }
+
     let tens = (word / 10)
         .try_into()
         .map_err(|_| Error::InvalidInputData)?;
@@ -98,7 +204,9 @@ impl Rtc {
     /// # Errors
     ///
     /// Returns [`Error::InvalidInputData`] if the `init` datetime is outside
-    /// of the valid range (years 2000-2099).
+    /// of the valid range (years 2000-2099), [`Error::ClockTimeout`] if the
+    /// selected clock source doesn't start, or [`Error::InitTimeout`] if the
+    /// RTC can't be initialized.
     ///
     /// # Panics
     ///
@@ -133,12 +241,13 @@ impl Rtc {
         // Enable the selected LS clock
         match clock_source {
             ClockSource::LSE => {
-                rcc.enable_lse(pwr);
+                rcc.try_enable_lse(pwr, LseConfig::default())
+                    .map_err(|_| Error::ClockTimeout)?;
                 rtc_clk = 32_768u32.Hz(); // LSE crystal frequency
                 rtc_sel_mask = 0b01;
             }
             ClockSource::LSI => {
-                rcc.enable_lsi(pwr);
+                rcc.try_enable_lsi(pwr).map_err(|_| Error::ClockTimeout)?;
                 rtc_clk = 37u32.Hz(); // Approx freq given in datasheet
                 rtc_sel_mask = 0b10;
             }
@@ -182,9 +291,7 @@ impl Rtc {
         // Disable wakeup timer. It's periodic and persists over resets, but for
         // ease of use, let's disable it on intialization, unless the user
         // wishes to start it again.
-        //
-        // Can't panic, as the error type is `Void`.
-        rtc.wakeup_timer().cancel().unwrap();
+        rtc.wakeup_timer().cancel()?;
 
         // Clear RSF bit, in case we woke up from Stop or Standby mode. This is
         // necessary, according to section 26.4.8.
@@ -217,7 +324,10 @@ impl Rtc {
             rtc.isr.modify(|_, w| w.init().set_bit());
 
             // Wait until RTC register access is allowed
-            while rtc.isr.read().initf().bit_is_clear() {}
+            if let Err(err) = wait_for(|| rtc.isr.read().initf().bit_is_set(), Error::InitTimeout) {
+                rtc.isr.modify(|_, w| w.init().clear_bit());
+                return Err(err);
+            }
 
             // Configure RTC. For now, the default values are all fine.
             rtc.cr.reset();
@@ -267,13 +377,70 @@ impl Rtc {
 
             // Exit initialization
             rtc.isr.modify(|_, w| w.init().clear_bit());
-        });
 
-        Ok(())
+            Ok(())
+        })
+    }
+
+    /// Attaches to an RTC that is already running.
+    ///
+    /// Unlike [`Rtc::new`], this doesn't touch the clock configuration,
+    /// prescalers, or the wakeup timer, so the RTC keeps counting undisturbed,
+    /// for example across a firmware update. As nothing is written, no `PWR` is
+    /// required. Methods that modify the RTC still require backup write
+    /// protection to be disabled though, which `PWR::new` does.
+    ///
+    /// Returns [`Error::NotInitialized`], if the RTC clock isn't enabled or the
+    /// calendar has never been initialized.
+    pub fn attach(rtc: pac::RTC, rcc: &Rcc) -> Result<Self, Error> {
+        let csr = rcc.rb.csr.read();
+        let rtc_clk = match csr.rtcsel().bits() {
+            0b01 => 32_768,
+            0b10 => 37_000,
+            _ => return Err(Error::NotInitialized),
+        };
+        if csr.rtcen().bit_is_clear() || rtc.isr.read().inits().bit_is_clear() {
+            return Err(Error::NotInitialized);
+        }
+
+        let read_twice = rcc.clocks.apb1_clk().0 < 7 * rtc_clk;
+
+        Ok(Self { rtc, read_twice })
+    }
+
+    /// Checks if the calendar has been initialized.
+    ///
+    /// The calendar keeps its initialization over resets, as long as the
+    /// backup domain stays powered.
+    pub fn is_initialized(&self) -> bool {
+        self.rtc.isr.read().inits().bit_is_set()
+    }
+
+    /// Releases the raw RTC peripheral.
+    ///
+    /// The RTC keeps running with its current configuration.
+    pub fn release(self) -> pac::RTC {
+        self.rtc
     }
 
     /// Read and return the current date/time from the RTC.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if the calendar registers don't synchronize. See
+    /// [`Rtc::try_now`].
     pub fn now(&mut self) -> NaiveDateTime {
+        match self.try_now() {
+            Ok(instant) => instant,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Read and return the current date/time from the RTC.
+    ///
+    /// Returns `Error::SyncTimeout`, if the calendar registers don't
+    /// synchronize, which happens if the RTC isn't clocked.
+    pub fn try_now(&mut self) -> Result<NaiveDateTime, Error> {
         // We need to wait until the RSF bit is set, for a multitude of reasons:
         // - In case the last read was within two cycles of RTCCLK. Not sure why
         //   that's important, but the documentation says so.
@@ -282,7 +449,8 @@ impl Rtc {
         // - In case the registers are not yet ready after initialization.
         //
         // All of this is explain in section 26.4.8.
-        while self.rtc.isr.read().rsf().bit_is_clear() {}
+        let isr = &self.rtc.isr;
+        wait_for(|| isr.read().rsf().bit_is_set(), Error::SyncTimeout)?;
 
         // Reading the TR register locks the DR register until we clear the RSF
         // flag, so there's no danger of reading something weird here, as long
@@ -320,10 +488,10 @@ impl Rtc {
         let minute = bcd2_decode(tr.mnt().bits(), tr.mnu().bits());
         let second = bcd2_decode(tr.st().bits(), tr.su().bits());
 
-        NaiveDate::from_ymd_opt(year, month, day)
+        Ok(NaiveDate::from_ymd_opt(year, month, day)
             .unwrap()
             .and_hms_opt(hour, minute, second)
-            .unwrap()
+            .unwrap())
     }
 
     /// Enable interrupts
@@ -374,6 +542,54 @@ impl Rtc {
         })
     }
 
+    /// Routes `signal` to the RTC_OUT pin
+    ///
+    /// The pin is driven by the RTC directly, without involving the CPU, so
+    /// the output keeps working in all low-power modes. The calibration
+    /// output can be used to measure the accuracy of the RTC clock, the alarm
+    /// and wakeup outputs to drive external circuits.
+    ///
+    /// The calibration frequencies assume the prescalers configured by
+    /// [`Rtc::new`], which divide the 32768 Hz LSE down to 1 Hz.
+    pub fn enable_output(&mut self, signal: OutputSignal, config: OutputConfig) {
+        let (osel, coe, cosel) = match signal {
+            OutputSignal::Calibration512Hz => (0b00, true, false),
+            OutputSignal::Calibration1Hz => (0b00, true, true),
+            OutputSignal::AlarmA => (0b01, false, false),
+            OutputSignal::AlarmB => (0b10, false, false),
+            OutputSignal::Wakeup => (0b11, false, false),
+        };
+
+        self.write(|rtc| {
+            rtc.or.modify(|_, w| {
+                w.rtc_out_rmp()
+                    .bit(config.pin == OutputPin::PB14)
+                    .rtc_alarm_type()
+                    .bit(config.output_type == OutputType::PushPull)
+            });
+            #[allow(unused_unsafe)]
+            rtc.cr.modify(|_, w| unsafe {
+                w.osel()
+                    .bits(osel)
+                    .pol()
+                    .bit(config.polarity == OutputPolarity::Low)
+                    .cosel()
+                    .bit(cosel)
+                    .coe()
+                    .bit(coe)
+            });
+        })
+    }
+
+    /// Disconnects the RTC from the RTC_OUT pin
+    pub fn disable_output(&mut self) {
+        self.write(|rtc| {
+            #[allow(unused_unsafe)]
+            rtc.cr
+                .modify(|_, w| unsafe { w.osel().bits(0b00).coe().clear_bit() });
+        })
+    }
+
     /// Access the wakeup timer
     pub fn wakeup_timer(&mut self) -> WakeupTimer {
         WakeupTimer { rtc: self }
@@ -426,31 +642,21 @@ pub struct WakeupTimer<'r> {
     rtc: &'r mut Rtc,
 }
 
-impl timer::Periodic for WakeupTimer<'_> {}
-
-impl timer::CountDown for WakeupTimer<'_> {
-    type Time = u32;
-
+impl WakeupTimer<'_> {
     /// Starts the wakeup timer
     ///
-    /// The `delay` argument specifies the timer delay in seconds. Up to 17 bits
-    /// of delay are supported, giving us a range of over 36 hours.
+    /// Like `CountDown::start`, but returns `Error::WakeupTimerTimeout`,
+    /// if the wakeup timer can't be configured.
     ///
     /// # Panics
     ///
-    /// The `delay` argument must be in the range `1 <= delay < 2^17`.
-    /// Panics, if `delay` is outside of that range.
-    fn start<T>(&mut self, delay: T)
-    where
-        T: Into<Self::Time>,
-    {
-        let delay = delay.into();
+    /// Panics, if `delay` is outside of the range `1 <= delay < 2^17`.
+    pub fn try_start(&mut self, delay: u32) -> Result<(), Error> {
         assert!((1..=0x1_FF_FF).contains(&delay));
 
         let delay = delay - 1;
 
-        // Can't panic, as the error type is `Void`.
-        self.cancel().unwrap();
+        self.cancel()?;
 
         self.rtc.write(|rtc| {
             // Set the wakeup delay
@@ -478,7 +684,36 @@ impl timer::CountDown for WakeupTimer<'_> {
 
         // Let's wait for WUTWF to clear. Otherwise we might run into a race
         // condition, if the user calls this method again really quickly.
-        while self.rtc.rtc.isr.read().wutwf().bit_is_set() {}
+        let isr = &self.rtc.rtc.isr;
+        wait_for(
+            || isr.read().wutwf().bit_is_clear(),
+            Error::WakeupTimerTimeout,
+        )
+    }
+}
+
+impl timer::Periodic for WakeupTimer<'_> {}
+
+impl timer::CountDown for WakeupTimer<'_> {
+    type Time = u32;
+
+    /// Starts the wakeup timer
+    ///
+    /// The `delay` argument specifies the timer delay in seconds. Up to 17 bits
+    /// of delay are supported, giving us a range of over 36 hours.
+    ///
+    /// # Panics
+    ///
+    /// The `delay` argument must be in the range `1 <= delay < 2^17`.
+    /// Panics, if `delay` is outside of that range, or if the wakeup timer
+    /// can't be configured. See [`WakeupTimer::try_start`].
+    fn start<T>(&mut self, delay: T)
+    where
+        T: Into<Self::Time>,
+    {
+        if let Err(err) = self.try_start(delay.into()) {
+            panic!("{}", err);
+        }
     }
 
     fn wait(&mut self) -> nb::Result<(), Void> {
@@ -496,7 +731,7 @@ impl timer::CountDown for WakeupTimer<'_> {
 }
 
 impl timer::Cancel for WakeupTimer<'_> {
-    type Error = Void;
+    type Error = Error;
 
     fn cancel(&mut self) -> Result<(), Self::Error> {
         self.rtc.write(|rtc| {
@@ -504,7 +739,10 @@ impl timer::Cancel for WakeupTimer<'_> {
             rtc.cr.modify(|_, w| w.wute().clear_bit());
 
             // Wait until we're allowed to update the wakeup timer configuration
-            while rtc.isr.read().wutwf().bit_is_clear() {}
+            wait_for(
+                || rtc.isr.read().wutwf().bit_is_set(),
+                Error::WakeupTimerTimeout,
+            )?;
 
             // Clear wakeup timer flag
             rtc.isr.modify(|_, w| w.wutf().clear_bit());
@@ -520,8 +758,34 @@ impl timer::Cancel for WakeupTimer<'_> {
             // instead of WUTF. In that case, this might be a bug, so if you're
             // seeing something weird, adding a busy loop of some length here
             // would be a good start of your investigation.
-        });
 
-        Ok(())
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bcd2_roundtrip() {
+        for word in 0..=99 {
+            let bcd = bcd2_encode(word).unwrap();
+            assert!(bcd.tens < 10);
+            assert!(bcd.units < 10);
+            assert_eq!(bcd2_decode(bcd.tens, bcd.units), word);
+        }
+    }
+
+    #[test]
+    fn bcd2_encode_digits() {
+        let bcd = bcd2_encode(59).unwrap();
+        assert_eq!((bcd.tens, bcd.units), (5, 9));
+    }
+
+    #[test]
+    fn bcd2_encode_out_of_range() {
+        assert!(matches!(bcd2_encode(100), Err(Error::InvalidInputData)));
     }
 }
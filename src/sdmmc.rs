@@ -0,0 +1,619 @@
+//! SD card support via SPI
+//!
+//! Adapts an [`Spi`] and a chip select pin to
+//! [`embedded_sdmmc::BlockDevice`], so the card can be used with
+//! `embedded-sdmmc`'s FAT implementation. Supports SD version 1, SD version 2,
+//! and SDHC/SDXC cards.
+//!
+//! The card is initialized at 400 kHz, as required by the SD specification.
+//! [`SdCard::init`] switches to the frequency passed to [`SdCard::new`]
+//! afterwards, so the SPI peripheral can be created with any frequency.
+//!
+//! ``` ignore
+//! let spi = dp.SPI1.spi((sck, miso, mosi), spi::MODE_0, 400_000.Hz(), &mut rcc);
+//! let mut card = SdCard::new(spi, cs, 16_000_000.Hz(), &rcc);
+//! card.init()?;
+//!
+//! let mut controller = embedded_sdmmc::Controller::new(card, time_source);
+//! ```
+//!
+//! [`Spi`]: crate::spi::Spi
+
+use core::{cell::RefCell, fmt};
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use embedded_time::rate::Hertz;
+use nb::block;
+
+use crate::{
+    hal::{digital::v2::OutputPin, spi::FullDuplex},
+    pac::SPI1,
+    rcc::{Clocks, Rcc},
+    spi::{self, Spi},
+};
+
+#[cfg(any(
+    feature = "io-STM32L051",
+    feature = "io-STM32L071",
+    feature = "stm32l0x2",
+    feature = "stm32l0x3"
+))]
+use crate::pac::SPI2;
+
+/// SPI clock frequency during card initialization
+const INIT_FREQUENCY: u32 = 400_000;
+
+/// Number of attempts to get the card into the idle state
+const IDLE_RETRIES: u32 = 32;
+/// Number of polls while waiting for the card to finish initialization
+const INIT_RETRIES: u32 = 10_000;
+/// Number of bytes to read while waiting for a data token
+const READ_RETRIES: u32 = 100_000;
+/// Number of bytes to read while waiting for the card to become ready
+const BUSY_RETRIES: u32 = 1_000_000;
+
+/// GO_IDLE_STATE
+const CMD0: u8 = 0;
+/// SEND_IF_COND
+const CMD8: u8 = 8;
+/// SEND_CSD
+const CMD9: u8 = 9;
+/// SET_BLOCKLEN
+const CMD16: u8 = 16;
+/// READ_SINGLE_BLOCK
+const CMD17: u8 = 17;
+/// WRITE_BLOCK
+const CMD24: u8 = 24;
+/// SD_SEND_OP_COND
+const ACMD41: u8 = 41;
+/// APP_CMD
+const CMD55: u8 = 55;
+/// READ_OCR
+const CMD58: u8 = 58;
+
+/// R1 response: card is ready
+const R1_READY_STATE: u8 = 0x00;
+/// R1 response: card is in the idle state
+const R1_IDLE_STATE: u8 = 0x01;
+/// R1 response: illegal command
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+/// Token preceding a data block
+const DATA_START_BLOCK: u8 = 0xfe;
+/// Data response: data accepted
+const DATA_RES_ACCEPTED: u8 = 0x05;
+/// Mask for the data response bits
+const DATA_RES_MASK: u8 = 0x1f;
+
+/// Size of a block in bytes
+const BLOCK_SIZE: usize = 512;
+
+/// SD card error
+#[derive(Debug)]
+pub enum Error {
+    /// The SPI peripheral reported an error
+    Spi(spi::Error),
+
+    /// The chip select pin couldn't be set
+    ChipSelect,
+
+    /// The card didn't respond in time
+    Timeout,
+
+    /// The card responded to a command with an error
+    ///
+    /// Contains the R1 response.
+    Command(u8),
+
+    /// The card sent an error token instead of a data block
+    Read(u8),
+
+    /// The card rejected a data block
+    ///
+    /// Contains the data response.
+    Write(u8),
+
+    /// The card is not supported
+    UnsupportedCard,
+
+    /// [`SdCard::init`] hasn't been called successfully
+    NotInitialized,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Spi(error) => write!(f, "SPI error: {}", error),
+            Error::ChipSelect => f.write_str("setting chip select failed"),
+            Error::Timeout => f.write_str("card didn't respond in time"),
+            Error::Command(r1) => write!(f, "command failed (R1 = {:#04x})", r1),
+            Error::Read(token) => write!(f, "read failed (error token = {:#04x})", token),
+            Error::Write(response) => {
+                write!(f, "write rejected (data response = {:#04x})", response)
+            }
+            Error::UnsupportedCard => f.write_str("unsupported card"),
+            Error::NotInitialized => f.write_str("card not initialized"),
+        }
+    }
+}
+
+impl From<spi::Error> for Error {
+    fn from(error: spi::Error) -> Self {
+        Error::Spi(error)
+    }
+}
+
+/// The type of card detected by [`SdCard::init`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardType {
+    /// SD version 1 (byte addressing)
+    Sd1,
+
+    /// SD version 2 (byte addressing)
+    Sd2,
+
+    /// SDHC or SDXC (block addressing)
+    Sdhc,
+}
+
+/// An SPI bus that can be used to talk to an SD card
+///
+/// Implemented for [`Spi`]. The card needs to be initialized at a lower
+/// frequency than it's used at, so the bus needs to support switching
+/// frequencies.
+pub trait SpiBus: FullDuplex<u8, Error = spi::Error> {
+    /// Changes the SPI clock frequency
+    fn set_frequency(&mut self, freq: Hertz, clocks: Clocks) -> Result<(), spi::Error>;
+}
+
+macro_rules! spi_bus {
+    ($($SPIX:ident,)+) => {
+        $(
+            impl<PINS> SpiBus for Spi<$SPIX, PINS> {
+                fn set_frequency(&mut self, freq: Hertz, clocks: Clocks) -> Result<(), spi::Error> {
+                    <Spi<$SPIX, PINS>>::set_frequency(self, freq, clocks)
+                }
+            }
+        )+
+    }
+}
+
+spi_bus!(SPI1,);
+
+#[cfg(any(
+    feature = "io-STM32L051",
+    feature = "io-STM32L071",
+    feature = "stm32l0x2",
+    feature = "stm32l0x3"
+))]
+spi_bus!(SPI2,);
+
+/// An SD card connected via SPI
+///
+/// See the [module documentation](self) for an example.
+pub struct SdCard<SPI, CS> {
+    inner: RefCell<Inner<SPI, CS>>,
+}
+
+impl<SPI, CS> SdCard<SPI, CS>
+where
+    SPI: SpiBus,
+    CS: OutputPin,
+{
+    /// Creates a new instance of `SdCard`
+    ///
+    /// `frequency` is the SPI clock frequency used after initialization. SD
+    /// cards support up to 25 MHz.
+    ///
+    /// The card isn't accessed until [`SdCard::init`] is called.
+    pub fn new<T>(spi: SPI, cs: CS, frequency: T, rcc: &Rcc) -> Self
+    where
+        T: Into<Hertz>,
+    {
+        Self {
+            inner: RefCell::new(Inner {
+                spi,
+                cs,
+                clocks: rcc.clocks,
+                frequency: frequency.into(),
+                card_type: None,
+            }),
+        }
+    }
+
+    /// Initializes the card
+    ///
+    /// Switches the SPI peripheral to 400 kHz, runs the SD initialization
+    /// sequence, then switches to the frequency passed to [`SdCard::new`].
+    ///
+    /// Can be called again to re-initialize the card, for example after it
+    /// has been swapped.
+    pub fn init(&mut self) -> Result<CardType, Error> {
+        self.inner.get_mut().init()
+    }
+
+    /// Returns the card type, if the card has been initialized
+    pub fn card_type(&self) -> Option<CardType> {
+        self.inner.borrow().card_type
+    }
+
+    /// Releases the SPI peripheral and the chip select pin
+    pub fn free(self) -> (SPI, CS) {
+        let inner = self.inner.into_inner();
+        (inner.spi, inner.cs)
+    }
+}
+
+impl<SPI, CS> BlockDevice for SdCard<SPI, CS>
+where
+    SPI: SpiBus,
+    CS: OutputPin,
+{
+    type Error = Error;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let mut inner = self.inner.borrow_mut();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            inner.read_block(start_block_idx.0 + i as u32, &mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let mut inner = self.inner.borrow_mut();
+        for (i, block) in blocks.iter().enumerate() {
+            inner.write_block(start_block_idx.0 + i as u32, &block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.inner.borrow_mut().num_blocks().map(BlockCount)
+    }
+}
+
+struct Inner<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+    clocks: Clocks,
+    frequency: Hertz,
+    card_type: Option<CardType>,
+}
+
+impl<SPI, CS> Inner<SPI, CS>
+where
+    SPI: SpiBus,
+    CS: OutputPin,
+{
+    fn init(&mut self) -> Result<CardType, Error> {
+        self.card_type = None;
+        self.spi.set_frequency(Hertz(INIT_FREQUENCY), self.clocks)?;
+
+        // The card needs at least 74 clock cycles with CS high to enter native
+        // mode, before it can be switched to SPI mode.
+        self.cs.set_high().map_err(|_| Error::ChipSelect)?;
+        for _ in 0..10 {
+            self.transfer(0xff)?;
+        }
+
+        let card_type = self.selected(Self::init_selected)?;
+        if card_type != CardType::Sdhc {
+            // Make sure byte-addressed cards use the same block size as
+            // everyone else.
+            let r1 = self.selected(|inner| inner.command(CMD16, BLOCK_SIZE as u32))?;
+            if r1 != R1_READY_STATE {
+                return Err(Error::Command(r1));
+            }
+        }
+
+        self.spi.set_frequency(self.frequency, self.clocks)?;
+        self.card_type = Some(card_type);
+
+        Ok(card_type)
+    }
+
+    fn init_selected(&mut self) -> Result<CardType, Error> {
+        let mut attempts = 0;
+        loop {
+            match self.command(CMD0, 0)? {
+                R1_IDLE_STATE => break,
+                _ if attempts < IDLE_RETRIES => attempts += 1,
+                r1 => return Err(Error::Command(r1)),
+            }
+        }
+
+        // Version 1 cards don't know CMD8. Version 2 cards echo the check
+        // pattern.
+        let r1 = self.command(CMD8, 0x1aa)?;
+        let version_2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if version_2 {
+            let mut r7 = [0; 4];
+            self.receive(&mut r7)?;
+            if r7[3] != 0xaa {
+                return Err(Error::UnsupportedCard);
+            }
+        }
+
+        // Request high capacity support (HCS), if the card could provide it
+        let arg = if version_2 { 0x4000_0000 } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_RETRIES {
+            if self.app_command(ACMD41, arg)? == R1_READY_STATE {
+                ready = true;
+                break;
+            }
+        }
+        if !ready {
+            return Err(Error::Timeout);
+        }
+
+        if !version_2 {
+            return Ok(CardType::Sd1);
+        }
+
+        let r1 = self.command(CMD58, 0)?;
+        if r1 != R1_READY_STATE {
+            return Err(Error::Command(r1));
+        }
+        let mut ocr = [0; 4];
+        self.receive(&mut ocr)?;
+
+        // Card capacity status (CCS)
+        if ocr[0] & 0x40 != 0 {
+            Ok(CardType::Sdhc)
+        } else {
+            Ok(CardType::Sd2)
+        }
+    }
+
+    fn read_block(&mut self, index: u32, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), Error> {
+        let address = self.address(index)?;
+        self.selected(|inner| {
+            let r1 = inner.command(CMD17, address)?;
+            if r1 != R1_READY_STATE {
+                return Err(Error::Command(r1));
+            }
+            inner.read_data(buffer)
+        })
+    }
+
+    fn write_block(&mut self, index: u32, buffer: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        let address = self.address(index)?;
+        self.selected(|inner| {
+            let r1 = inner.command(CMD24, address)?;
+            if r1 != R1_READY_STATE {
+                return Err(Error::Command(r1));
+            }
+            inner.write_data(buffer)
+        })
+    }
+
+    fn num_blocks(&mut self) -> Result<u32, Error> {
+        self.address(0)?;
+
+        let mut csd = [0; 16];
+        self.selected(|inner| {
+            let r1 = inner.command(CMD9, 0)?;
+            if r1 != R1_READY_STATE {
+                return Err(Error::Command(r1));
+            }
+            inner.read_data(&mut csd)
+        })?;
+
+        csd_num_blocks(&csd).ok_or(Error::UnsupportedCard)
+    }
+
+    /// Converts a block index into the address used in commands
+    fn address(&self, index: u32) -> Result<u32, Error> {
+        match self.card_type {
+            Some(CardType::Sdhc) => Ok(index),
+            Some(_) => Ok(index * BLOCK_SIZE as u32),
+            None => Err(Error::NotInitialized),
+        }
+    }
+
+    /// Runs `f` with the card selected
+    ///
+    /// The card is deselected afterwards, even if `f` returns an error.
+    fn selected<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R, Error>) -> Result<R, Error> {
+        self.cs.set_low().map_err(|_| Error::ChipSelect)?;
+        let result = f(self);
+        self.cs.set_high().map_err(|_| Error::ChipSelect)?;
+
+        // The card only releases MISO on the next clock edge
+        self.transfer(0xff)?;
+
+        result
+    }
+
+    fn app_command(&mut self, command: u8, arg: u32) -> Result<u8, Error> {
+        self.command(CMD55, 0)?;
+        self.command(command, arg)
+    }
+
+    /// Sends a command and returns the R1 response
+    fn command(&mut self, command: u8, arg: u32) -> Result<u8, Error> {
+        if command != CMD0 {
+            self.wait_ready()?;
+        }
+
+        let arg = arg.to_be_bytes();
+        let mut frame = [0x40 | command, arg[0], arg[1], arg[2], arg[3], 0];
+        frame[5] = (crc7(&frame[..5]) << 1) | 1;
+        for &byte in &frame {
+            self.transfer(byte)?;
+        }
+
+        // The response arrives within 8 bytes
+        for _ in 0..8 {
+            let r1 = self.transfer(0xff)?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+
+    /// Receives a data block, including the data token and CRC
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut token = 0xff;
+        for _ in 0..READ_RETRIES {
+            token = self.transfer(0xff)?;
+            if token != 0xff {
+                break;
+            }
+        }
+        match token {
+            DATA_START_BLOCK => {}
+            0xff => return Err(Error::Timeout),
+            token => return Err(Error::Read(token)),
+        }
+
+        self.receive(buffer)?;
+
+        // Ignore the CRC, as CRC checking is disabled in SPI mode by default
+        self.transfer(0xff)?;
+        self.transfer(0xff)?;
+
+        Ok(())
+    }
+
+    /// Sends a data block, including the data token and CRC
+    fn write_data(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.transfer(DATA_START_BLOCK)?;
+        self.send(buffer)?;
+        self.transfer(0xff)?;
+        self.transfer(0xff)?;
+
+        let response = self.transfer(0xff)?;
+        if response & DATA_RES_MASK != DATA_RES_ACCEPTED {
+            return Err(Error::Write(response));
+        }
+
+        self.wait_ready()
+    }
+
+    /// Receives bytes by clocking out `0xff`
+    ///
+    /// This is where block data is transferred, so it's the place to switch to
+    /// full-duplex DMA transfers, once the SPI API supports them.
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        for byte in buffer {
+            *byte = self.transfer(0xff)?;
+        }
+        Ok(())
+    }
+
+    /// Sends bytes, discarding the received data
+    ///
+    /// See [`Inner::receive`].
+    fn send(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        for &byte in buffer {
+            self.transfer(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Waits until the card stops signaling busy
+    fn wait_ready(&mut self) -> Result<(), Error> {
+        for _ in 0..BUSY_RETRIES {
+            if self.transfer(0xff)? == 0xff {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn transfer(&mut self, byte: u8) -> Result<u8, Error> {
+        block!(self.spi.send(byte))?;
+        Ok(block!(self.spi.read())?)
+    }
+}
+
+/// Computes the CRC7 used in command frames
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let feedback = ((byte >> bit) ^ (crc >> 6)) & 1;
+            crc = (crc << 1) & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// Computes the number of 512-byte blocks from the CSD register
+///
+/// Returns `None`, if the CSD structure version is unknown.
+fn csd_num_blocks(csd: &[u8; 16]) -> Option<u32> {
+    match csd[0] >> 6 {
+        // CSD version 1.0 (SD version 1 and 2 standard capacity)
+        0 => {
+            let c_size = (u32::from(csd[6] & 0x03) << 10)
+                | (u32::from(csd[7]) << 2)
+                | (u32::from(csd[8]) >> 6);
+            let c_size_mult = (u32::from(csd[9] & 0x03) << 1) | (u32::from(csd[10]) >> 7);
+            let read_bl_len = u32::from(csd[5] & 0x0f);
+            let shift = (c_size_mult + 2 + read_bl_len).checked_sub(9)?;
+            Some((c_size + 1) << shift)
+        }
+        // CSD version 2.0 (SDHC/SDXC), capacity in units of 512 KiB
+        1 => {
+            let c_size =
+                (u32::from(csd[7] & 0x3f) << 16) | (u32::from(csd[8]) << 8) | u32::from(csd[9]);
+            Some((c_size + 1) * 1024)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_crc() {
+        assert_eq!(crc7(&[0x40 | CMD0, 0x00, 0x00, 0x00, 0x00]), 0x4a);
+        assert_eq!(crc7(&[0x40 | CMD8, 0x00, 0x00, 0x01, 0xaa]), 0x43);
+        assert_eq!(crc7(&[0x40 | CMD17, 0x00, 0x00, 0x00, 0x00]), 0x2a);
+    }
+
+    #[test]
+    fn csd_v2() {
+        // 8 GB SDHC card, C_SIZE = 0x3b37
+        let csd = [
+            0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00, 0x00, 0x3b, 0x37, 0x7f, 0x80, 0x0a, 0x40,
+            0x00, 0x8b,
+        ];
+        assert_eq!(csd_num_blocks(&csd), Some((0x3b37 + 1) * 1024));
+    }
+
+    #[test]
+    fn csd_v1() {
+        // 1 GB card: READ_BL_LEN = 9, C_SIZE = 4095, C_SIZE_MULT = 7
+        let mut csd = [0; 16];
+        csd[5] = 0x09;
+        csd[6] = 0x03;
+        csd[7] = 0xff;
+        csd[8] = 0xc0;
+        csd[9] = 0x03;
+        csd[10] = 0x80;
+        assert_eq!(csd_num_blocks(&csd), Some(4096 << 9));
+    }
+
+    #[test]
+    fn csd_unknown_version() {
+        let mut csd = [0; 16];
+        csd[0] = 0x80;
+        assert_eq!(csd_num_blocks(&csd), None);
+    }
+}
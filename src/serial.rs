@@ -5,11 +5,11 @@ use core::ptr;
 
 use nb::block;
 
-use crate::gpio::{AltMode, PinMode};
 use crate::hal;
 use crate::hal::prelude::*;
 pub use crate::pac::{LPUART1, USART1, USART2, USART4, USART5};
 use crate::rcc::{Enable, Rcc, LSE};
+use crate::time::IntoBaud;
 use embedded_time::rate::{Baud, Extensions};
 
 #[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
@@ -33,6 +33,12 @@ use crate::dma::Buffer;
     feature = "io-STM32L071"
 ))]
 use crate::gpio::gpioc::*;
+#[cfg(any(
+    feature = "io-STM32L021",
+    feature = "io-STM32L031",
+    feature = "io-STM32L051",
+    feature = "io-STM32L071"
+))]
 use crate::gpio::{gpioa::*, gpiob::*};
 #[cfg(feature = "io-STM32L071")]
 use crate::gpio::{gpiod::*, gpioe::*};
@@ -49,6 +55,42 @@ pub enum Error {
     Overrun,
     /// Parity check error
     Parity,
+    /// Bytes in a circular DMA buffer have been overwritten before they were
+    /// read
+    ///
+    /// See [`CircularRx::read_available`].
+    BufferOverrun,
+    /// An ongoing transmission didn't complete in time
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Framing => f.write_str("framing error"),
+            Error::Noise => f.write_str("noise detected"),
+            Error::Overrun => f.write_str("RX buffer overrun"),
+            Error::Parity => f.write_str("parity error"),
+            Error::BufferOverrun => f.write_str("DMA buffer overrun"),
+            Error::Timeout => f.write_str("transmission timed out"),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+impl embedded_hal_nb::serial::Error for Error {
+    fn kind(&self) -> embedded_hal_nb::serial::ErrorKind {
+        use embedded_hal_nb::serial::ErrorKind;
+
+        match self {
+            Error::Framing => ErrorKind::FrameFormat,
+            Error::Noise => ErrorKind::Noise,
+            Error::Overrun => ErrorKind::Overrun,
+            Error::Parity => ErrorKind::Parity,
+            Error::BufferOverrun => ErrorKind::Other,
+            Error::Timeout => ErrorKind::Other,
+        }
+    }
 }
 
 /// Interrupt event
@@ -67,6 +109,26 @@ pub enum Event {
     Txe,
     /// Idle line state detected.
     Idle,
+    /// Transmission complete.
+    ///
+    /// The last character has been fully transmitted and the TX buffer is
+    /// empty. This event is cleared by writing another character to the UART
+    /// or by calling `flush`.
+    Tc,
+    /// The CTS input has toggled.
+    Cts,
+    /// Parity error detected.
+    ///
+    /// This event is cleared by `check_errors` or `clear_errors`.
+    Pe,
+    /// Framing error, overrun error or noise detected.
+    ///
+    /// This event is cleared by `check_errors` or `clear_errors`.
+    Error,
+    /// The character set using `set_match_character` has been received.
+    ///
+    /// This event is cleared by calling `Rx::clear_character_match`.
+    CharacterMatch,
 }
 
 pub enum WordLength {
@@ -91,16 +153,75 @@ pub enum StopBits {
     STOP1P5,
 }
 
+/// Oversampling method of the receiver
+///
+/// Oversampling by 8 allows higher baud rates for a given clock frequency, at
+/// the cost of a lower tolerance to clock deviations.
+///
+/// Not supported by LPUART1, where this setting is ignored.
+pub enum Oversampling {
+    #[doc = "Oversampling by 16 (reset value)"]
+    Over16,
+    #[doc = "Oversampling by 8"]
+    Over8,
+}
+
+/// Hardware flow control
+///
+/// Requires the RTS and/or CTS pins, see the `*_with_flow_control`,
+/// `*_with_rts`, and `*_with_cts` constructors of `Serial`, for example
+/// `Serial::usart2_with_flow_control`. The constructors that don't take these
+/// pins return `InvalidConfig`, if flow control is selected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlowControl {
+    /// No flow control (reset value)
+    None,
+    /// RTS is driven low while the receiver can accept data
+    Rts,
+    /// Transmission is only started while CTS is low
+    Cts,
+    /// Both RTS and CTS
+    RtsCts,
+}
+
 pub struct Config {
     pub baudrate: Baud,
     pub wordlength: WordLength,
     pub parity: Parity,
     pub stopbits: StopBits,
+    pub oversampling: Oversampling,
+    /// Use one sample bit instead of three
+    ///
+    /// This disables noise detection, but increases the tolerance to clock
+    /// deviations. Not supported by LPUART1, where this setting is ignored.
+    pub onebit: bool,
+    /// Use single-wire half-duplex mode
+    ///
+    /// TX and RX share the TX pin, and the RX pin isn't used. Use the
+    /// `*_half_duplex` constructors of `Serial`, for example
+    /// `Serial::usart2_half_duplex`, to construct a `Serial` with only a TX
+    /// pin. The constructors that take an RX pin return `InvalidConfig`, if
+    /// this is set. The line needs a pull-up, as the pin is only driven while
+    /// transmitting.
+    pub half_duplex: bool,
+    /// Configure the TX pin as open-drain
+    ///
+    /// Allows multiple nodes to share a line (wired-OR), for example together
+    /// with `half_duplex`. The line needs a pull-up. If this is `false`, the
+    /// output type of the TX pin is left unchanged.
+    pub tx_open_drain: bool,
+    /// Invert the TX pin
+    ///
+    /// The idle level of the line becomes low. Useful for buses with inverted
+    /// logic levels, or for open-drain buses with an inverting driver.
+    pub tx_invert: bool,
+    /// Hardware flow control
+    pub flow_control: FlowControl,
 }
 
 impl Config {
-    pub fn baudrate(mut self, baudrate: impl Into<Baud>) -> Self {
-        self.baudrate = baudrate.into();
+    pub fn baudrate(mut self, baudrate: impl IntoBaud) -> Self {
+        self.baudrate = baudrate.into_baud();
         self
     }
 
@@ -133,6 +254,36 @@ impl Config {
         self.stopbits = stopbits;
         self
     }
+
+    pub fn oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.oversampling = oversampling;
+        self
+    }
+
+    pub fn onebit(mut self, onebit: bool) -> Self {
+        self.onebit = onebit;
+        self
+    }
+
+    pub fn half_duplex(mut self, half_duplex: bool) -> Self {
+        self.half_duplex = half_duplex;
+        self
+    }
+
+    pub fn tx_open_drain(mut self, tx_open_drain: bool) -> Self {
+        self.tx_open_drain = tx_open_drain;
+        self
+    }
+
+    pub fn tx_invert(mut self, tx_invert: bool) -> Self {
+        self.tx_invert = tx_invert;
+        self
+    }
+
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -146,38 +297,90 @@ impl Default for Config {
             wordlength: WordLength::DataBits8,
             parity: Parity::ParityNone,
             stopbits: StopBits::STOP1,
+            oversampling: Oversampling::Over16,
+            onebit: false,
+            half_duplex: false,
+            tx_open_drain: false,
+            tx_invert: false,
+            flow_control: FlowControl::None,
         }
     }
 }
 
-/// Trait to mark serial pins with transmit capability.
+/// Pins that can be used as the transmit output (`USARTx_TX`, `LPUART1_TX`)
 pub trait TxPin<USART> {
+    /// Switches the pin to the TX alternate function of `USART`
+    ///
+    /// The function is AF0, AF2, AF4, AF6, or AF7, depending on the pin.
     fn setup(&self);
+
+    /// Configures the output type of the pin as open-drain or push-pull
+    fn set_open_drain(&self, open_drain: bool);
 }
 
-/// Trait to mark serial pins with receive capability.
+/// Pins that can be used as the receive input (`USARTx_RX`, `LPUART1_RX`)
 pub trait RxPin<USART> {
+    /// Switches the pin to the RX alternate function of `USART`
+    ///
+    /// The function is AF0, AF2, AF4, AF6, or AF7, depending on the pin.
+    fn setup(&self);
+}
+
+/// Pins that can be used as the request-to-send output (`USARTx_RTS`,
+/// `LPUART1_RTS`)
+pub trait RtsPin<USART> {
+    /// Switches the pin to the RTS alternate function of `USART`
+    ///
+    /// The function is AF0, AF2, AF4, AF5, or AF6, depending on the pin.
+    fn setup(&self);
+}
+
+/// Pins that can be used as the clear-to-send input (`USARTx_CTS`,
+/// `LPUART1_CTS`)
+pub trait CtsPin<USART> {
+    /// Switches the pin to the CTS alternate function of `USART`
+    ///
+    /// The function is AF0, AF4, AF5, or AF6, depending on the pin.
     fn setup(&self);
 }
 
-/// Macro to implement `TxPin` / `RxPin` for a certain pin, using a certain
+/// Macro to implement `TxPin` / `RxPin` / `RtsPin` / `CtsPin` for a certain pin, using a certain
 /// alternative function and for a certain serial peripheral.
+///
+/// Unused, if no `io-*` feature is selected.
+#[allow(unused_macros)]
 macro_rules! impl_pins {
     ($($pin:ident, $alt:ident, $instance:ty, $trait:ident;)*) => {
         $(
-            impl<MODE: PinMode> $trait<$instance> for $pin<MODE> {
-                fn setup(&self) {
-                    self.set_alt_mode(AltMode::$alt);
-                }
-            }
+            impl_pins!(@impl $trait, $pin, $alt, $instance);
         )*
-    }
+    };
+    (@impl TxPin, $pin:ident, $alt:ident, $instance:ty) => {
+        impl<MODE: crate::gpio::PinMode> TxPin<$instance> for $pin<MODE> {
+            fn setup(&self) {
+                self.set_alt_mode(crate::gpio::AltMode::$alt);
+            }
+
+            fn set_open_drain(&self, open_drain: bool) {
+                self.set_output_type(open_drain);
+            }
+        }
+    };
+    (@impl $trait:ident, $pin:ident, $alt:ident, $instance:ty) => {
+        impl<MODE: crate::gpio::PinMode> $trait<$instance> for $pin<MODE> {
+            fn setup(&self) {
+                self.set_alt_mode(crate::gpio::AltMode::$alt);
+            }
+        }
+    };
 }
 
 #[cfg(feature = "io-STM32L021")]
 impl_pins!(
     PA0, AF0, USART2, RxPin;
+    PA0, AF4, USART2, CtsPin;
     PA0, AF6, LPUART1, RxPin;
+    PA1, AF4, USART2, RtsPin;
     PA1, AF6, LPUART1, TxPin;
     PA2, AF4, USART2, TxPin;
     PA2, AF6, LPUART1, TxPin;
@@ -199,16 +402,20 @@ impl_pins!(
 
 #[cfg(feature = "io-STM32L031")]
 impl_pins!(
+    PA0, AF4, USART2, CtsPin;
+    PA1, AF4, USART2, RtsPin;
     PA2, AF4, USART2, TxPin;
     PA2, AF6, LPUART1, TxPin;
     PA3, AF4, USART2, RxPin;
     PA3, AF6, LPUART1, RxPin;
+    PA6, AF4, LPUART1, CtsPin;
     PA9, AF4, USART2, TxPin;
     PA10, AF4, USART2, RxPin;
     PA13, AF6, LPUART1, RxPin;
     PA14, AF4, USART2, TxPin;
     PA14, AF6, LPUART1, TxPin;
     PA15, AF4, USART2, RxPin;
+    PB1, AF4, LPUART1, RtsPin;
     PB6, AF0, USART2, TxPin;
     PB7, AF0, USART2, RxPin;
     PB10, AF6, LPUART1, TxPin;
@@ -218,16 +425,24 @@ impl_pins!(
 
 #[cfg(feature = "io-STM32L051")]
 impl_pins!(
+    PA0, AF4, USART2, CtsPin;
+    PA1, AF4, USART2, RtsPin;
     PA2, AF4, USART2, TxPin;
     PA3, AF4, USART2, RxPin;
+    PA6, AF4, LPUART1, CtsPin;
     PA9, AF4, USART1, TxPin;
     PA10, AF4, USART1, RxPin;
+    PA11, AF4, USART1, CtsPin;
+    PA12, AF4, USART1, RtsPin;
     PA14, AF4, USART2, TxPin;
     PA15, AF4, USART2, RxPin;
+    PB1, AF4, LPUART1, RtsPin;
     PB6, AF0, USART1, TxPin;
     PB7, AF0, USART1, RxPin;
     PB10, AF4, LPUART1, TxPin;
     PB11, AF4, LPUART1, RxPin;
+    PB12, AF2, LPUART1, RtsPin;
+    PB13, AF4, LPUART1, CtsPin;
     PC4, AF2, LPUART1, TxPin;
     PC5, AF2, LPUART1, RxPin;
     PC10, AF0, LPUART1, TxPin;
@@ -236,26 +451,39 @@ impl_pins!(
 
 #[cfg(feature = "io-STM32L071")]
 impl_pins!(
+    PA0, AF4, USART2, CtsPin;
     PA0, AF6, USART4, TxPin;
+    PA1, AF4, USART2, RtsPin;
     PA1, AF6, USART4, RxPin;
     PA2, AF4, USART2, TxPin;
     PA2, AF6, LPUART1, TxPin;
     PA3, AF4, USART2, RxPin;
     PA3, AF6, LPUART1, RxPin;
+    PA6, AF4, LPUART1, CtsPin;
     PA9, AF4, USART1, TxPin;
     PA10, AF4, USART1, RxPin;
+    PA11, AF4, USART1, CtsPin;
+    PA12, AF4, USART1, RtsPin;
     PA13, AF6, LPUART1, RxPin;
     PA14, AF4, USART2, TxPin;
     PA14, AF6, LPUART1, TxPin;
     PA15, AF4, USART2, RxPin;
+    PA15, AF6, USART4, RtsPin;
+    PB1, AF4, LPUART1, RtsPin;
+    PB3, AF5, USART1, RtsPin;
     PB3, AF6, USART5, TxPin;
+    PB4, AF5, USART1, CtsPin;
     PB4, AF6, USART5, RxPin;
+    PB5, AF6, USART5, RtsPin;
     PB6, AF0, USART1, TxPin;
     PB7, AF0, USART1, RxPin;
+    PB7, AF6, USART4, CtsPin;
     PB10, AF4, LPUART1, TxPin;
     PB10, AF7, LPUART1, RxPin;
     PB11, AF4, LPUART1, RxPin;
     PB11, AF7, LPUART1, TxPin;
+    PB12, AF2, LPUART1, RtsPin;
+    PB13, AF4, LPUART1, CtsPin;
     PC0, AF6, LPUART1, RxPin;
     PC1, AF6, LPUART1, TxPin;
     PC4, AF2, LPUART1, TxPin;
@@ -266,10 +494,14 @@ impl_pins!(
     PC11, AF6, USART4, RxPin;
     PC12, AF2, USART5, TxPin;
     PD2, AF6, USART5, RxPin;
+    PD3, AF0, USART2, CtsPin;
+    PD4, AF0, USART2, RtsPin;
     PD5, AF0, USART2, TxPin;
     PD6, AF0, USART2, RxPin;
     PD8, AF0, LPUART1, TxPin;
     PD9, AF0, LPUART1, RxPin;
+    PD11, AF0, LPUART1, CtsPin;
+    PD12, AF0, LPUART1, RtsPin;
     PE8, AF6, USART4, TxPin;
     PE9, AF6, USART4, RxPin;
     PE10, AF6, USART5, TxPin;
@@ -277,15 +509,104 @@ impl_pins!(
 );
 
 /// Serial abstraction
-pub struct Serial<USART> {
+///
+/// Owns the TX and RX pins (plus the RTS and CTS pins, if hardware flow control
+/// is used), or only the TX pin in half-duplex mode, which are returned by
+/// [`Serial::release`].
+pub struct Serial<USART, PINS> {
     usart: USART,
     rx: Rx<USART>,
     tx: Tx<USART>,
+    pins: PINS,
 }
 
 /// Serial receiver
 pub struct Rx<USART> {
     _usart: PhantomData<USART>,
+    overrun_mode: OverrunMode,
+    counters: ErrorCounters,
+}
+
+impl<USART> Rx<USART> {
+    fn new() -> Self {
+        Rx {
+            _usart: PhantomData,
+            overrun_mode: OverrunMode::Report,
+            counters: ErrorCounters::default(),
+        }
+    }
+
+    /// Selects how overrun errors are handled
+    ///
+    /// See [`OverrunMode`].
+    pub fn set_overrun_mode(&mut self, mode: OverrunMode) {
+        self.overrun_mode = mode;
+    }
+
+    /// Returns the reception error statistics
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.counters
+    }
+
+    /// Resets the reception error statistics
+    pub fn reset_error_counters(&mut self) {
+        self.counters = ErrorCounters::default();
+    }
+}
+
+/// How the receiver handles overrun errors
+///
+/// An overrun occurs, if a byte is received before the previous one has been
+/// read. The previous byte is kept, the new one is lost.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverrunMode {
+    /// Overruns are returned as [`Error::Overrun`]
+    ///
+    /// This is the default.
+    Report,
+    /// Overruns are cleared automatically and counted in
+    /// [`ErrorCounters::overrun`]
+    ///
+    /// Useful for links that can tolerate lost bytes, like telemetry, as
+    /// the receive path doesn't need to handle the error.
+    Count,
+}
+
+/// Reception error statistics
+///
+/// See [`Rx::error_counters`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ErrorCounters {
+    /// Number of overruns, while in [`OverrunMode::Count`]
+    ///
+    /// Several overruns in a row are only counted once, as the hardware only
+    /// flags the first one.
+    pub overrun: u32,
+}
+
+/// Calculates the value of the BRR register
+///
+/// `pclk` is the peripheral clock frequency in Hz. LPUART1 uses a different
+/// encoding than the USARTs, and doesn't support oversampling by 8.
+fn calculate_brr(pclk: u32, baudrate: u32, over8: bool, lpuart: bool) -> u32 {
+    let brr = if over8 {
+        // USARTDIV = 2 * f_ck / baudrate, with BRR[2:0] = USARTDIV[3:0] >> 1
+        let div = (2 * pclk + baudrate / 2) / baudrate;
+        (div & !0xf) | ((div & 0xf) >> 1)
+    } else {
+        let div = (pclk * 25) / (4 * baudrate);
+        let mantissa = div / 100;
+        let fraction = ((div - mantissa * 100) * 16 + 50) / 100;
+        // The fraction is rounded and can be 16, which needs to carry over
+        // into the mantissa.
+        (mantissa << 4) + fraction
+    };
+
+    if lpuart {
+        brr * 256
+    } else {
+        brr
+    }
 }
 
 /// Serial transmitter
@@ -295,11 +616,19 @@ pub struct Tx<USART> {
 
 macro_rules! usart {
     ($(
-        $USARTX:ident: ($usartX:ident, $pclkX:ident, $SerialExt:ident),
+        $USARTX:ident: (
+            $usartX:ident,
+            $usartX_half_duplex:ident,
+            $usartX_with_rts:ident,
+            $usartX_with_cts:ident,
+            $usartX_with_flow_control:ident,
+            $pclkX:ident,
+            $SerialExt:ident
+        ),
     )+) => {
         $(
             pub trait $SerialExt<TX, RX> {
-                fn usart(self, tx: TX, rx: RX, config: Config, rcc: &mut Rcc) -> Result<Serial<$USARTX>, InvalidConfig>;
+                fn usart(self, tx: TX, rx: RX, config: Config, rcc: &mut Rcc) -> Result<Serial<$USARTX, (TX, RX)>, InvalidConfig>;
             }
 
             impl<TX, RX> $SerialExt<TX, RX> for $USARTX
@@ -307,38 +636,206 @@ macro_rules! usart {
                     TX: TxPin<$USARTX>,
                     RX: RxPin<$USARTX>,
             {
-                fn usart(self, tx: TX, rx: RX, config: Config, rcc: &mut Rcc) -> Result<Serial<$USARTX>, InvalidConfig> {
+                fn usart(self, tx: TX, rx: RX, config: Config, rcc: &mut Rcc) -> Result<Serial<$USARTX, (TX, RX)>, InvalidConfig> {
                     Serial::$usartX(self, tx, rx, config, rcc)
                 }
             }
 
-            impl Serial<$USARTX> {
-                pub fn $usartX<TX, RX>(
+            impl<TX, RX> Serial<$USARTX, (TX, RX)>
+            where
+                TX: TxPin<$USARTX>,
+                RX: RxPin<$USARTX>,
+            {
+                pub fn $usartX(
                     usart: $USARTX,
                     tx: TX,
                     rx: RX,
                     config: Config,
                     rcc: &mut Rcc,
-                ) -> Result<Self, InvalidConfig>
-                where
-                    TX: TxPin<$USARTX>,
-                    RX: RxPin<$USARTX>,
-                {
+                ) -> Result<Self, InvalidConfig> {
+                    // Flow control requires the RTS/CTS pins, and half-duplex
+                    // mode doesn't use the RX pin
+                    if config.flow_control != FlowControl::None || config.half_duplex {
+                        return Err(InvalidConfig);
+                    }
+
+                    tx.setup();
+                    rx.setup();
+
+                    if config.tx_open_drain {
+                        tx.set_open_drain(true);
+                    }
+
+                    Self::init(usart, (tx, rx), config, rcc)
+                }
+            }
+
+            impl<TX, RX, RTS> Serial<$USARTX, (TX, RX, RTS)>
+            where
+                TX: TxPin<$USARTX>,
+                RX: RxPin<$USARTX>,
+                RTS: RtsPin<$USARTX>,
+            {
+                /// Creates a `Serial` with RTS flow control only
+                ///
+                /// `config.flow_control` is ignored, `FlowControl::Rts` is
+                /// always used. Returns `InvalidConfig`, if
+                /// `config.half_duplex` is set.
+                pub fn $usartX_with_rts(
+                    usart: $USARTX,
+                    tx: TX,
+                    rx: RX,
+                    rts: RTS,
+                    config: Config,
+                    rcc: &mut Rcc,
+                ) -> Result<Self, InvalidConfig> {
+                    // Half-duplex mode doesn't use the RX pin
+                    if config.half_duplex {
+                        return Err(InvalidConfig);
+                    }
+
+                    tx.setup();
+                    rx.setup();
+                    rts.setup();
+
+                    if config.tx_open_drain {
+                        tx.set_open_drain(true);
+                    }
+
+                    Self::init(usart, (tx, rx, rts), config.flow_control(FlowControl::Rts), rcc)
+                }
+            }
+
+            impl<TX, RX, CTS> Serial<$USARTX, (TX, RX, CTS)>
+            where
+                TX: TxPin<$USARTX>,
+                RX: RxPin<$USARTX>,
+                CTS: CtsPin<$USARTX>,
+            {
+                /// Creates a `Serial` with CTS flow control only
+                ///
+                /// `config.flow_control` is ignored, `FlowControl::Cts` is
+                /// always used. Returns `InvalidConfig`, if
+                /// `config.half_duplex` is set.
+                pub fn $usartX_with_cts(
+                    usart: $USARTX,
+                    tx: TX,
+                    rx: RX,
+                    cts: CTS,
+                    config: Config,
+                    rcc: &mut Rcc,
+                ) -> Result<Self, InvalidConfig> {
+                    // Half-duplex mode doesn't use the RX pin
+                    if config.half_duplex {
+                        return Err(InvalidConfig);
+                    }
+
+                    tx.setup();
+                    rx.setup();
+                    cts.setup();
+
+                    if config.tx_open_drain {
+                        tx.set_open_drain(true);
+                    }
+
+                    Self::init(usart, (tx, rx, cts), config.flow_control(FlowControl::Cts), rcc)
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> Serial<$USARTX, (TX, RX, RTS, CTS)>
+            where
+                TX: TxPin<$USARTX>,
+                RX: RxPin<$USARTX>,
+                RTS: RtsPin<$USARTX>,
+                CTS: CtsPin<$USARTX>,
+            {
+                /// Creates a `Serial` with hardware flow control
+                ///
+                /// Takes the RTS and CTS pins in addition to the TX and RX pins.
+                /// `config.flow_control` selects which of them are actually used, for
+                /// example `FlowControl::RtsCts` for both. If only one of them is
+                /// needed, use the `*_with_rts` or `*_with_cts` constructor
+                /// instead. Returns `InvalidConfig`, if `config.half_duplex`
+                /// is set.
+                pub fn $usartX_with_flow_control(
+                    usart: $USARTX,
+                    tx: TX,
+                    rx: RX,
+                    rts: RTS,
+                    cts: CTS,
+                    config: Config,
+                    rcc: &mut Rcc,
+                ) -> Result<Self, InvalidConfig> {
+                    // Half-duplex mode doesn't use the RX pin
+                    if config.half_duplex {
+                        return Err(InvalidConfig);
+                    }
+
                     tx.setup();
                     rx.setup();
+                    rts.setup();
+                    cts.setup();
+
+                    if config.tx_open_drain {
+                        tx.set_open_drain(true);
+                    }
+
+                    Self::init(usart, (tx, rx, rts, cts), config, rcc)
+                }
+            }
+
+            impl<TX> Serial<$USARTX, TX>
+            where
+                TX: TxPin<$USARTX>,
+            {
+                /// Creates a `Serial` in single-wire half-duplex mode
+                ///
+                /// Only takes a TX pin, which is used for both directions.
+                /// `config.half_duplex` is ignored, half-duplex mode is always
+                /// enabled. The receiver sees all transmitted bytes, so they
+                /// need to be read back (or the errors cleared) after
+                /// transmitting. Flow control isn't supported.
+                pub fn $usartX_half_duplex(
+                    usart: $USARTX,
+                    tx: TX,
+                    config: Config,
+                    rcc: &mut Rcc,
+                ) -> Result<Self, InvalidConfig> {
+                    if config.flow_control != FlowControl::None {
+                        return Err(InvalidConfig);
+                    }
+
+                    tx.setup();
 
+                    if config.tx_open_drain {
+                        tx.set_open_drain(true);
+                    }
+
+                    Self::init(usart, tx, config.half_duplex(true), rcc)
+                }
+            }
+
+            impl<PINS> Serial<$USARTX, PINS> {
+                fn init(
+                    usart: $USARTX,
+                    pins: PINS,
+                    config: Config,
+                    rcc: &mut Rcc,
+                ) -> Result<Self, InvalidConfig> {
                     // Enable clock for USART
                     <$USARTX>::enable(rcc);
 
-                    // Calculate correct baudrate divisor on the fly
-                    let div = (rcc.clocks.$pclkX().0 * 25) / (4 * config.baudrate.0);
-                    let mantissa = div / 100;
-                    let fraction = ((div - mantissa * 100) * 16 + 50) / 100;
-                    let mut brr = mantissa << 4 | fraction;
+                    // LPUART1 doesn't support oversampling by 8 or one-bit sampling
+                    let is_lpuart = stringify!($usartX) == "lpuart1";
+                    let over8 = !is_lpuart && matches!(config.oversampling, Oversampling::Over8);
+                    let onebit = !is_lpuart && config.onebit;
 
-                    if stringify!($usartX) == "lpuart1" {
-                        brr *= 256
-                    }
+                    let brr = calculate_brr(
+                        rcc.clocks.$pclkX().0,
+                        config.baudrate.0,
+                        over8,
+                        is_lpuart,
+                    );
 
                     usart
                         .brr
@@ -357,12 +854,27 @@ macro_rules! usart {
                             .dmar().enabled()
                     );
 
+                    if config.half_duplex {
+                        usart.cr3.modify(|_, w| w.hdsel().set_bit());
+                    }
+
+                    let (rts, cts) = match config.flow_control {
+                        FlowControl::None => (false, false),
+                        FlowControl::Rts => (true, false),
+                        FlowControl::Cts => (false, true),
+                        FlowControl::RtsCts => (true, true),
+                    };
+                    usart.cr3.modify(|_, w| w.rtse().bit(rts).ctse().bit(cts));
+
+                    // ONEBIT (CR3 bit 11) isn't available through the API of all instances
+                    if onebit {
+                        usart.cr3.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 11)) });
+                    }
+
                     // Enable transmission and receiving
                     // and configure frame
                     usart.cr1.write(|w| {
-                        w.ue()
-                            .set_bit()
-                            .te()
+                        w.te()
                             .set_bit()
                             .re()
                             .set_bit()
@@ -388,11 +900,23 @@ macro_rules! usart {
                             StopBits::STOP2 => 0b10,
                             StopBits::STOP1P5 => 0b11,
                         })
+                        .txinv().bit(config.tx_invert)
                     );
+
+                    // OVER8 (CR1 bit 15) isn't available through the API of all instances
+                    if over8 {
+                        usart.cr1.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 15)) });
+                    }
+
+                    // Enable USART after configuration, as most of the settings above can only be
+                    // changed while it is disabled
+                    usart.cr1.modify(|_, w| w.ue().set_bit());
+
                     Ok(Serial {
                         usart,
                         tx: Tx { _usart: PhantomData },
-                        rx: Rx { _usart: PhantomData },
+                        rx: Rx::new(),
+                        pins,
                     })
                 }
 
@@ -408,6 +932,21 @@ macro_rules! usart {
                         Event::Idle => {
                             self.usart.cr1.modify(|_, w| w.idleie().set_bit())
                         },
+                        Event::Tc => {
+                            self.usart.cr1.modify(|_, w| w.tcie().set_bit())
+                        },
+                        Event::Cts => {
+                            self.usart.cr3.modify(|_, w| w.ctsie().set_bit())
+                        },
+                        Event::Pe => {
+                            self.usart.cr1.modify(|_, w| w.peie().set_bit())
+                        },
+                        Event::Error => {
+                            self.usart.cr3.modify(|_, w| w.eie().set_bit())
+                        },
+                        Event::CharacterMatch => {
+                            self.usart.cr1.modify(|_, w| w.cmie().set_bit())
+                        },
                     }
                 }
 
@@ -423,6 +962,21 @@ macro_rules! usart {
                         Event::Idle => {
                             self.usart.cr1.modify(|_, w| w.idleie().clear_bit())
                         },
+                        Event::Tc => {
+                            self.usart.cr1.modify(|_, w| w.tcie().clear_bit())
+                        },
+                        Event::Cts => {
+                            self.usart.cr3.modify(|_, w| w.ctsie().clear_bit())
+                        },
+                        Event::Pe => {
+                            self.usart.cr1.modify(|_, w| w.peie().clear_bit())
+                        },
+                        Event::Error => {
+                            self.usart.cr3.modify(|_, w| w.eie().clear_bit())
+                        },
+                        Event::CharacterMatch => {
+                            self.usart.cr1.modify(|_, w| w.cmie().clear_bit())
+                        },
                     }
                 }
 
@@ -440,20 +994,52 @@ macro_rules! usart {
                 /// `pending_event`.
                 pub fn pending_event(&self) -> Option<Event> {
                     let cr1 = self.usart.cr1.read();
+                    let cr3 = self.usart.cr3.read();
                     let isr = self.usart.isr.read();
 
+                    let error = isr.fe().bit_is_set()
+                        || isr.ore().bit_is_set()
+                        || isr.nf().bit_is_set();
+
                     if cr1.rxneie().bit_is_set() && isr.rxne().bit_is_set() {
                         // Give highest priority to RXNE to help with avoiding overrun
                         Some(Event::Rxne)
+                    } else if cr1.peie().bit_is_set() && isr.pe().bit_is_set() {
+                        Some(Event::Pe)
+                    } else if cr3.eie().bit_is_set() && error {
+                        Some(Event::Error)
+                    } else if cr1.cmie().bit_is_set() && isr.cmf().bit_is_set() {
+                        Some(Event::CharacterMatch)
                     } else if cr1.txeie().bit_is_set() && isr.txe().bit_is_set() {
                         Some(Event::Txe)
+                    } else if cr1.tcie().bit_is_set() && isr.tc().bit_is_set() {
+                        Some(Event::Tc)
                     } else if cr1.idleie().bit_is_set() && isr.idle().bit_is_set() {
                         Some(Event::Idle)
+                    } else if cr3.ctsie().bit_is_set() && isr.ctsif().bit_is_set() {
+                        Some(Event::Cts)
                     } else {
                         None
                     }
                 }
 
+                /// Sets the character that triggers `Event::CharacterMatch` when received.
+                ///
+                /// This is useful to detect the end of a frame in protocols that use a delimiter,
+                /// like `b'\n'` or `0x7E`, also while receiving using DMA.
+                ///
+                /// The USART is briefly disabled to change the character, so this should not be
+                /// called while a transmission or reception is ongoing.
+                pub fn set_match_character(&mut self, character: u8) {
+                    self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+                    // ADD[7:0] (CR2 bits 31:24) isn't available through the API of all instances.
+                    // ADDM7 (CR2 bit 4) selects comparison of the full character.
+                    self.usart.cr2.modify(|r, w| unsafe {
+                        w.bits((r.bits() & 0x00ff_ffff) | (u32::from(character) << 24) | (1 << 4))
+                    });
+                    self.usart.cr1.modify(|_, w| w.ue().set_bit());
+                }
+
                 /// Checks for reception errors that may have occurred.
                 ///
                 /// Note that multiple errors can be signaled at the same time. In that case,
@@ -467,16 +1053,59 @@ macro_rules! usart {
                     self.rx.clear_errors()
                 }
 
+                /// Selects how overrun errors are handled
+                ///
+                /// Works like [`Rx::set_overrun_mode`].
+                pub fn set_overrun_mode(&mut self, mode: OverrunMode) {
+                    self.rx.set_overrun_mode(mode)
+                }
+
+                /// Returns the reception error statistics
+                pub fn error_counters(&self) -> ErrorCounters {
+                    self.rx.error_counters()
+                }
+
                 pub fn split(self) -> (Tx<$USARTX>, Rx<$USARTX>) {
                     (self.tx, self.rx)
                 }
 
-                pub fn release(self) -> $USARTX {
-                    self.usart
+                /// Releases the USART peripheral and the pins
+                ///
+                /// The pins stay in alternate function mode. Use one of their `into_*`
+                /// methods to reconfigure them, for example as analog inputs to save
+                /// power.
+                pub fn release(self) -> ($USARTX, PINS) {
+                    (self.usart, self.pins)
+                }
+
+                /// Disables the USART and its clock, then releases it and the
+                /// pins
+                ///
+                /// Waits for an ongoing transmission to complete first. Use
+                /// this instead of `release`, if the USART isn't needed for a
+                /// while, so its clock doesn't keep drawing power.
+                ///
+                /// Returns `self` together with [`Error::Timeout`], without
+                /// disabling anything, if the transmission doesn't complete
+                /// in time.
+                pub fn release_and_disable(self, rcc: &mut Rcc)
+                    -> Result<($USARTX, PINS), (Self, Error)>
+                {
+                    let usart = &self.usart;
+                    if let Err(err) = crate::wait_for(
+                        || usart.isr.read().tc().bit_is_set(),
+                        Error::Timeout,
+                    ) {
+                        return Err((self, err));
+                    }
+
+                    self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+                    <$USARTX>::disable(rcc);
+                    Ok((self.usart, self.pins))
                 }
             }
 
-            impl hal::serial::Read<u8> for Serial<$USARTX> {
+            impl<PINS> hal::serial::Read<u8> for Serial<$USARTX, PINS> {
                 type Error = Error;
 
                 fn read(&mut self) -> nb::Result<u8, Error> {
@@ -484,7 +1113,7 @@ macro_rules! usart {
                 }
             }
 
-             impl hal::serial::Write<u8> for  Serial<$USARTX> {
+             impl<PINS> hal::serial::Write<u8> for Serial<$USARTX, PINS> {
                 type Error = Error;
 
                 fn flush(&mut self) -> nb::Result<(), Self::Error> {
@@ -519,6 +1148,21 @@ macro_rules! usart {
                     icr.write(|w| w.idlecf().set_bit());
                 }
 
+                /// Returns true if the character set using `Serial::set_match_character` has
+                /// been received
+                ///
+                /// This flag is set by hardware even when interrupts are disabled (CMIE=0 in CR1)
+                pub fn is_character_match(&self) -> bool {
+                    let isr = unsafe { (*$USARTX::ptr()).isr.read() };
+                    isr.cmf().bit_is_set()
+                }
+
+                /// Clear character match interrupt flag
+                pub fn clear_character_match(&self) {
+                    let icr = unsafe { &(*$USARTX::ptr()).icr };
+                    icr.write(|w| w.cmcf().set_bit());
+                }
+
                 /// Checks for reception errors that may have occurred.
                 ///
                 /// Note that multiple errors can be signaled at the same time. In that case,
@@ -540,7 +1184,12 @@ macro_rules! usart {
                         return Err(Error::Noise.into());
                     } else if isr.ore().bit_is_set() {
                         icr.write(|w| {w.orecf().set_bit()});
-                        return Err(Error::Overrun.into());
+                        match self.overrun_mode {
+                            OverrunMode::Report => return Err(Error::Overrun.into()),
+                            OverrunMode::Count => {
+                                self.counters.overrun = self.counters.overrun.wrapping_add(1);
+                            }
+                        }
                     }
 
                     Ok(())
@@ -612,6 +1261,90 @@ macro_rules! usart {
                         )
                     }
                 }
+
+                /// Starts continuous reception into a circular buffer
+                ///
+                /// The DMA writes received bytes into `buffer`, wrapping around at its
+                /// end, without ever stopping. Use [`CircularRx::read_available`] to read
+                /// them. As long as the bytes are read before the DMA wraps around and
+                /// overwrites them, no data is lost, unlike with consecutive
+                /// [`Rx::read_all`] transfers.
+                ///
+                /// # Panics
+                ///
+                /// Panics, if `buffer` is larger than 65535 bytes.
+                pub fn read_circular<Buffer, Channel>(self,
+                    dma:     &mut dma::Handle,
+                    buffer:  Pin<Buffer>,
+                    channel: Channel,
+                )
+                    -> CircularRx<$USARTX, Channel, Buffer>
+                    where
+                        Self:           dma::Target<Channel>,
+                        Buffer:         DerefMut + 'static,
+                        Buffer::Target: AsMutSlice<Element=u8>,
+                        Channel:        dma::Channel,
+                {
+                    let num_words = (*buffer).len();
+                    let (overrun_mode, counters) = (self.overrun_mode, self.counters);
+
+                    // Safe, because we're only taking the address of a
+                    // register.
+                    let address =
+                        &unsafe { &*$USARTX::ptr() }.rdr as *const _ as u32;
+
+                    // The cast to `u16` could truncate the value, but if it
+                    // does, `Transfer::new` is going to panic anyway.
+                    let circular_buffer =
+                        dma::CircularBuffer::new(buffer.as_ptr(), num_words as u16);
+
+                    // Safe, because the trait bounds of this method guarantee
+                    // that the buffer can be written to.
+                    let transfer = unsafe {
+                        dma::Transfer::new(
+                            dma,
+                            self,
+                            channel,
+                            buffer,
+                            num_words,
+                            address,
+                            dma::Priority::high(),
+                            dma::Direction::peripheral_to_memory(),
+                            true,
+                        )
+                    }
+                    .start();
+
+                    CircularRx {
+                        transfer,
+                        buffer: circular_buffer,
+                        overrun_mode,
+                        counters,
+                    }
+                }
+            }
+
+            #[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+            impl<Channel, Buffer> CircularRx<$USARTX, Channel, Buffer> {
+                /// Checks for reception errors that may have occurred.
+                ///
+                /// Works like [`Rx::check_errors`], using the overrun mode and error
+                /// statistics of the circular reception.
+                pub fn check_errors(&mut self) -> Result<(), Error> {
+                    let mut rx = Rx::<$USARTX> {
+                        _usart: PhantomData,
+                        overrun_mode: self.overrun_mode,
+                        counters: self.counters,
+                    };
+                    let result = rx.check_errors();
+                    self.counters = rx.counters;
+                    result
+                }
+
+                /// Clears any signaled errors without returning them.
+                pub fn clear_errors(&mut self) {
+                    Rx::<$USARTX>::new().clear_errors()
+                }
             }
 
             impl hal::serial::Read<u8> for Rx<$USARTX> {
@@ -679,6 +1412,15 @@ macro_rules! usart {
                 }
             }
 
+            #[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+            impl sealed::TxAddress for Tx<$USARTX> {
+                fn tdr_address() -> u32 {
+                    // Safe, because we're only taking the address of a
+                    // register.
+                    &unsafe { &*$USARTX::ptr() }.tdr as *const _ as u32
+                }
+            }
+
             #[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
             impl Tx<$USARTX> {
                 pub fn write_all<Buffer, Channel>(self,
@@ -736,32 +1478,58 @@ macro_rules! usart {
     }
 }
 
-// LPUART1 and USART2 are available on category 1/2/3/5 MCUs
-#[cfg(any(
-    feature = "io-STM32L021",
-    feature = "io-STM32L031",
-    feature = "io-STM32L051",
-    feature = "io-STM32L071",
-))]
-usart! {
-    LPUART1: (lpuart1, apb1_clk, Serial1LpExt),
-    USART2: (usart2, apb1_clk, Serial2Ext),
-}
-
-// USART1 is available on category 3/5 MCUs
-#[cfg(any(feature = "io-STM32L051", feature = "io-STM32L071"))]
-usart! {
-    USART1: (usart1, apb1_clk, Serial1Ext),
-}
-
-// USART4 and USART5 are available on category 5 MCUs
-#[cfg(feature = "io-STM32L071")]
+// All sub-families share the same USART peripherals. Which of them are actually
+// available on a specific MCU is determined by the pins, which are only
+// implemented for the selected `io-*` feature.
 usart! {
-    USART4: (usart4, apb1_clk, Serial4Ext),
-    USART5: (usart5, apb1_clk, Serial5Ext),
+    LPUART1: (
+        lpuart1,
+        lpuart1_half_duplex,
+        lpuart1_with_rts,
+        lpuart1_with_cts,
+        lpuart1_with_flow_control,
+        apb1_clk,
+        Serial1LpExt
+    ),
+    USART1: (
+        usart1,
+        usart1_half_duplex,
+        usart1_with_rts,
+        usart1_with_cts,
+        usart1_with_flow_control,
+        apb1_clk,
+        Serial1Ext
+    ),
+    USART2: (
+        usart2,
+        usart2_half_duplex,
+        usart2_with_rts,
+        usart2_with_cts,
+        usart2_with_flow_control,
+        apb1_clk,
+        Serial2Ext
+    ),
+    USART4: (
+        usart4,
+        usart4_half_duplex,
+        usart4_with_rts,
+        usart4_with_cts,
+        usart4_with_flow_control,
+        apb1_clk,
+        Serial4Ext
+    ),
+    USART5: (
+        usart5,
+        usart5_half_duplex,
+        usart5_with_rts,
+        usart5_with_cts,
+        usart5_with_flow_control,
+        apb1_clk,
+        Serial5Ext
+    ),
 }
 
-impl Serial<LPUART1> {
+impl<PINS> Serial<LPUART1, PINS> {
     /// Switches LPUART1 clock course to LSE
     ///
     /// Consumes LSE token, to get guarantee that
@@ -796,9 +1564,281 @@ impl Serial<LPUART1> {
     }
 }
 
-impl<USART> fmt::Write for Serial<USART>
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+mod sealed {
+    pub trait TxAddress {
+        fn tdr_address() -> u32;
+    }
+}
+
+/// State of the transmitter of a [`DmaLogger`]
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+enum LoggerTx<USART, Channel> {
+    Idle(Tx<USART>, Channel),
+    Busy(dma::Transfer<Tx<USART>, Channel, &'static mut [u8], dma::Started>),
+}
+
+/// Non-blocking logger, writing formatted text using DMA
+///
+/// Uses two buffers: While one of them is transmitted by the DMA, text is
+/// written into the other one. Once the transmission is complete, the buffers
+/// are swapped on the next write (or call to [`DmaLogger::poll`]). This way,
+/// writing to the logger only takes as long as copying the text, as long as
+/// the buffers don't fill up faster than they are transmitted.
+///
+/// If both buffers are full, writing blocks until the transmission of one
+/// buffer has finished.
+///
+/// ``` ignore
+/// let logger = DmaLogger::new(tx, dma.handle, dma.channels.channel4, buf_a, buf_b);
+/// writeln!(logger, "adc: {}", value).unwrap();
+/// ```
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+pub struct DmaLogger<USART, Channel> {
+    handle: dma::Handle,
+    tx: Option<LoggerTx<USART, Channel>>,
+    /// Buffer that text is written into
+    buffer: Option<&'static mut [u8]>,
+    /// Number of bytes written into `buffer`
+    len: usize,
+    /// The other buffer, while it's not being transmitted
+    spare: Option<&'static mut [u8]>,
+}
+
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+impl<USART, Channel> DmaLogger<USART, Channel>
+where
+    Tx<USART>: sealed::TxAddress + dma::Target<Channel>,
+    Channel: dma::Channel,
+{
+    /// Creates a new logger
+    ///
+    /// The logger takes ownership of the DMA handle, as it needs it to start
+    /// every transfer. It can still be used to set up transfers on other
+    /// channels using [`DmaLogger::dma_handle`].
+    ///
+    /// # Panics
+    ///
+    /// Panics, if one of the buffers is empty or larger than 65535 bytes.
+    pub fn new(
+        tx: Tx<USART>,
+        handle: dma::Handle,
+        channel: Channel,
+        buffer_a: &'static mut [u8],
+        buffer_b: &'static mut [u8],
+    ) -> Self {
+        assert!(!buffer_a.is_empty() && buffer_a.len() <= usize::from(u16::MAX));
+        assert!(!buffer_b.is_empty() && buffer_b.len() <= usize::from(u16::MAX));
+
+        DmaLogger {
+            handle,
+            tx: Some(LoggerTx::Idle(tx, channel)),
+            buffer: Some(buffer_a),
+            len: 0,
+            spare: Some(buffer_b),
+        }
+    }
+
+    /// Starts the transmission of buffered text, if the DMA is idle
+    ///
+    /// Writing to the logger does this automatically. Call this method
+    /// periodically to make sure the last text written is transmitted as well.
+    pub fn poll(&mut self) {
+        let tx = match self.tx.take() {
+            Some(LoggerTx::Busy(transfer)) if transfer.is_active() => {
+                self.tx = Some(LoggerTx::Busy(transfer));
+                return;
+            }
+            Some(LoggerTx::Busy(transfer)) => {
+                // A transfer error means some text got lost, which can't be
+                // helped at this point.
+                let res = match transfer.wait() {
+                    Ok(res) => res,
+                    Err((res, _)) => res,
+                };
+                self.spare = Some(Pin::into_inner(res.buffer));
+                (res.target, res.channel)
+            }
+            Some(LoggerTx::Idle(tx, channel)) => (tx, channel),
+            None => unreachable!(),
+        };
+
+        if self.len == 0 {
+            self.tx = Some(LoggerTx::Idle(tx.0, tx.1));
+            return;
+        }
+
+        let buffer = self.buffer.take().unwrap();
+        // Safe, because the buffer is `'static` and owned by the transfer
+        // until it's returned to us.
+        let transfer = unsafe {
+            dma::Transfer::new(
+                &mut self.handle,
+                tx.0,
+                tx.1,
+                Pin::new(buffer),
+                self.len,
+                Tx::<USART>::tdr_address(),
+                dma::Priority::low(),
+                dma::Direction::memory_to_peripheral(),
+                false,
+            )
+        }
+        .start();
+
+        self.tx = Some(LoggerTx::Busy(transfer));
+        self.buffer = self.spare.take();
+        self.len = 0;
+    }
+
+    /// Blocks until all buffered text has been transmitted
+    pub fn flush(&mut self) {
+        loop {
+            self.poll();
+            if self.len == 0 && matches!(self.tx, Some(LoggerTx::Idle(..))) {
+                return;
+            }
+        }
+    }
+
+    /// Returns the DMA handle, to set up transfers on other channels
+    pub fn dma_handle(&mut self) -> &mut dma::Handle {
+        &mut self.handle
+    }
+
+    /// Transmits all buffered text and releases the resources
+    pub fn free(
+        mut self,
+    ) -> (
+        Tx<USART>,
+        dma::Handle,
+        Channel,
+        &'static mut [u8],
+        &'static mut [u8],
+    ) {
+        self.flush();
+        match self.tx {
+            Some(LoggerTx::Idle(tx, channel)) => (
+                tx,
+                self.handle,
+                channel,
+                self.buffer.unwrap(),
+                self.spare.unwrap(),
+            ),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+impl<USART, Channel> fmt::Write for DmaLogger<USART, Channel>
+where
+    Tx<USART>: sealed::TxAddress + dma::Target<Channel>,
+    Channel: dma::Channel,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+
+        while !bytes.is_empty() {
+            self.poll();
+
+            let buffer = self.buffer.as_mut().unwrap();
+            let n = bytes.len().min(buffer.len() - self.len);
+            buffer[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+        }
+
+        self.poll();
+
+        Ok(())
+    }
+}
+
+/// Continuous reception into a circular DMA buffer
+///
+/// Created by [`Rx::read_circular`].
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+pub struct CircularRx<USART, Channel, Buffer> {
+    transfer: dma::Transfer<Rx<USART>, Channel, Buffer, dma::Started>,
+    buffer: dma::CircularBuffer<u8>,
+    overrun_mode: OverrunMode,
+    counters: ErrorCounters,
+}
+
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+impl<USART, Channel, Buffer> CircularRx<USART, Channel, Buffer>
 where
-    Serial<USART>: hal::serial::Write<u8>,
+    Channel: dma::Channel,
+{
+    /// Returns an iterator over all currently available bytes
+    ///
+    /// The iterator returns `None`, once all received bytes have been read.
+    ///
+    /// If the DMA has overwritten bytes before they were read, the iterator
+    /// returns `Error::BufferOverrun`. The buffer is emptied in that case, and
+    /// bytes received from then on can be read normally.
+    pub fn read_available(&mut self) -> ReadAvailable<'_, USART, Channel, Buffer> {
+        ReadAvailable {
+            buffer: &mut self.buffer,
+            transfer: &self.transfer,
+        }
+    }
+
+    /// Selects how overrun errors are handled
+    ///
+    /// See [`OverrunMode`].
+    pub fn set_overrun_mode(&mut self, mode: OverrunMode) {
+        self.overrun_mode = mode;
+    }
+
+    /// Returns the reception error statistics
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.counters
+    }
+
+    /// Resets the reception error statistics
+    pub fn reset_error_counters(&mut self) {
+        self.counters = ErrorCounters::default();
+    }
+
+    /// Stops reception and returns the receiver, the DMA channel, and the
+    /// buffer
+    ///
+    /// The receiver keeps the overrun mode and error statistics.
+    pub fn stop(self) -> (Rx<USART>, Channel, Pin<Buffer>) {
+        let res = self.transfer.stop();
+        let mut rx = res.target;
+        rx.overrun_mode = self.overrun_mode;
+        rx.counters = self.counters;
+        (rx, res.channel, res.buffer)
+    }
+}
+
+/// Iterator over the bytes available in a [`CircularRx`]
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+pub struct ReadAvailable<'r, USART, Channel, Buffer> {
+    buffer: &'r mut dma::CircularBuffer<u8>,
+    transfer: &'r dma::Transfer<Rx<USART>, Channel, Buffer, dma::Started>,
+}
+
+#[cfg(any(feature = "stm32l0x1", feature = "stm32l0x2", feature = "stm32l0x3"))]
+impl<USART, Channel, Buffer> Iterator for ReadAvailable<'_, USART, Channel, Buffer>
+where
+    Channel: dma::Channel,
+{
+    type Item = Result<u8, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buffer
+            .read(self.transfer)
+            .map(|result| result.map_err(|dma::Overrun| Error::BufferOverrun))
+    }
+}
+
+impl<USART, PINS> fmt::Write for Serial<USART, PINS>
+where
+    Serial<USART, PINS>: hal::serial::Write<u8>,
 {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         let _ = s.as_bytes().iter().map(|c| block!(self.write(*c))).last();
@@ -821,3 +1861,33 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brr_over16() {
+        assert_eq!(calculate_brr(16_000_000, 9_600, false, false), 1667);
+        assert_eq!(calculate_brr(32_000_000, 115_200, false, false), 278);
+    }
+
+    #[test]
+    fn brr_over16_fraction_carry() {
+        // 1.225 MHz / 9600 Bd = 127.6, where the rounded fraction is 16
+        assert_eq!(calculate_brr(1_225_000, 9_600, false, false), 128);
+    }
+
+    #[test]
+    fn brr_over8() {
+        // USARTDIV = 3333 = 0xd05, BRR[2:0] = 0x5 >> 1
+        assert_eq!(calculate_brr(16_000_000, 9_600, true, false), 0xd02);
+        // USARTDIV = 556 = 0x22c, BRR[2:0] = 0xc >> 1
+        assert_eq!(calculate_brr(32_000_000, 115_200, true, false), 0x226);
+    }
+
+    #[test]
+    fn brr_lpuart() {
+        assert_eq!(calculate_brr(1_000_000, 9_600, false, true), 104 * 256);
+    }
+}
@@ -0,0 +1,468 @@
+//! Software (bit-banged) UART
+//!
+//! For boards where all USARTs are taken, or where the pins aren't connected
+//! to one. Works on any GPIO pins, implementing the same embedded-hal traits as
+//! [`Serial`]. Transmission is reliable, reception is best effort: the RX line
+//! is only sampled on timer ticks, so the sampling point can be off by a
+//! quarter of a bit, and bytes are lost if the interrupt is delayed for too
+//! long.
+//!
+//! The bit timing is derived from a periodic timer, which is started at four
+//! times the baud rate. [`SoftUart::on_tick`] needs to be called on every
+//! timer period, usually from the timer interrupt:
+//!
+//! ``` ignore
+//! let mut timer = Timer::new(dp.TIM2, &mut rcc);
+//! timer.listen();
+//! let uart = SoftUart::new(tx, rx, timer, 9_600.Bd()).unwrap();
+//!
+//! // In the TIM2 interrupt
+//! uart.on_tick().unwrap();
+//!
+//! // Anywhere else, without blocking while the interrupt is masked
+//! if let Ok(byte) = uart.read() {
+//!     uart.write(byte).ok();
+//! }
+//! ```
+//!
+//! As the UART is shared with the interrupt handler, it has to be accessed
+//! through a mutex. Never block on `write` or `read` while holding the mutex,
+//! as the transfer can't progress in that case.
+//!
+//! [`Serial`]: crate::serial::Serial
+
+use core::{convert::Infallible, fmt};
+
+use crate::hal::digital::v2::{InputPin, OutputPin};
+use crate::hal::serial;
+use crate::hal::timer::{CountDown, Periodic};
+use crate::time::IntoBaud;
+use embedded_time::rate::Hertz;
+
+/// Number of timer ticks per bit
+const OVERSAMPLING: u8 = 4;
+
+/// Number of bits in a frame: start bit, 8 data bits, stop bit
+const FRAME_BITS: u8 = 10;
+
+/// Index of the stop bit in a frame
+const STOP_BIT: u8 = FRAME_BITS - 1;
+
+/// Software UART errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Accessing a pin failed
+    Pin(E),
+    /// The stop bit of a received byte was low
+    Framing,
+    /// A byte was received before the previous one was read
+    Overrun,
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Pin(error) => write!(f, "accessing a pin failed: {:?}", error),
+            Error::Framing => f.write_str("framing error"),
+            Error::Overrun => f.write_str("RX buffer overrun"),
+        }
+    }
+}
+
+/// Placeholder for the RX pin of a transmit-only [`SoftUart`]
+///
+/// Always reads high, so nothing is ever received.
+pub struct NoRx;
+
+impl InputPin for NoRx {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum RxState {
+    Idle,
+    /// Receiving bit `bit` of the frame, which is sampled after `ticks` more
+    /// ticks
+    Receiving {
+        ticks: u8,
+        bit: u8,
+        byte: u8,
+    },
+}
+
+/// Software UART
+///
+/// Uses 8 data bits, no parity, and 1 stop bit. `TX` must be an output, `RX`
+/// an input, ideally with a pull-up resistor.
+pub struct SoftUart<TX, RX, TIM> {
+    tx: TX,
+    rx: RX,
+    timer: TIM,
+
+    /// The remaining bits of the frame, LSB first
+    tx_frame: u16,
+    /// Number of bits in `tx_frame` that still need to be sent
+    tx_bits: u8,
+    /// Number of ticks until the next bit is sent
+    tx_ticks: u8,
+
+    rx_state: RxState,
+    received: Option<u8>,
+    rx_error: Option<Error<Infallible>>,
+}
+
+impl<TX, RX, TIM, E> SoftUart<TX, RX, TIM>
+where
+    TX: OutputPin<Error = E>,
+    RX: InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    /// Creates a new software UART running at `baudrate`
+    ///
+    /// Sets TX to its idle level and starts `timer` at four times the baud
+    /// rate. Enable the interrupt of `timer` before passing it in, if
+    /// [`SoftUart::on_tick`] is called from the interrupt handler.
+    pub fn new(mut tx: TX, rx: RX, mut timer: TIM, baudrate: impl IntoBaud) -> Result<Self, E> {
+        tx.set_high()?;
+        timer.start(Hertz(baudrate.into_baud().0 * u32::from(OVERSAMPLING)));
+
+        Ok(SoftUart {
+            tx,
+            rx,
+            timer,
+            tx_frame: 0,
+            tx_bits: 0,
+            tx_ticks: 0,
+            rx_state: RxState::Idle,
+            received: None,
+            rx_error: None,
+        })
+    }
+
+    /// Releases the pins and the timer
+    pub fn free(self) -> (TX, RX, TIM) {
+        (self.tx, self.rx, self.timer)
+    }
+
+    /// Advances the transmitter and receiver by one tick
+    ///
+    /// Needs to be called on every period of the timer, usually from its
+    /// interrupt handler. Does nothing, if the timer period hasn't elapsed
+    /// yet, so it can also be called in a loop.
+    pub fn on_tick(&mut self) -> Result<(), E> {
+        if self.timer.wait().is_err() {
+            return Ok(());
+        }
+
+        self.tick_tx()?;
+        self.tick_rx()
+    }
+
+    /// Indicates whether a byte is still being transmitted
+    pub fn is_busy(&self) -> bool {
+        self.tx_bits > 0 || self.tx_ticks > 0
+    }
+
+    fn tick_tx(&mut self) -> Result<(), E> {
+        if self.tx_ticks > 0 {
+            self.tx_ticks -= 1;
+            return Ok(());
+        }
+        if self.tx_bits == 0 {
+            return Ok(());
+        }
+
+        if self.tx_frame & 1 != 0 {
+            self.tx.set_high()?;
+        } else {
+            self.tx.set_low()?;
+        }
+        self.tx_frame >>= 1;
+        self.tx_bits -= 1;
+        self.tx_ticks = OVERSAMPLING - 1;
+        Ok(())
+    }
+
+    fn tick_rx(&mut self) -> Result<(), E> {
+        self.rx_state = match self.rx_state {
+            RxState::Idle => {
+                if self.rx.is_low()? {
+                    // Sample in the middle of the start bit
+                    RxState::Receiving {
+                        ticks: OVERSAMPLING / 2,
+                        bit: 0,
+                        byte: 0,
+                    }
+                } else {
+                    RxState::Idle
+                }
+            }
+            RxState::Receiving { ticks, bit, byte } if ticks > 1 => RxState::Receiving {
+                ticks: ticks - 1,
+                bit,
+                byte,
+            },
+            RxState::Receiving { bit, byte, .. } => {
+                let high = self.rx.is_high()?;
+                match bit {
+                    // A start bit that's gone again was a glitch.
+                    0 if high => RxState::Idle,
+                    0 => RxState::Receiving {
+                        ticks: OVERSAMPLING,
+                        bit: 1,
+                        byte,
+                    },
+                    // Data bits, LSB first
+                    1..=8 => RxState::Receiving {
+                        ticks: OVERSAMPLING,
+                        bit: bit + 1,
+                        byte: (byte >> 1) | if high { 0x80 } else { 0 },
+                    },
+                    _ => {
+                        self.receive(byte, high);
+                        RxState::Idle
+                    }
+                }
+            }
+        };
+        Ok(())
+    }
+
+    fn receive(&mut self, byte: u8, stop_bit: bool) {
+        if !stop_bit {
+            self.rx_error = Some(Error::Framing);
+        } else if self.received.replace(byte).is_some() {
+            self.rx_error = Some(Error::Overrun);
+        }
+    }
+}
+
+impl<TX, TIM> SoftUart<TX, NoRx, TIM>
+where
+    TX: OutputPin<Error = Infallible>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    /// Creates a new transmit-only software UART running at `baudrate`
+    ///
+    /// See [`SoftUart::new`].
+    pub fn tx_only(tx: TX, timer: TIM, baudrate: impl IntoBaud) -> Self {
+        match Self::new(tx, NoRx, timer, baudrate) {
+            Ok(uart) => uart,
+            Err(never) => match never {},
+        }
+    }
+}
+
+impl<TX, RX, TIM, E> serial::Write<u8> for SoftUart<TX, RX, TIM>
+where
+    TX: OutputPin<Error = E>,
+    RX: InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    type Error = Error<E>;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // Start bit (low), data bits LSB first, stop bit (high)
+        self.tx_frame = (u16::from(byte) << 1) | (1 << STOP_BIT);
+        self.tx_bits = FRAME_BITS;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        if self.is_busy() {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<TX, RX, TIM, E> serial::Read<u8> for SoftUart<TX, RX, TIM>
+where
+    TX: OutputPin<Error = E>,
+    RX: InputPin<Error = E>,
+    TIM: CountDown<Time = Hertz> + Periodic,
+{
+    type Error = Error<E>;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        match self.rx_error.take() {
+            Some(Error::Framing) => return Err(nb::Error::Other(Error::Framing)),
+            Some(Error::Overrun) => return Err(nb::Error::Other(Error::Overrun)),
+            Some(Error::Pin(never)) => match never {},
+            None => {}
+        }
+        self.received.take().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_time::rate::Baud;
+    use void::Void;
+
+    use super::*;
+    use crate::hal::serial::{Read, Write};
+
+    /// A line that can be driven by the transmitter and read by the receiver
+    struct MockPin(bool);
+
+    impl OutputPin for MockPin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0 = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0 = true;
+            Ok(())
+        }
+    }
+
+    impl InputPin for MockPin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Self::Error> {
+            Ok(self.0)
+        }
+
+        fn is_low(&self) -> Result<bool, Self::Error> {
+            Ok(!self.0)
+        }
+    }
+
+    /// A timer whose period always elapsed
+    struct MockTimer;
+
+    impl CountDown for MockTimer {
+        type Time = Hertz;
+
+        fn start<T: Into<Hertz>>(&mut self, _: T) {}
+
+        fn wait(&mut self) -> nb::Result<(), Void> {
+            Ok(())
+        }
+    }
+
+    impl Periodic for MockTimer {}
+
+    type MockUart = SoftUart<MockPin, MockPin, MockTimer>;
+
+    fn uart() -> MockUart {
+        SoftUart::new(MockPin(false), MockPin(true), MockTimer, Baud(9_600)).unwrap()
+    }
+
+    /// Drives the RX line with a frame: start bit, `byte` LSB first, and a
+    /// stop bit at the level of `stop_bit`
+    fn receive_byte(uart: &mut MockUart, byte: u8, stop_bit: bool) {
+        let frame = (u16::from(byte) << 1) | (u16::from(stop_bit) << STOP_BIT);
+        for bit in 0..FRAME_BITS {
+            uart.rx.0 = frame & (1 << bit) != 0;
+            for _ in 0..OVERSAMPLING {
+                uart.on_tick().unwrap();
+            }
+        }
+        // Idle
+        uart.rx.0 = true;
+        for _ in 0..OVERSAMPLING {
+            uart.on_tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn transmit_frame() {
+        let mut uart = uart();
+        assert!(uart.tx.0);
+
+        uart.write(0xa5).unwrap();
+        assert!(uart.is_busy());
+        assert!(matches!(uart.write(0), Err(nb::Error::WouldBlock)));
+
+        let mut levels = [false; FRAME_BITS as usize];
+        for level in levels.iter_mut() {
+            uart.on_tick().unwrap();
+            *level = uart.tx.0;
+            // The level is held for the whole bit
+            for _ in 1..OVERSAMPLING {
+                uart.on_tick().unwrap();
+                assert_eq!(uart.tx.0, *level);
+            }
+        }
+
+        // Start bit, 0xa5 LSB first, stop bit
+        assert_eq!(
+            levels,
+            [false, true, false, true, false, false, true, false, true, true]
+        );
+        assert!(!uart.is_busy());
+        assert!(uart.flush().is_ok());
+    }
+
+    #[test]
+    fn receive_data_bits() {
+        let mut uart = uart();
+        assert!(matches!(uart.read(), Err(nb::Error::WouldBlock)));
+
+        receive_byte(&mut uart, 0x3c, true);
+        assert_eq!(uart.read().unwrap(), 0x3c);
+        assert!(matches!(uart.read(), Err(nb::Error::WouldBlock)));
+
+        receive_byte(&mut uart, 0xff, true);
+        assert_eq!(uart.read().unwrap(), 0xff);
+    }
+
+    #[test]
+    fn start_bit_glitch() {
+        let mut uart = uart();
+
+        // Low for a single tick, which is gone by the middle of the start bit
+        uart.rx.0 = false;
+        uart.on_tick().unwrap();
+        uart.rx.0 = true;
+        for _ in 0..FRAME_BITS * OVERSAMPLING {
+            uart.on_tick().unwrap();
+        }
+        assert!(matches!(uart.rx_state, RxState::Idle));
+        assert!(matches!(uart.read(), Err(nb::Error::WouldBlock)));
+
+        // A real frame is still received afterwards
+        receive_byte(&mut uart, 0x42, true);
+        assert_eq!(uart.read().unwrap(), 0x42);
+    }
+
+    #[test]
+    fn stop_bit_error() {
+        let mut uart = uart();
+
+        receive_byte(&mut uart, 0x55, false);
+        assert!(matches!(uart.read(), Err(nb::Error::Other(Error::Framing))));
+        assert!(matches!(uart.read(), Err(nb::Error::WouldBlock)));
+
+        receive_byte(&mut uart, 0x55, true);
+        assert_eq!(uart.read().unwrap(), 0x55);
+    }
+
+    #[test]
+    fn overrun() {
+        let mut uart = uart();
+
+        receive_byte(&mut uart, 0x01, true);
+        receive_byte(&mut uart, 0x02, true);
+        assert!(matches!(uart.read(), Err(nb::Error::Other(Error::Overrun))));
+        assert_eq!(uart.read().unwrap(), 0x02);
+    }
+}
@@ -1,5 +1,6 @@
 use core::{
-    cell::UnsafeCell,
+    cell::{Cell, UnsafeCell},
+    fmt,
     marker::PhantomData,
     ops::{Deref, DerefMut},
     pin::Pin,
@@ -24,7 +25,7 @@ use crate::pac::SPI1;
     feature = "stm32l0x3"
 ))]
 use crate::pac::SPI2;
-use crate::rcc::{Enable, Rcc};
+use crate::rcc::{Clocks, Enable, Rcc};
 
 pub use hal::spi::{Mode, Phase, Polarity, MODE_0, MODE_1, MODE_2, MODE_3};
 
@@ -42,16 +43,51 @@ pub enum Error {
     Crc,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Busy => f.write_str("peripheral busy"),
+            Error::FrameError => f.write_str("frame format error"),
+            Error::Overrun => f.write_str("overrun"),
+            Error::ModeFault => f.write_str("mode fault"),
+            Error::Crc => f.write_str("CRC error"),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::spi::Error for Error {
+    fn kind(&self) -> embedded_hal_1::spi::ErrorKind {
+        use embedded_hal_1::spi::ErrorKind;
+
+        match self {
+            Error::Busy => ErrorKind::Other,
+            Error::FrameError => ErrorKind::FrameFormat,
+            Error::Overrun => ErrorKind::Overrun,
+            Error::ModeFault => ErrorKind::ModeFault,
+            Error::Crc => ErrorKind::Other,
+        }
+    }
+}
+
+/// A set of pins that can be used for `SPI`: SCK, MISO, and MOSI
 pub trait Pins<SPI> {
+    /// Switches all pins to their alternate functions
     fn setup(&self);
 }
+/// Pins that can be used as the clock output (`SPIx_SCK`)
 pub trait PinSck<SPI> {
+    /// Switches the pin to the SCK alternate function of `SPI`, AF0
     fn setup(&self);
 }
+/// Pins that can be used as the data input in master mode (`SPIx_MISO`)
 pub trait PinMiso<SPI> {
+    /// Switches the pin to the MISO alternate function of `SPI`, AF0
     fn setup(&self);
 }
+/// Pins that can be used as the data output in master mode (`SPIx_MOSI`)
 pub trait PinMosi<SPI> {
+    /// Switches the pin to the MOSI alternate function of `SPI`, AF0
     fn setup(&self);
 }
 
@@ -247,19 +283,7 @@ macro_rules! spi {
                         w.txdmaen().set_bit()
                     });
 
-                    let spi_freq = freq.into().0;
-                    let apb_freq = rcc.clocks.$pclkX().0;
-                    let br = match apb_freq / spi_freq {
-                        0 => unreachable!(),
-                        1..=2 => 0b000,
-                        3..=5 => 0b001,
-                        6..=11 => 0b010,
-                        12..=23 => 0b011,
-                        24..=47 => 0b100,
-                        48..=95 => 0b101,
-                        96..=191 => 0b110,
-                        _ => 0b111,
-                    };
+                    let br = baud_rate_bits(rcc.clocks.$pclkX().0, freq.into().0);
 
                     // mstr: master configuration
                     // lsbfirst: MSB first
@@ -301,6 +325,67 @@ macro_rules! spi {
                     (self.spi, self.pins)
                 }
 
+                /// Disables the SPI peripheral and its clock, then releases it
+                /// and the pins
+                ///
+                /// Waits for an ongoing transfer to finish first. Use this
+                /// instead of `free`, if the peripheral isn't needed for a
+                /// while, so its clock doesn't keep drawing power.
+                ///
+                /// Returns `self` together with [`Error::Busy`], without
+                /// disabling anything, if the transfer doesn't finish in time.
+                pub fn free_and_disable(self, rcc: &mut Rcc)
+                    -> Result<($SPIX, PINS), (Self, Error)>
+                {
+                    let spi = &self.spi;
+                    if let Err(err) = crate::wait_for(
+                        || spi.sr.read().bsy().bit_is_clear(),
+                        Error::Busy,
+                    ) {
+                        return Err((self, err));
+                    }
+
+                    self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                    <$SPIX>::disable(rcc);
+                    Ok((self.spi, self.pins))
+                }
+
+                /// Changes the SPI clock frequency
+                ///
+                /// Waits for an ongoing transfer to finish, then briefly
+                /// disables the peripheral to switch the prescaler. Like in
+                /// the constructor, the frequency is derived from the APB
+                /// clock, so the actual frequency might be lower than
+                /// requested.
+                ///
+                /// Returns [`Error::Busy`], if the ongoing transfer doesn't
+                /// finish in time.
+                pub fn set_frequency<T>(&mut self, freq: T, clocks: Clocks)
+                    -> Result<(), Error>
+                where
+                    T: Into<Hertz>
+                {
+                    let br = baud_rate_bits(clocks.$pclkX().0, freq.into().0);
+
+                    crate::wait_for(
+                        || self.spi.sr.read().bsy().bit_is_clear(),
+                        Error::Busy,
+                    )?;
+
+                    self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                    #[allow(unused_unsafe)]
+                    self.spi.cr1.modify(|_, w| unsafe { w.br().bits(br) });
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+
+                    Ok(())
+                }
+
+                /// Reads the whole buffer using DMA
+                ///
+                /// Buffers larger than 65535 bytes are read in several
+                /// consecutive DMA transfers. The returned [`Transfer`]
+                /// starts the next one whenever the previous one has
+                /// completed.
                 pub fn read_all<Channel, Buffer>(
                     self,
                     dma:     &mut dma::Handle,
@@ -330,8 +415,12 @@ macro_rules! spi {
                         Buffer:         DerefMut + 'static,
                         Buffer::Target: AsMutSlice<Element=u8>,
                 {
+                    assert!(buffer.len() >= num_words);
+
                     let token = Rx(PhantomData);
                     let address = &unsafe { &*$SPIX::ptr() }.dr as *const _ as u32;
+                    let first_chunk = num_words.min(MAX_CHUNK);
+                    let next_address = buffer.as_ptr() as u32 + first_chunk as u32;
                     // Safe, because the trait bounds of this method guarantee that the
                     // buffer can be written to.
                     let inner = unsafe {
@@ -340,7 +429,7 @@ macro_rules! spi {
                             token,
                             channel,
                             buffer,
-                            num_words,
+                            first_chunk,
                             address,
                             dma::Priority::high(),
                             dma::Direction::peripheral_to_memory(),
@@ -350,9 +439,17 @@ macro_rules! spi {
                     Transfer {
                         target: self,
                         inner,
+                        next_address: Cell::new(next_address),
+                        remaining: Cell::new(num_words - first_chunk),
                     }
                 }
 
+                /// Writes the whole buffer using DMA
+                ///
+                /// Buffers larger than 65535 bytes are written in several
+                /// consecutive DMA transfers. The returned [`Transfer`]
+                /// starts the next one whenever the previous one has
+                /// completed.
                 pub fn write_all<Channel, Buffer>(
                     self,
                     dma:     &mut dma::Handle,
@@ -382,8 +479,12 @@ macro_rules! spi {
                         Buffer:         Deref + 'static,
                         Buffer::Target: AsSlice<Element=u8>,
                 {
+                    assert!(buffer.len() >= num_words);
+
                     let token = Tx(PhantomData);
                     let address = &unsafe { &*$SPIX::ptr() }.dr as *const _ as u32;
+                    let first_chunk = num_words.min(MAX_CHUNK);
+                    let next_address = buffer.as_ptr() as u32 + first_chunk as u32;
                     // Safe, because the trait bounds of this method guarantee that the
                     // buffer can be written to.
                     let inner = unsafe {
@@ -392,7 +493,7 @@ macro_rules! spi {
                             token,
                             channel,
                             buffer,
-                            num_words,
+                            first_chunk,
                             address,
                             dma::Priority::high(),
                             dma::Direction::memory_to_peripheral(),
@@ -402,6 +503,8 @@ macro_rules! spi {
                     Transfer {
                         target: self,
                         inner,
+                        next_address: Cell::new(next_address),
+                        remaining: Cell::new(num_words - first_chunk),
                     }
                 }
             }
@@ -480,6 +583,23 @@ spi! {
     SPI2: (spi2, apb1_clk),
 }
 
+/// Computes the `BR` bits for the requested SPI frequency
+///
+/// Frequencies above what the peripheral clock allows are clamped to the
+/// fastest divider.
+fn baud_rate_bits(apb_freq: u32, spi_freq: u32) -> u8 {
+    match apb_freq / spi_freq.max(1) {
+        0..=2 => 0b000,
+        3..=5 => 0b001,
+        6..=11 => 0b010,
+        12..=23 => 0b011,
+        24..=47 => 0b100,
+        48..=95 => 0b101,
+        96..=191 => 0b110,
+        _ => 0b111,
+    }
+}
+
 /// Token used for DMA transfers
 ///
 /// This is an implementation detail. The user doesn't have to deal with this
@@ -492,10 +612,25 @@ pub struct Tx<I>(PhantomData<I>);
 /// directly.
 pub struct Rx<I>(PhantomData<I>);
 
+/// Maximum number of words in a single DMA transfer
+const MAX_CHUNK: usize = u16::max_value() as usize;
+
 /// Wrapper around a [`dma::Transfer`].
+///
+/// A single DMA transfer is limited to 65535 words, so larger buffers are
+/// transferred in chunks. The next chunk is started by [`Transfer::is_active`]
+/// and [`Transfer::wait`], once the previous one has completed. If the
+/// transfer complete interrupt is enabled, it fires after every chunk, so call
+/// [`Transfer::is_active`] from the interrupt handler to keep the transfer
+/// going. Note that there's a short gap between chunks, during which the SPI
+/// peripheral is idle.
 pub struct Transfer<Target, Token, Channel, Buffer, State> {
     target: Target,
     inner: dma::Transfer<Token, Channel, Buffer, State>,
+    /// Memory address of the next chunk
+    next_address: Cell<u32>,
+    /// Number of words that haven't been assigned to a chunk yet
+    remaining: Cell<usize>,
 }
 
 impl<Target, Token, Channel, Buffer> Transfer<Target, Token, Channel, Buffer, dma::Ready>
@@ -519,6 +654,8 @@ where
         Transfer {
             target: self.target,
             inner: self.inner.start(),
+            next_address: self.next_address,
+            remaining: self.remaining,
         }
     }
 }
@@ -528,8 +665,29 @@ where
     Channel: dma::Channel,
 {
     /// Indicates whether the transfer is still ongoing
+    ///
+    /// Starts the next chunk, if the previous one has completed.
     pub fn is_active(&self) -> bool {
-        self.inner.is_active()
+        if self.inner.is_active() {
+            return true;
+        }
+        self.start_next_chunk()
+    }
+
+    /// Starts the next chunk, returning `false` if there is none
+    fn start_next_chunk(&self) -> bool {
+        let remaining = self.remaining.get();
+        if remaining == 0 {
+            return false;
+        }
+
+        let chunk = remaining.min(MAX_CHUNK);
+        let address = self.next_address.get();
+        self.inner.continue_at(address, chunk as u16);
+
+        self.next_address.set(address + chunk as u32);
+        self.remaining.set(remaining - chunk);
+        true
     }
 
     /// Waits for the transfer to finish and returns the owned resources
@@ -541,6 +699,15 @@ where
     /// This function will return immediately, if [`Transfer::is_active`]
     /// returns `false`.
     pub fn wait(self) -> dma::TransferResourcesResult<Target, Channel, Buffer> {
+        let mut failed = false;
+        while self.remaining.get() > 0 && !failed {
+            if self.inner.is_active() {
+                failed = self.inner.error_occured();
+            } else {
+                self.start_next_chunk();
+            }
+        }
+
         // Need to move `target` out of `self`, otherwise the closure captures
         // `self` completely.
         let target = self.target;
@@ -551,6 +718,10 @@ where
             buffer: res.buffer,
         };
 
+        if failed {
+            return Err((map_resources(self.inner.stop()), dma::Error));
+        }
+
         match self.inner.wait() {
             Ok(res) => Ok(map_resources(res)),
             Err((res, err)) => Err((map_resources(res), err)),
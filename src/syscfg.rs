@@ -2,24 +2,154 @@
 //!
 //! See STM32L0x2 reference manual, chapter 10.
 
-use crate::{
-    pac,
-    rcc::{Enable, Rcc, Reset},
-};
+use core::fmt;
+
+use crate::{pac, rcc::Rcc, wait_for};
 
 type PacSyscfg = pac::SYSCFG;
 
 pub struct SYSCFG {
     pub(crate) syscfg: PacSyscfg,
+    vref_users: [u8; VrefBuffer::COUNT],
 }
 
 impl SYSCFG {
+    /// Enables the SYSCFG peripheral
+    ///
+    /// The clock of SYSCFG is shared with other drivers through
+    /// [`Rcc::acquire`], so it's only reset if no one else is using it yet.
     pub fn new(syscfg: PacSyscfg, rcc: &mut Rcc) -> Self {
-        // Enable SYSCFG peripheral
-        PacSyscfg::enable(rcc);
-        // Reset SYSCFG peripheral
-        PacSyscfg::reset(rcc);
+        rcc.acquire::<PacSyscfg>();
+
+        SYSCFG {
+            syscfg,
+            vref_users: [0; VrefBuffer::COUNT],
+        }
+    }
+
+    /// Releases the SYSCFG peripheral
+    ///
+    /// Its clock is disabled, unless other drivers still use it.
+    pub fn free(self, rcc: &mut Rcc) -> PacSyscfg {
+        rcc.release::<PacSyscfg>();
+        self.syscfg
+    }
+
+    /// Enables a buffer of the internal voltage reference
+    ///
+    /// The buffers share the VREFINT enable bit in `SYSCFG_CFGR3`. Enables are
+    /// counted per buffer, so independent users of the same buffer, or of
+    /// different buffers, don't switch off VREFINT for each other. Each call
+    /// needs to be matched by a call to [`SYSCFG::disable_vref_buffer`].
+    ///
+    /// Waits until VREFINT is ready. Returns [`VrefintTimeout`], if it doesn't
+    /// become ready in time. The buffer is disabled again in that case, so
+    /// the call doesn't need to be matched.
+    pub fn enable_vref_buffer(&mut self, buffer: VrefBuffer) -> Result<(), VrefintTimeout> {
+        let users = &mut self.vref_users[buffer as usize];
+        *users = users.checked_add(1).expect("too many VREFINT buffer users");
+
+        self.syscfg.cfgr3.modify(|_, w| {
+            match buffer {
+                VrefBuffer::Adc => w.enbuf_vrefint_adc().set_bit(),
+                VrefBuffer::TemperatureSensor => w.enbuf_sensor_adc().set_bit(),
+                VrefBuffer::Comp2 => w.enbuf_vrefint_comp2().set_bit(),
+                #[cfg(any(feature = "stm32l0x2", feature = "stm32l0x3"))]
+                VrefBuffer::Hsi48 => w.enref_hsi48().set_bit(),
+            };
+            w.en_vrefint().set_bit()
+        });
+
+        let ready = wait_for(|| self.is_vrefint_ready(), VrefintTimeout);
+        if ready.is_err() {
+            self.disable_vref_buffer(buffer);
+        }
+        ready
+    }
+
+    /// Disables a buffer of the internal voltage reference
+    ///
+    /// The buffer is only switched off once all users that enabled it have
+    /// disabled it again, and VREFINT once no buffer is in use anymore. Does
+    /// nothing, if the buffer hasn't been enabled with
+    /// [`SYSCFG::enable_vref_buffer`].
+    pub fn disable_vref_buffer(&mut self, buffer: VrefBuffer) {
+        let users = &mut self.vref_users[buffer as usize];
+        if *users == 0 {
+            return;
+        }
+        *users -= 1;
+        if *users > 0 {
+            return;
+        }
+
+        let in_use = self.vref_users.iter().any(|&users| users > 0);
+        self.syscfg.cfgr3.modify(|_, w| {
+            match buffer {
+                VrefBuffer::Adc => w.enbuf_vrefint_adc().clear_bit(),
+                VrefBuffer::TemperatureSensor => w.enbuf_sensor_adc().clear_bit(),
+                VrefBuffer::Comp2 => w.enbuf_vrefint_comp2().clear_bit(),
+                #[cfg(any(feature = "stm32l0x2", feature = "stm32l0x3"))]
+                VrefBuffer::Hsi48 => w.enref_hsi48().clear_bit(),
+            };
+            w.en_vrefint().bit(in_use)
+        });
+    }
 
-        SYSCFG { syscfg }
+    /// Indicates whether VREFINT is enabled and ready
+    pub fn is_vrefint_ready(&self) -> bool {
+        self.syscfg.cfgr3.read().vrefint_rdyf().bit_is_set()
     }
+
+    /// Connects VREFINT to the `PB0` and/or `PB1` pins
+    pub fn set_vref_out(&mut self, out: VrefOut) {
+        #[allow(unused_unsafe)]
+        self.syscfg
+            .cfgr3
+            .modify(|_, w| unsafe { w.sel_vref_out().bits(out as u8) });
+    }
+}
+
+/// VREFINT didn't become ready in time
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VrefintTimeout;
+
+impl fmt::Display for VrefintTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("VREFINT didn't become ready")
+    }
+}
+
+/// Buffers of the internal voltage reference (`SYSCFG_CFGR3.ENBUF_*`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VrefBuffer {
+    /// VREFINT for the ADC
+    Adc,
+    /// The temperature sensor for the ADC
+    TemperatureSensor,
+    /// VREFINT for comparator 2
+    Comp2,
+    /// VREFINT for the HSI48 oscillator
+    #[cfg(any(feature = "stm32l0x2", feature = "stm32l0x3"))]
+    Hsi48,
+}
+
+impl VrefBuffer {
+    #[cfg(any(feature = "stm32l0x2", feature = "stm32l0x3"))]
+    const COUNT: usize = 4;
+    #[cfg(not(any(feature = "stm32l0x2", feature = "stm32l0x3")))]
+    const COUNT: usize = 3;
+}
+
+/// Pins VREFINT is connected to (`SYSCFG_CFGR3.SEL_VREF_OUT`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VrefOut {
+    /// VREFINT isn't connected to any pin
+    None = 0b00,
+    /// VREFINT is connected to `PB0`
+    PB0 = 0b01,
+    /// VREFINT is connected to `PB1`
+    PB1 = 0b10,
+    /// VREFINT is connected to `PB0` and `PB1`
+    Both = 0b11,
 }
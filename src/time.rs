@@ -0,0 +1,151 @@
+//! Time units
+//!
+//! The HAL uses the units of [`embedded_time`] for frequencies and durations. Functions that accept
+//! a frequency, baud rate, or duration are generic over the conversion traits in this module, which
+//! are implemented for the embedded-time units and, with the `fugit` feature enabled, for the
+//! corresponding units of the `fugit` crate. This allows for a gradual migration to `fugit`, while
+//! existing code keeps working.
+//!
+//! Values returned by the HAL, like the clock frequencies in [`Clocks`], can be converted to `fugit`
+//! units using `ToFugit`.
+//!
+//! [`Clocks`]: crate::rcc::Clocks
+
+use embedded_time::duration::{Hours, Microseconds, Milliseconds, Minutes, Nanoseconds, Seconds};
+use embedded_time::rate::{Baud, Hertz, Kilobaud, Kilohertz, Megabaud, Megahertz};
+
+/// A frequency that can be converted to [`Hertz`]
+pub trait IntoHertz {
+    fn into_hertz(self) -> Hertz;
+}
+
+/// A baud rate that can be converted to [`Baud`]
+pub trait IntoBaud {
+    fn into_baud(self) -> Baud;
+}
+
+/// A duration that can be converted to [`Microseconds`]
+///
+/// Durations that don't fit into `u32` microseconds are clamped at `u32::MAX`.
+pub trait IntoMicroseconds {
+    fn into_microseconds(self) -> Microseconds;
+}
+
+macro_rules! into_hertz {
+    ($($Rate:ident: $factor:expr,)+) => {
+        $(
+            impl IntoHertz for $Rate {
+                fn into_hertz(self) -> Hertz {
+                    Hertz(self.0 * $factor)
+                }
+            }
+        )+
+    };
+}
+
+into_hertz!(
+    Hertz: 1,
+    Kilohertz: 1_000,
+    Megahertz: 1_000_000,
+);
+
+macro_rules! into_baud {
+    ($($Rate:ident: $factor:expr,)+) => {
+        $(
+            impl IntoBaud for $Rate {
+                fn into_baud(self) -> Baud {
+                    Baud(self.0 * $factor)
+                }
+            }
+        )+
+    };
+}
+
+into_baud!(
+    Baud: 1,
+    Kilobaud: 1_000,
+    Megabaud: 1_000_000,
+);
+
+impl IntoMicroseconds for Nanoseconds {
+    fn into_microseconds(self) -> Microseconds {
+        Microseconds(self.0 / 1_000)
+    }
+}
+
+macro_rules! into_microseconds {
+    ($($Duration:ident: $factor:expr,)+) => {
+        $(
+            impl IntoMicroseconds for $Duration {
+                fn into_microseconds(self) -> Microseconds {
+                    Microseconds(self.0.saturating_mul($factor))
+                }
+            }
+        )+
+    };
+}
+
+into_microseconds!(
+    Microseconds: 1,
+    Milliseconds: 1_000,
+    Seconds: 1_000_000,
+    Minutes: 60_000_000,
+    Hours: 3_600_000_000,
+);
+
+#[cfg(feature = "fugit")]
+impl<const NOM: u32, const DENOM: u32> IntoHertz for fugit::Rate<u32, NOM, DENOM> {
+    fn into_hertz(self) -> Hertz {
+        Hertz(self.to_Hz())
+    }
+}
+
+/// `fugit` has no dedicated baud rate type, so rates in Hz are interpreted as bits per second.
+#[cfg(feature = "fugit")]
+impl<const NOM: u32, const DENOM: u32> IntoBaud for fugit::Rate<u32, NOM, DENOM> {
+    fn into_baud(self) -> Baud {
+        Baud(self.to_Hz())
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl<const NOM: u32, const DENOM: u32> IntoMicroseconds for fugit::Duration<u32, NOM, DENOM> {
+    fn into_microseconds(self) -> Microseconds {
+        Microseconds(self.to_micros())
+    }
+}
+
+/// Conversion of embedded-time units to `fugit` units
+#[cfg(feature = "fugit")]
+pub trait ToFugit {
+    type Output;
+
+    fn to_fugit(self) -> Self::Output;
+}
+
+#[cfg(feature = "fugit")]
+impl ToFugit for Hertz {
+    type Output = fugit::HertzU32;
+
+    fn to_fugit(self) -> Self::Output {
+        fugit::HertzU32::from_raw(self.0)
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl ToFugit for Baud {
+    type Output = fugit::HertzU32;
+
+    fn to_fugit(self) -> Self::Output {
+        fugit::HertzU32::from_raw(self.0)
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl ToFugit for Microseconds {
+    type Output = fugit::MicrosDurationU32;
+
+    fn to_fugit(self) -> Self::Output {
+        fugit::MicrosDurationU32::from_raw(self.0)
+    }
+}
@@ -1,17 +1,27 @@
 //! Timers
-use crate::hal::timer::{CountDown, Periodic};
+use crate::adc;
+use crate::encoder::{PinCh1, PinCh2};
+use crate::gpio::gpioa::{PA0, PA15, PA5};
+use crate::gpio::{AltMode, PinMode};
+use crate::hal::{
+    self,
+    timer::{CountDown, Periodic},
+};
 use crate::pac::{tim2, tim21, tim22, tim6, TIM2, TIM21, TIM22, TIM3, TIM6};
 use crate::rcc::{Clocks, Enable, Rcc, Reset};
+use crate::time::{IntoHertz, IntoMicroseconds};
 use cast::{u16, u32};
+use core::{convert::Infallible, fmt};
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m::peripheral::SYST;
+use embedded_time::duration::Milliseconds;
 use embedded_time::rate::Hertz;
 use void::Void;
 
 pub trait TimerExt<TIM> {
     fn timer<T>(self, timeout: T, rcc: &mut Rcc) -> Timer<TIM>
     where
-        T: Into<Hertz>;
+        T: IntoHertz;
 }
 
 /// Hardware timers
@@ -24,14 +34,14 @@ impl Timer<SYST> {
     /// Configures the SYST clock as a periodic count down timer
     pub fn syst<T>(mut syst: SYST, timeout: T, rcc: &mut Rcc) -> Self
     where
-        T: Into<Hertz>,
+        T: IntoHertz,
     {
         syst.set_clock_source(SystClkSource::Core);
         let mut timer = Timer {
             tim: syst,
             clocks: rcc.clocks,
         };
-        timer.start(timeout);
+        timer.start(timeout.into_hertz());
         timer
     }
 
@@ -46,6 +56,31 @@ impl Timer<SYST> {
     }
 }
 
+impl Timer<SYST> {
+    /// Starts the timer, so that it expires after `timeout`
+    ///
+    /// Returns an error, if `timeout` doesn't fit into the 24-bit reload
+    /// register at the current system clock frequency.
+    pub fn start_duration<T>(&mut self, timeout: T) -> Result<(), DurationError>
+    where
+        T: IntoMicroseconds,
+    {
+        let ticks = u64::from(self.clocks.sys_clk().0) * u64::from(timeout.into_microseconds().0)
+            / 1_000_000;
+        if ticks < 2 {
+            return Err(DurationError::TooShort);
+        }
+        if ticks > 1 << 24 {
+            return Err(DurationError::TooLong);
+        }
+
+        self.tim.set_reload(ticks as u32 - 1);
+        self.tim.clear_current();
+        self.tim.enable_counter();
+        Ok(())
+    }
+}
+
 impl CountDown for Timer<SYST> {
     type Time = Hertz;
 
@@ -73,7 +108,7 @@ impl CountDown for Timer<SYST> {
 impl TimerExt<SYST> for SYST {
     fn timer<T>(self, timeout: T, rcc: &mut Rcc) -> Timer<SYST>
     where
-        T: Into<Hertz>,
+        T: IntoHertz,
     {
         Timer::syst(self, timeout, rcc)
     }
@@ -101,12 +136,12 @@ impl<T: GeneralPurposeTimer> Timer<T> {
 }
 
 macro_rules! timers {
-    ($($TIM:ident: ($tim:ident, $timclk:ident, $mms:ty),)+) => {
+    ($($TIM:ident: ($tim:ident, $timclk:ident, $mms:ty, $mms_max:ident),)+) => {
         $(
             impl TimerExt<$TIM> for $TIM {
                 fn timer<T>(self, timeout: T, rcc: &mut Rcc) -> Timer<$TIM>
                     where
-                        T: Into<Hertz>,
+                        T: IntoHertz,
                 {
                     Timer::$tim(self, timeout, rcc)
                 }
@@ -116,10 +151,10 @@ macro_rules! timers {
                 /// Configures a TIM peripheral as a periodic count down timer
                 pub fn $tim<T>(tim: $TIM, timeout: T, rcc: &mut Rcc) -> Self
                 where
-                    T: Into<Hertz>,
+                    T: IntoHertz,
                 {
                     let mut timer = Timer::new(tim, rcc);
-                    timer.start(timeout);
+                    timer.start(timeout.into_hertz());
                     timer
                 }
 
@@ -178,40 +213,122 @@ macro_rules! timers {
                 where
                     T: Into<Hertz>,
                 {
+                    let (psc, arr) = get_clock_config(timeout.into().0, self.clocks.$timclk().0);
+                    self.start_with_config(psc, arr, false);
+                }
+
+                fn wait(&mut self) -> nb::Result<(), Void> {
+                    if self.tim.sr.read().uif().bit_is_clear() {
+                        Err(nb::Error::WouldBlock)
+                    } else {
+                        self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                        Ok(())
+                    }
+                }
+            }
+
+            impl Timer<$TIM> {
+                /// Starts the timer at `freq`, if the frequency can be produced
+                ///
+                /// Unlike `CountDown::start`, this checks the frequency
+                /// against [`Timer::min_frequency`] and
+                /// [`Timer::max_frequency`] first.
+                pub fn try_start<T>(&mut self, freq: T) -> Result<(), FrequencyError>
+                where
+                    T: IntoHertz,
+                {
+                    let (psc, arr) = try_clock_config(
+                        freq.into_hertz().0,
+                        self.clocks.$timclk().0,
+                    )?;
+                    self.start_with_config(psc, arr, false);
+                    Ok(())
+                }
+
+                /// Returns the lowest frequency the timer can be started at
+                ///
+                /// The prescaler and the counter can divide the timer clock by
+                /// up to 2^32, so this is 1 Hz for all possible clocks.
+                pub fn min_frequency(&self) -> Hertz {
+                    Hertz(min_frequency(self.clocks.$timclk().0))
+                }
+
+                /// Returns the highest frequency the timer can be started at
+                ///
+                /// The counter has to count at least two ticks per period, so
+                /// this is half the timer clock.
+                pub fn max_frequency(&self) -> Hertz {
+                    Hertz(max_frequency(self.clocks.$timclk().0))
+                }
+
+                /// Starts the timer, so that it expires after `timeout`
+                ///
+                /// Unlike `CountDown::start`, which takes a frequency, this
+                /// returns an error if the duration can't be represented with
+                /// the timer clock, instead of panicking.
+                pub fn start_duration<T>(&mut self, timeout: T) -> Result<(), DurationError>
+                where
+                    T: IntoMicroseconds,
+                {
+                    let (psc, arr) = calculate_timeout_ticks(
+                        self.clocks.$timclk().0,
+                        timeout.into_microseconds().0,
+                    )?;
+                    self.start_with_config(psc, arr, false);
+                    Ok(())
+                }
+
+                /// Starts the timer in one-pulse mode, so that it expires once
+                /// after `timeout`
+                ///
+                /// The counter stops by itself when it expires, and the update
+                /// flag is set, so `CountDown::wait` returns once. Call this
+                /// method again to restart the timer. Starting the timer with
+                /// `CountDown::start` or [`Timer::start_duration`] switches back
+                /// to periodic mode.
+                pub fn start_one_shot<T>(&mut self, timeout: T) -> Result<(), DurationError>
+                where
+                    T: IntoMicroseconds,
+                {
+                    let (psc, arr) = calculate_timeout_ticks(
+                        self.clocks.$timclk().0,
+                        timeout.into_microseconds().0,
+                    )?;
+                    self.start_with_config(psc, arr, true);
+                    Ok(())
+                }
+
+                /// Returns whether the counter is running
+                ///
+                /// In one-pulse mode, this returns `false` once the timer has
+                /// expired.
+                pub fn is_running(&self) -> bool {
+                    self.tim.cr1.read().cen().bit_is_set()
+                }
+
+                fn start_with_config(&mut self, psc: u16, arr: u16, one_pulse: bool) {
                     // pause
                     self.pause();
                     // reset counter
                     self.tim.cnt.reset();
 
-                    let freq = timeout.into().0;
-                    let ticks = self.clocks.$timclk().0 / freq;
-                    let psc = u16((ticks - 1) / (1 << 16)).unwrap();
                     self.tim.psc.write(|w| w.psc().bits(psc));
                     // This is only unsafe for some timers, so we need this to
                     // suppress the warnings.
                     #[allow(unused_unsafe)]
-                    self.tim.arr.write(|w|
-                        unsafe {
-                            w.arr().bits(u16(ticks / u32(psc + 1)).unwrap())
-                        }
-                    );
+                    self.tim.arr.write(|w| unsafe { w.arr().bits(arr) });
 
                     // Load prescaler value and reset its counter.
                     // Setting URS makes sure no interrupt is generated.
-                    self.tim.cr1.modify(|_, w| w.urs().set_bit());
+                    self.tim.cr1.modify(|_, w| {
+                        w.urs().set_bit();
+                        // Stop the counter at the next update event?
+                        w.opm().bit(one_pulse)
+                    });
                     self.tim.egr.write(|w| w.ug().set_bit());
 
                     self.resume();
                 }
-
-                fn wait(&mut self) -> nb::Result<(), Void> {
-                    if self.tim.sr.read().uif().bit_is_clear() {
-                        Err(nb::Error::WouldBlock)
-                    } else {
-                        self.tim.sr.modify(|_, w| w.uif().clear_bit());
-                        Ok(())
-                    }
-                }
             }
 
             impl Periodic for Timer<$TIM> {}
@@ -223,10 +340,224 @@ macro_rules! timers {
                     self.cr2.modify(|_, w| w.mms().variant(variant));
                 }
             }
+
+            impl MasterTimer for $TIM {
+                fn set_master_mode(&mut self, mode: MasterMode) {
+                    assert!(
+                        mode as u8 <= MasterMode::$mms_max as u8,
+                        "Master mode not supported by this timer"
+                    );
+                    #[allow(unused_unsafe)]
+                    self.cr2.modify(|_, w| unsafe { w.mms().bits(mode as u8) });
+                }
+            }
         )+
     }
 }
 
+/// Internal signals that can be connected to TIM21 input 1 (`TIM21_OR.TI1_RMP`)
+///
+/// Capturing internal clocks allows measuring them against the timer clock,
+/// for example to calibrate LSI or MSI.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tim21Ti1 {
+    /// TI1 is connected to its GPIO pin
+    Gpio = 0b000,
+    /// RTC wakeup interrupt
+    RtcWakeup = 0b001,
+    /// HSE divided by the RTC prescaler (`RCC_CR.RTCPRE`)
+    HseRtc = 0b010,
+    /// MSI
+    Msi = 0b011,
+    /// LSE
+    Lse = 0b100,
+    /// LSI
+    Lsi = 0b101,
+    /// COMP1 output
+    Comp1 = 0b110,
+    /// MCO output
+    Mco = 0b111,
+}
+
+/// Internal signals that can be connected to TIM21 input 2 (`TIM21_OR.TI2_RMP`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tim21Ti2 {
+    /// TI2 is connected to its GPIO pin
+    Gpio = 0b0,
+    /// COMP2 output
+    Comp2 = 0b1,
+}
+
+/// Internal signals that can be connected to TIM22 input 1 (`TIM22_OR.TI1_RMP`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tim22Ti1 {
+    /// TI1 is connected to its GPIO pin
+    Gpio = 0b00,
+    /// COMP2 output
+    Comp2 = 0b01,
+    /// COMP1 output
+    Comp1 = 0b10,
+}
+
+/// Internal signals that can be connected to the external trigger input of
+/// TIM21 and TIM22 (`TIMx_OR.ETR_RMP`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EtrRemap {
+    /// ETR is connected to its GPIO pin
+    Gpio = 0b00,
+    /// COMP2 output
+    Comp2 = 0b01,
+    /// COMP1 output
+    Comp1 = 0b10,
+    /// LSE
+    Lse = 0b11,
+}
+
+impl Timer<TIM21> {
+    /// Selects the signal connected to input 1
+    ///
+    /// Input capture on channel 1 then timestamps edges of that signal.
+    pub fn remap_ti1(&mut self, input: Tim21Ti1) {
+        #[allow(unused_unsafe)]
+        self.tim
+            .or
+            .modify(|_, w| unsafe { w.ti1_rmp().bits(input as u8) });
+    }
+
+    /// Selects the signal connected to input 2
+    pub fn remap_ti2(&mut self, input: Tim21Ti2) {
+        self.tim
+            .or
+            .modify(|_, w| w.ti2_rmp().bit(input == Tim21Ti2::Comp2));
+    }
+
+    /// Selects the signal connected to the external trigger input
+    pub fn remap_etr(&mut self, input: EtrRemap) {
+        #[allow(unused_unsafe)]
+        self.tim
+            .or
+            .modify(|_, w| unsafe { w.etr_rmp().bits(input as u8) });
+    }
+}
+
+impl Timer<TIM22> {
+    /// Selects the signal connected to input 1
+    ///
+    /// Input capture on channel 1 then timestamps edges of that signal.
+    pub fn remap_ti1(&mut self, input: Tim22Ti1) {
+        #[allow(unused_unsafe)]
+        self.tim
+            .or
+            .modify(|_, w| unsafe { w.ti1_rmp().bits(input as u8) });
+    }
+
+    /// Selects the signal connected to the external trigger input
+    pub fn remap_etr(&mut self, input: EtrRemap) {
+        #[allow(unused_unsafe)]
+        self.tim
+            .or
+            .modify(|_, w| unsafe { w.etr_rmp().bits(input as u8) });
+    }
+}
+
+/// Calculates the prescaler and auto-reload values for a timer frequency
+///
+/// Returns `(psc, arr)` for a timer running at `freq`, with a timer kernel
+/// clock of `clk`. The prescaler is chosen as small as possible, to get the
+/// best resolution.
+///
+/// # Panics
+///
+/// Panics, if `freq` is too low to be reached with a 16-bit prescaler.
+pub(crate) fn get_clock_config(freq: u32, clk: u32) -> (u16, u16) {
+    let ticks = clk / freq;
+    // Dividing by 2^16 without subtracting 1 first makes sure that `arr` fits
+    // into 16 bits, even if `ticks` is an exact multiple of 2^16.
+    let psc = u16(ticks / (1 << 16)).unwrap();
+    let arr = u16(ticks / u32(psc + 1)).unwrap();
+    (psc, arr)
+}
+
+/// Returns the lowest frequency a timer running from `clk` can produce
+fn min_frequency(clk: u32) -> u32 {
+    // Largest divider of prescaler and counter combined
+    const MAX_DIVIDER: u64 = 1 << 32;
+    ((u64::from(clk) + MAX_DIVIDER - 1) / MAX_DIVIDER).max(1) as u32
+}
+
+/// Returns the highest frequency a timer running from `clk` can produce
+fn max_frequency(clk: u32) -> u32 {
+    clk / 2
+}
+
+/// Like [`get_clock_config`], but returns an error instead of panicking or
+/// stopping the counter, if `freq` can't be produced
+pub(crate) fn try_clock_config(freq: u32, clk: u32) -> Result<(u16, u16), FrequencyError> {
+    if freq < min_frequency(clk) {
+        return Err(FrequencyError::TooLow);
+    }
+    if freq > max_frequency(clk) {
+        return Err(FrequencyError::TooHigh);
+    }
+    Ok(get_clock_config(freq, clk))
+}
+
+/// Calculates prescaler and auto-reload value for a timer that expires after
+/// `timeout` µs, when running from a clock of `clk` Hz
+///
+/// Unlike [`get_clock_config`], this accounts for the auto-reload value being
+/// one less than the number of ticks per period.
+pub(crate) fn calculate_timeout_ticks(clk: u32, timeout: u32) -> Result<(u16, u16), DurationError> {
+    let ticks = u64::from(clk) * u64::from(timeout) / 1_000_000;
+    // The counter doesn't run with an auto-reload value of 0.
+    if ticks < 2 {
+        return Err(DurationError::TooShort);
+    }
+    if ticks > 1 << 32 {
+        return Err(DurationError::TooLong);
+    }
+
+    let psc = (ticks - 1) >> 16;
+    let arr = ticks / (psc + 1) - 1;
+    Ok((psc as u16, arr as u16))
+}
+
+/// The requested duration can't be produced by the timer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DurationError {
+    /// The duration is shorter than two ticks of the timer clock
+    TooShort,
+    /// The duration exceeds the range of the timer
+    TooLong,
+}
+
+impl fmt::Display for DurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DurationError::TooShort => f.write_str("duration too short"),
+            DurationError::TooLong => f.write_str("duration too long"),
+        }
+    }
+}
+
+/// The requested frequency can't be produced by the timer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrequencyError {
+    /// The frequency is lower than the timer can produce
+    TooLow,
+    /// The frequency is higher than half the timer clock
+    TooHigh,
+}
+
+impl fmt::Display for FrequencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrequencyError::TooLow => f.write_str("frequency too low"),
+            FrequencyError::TooHigh => f.write_str("frequency too high"),
+        }
+    }
+}
+
 /// Two linked 16 bit timers that form a 32 bit timer.
 pub trait LinkedTimer {
     /// Return the current 16 bit counter value of the MSB timer.
@@ -247,17 +578,49 @@ pub trait LinkedTimer {
 /// The two timers are configured so that an overflow of the primary timer
 /// triggers an update on the secondary timer. This way, two 16 bit timers can
 /// be combined to a single 32 bit timer.
+///
+/// The pair implements `CountDown` for timeouts of up to 49 days, and
+/// `Capture` to capture the 32 bit counter on an edge of the capture inputs of
+/// the primary timer. Both share the prescaler of the primary timer, which
+/// also determines the rate at which the counter returned by
+/// [`LinkedTimer::get_counter`] increments.
 pub struct LinkedTimerPair<PRIMARY, SECONDARY> {
     /// Timer in primary mode
     tim_primary: PRIMARY,
     /// Timer in secondary mode
     tim_secondary: SECONDARY,
+    /// Clock of the primary timer, before the prescaler
+    clk: Hertz,
+    /// Counter value at which the current `CountDown` period started
+    start: u32,
+    /// Length of the `CountDown` period, in ticks
+    period: u32,
+}
+
+/// Capture channel of the primary timer of a [`LinkedTimerPair`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CaptureChannel {
+    /// Channel 1
+    C1,
+    /// Channel 2
+    C2,
+}
+
+/// Calculates the prescaler and number of ticks of a 32 bit timer for
+/// `timeout_ms`
+fn calculate_timeout_ticks_32(clk: u32, timeout_ms: u32) -> (u16, u32) {
+    let ticks = u64::from(clk) * u64::from(timeout_ms) / 1_000;
+    // Even for the longest timeout at the fastest clock, this fits into the
+    // prescaler.
+    let psc = (ticks >> 32) as u16;
+    let ticks = ticks / (u64::from(psc) + 1);
+    (psc, (ticks as u32).max(1))
 }
 
 macro_rules! linked_timers {
     ($(
         ($PRIMARY:ident, $SECONDARY:ident): (
-            $new:ident,
+            $new:ident, $timclk:ident,
             $mms:ty, $sms:ty, $ts:expr
         ),
     )+) => {
@@ -290,7 +653,73 @@ macro_rules! linked_timers {
                     // where the rising edges of the selected trigger (TRGI) clock the counter.
                     tim_secondary.smcr.modify(|_, w| w.sms().variant(<$sms>::ExtClockMode));
 
-                    Self { tim_primary, tim_secondary }
+                    Self {
+                        tim_primary,
+                        tim_secondary,
+                        clk: rcc.clocks.$timclk(),
+                        start: 0,
+                        period: 0,
+                    }
+                }
+
+                /// Returns the frequency at which the counter increments
+                pub fn tick_frequency(&self) -> Hertz {
+                    let psc = u32::from(self.tim_primary.psc.read().psc().bits());
+                    Hertz(self.clk.0 / (psc + 1))
+                }
+
+                /// Sets the prescaler and resets the counter
+                fn set_prescaler(&mut self, psc: u16) {
+                    self.pause();
+                    self.tim_primary.psc.write(|w| w.psc().bits(psc));
+                    // Load the prescaler. This also triggers the secondary
+                    // timer, so the counters are reset afterwards.
+                    self.tim_primary.egr.write(|w| w.ug().set_bit());
+                    self.tim_primary.cnt.reset();
+                    self.tim_secondary.cnt.reset();
+                    self.start = 0;
+                    self.resume();
+                }
+
+                /// Captures the counter on rising edges of `pin`, using
+                /// channel 1 of the primary timer
+                ///
+                /// The capture needs to be enabled with `Capture::enable`.
+                pub fn capture_ch1<PIN: PinCh1<$PRIMARY>>(&mut self, pin: PIN) {
+                    pin.into_alt_mode();
+                    self.tim_primary.ccer.modify(|_, w| w.cc1e().clear_bit());
+                    #[allow(unused_unsafe)]
+                    self.tim_primary.ccmr1_input().modify(|_, w| unsafe { w.cc1s().bits(0b01) });
+                    self.tim_primary.ccer.modify(|_, w| w.cc1p().clear_bit().cc1np().clear_bit());
+                }
+
+                /// Captures the counter on rising edges of `pin`, using
+                /// channel 2 of the primary timer
+                ///
+                /// The capture needs to be enabled with `Capture::enable`.
+                pub fn capture_ch2<PIN: PinCh2<$PRIMARY>>(&mut self, pin: PIN) {
+                    pin.into_alt_mode();
+                    self.tim_primary.ccer.modify(|_, w| w.cc2e().clear_bit());
+                    #[allow(unused_unsafe)]
+                    self.tim_primary.ccmr1_input().modify(|_, w| unsafe { w.cc2s().bits(0b01) });
+                    self.tim_primary.ccer.modify(|_, w| w.cc2p().clear_bit().cc2np().clear_bit());
+                }
+
+                /// Extends a 16 bit capture of the primary timer to 32 bits
+                ///
+                /// The upper half is taken from the secondary timer. If the
+                /// primary timer has wrapped since the capture, the secondary
+                /// timer has already been incremented, so it's corrected. This
+                /// only works, if the capture is read before the primary timer
+                /// wraps again.
+                fn extend_capture(&self, lsb: u16) -> u32 {
+                    let now = self.get_counter();
+                    let msb = if (now as u16) < lsb {
+                        (now >> 16).wrapping_sub(1)
+                    } else {
+                        now >> 16
+                    };
+                    (msb << 16) | u32::from(lsb)
                 }
 
                 /// Pause counting
@@ -345,28 +774,836 @@ macro_rules! linked_timers {
                     // Reset counter
                     self.tim_primary.cnt.reset();
                     self.tim_secondary.cnt.reset();
+                    self.start = 0;
                     // Continue
                     self.resume();
                 }
             }
+
+            impl CountDown for LinkedTimerPair<$PRIMARY, $SECONDARY> {
+                type Time = Milliseconds;
+
+                /// Starts a periodic timeout
+                ///
+                /// Changes the prescaler, so the counter runs as fast as
+                /// possible for the given timeout, and resets the counter.
+                fn start<T>(&mut self, timeout: T)
+                where
+                    T: Into<Milliseconds>,
+                {
+                    let (psc, period) = calculate_timeout_ticks_32(self.clk.0, timeout.into().0);
+                    self.set_prescaler(psc);
+                    self.period = period;
+                }
+
+                /// Returns `Ok`, once the timeout has elapsed
+                ///
+                /// The next period starts exactly where the last one ended,
+                /// so periods don't drift, as long as this is called at least
+                /// once per overflow of the 32 bit counter.
+                fn wait(&mut self) -> nb::Result<(), Void> {
+                    let elapsed = self.get_counter().wrapping_sub(self.start);
+                    if elapsed < self.period {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    self.start = self.start.wrapping_add(self.period);
+                    Ok(())
+                }
+            }
+
+            impl Periodic for LinkedTimerPair<$PRIMARY, $SECONDARY> {}
+
+            impl hal::Capture for LinkedTimerPair<$PRIMARY, $SECONDARY> {
+                type Error = Infallible;
+                type Channel = CaptureChannel;
+                type Time = Hertz;
+                type Capture = u32;
+
+                /// Returns the 32 bit counter value of the last capture
+                ///
+                /// Needs to be called before the primary timer wraps around
+                /// again, see [`LinkedTimerPair::tick_frequency`].
+                fn capture(&mut self, channel: CaptureChannel) -> nb::Result<u32, Infallible> {
+                    let sr = self.tim_primary.sr.read();
+                    // Reading CCRx clears the capture flag
+                    let lsb = match channel {
+                        CaptureChannel::C1 if sr.cc1if().bit_is_set() => {
+                            self.tim_primary.ccr1.read().bits() as u16
+                        }
+                        CaptureChannel::C2 if sr.cc2if().bit_is_set() => {
+                            self.tim_primary.ccr2.read().bits() as u16
+                        }
+                        _ => return Err(nb::Error::WouldBlock),
+                    };
+                    Ok(self.extend_capture(lsb))
+                }
+
+                fn disable(&mut self, channel: CaptureChannel) {
+                    self.tim_primary.ccer.modify(|_, w| match channel {
+                        CaptureChannel::C1 => w.cc1e().clear_bit(),
+                        CaptureChannel::C2 => w.cc2e().clear_bit(),
+                    });
+                }
+
+                fn enable(&mut self, channel: CaptureChannel) {
+                    self.tim_primary.ccer.modify(|_, w| match channel {
+                        CaptureChannel::C1 => w.cc1e().set_bit(),
+                        CaptureChannel::C2 => w.cc2e().set_bit(),
+                    });
+                }
+
+                /// Returns the frequency at which the counter increments
+                fn get_resolution(&self) -> Hertz {
+                    self.tick_frequency()
+                }
+
+                /// Sets the frequency at which the counter increments
+                ///
+                /// The actual frequency might be higher, as it's derived from
+                /// the timer clock. Resets the counter.
+                fn set_resolution<R>(&mut self, resolution: R)
+                where
+                    R: Into<Hertz>,
+                {
+                    let ticks = self.clk.0 / resolution.into().0.max(1);
+                    let psc = u16(ticks.saturating_sub(1)).unwrap_or(0xffff);
+                    self.set_prescaler(psc);
+                }
+            }
         )+
     }
 }
 
 timers! {
-    TIM2: (tim2, apb1_tim_clk, tim2::cr2::MMS_A),
-    TIM3: (tim3, apb1_tim_clk, tim2::cr2::MMS_A),
-    TIM6: (tim6, apb1_tim_clk, tim6::cr2::MMS_A),
-    TIM21: (tim21, apb2_tim_clk, tim21::cr2::MMS_A),
-    TIM22: (tim22, apb2_tim_clk, tim22::cr2::MMS_A),
+    TIM2: (tim2, apb1_tim_clk, tim2::cr2::MMS_A, CompareOc4Ref),
+    TIM3: (tim3, apb1_tim_clk, tim2::cr2::MMS_A, CompareOc4Ref),
+    TIM6: (tim6, apb1_tim_clk, tim6::cr2::MMS_A, Update),
+    TIM21: (tim21, apb2_tim_clk, tim21::cr2::MMS_A, CompareOc2Ref),
+    TIM22: (tim22, apb2_tim_clk, tim22::cr2::MMS_A, CompareOc2Ref),
 }
 
 linked_timers! {
     // Internal trigger connection: RM0377 table 76
-    (TIM2, TIM3): (tim2_tim3, tim2::cr2::MMS_A, tim2::smcr::SMS_A, tim2::smcr::TS_A::Itr0),
+    (TIM2, TIM3): (tim2_tim3, apb1_tim_clk, tim2::cr2::MMS_A, tim2::smcr::SMS_A, tim2::smcr::TS_A::Itr0),
     // Internal trigger connection: RM0377 table 80
-    (TIM21, TIM22): (tim21_tim22, tim21::cr2::MMS_A, tim22::smcr::SMS_A, tim22::smcr::TS_A::Itr0),
+    (TIM21, TIM22): (tim21_tim22, apb2_tim_clk, tim21::cr2::MMS_A, tim22::smcr::SMS_A, tim22::smcr::TS_A::Itr0),
 
     // Note: Other combinations would be possible as well, e.g. (TIM21, TIM2) or (TIM2, TIM22).
     // They can be implemented if needed.
 }
+
+/// Source of the trigger output (TRGO) of a timer (`TIMx_CR2.MMS`)
+///
+/// TIM6 only supports `Reset`, `Enable`, and `Update`. TIM21 and TIM22 only
+/// have two channels, so they don't support `CompareOc3Ref` and
+/// `CompareOc4Ref`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MasterMode {
+    /// The UG bit, or a reset of the timer by its slave mode controller
+    Reset = 0b000,
+    /// The counter enable signal
+    Enable = 0b001,
+    /// The update event, i.e. every overflow or underflow of the counter
+    Update = 0b010,
+    /// A pulse whenever a capture or compare match on channel 1 occurs
+    ComparePulse = 0b011,
+    /// The output compare reference signal of channel 1
+    CompareOc1Ref = 0b100,
+    /// The output compare reference signal of channel 2
+    CompareOc2Ref = 0b101,
+    /// The output compare reference signal of channel 3
+    CompareOc3Ref = 0b110,
+    /// The output compare reference signal of channel 4
+    CompareOc4Ref = 0b111,
+}
+
+/// Slave mode of a timer triggered by another timer (`TIMx_SMCR.SMS`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlaveMode {
+    /// The trigger input is ignored
+    Disabled = 0b000,
+    /// A rising edge of the trigger resets the counter
+    Reset = 0b100,
+    /// The counter runs while the trigger is high
+    Gated = 0b101,
+    /// A rising edge of the trigger starts the counter
+    Trigger = 0b110,
+    /// Rising edges of the trigger clock the counter
+    ExternalClock = 0b111,
+}
+
+/// Timers with a trigger output (TRGO)
+pub trait MasterTimer {
+    /// Selects the source of the trigger output
+    ///
+    /// # Panics
+    ///
+    /// Panics, if the timer doesn't support `mode`. See [`MasterMode`].
+    fn set_master_mode(&mut self, mode: MasterMode);
+}
+
+/// Indicates that the trigger output of a timer is connected to an internal
+/// trigger input (ITRx) of the timer `SLAVE`
+pub trait InternalTrigger<SLAVE> {
+    /// The value of `TIMx_SMCR.TS` of `SLAVE` that selects this timer
+    const TS: u8;
+}
+
+/// Timers whose trigger output can start ADC conversions
+pub trait AdcTrigger: MasterTimer {
+    /// The ADC trigger connected to the trigger output
+    const ADC_TRIGGER: adc::Trigger;
+}
+
+impl<TIM: MasterTimer> Timer<TIM> {
+    /// Selects the source of the trigger output (TRGO)
+    ///
+    /// This is a typed alternative to [`Timer::select_master_mode`].
+    ///
+    /// # Panics
+    ///
+    /// Panics, if the timer doesn't support `mode`. See [`MasterMode`].
+    pub fn set_master_mode(&mut self, mode: MasterMode) {
+        self.tim.set_master_mode(mode);
+    }
+}
+
+impl<TIM: AdcTrigger> Timer<TIM> {
+    /// Configures the trigger output to trigger ADC conversions
+    ///
+    /// Selects `mode` as the source of the trigger output, and returns the
+    /// matching trigger to pass to `Adc::start`. Use [`MasterMode::Update`] to
+    /// start a conversion at the frequency of the timer.
+    pub fn adc_trigger(&mut self, mode: MasterMode) -> adc::Trigger {
+        self.tim.set_master_mode(mode);
+        TIM::ADC_TRIGGER
+    }
+}
+
+macro_rules! slave_timers {
+    ($($TIM:ident,)+) => {
+        $(
+            impl Timer<$TIM> {
+                /// Connects this timer to the trigger output of `master`
+                ///
+                /// Selects `mode` as the source of the trigger output of
+                /// `master`, selects the internal trigger input that `master`
+                /// is connected to, and reacts to the trigger according to
+                /// `slave_mode`.
+                ///
+                /// # Panics
+                ///
+                /// Panics, if `master` doesn't support `mode`. See [`MasterMode`].
+                pub fn trigger_from<MASTER>(
+                    &mut self,
+                    master: &mut Timer<MASTER>,
+                    mode: MasterMode,
+                    slave_mode: SlaveMode,
+                ) where
+                    MASTER: MasterTimer + InternalTrigger<$TIM>,
+                {
+                    master.set_master_mode(mode);
+
+                    // TS must only be changed while the slave mode is disabled
+                    #[allow(unused_unsafe)]
+                    self.tim
+                        .smcr
+                        .modify(|_, w| unsafe { w.sms().bits(SlaveMode::Disabled as u8) });
+                    #[allow(unused_unsafe)]
+                    self.tim.smcr.modify(|_, w| unsafe { w.ts().bits(MASTER::TS) });
+                    #[allow(unused_unsafe)]
+                    self.tim
+                        .smcr
+                        .modify(|_, w| unsafe { w.sms().bits(slave_mode as u8) });
+                }
+            }
+        )+
+    }
+}
+
+slave_timers! {
+    TIM2,
+    TIM3,
+    TIM21,
+    TIM22,
+}
+
+macro_rules! internal_triggers {
+    ($($MASTER:ident => $SLAVE:ident: $ts:expr,)+) => {
+        $(
+            impl InternalTrigger<$SLAVE> for $MASTER {
+                const TS: u8 = $ts;
+            }
+        )+
+    }
+}
+
+// Internal trigger connections: RM0377 tables 76 and 80. Other connections can
+// be added if needed.
+internal_triggers! {
+    TIM2 => TIM3: 0b000,
+    TIM21 => TIM2: 0b000,
+    TIM21 => TIM22: 0b000,
+    TIM2 => TIM22: 0b001,
+}
+
+macro_rules! adc_triggers {
+    ($($(#[$attr:meta])* $TIM:ident: $trigger:ident,)+) => {
+        $(
+            $(#[$attr])*
+            impl AdcTrigger for $TIM {
+                const ADC_TRIGGER: adc::Trigger = adc::Trigger::$trigger;
+            }
+        )+
+    }
+}
+
+// TIM21 is not connected to the ADC
+adc_triggers! {
+    TIM2: TIM2_TRGO,
+    #[cfg(not(any(
+        feature = "io-STM32L021",
+        feature = "io-STM32L031",
+        feature = "io-STM32L051"
+    )))]
+    TIM3: TIM3_TRGO,
+    #[cfg(not(any(feature = "io-STM32L021", feature = "io-STM32L031")))]
+    TIM6: TIM6_TRGO,
+    #[cfg(not(feature = "io-STM32L021"))]
+    TIM22: TIM22_TRGO,
+}
+
+/// Pins that can be used as the external trigger input of a timer
+/// (`TIMx_ETR`)
+pub trait EtrPin<TIM> {
+    /// Switches the pin to the ETR alternate function of `TIM`
+    ///
+    /// For TIM2, that's AF5 on PA0, and AF2 on PA5 and PA15.
+    fn setup(&self);
+}
+
+impl<MODE: PinMode> EtrPin<TIM2> for PA0<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(AltMode::AF5);
+    }
+}
+
+impl<MODE: PinMode> EtrPin<TIM2> for PA5<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(AltMode::AF2);
+    }
+}
+
+impl<MODE: PinMode> EtrPin<TIM2> for PA15<MODE> {
+    fn setup(&self) {
+        self.set_alt_mode(AltMode::AF2);
+    }
+}
+
+/// Prescaler applied to the external trigger signal (`TIMx_SMCR.ETPS`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EtrPrescaler {
+    /// Count every edge
+    Div1 = 0b00,
+    /// Count every 2nd edge
+    Div2 = 0b01,
+    /// Count every 4th edge
+    Div4 = 0b10,
+    /// Count every 8th edge
+    Div8 = 0b11,
+}
+
+/// Digital filter applied to a timer input
+///
+/// Used for the input capture filters (`TIMx_CCMRx.ICxF`) and the external
+/// trigger filter (`TIMx_SMCR.ETF`), which share the same encoding. An edge is
+/// only accepted after `N` consecutive samples at the new level, taken at the
+/// given sampling frequency. `f_DTS` is the timer kernel clock, as this HAL
+/// doesn't set the clock division (`TIMx_CR1.CKD`).
+///
+/// The filter suppresses pulses shorter than roughly `N / f_sampling`. For
+/// example, with a 16 MHz timer clock, `FdtsDiv32N8` ignores pulses shorter
+/// than 16 µs, which is enough to debounce many mechanical encoders without an
+/// external RC network.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// No filter, sampling is done at f_DTS
+    NoFilter = 0b0000,
+    /// Sampling at f_CK_INT, N=2
+    FckIntN2 = 0b0001,
+    /// Sampling at f_CK_INT, N=4
+    FckIntN4 = 0b0010,
+    /// Sampling at f_CK_INT, N=8
+    FckIntN8 = 0b0011,
+    /// Sampling at f_DTS / 2, N=6
+    FdtsDiv2N6 = 0b0100,
+    /// Sampling at f_DTS / 2, N=8
+    FdtsDiv2N8 = 0b0101,
+    /// Sampling at f_DTS / 4, N=6
+    FdtsDiv4N6 = 0b0110,
+    /// Sampling at f_DTS / 4, N=8
+    FdtsDiv4N8 = 0b0111,
+    /// Sampling at f_DTS / 8, N=6
+    FdtsDiv8N6 = 0b1000,
+    /// Sampling at f_DTS / 8, N=8
+    FdtsDiv8N8 = 0b1001,
+    /// Sampling at f_DTS / 16, N=5
+    FdtsDiv16N5 = 0b1010,
+    /// Sampling at f_DTS / 16, N=6
+    FdtsDiv16N6 = 0b1011,
+    /// Sampling at f_DTS / 16, N=8
+    FdtsDiv16N8 = 0b1100,
+    /// Sampling at f_DTS / 32, N=5
+    FdtsDiv32N5 = 0b1101,
+    /// Sampling at f_DTS / 32, N=6
+    FdtsDiv32N6 = 0b1110,
+    /// Sampling at f_DTS / 32, N=8
+    FdtsDiv32N8 = 0b1111,
+}
+
+/// Counts pulses on the external trigger input (ETR) of a timer
+///
+/// The timer is configured in external clock mode 2, so every (prescaled and
+/// filtered) rising edge on the ETR pin increments the counter. The input
+/// signal is resynchronized to the timer clock, so the maximum countable
+/// frequency after the prescaler is half the timer clock.
+///
+/// The counter wraps around after 65535 pulses, which can be detected using
+/// the update (overflow) interrupt.
+pub struct PulseCounter<TIM, PIN> {
+    tim: TIM,
+    pin: PIN,
+}
+
+impl<PIN> PulseCounter<TIM2, PIN>
+where
+    PIN: EtrPin<TIM2>,
+{
+    /// Configures TIM2 to count pulses on the given ETR pin
+    pub fn tim2(
+        tim: TIM2,
+        pin: PIN,
+        prescaler: EtrPrescaler,
+        filter: Filter,
+        rcc: &mut Rcc,
+    ) -> Self {
+        TIM2::enable(rcc);
+        TIM2::reset(rcc);
+
+        pin.setup();
+
+        // Count over the full 16 bit range
+        #[allow(unused_unsafe)]
+        tim.arr.write(|w| unsafe { w.arr().bits(0xffff) });
+
+        // External clock mode 2: count edges on ETRF. This is independent of
+        // the slave mode selection, which stays disabled.
+        #[allow(unused_unsafe)]
+        tim.smcr.write(|w| unsafe {
+            w.etp()
+                .clear_bit()
+                .etps()
+                .bits(prescaler as u8)
+                .etf()
+                .bits(filter as u8)
+                .ece()
+                .set_bit()
+        });
+
+        // Only generate update interrupts on overflow
+        tim.cr1.modify(|_, w| w.urs().set_bit());
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.sr.write(|w| w.uif().clear_bit());
+
+        tim.cr1.modify(|_, w| w.cen().set_bit());
+
+        PulseCounter { tim, pin }
+    }
+
+    /// Returns the number of pulses counted since the last reset
+    pub fn count(&self) -> u16 {
+        self.tim.cnt.read().cnt().bits() as u16
+    }
+
+    /// Resets the pulse count to 0
+    pub fn reset(&mut self) {
+        self.tim.cnt.reset();
+    }
+
+    /// Starts listening for counter overflows
+    pub fn listen(&mut self) {
+        self.tim.dier.modify(|_, w| w.uie().set_bit());
+    }
+
+    /// Stops listening for counter overflows
+    pub fn unlisten(&mut self) {
+        self.tim.dier.modify(|_, w| w.uie().clear_bit());
+    }
+
+    /// Returns `true`, if the counter has overflowed since the last call to
+    /// `clear_irq`
+    pub fn did_overflow(&self) -> bool {
+        self.tim.sr.read().uif().bit_is_set()
+    }
+
+    /// Clears the overflow interrupt flag
+    pub fn clear_irq(&mut self) {
+        self.tim.sr.write(|w| w.uif().clear_bit());
+    }
+
+    /// Stops counting and releases the timer and pin
+    pub fn release(self) -> (TIM2, PIN) {
+        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+        self.tim.smcr.reset();
+        (self.tim, self.pin)
+    }
+}
+
+/// Behavior of a software timer once it expires
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SoftTimerMode {
+    /// The timer stops after expiring once
+    OneShot,
+    /// The timer is restarted automatically after expiring
+    Periodic,
+}
+
+/// Handle of a software timer, returned by [`SoftTimers::add`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SoftTimerId(usize);
+
+#[derive(Copy, Clone)]
+struct SoftTimer {
+    mode: SoftTimerMode,
+    ticks: u32,
+    remaining: u32,
+    running: bool,
+    expired: bool,
+    callback: Option<fn()>,
+}
+
+/// Multiplexes `N` software timers onto one hardware timer
+///
+/// [`SoftTimers::tick`] must be called on every tick of a periodic hardware
+/// timer, typically from its interrupt handler. All software timer periods are
+/// given in those ticks.
+///
+/// When a software timer expires, its callback is called from `tick` and its
+/// expired flag is set. The flag can be polled and cleared with
+/// [`SoftTimers::is_expired`], so timers can be used without callbacks.
+///
+/// To share the timers between the interrupt handler and the application,
+/// put them into a `Mutex<RefCell<_>>`, like other peripherals:
+///
+/// ``` ignore
+/// static TIMERS: Mutex<RefCell<SoftTimers<4>>> = Mutex::new(RefCell::new(SoftTimers::new()));
+///
+/// #[interrupt]
+/// fn TIM2() {
+///     interrupt::free(|cs| {
+///         TIMER.borrow(cs).borrow_mut().as_mut().unwrap().clear_irq();
+///         TIMERS.borrow(cs).borrow_mut().tick();
+///     });
+/// }
+/// ```
+pub struct SoftTimers<const N: usize> {
+    timers: [Option<SoftTimer>; N],
+}
+
+impl<const N: usize> SoftTimers<N> {
+    /// Creates a new set of software timers, with all slots free
+    pub const fn new() -> Self {
+        SoftTimers { timers: [None; N] }
+    }
+
+    /// Adds and starts a software timer that expires after `ticks` ticks
+    ///
+    /// `callback` is called from [`SoftTimers::tick`] whenever the timer
+    /// expires. Returns `None`, if all `N` slots are in use.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `ticks` is 0.
+    pub fn add(
+        &mut self,
+        mode: SoftTimerMode,
+        ticks: u32,
+        callback: Option<fn()>,
+    ) -> Option<SoftTimerId> {
+        assert!(ticks > 0);
+
+        let index = self.timers.iter().position(|timer| timer.is_none())?;
+        self.timers[index] = Some(SoftTimer {
+            mode,
+            ticks,
+            remaining: ticks,
+            running: true,
+            expired: false,
+            callback,
+        });
+        Some(SoftTimerId(index))
+    }
+
+    /// Stops the timer and frees its slot
+    ///
+    /// `id` must not be used anymore afterwards, as the slot may be reused by
+    /// the next call to [`SoftTimers::add`].
+    pub fn remove(&mut self, id: SoftTimerId) {
+        self.timers[id.0] = None;
+    }
+
+    /// Restarts the timer from its full period and clears the expired flag
+    pub fn restart(&mut self, id: SoftTimerId) {
+        if let Some(timer) = &mut self.timers[id.0] {
+            timer.remaining = timer.ticks;
+            timer.running = true;
+            timer.expired = false;
+        }
+    }
+
+    /// Stops the timer, keeping its slot
+    pub fn stop(&mut self, id: SoftTimerId) {
+        if let Some(timer) = &mut self.timers[id.0] {
+            timer.running = false;
+        }
+    }
+
+    /// Checks if the timer is running
+    pub fn is_running(&self, id: SoftTimerId) -> bool {
+        matches!(&self.timers[id.0], Some(timer) if timer.running)
+    }
+
+    /// Checks if the timer has expired since the last call, clearing the flag
+    pub fn is_expired(&mut self, id: SoftTimerId) -> bool {
+        match &mut self.timers[id.0] {
+            Some(timer) => core::mem::replace(&mut timer.expired, false),
+            None => false,
+        }
+    }
+
+    /// Advances all running timers by one tick
+    pub fn tick(&mut self) {
+        for timer in self.timers.iter_mut().flatten() {
+            if !timer.running {
+                continue;
+            }
+
+            timer.remaining -= 1;
+            if timer.remaining > 0 {
+                continue;
+            }
+
+            match timer.mode {
+                SoftTimerMode::OneShot => timer.running = false,
+                SoftTimerMode::Periodic => timer.remaining = timer.ticks,
+            }
+            timer.expired = true;
+
+            if let Some(callback) = timer.callback {
+                callback();
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for SoftTimers<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Error returned by [`block_timeout!`] and [`with_timeout`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimeoutError<E> {
+    /// The timer expired before the operation completed
+    Timeout,
+    /// The operation failed with an error
+    Other(E),
+}
+
+impl<E: fmt::Display> fmt::Display for TimeoutError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeoutError::Timeout => f.write_str("timeout"),
+            TimeoutError::Other(error) => fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+impl<E> From<E> for TimeoutError<E> {
+    fn from(error: E) -> Self {
+        TimeoutError::Other(error)
+    }
+}
+
+/// Blocks on a non-blocking operation until it completes or a timer expires
+///
+/// This is like `nb::block!`, but gives up once the `CountDown` timer passed
+/// as the first argument expires. The timer must already be started, and the
+/// first expiry counts as the timeout. Evaluates to
+/// `Result<T, TimeoutError<E>>`.
+///
+/// The operation is evaluated again on every iteration, so it can be any
+/// expression returning `nb::Result`, like a method call on a peripheral.
+///
+/// ``` ignore
+/// use stm32l0xx_hal::block_timeout;
+///
+/// timer.start(10.Hz());
+/// let byte = block_timeout!(timer, rx.read())?;
+/// ```
+#[macro_export]
+macro_rules! block_timeout {
+    ($timer:expr, $e:expr) => {
+        loop {
+            if let Some(result) = $crate::timer::poll_timeout(&mut $timer, $e) {
+                break result;
+            }
+        }
+    };
+}
+
+pub use crate::block_timeout;
+
+/// Starts `timer` and calls `op` until it completes or the timer expires
+///
+/// This is the function version of [`block_timeout!`].
+pub fn with_timeout<C, Time, T, E>(
+    timer: &mut C,
+    timeout: Time,
+    mut op: impl FnMut() -> nb::Result<T, E>,
+) -> Result<T, TimeoutError<E>>
+where
+    C: CountDown,
+    Time: Into<C::Time>,
+{
+    timer.start(timeout);
+    loop {
+        if let Some(result) = poll_timeout(timer, op()) {
+            return result;
+        }
+    }
+}
+
+/// Used by `block_timeout!` to check a single result of the operation
+///
+/// Returns `None`, if the operation would block and the timer hasn't expired.
+#[doc(hidden)]
+pub fn poll_timeout<C, T, E>(
+    timer: &mut C,
+    result: nb::Result<T, E>,
+) -> Option<Result<T, TimeoutError<E>>>
+where
+    C: CountDown,
+{
+    match result {
+        Ok(value) => Some(Ok(value)),
+        Err(nb::Error::Other(error)) => Some(Err(TimeoutError::Other(error))),
+        Err(nb::Error::WouldBlock) => match timer.wait() {
+            Ok(()) => Some(Err(TimeoutError::Timeout)),
+            Err(_) => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_config_without_prescaler() {
+        assert_eq!(get_clock_config(1_000, 16_000_000), (0, 16_000));
+        assert_eq!(get_clock_config(1, 65_535), (0, 65_535));
+    }
+
+    #[test]
+    fn clock_config_with_prescaler() {
+        let (psc, arr) = get_clock_config(1, 16_000_000);
+        assert_eq!((psc, arr), (244, 65_306));
+        assert_eq!(16_000_000 / ((u32::from(psc) + 1) * u32::from(arr)), 1);
+    }
+
+    #[test]
+    fn clock_config_multiple_of_2_16() {
+        // Used to overflow the auto-reload value
+        assert_eq!(get_clock_config(1, 65_536), (1, 32_768));
+        assert_eq!(get_clock_config(1, 131_072), (2, 43_690));
+    }
+
+    #[test]
+    fn clock_config_checked() {
+        assert_eq!(try_clock_config(1_000, 16_000_000), Ok((0, 16_000)));
+        assert_eq!(
+            try_clock_config(1, 32_000_000),
+            Ok(get_clock_config(1, 32_000_000))
+        );
+        assert_eq!(try_clock_config(8_000_000, 16_000_000), Ok((0, 2)));
+        assert_eq!(try_clock_config(0, 16_000_000), Err(FrequencyError::TooLow));
+        assert_eq!(
+            try_clock_config(8_000_001, 16_000_000),
+            Err(FrequencyError::TooHigh)
+        );
+    }
+
+    #[test]
+    fn frequency_range() {
+        assert_eq!(min_frequency(32_000_000), 1);
+        assert_eq!(min_frequency(u32::MAX), 1);
+        assert_eq!(max_frequency(32_000_000), 16_000_000);
+    }
+
+    #[test]
+    fn timeout_ticks() {
+        // 1 ms at 16 MHz fits without prescaler
+        assert_eq!(calculate_timeout_ticks(16_000_000, 1_000), Ok((0, 15_999)));
+        // exactly 2^16 ticks
+        assert_eq!(calculate_timeout_ticks(65_536, 1_000_000), Ok((0, 65_535)));
+        // one more tick needs the prescaler
+        assert_eq!(calculate_timeout_ticks(65_537, 1_000_000), Ok((1, 32_767)));
+        // 1 s at 32 MHz
+        let (psc, arr) = calculate_timeout_ticks(32_000_000, 1_000_000).unwrap();
+        assert_eq!((psc, arr), (488, 65_438));
+        assert!((u32::from(psc) + 1) * (u32::from(arr) + 1) <= 32_000_000);
+    }
+
+    #[test]
+    fn timeout_ticks_out_of_range() {
+        assert_eq!(
+            calculate_timeout_ticks(16_000_000, 0),
+            Err(DurationError::TooShort)
+        );
+        assert_eq!(
+            calculate_timeout_ticks(1_000_000, 1),
+            Err(DurationError::TooShort)
+        );
+        // 2^32 ticks are the maximum
+        assert_eq!(
+            calculate_timeout_ticks(1_000_000, u32::MAX),
+            Ok((65_535, 65_534))
+        );
+        assert_eq!(
+            calculate_timeout_ticks(32_000_000, u32::MAX),
+            Err(DurationError::TooLong)
+        );
+    }
+
+    #[test]
+    fn timeout_ticks_32() {
+        assert_eq!(
+            calculate_timeout_ticks_32(16_000_000, 1_000),
+            (0, 16_000_000)
+        );
+        // 3 hours
+        assert_eq!(
+            calculate_timeout_ticks_32(32_000_000, 10_800_000),
+            (80, 4_266_666_666)
+        );
+        // The longest timeout still fits
+        assert_eq!(
+            calculate_timeout_ticks_32(32_000_000, u32::MAX),
+            (31_999, u32::MAX)
+        );
+        // Never 0, or the timer would expire immediately
+        assert_eq!(calculate_timeout_ticks_32(16_000_000, 0), (0, 1));
+    }
+}
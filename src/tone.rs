@@ -0,0 +1,249 @@
+//! Tone generation on a PWM channel
+//!
+//! Drives a piezo buzzer or a small speaker with a square wave. [`Buzzer`]
+//! plays single notes or sequences of notes, either blocking, or driven by the
+//! update interrupt of the PWM timer.
+//!
+//! Frequency changes are buffered (see [`pwm::Timer::set_frequency_buffered`]),
+//! so the transition from one note to the next doesn't glitch.
+
+use crate::hal::{blocking::delay::DelayMs, PwmPin};
+use crate::pwm::{self, Assigned, Channel, Event, Instance, Pwm};
+use crate::rcc::Rcc;
+use crate::timer::get_clock_config;
+use embedded_time::{duration::Milliseconds, rate::Hertz};
+
+/// Frequency the timer runs at during rests, to keep counting time
+const REST_FREQUENCY: u32 = 1_000;
+
+/// A note, or a rest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Note {
+    /// The frequency of the note, or `None` for a rest
+    ///
+    /// A frequency of 0 Hz is played as a rest, too.
+    pub frequency: Option<Hertz>,
+
+    /// How long the note is played
+    pub duration: Milliseconds,
+}
+
+impl Note {
+    /// Creates a note of `frequency` Hz, that is played for `duration` ms
+    ///
+    /// A `frequency` of 0 creates a rest.
+    pub const fn new(frequency: u32, duration: u32) -> Self {
+        Self {
+            frequency: if frequency == 0 {
+                None
+            } else {
+                Some(Hertz(frequency))
+            },
+            duration: Milliseconds(duration),
+        }
+    }
+
+    /// Creates a rest of `duration` ms
+    pub const fn rest(duration: u32) -> Self {
+        Self {
+            frequency: None,
+            duration: Milliseconds(duration),
+        }
+    }
+
+    /// Returns the frequency of the note, or `None` for a rest
+    fn tone(&self) -> Option<u32> {
+        self.frequency.map(|f| f.0).filter(|&f| f > 0)
+    }
+
+    /// Returns the frequency the timer runs at while the note is played
+    fn timer_frequency(&self) -> u32 {
+        self.tone().unwrap_or(REST_FREQUENCY)
+    }
+
+    /// Returns the number of timer periods that make up the duration
+    fn periods(&self) -> u32 {
+        let frequency = self.timer_frequency();
+        let periods = u64::from(frequency) * u64::from(self.duration.0) / 1_000;
+        periods.min(u64::from(u32::MAX)) as u32
+    }
+}
+
+/// A buzzer on a PWM channel
+pub struct Buzzer<I, C, P> {
+    timer: pwm::Timer<I>,
+    channel: Pwm<I, C, Assigned<P>>,
+    clock: u32,
+    sequence: &'static [Note],
+    index: usize,
+    remaining: u32,
+}
+
+impl<I, C, P> Buzzer<I, C, P>
+where
+    I: Instance,
+    C: Channel,
+{
+    /// Creates a buzzer from a PWM timer and one of its channels
+    ///
+    /// The channel output is disabled until a note is played.
+    pub fn new(timer: pwm::Timer<I>, mut channel: Pwm<I, C, Assigned<P>>, rcc: &Rcc) -> Self {
+        channel.disable();
+
+        Self {
+            timer,
+            channel,
+            clock: I::clock_frequency(rcc),
+            sequence: &[],
+            index: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Plays `note`, blocking until it's finished
+    ///
+    /// The output is switched off afterwards.
+    pub fn play<D>(&mut self, note: Note, delay: &mut D)
+    where
+        D: DelayMs<u32>,
+    {
+        self.set_note(note);
+        delay.delay_ms(note.duration.0);
+        self.channel.disable();
+    }
+
+    /// Plays a sequence of notes, blocking until it's finished
+    pub fn play_sequence<D>(&mut self, notes: &[Note], delay: &mut D)
+    where
+        D: DelayMs<u32>,
+    {
+        for &note in notes {
+            self.set_note(note);
+            delay.delay_ms(note.duration.0);
+        }
+        self.channel.disable();
+    }
+
+    /// Starts playing a sequence of notes in the background
+    ///
+    /// Enables the update interrupt of the timer. Call
+    /// [`Buzzer::on_interrupt`] from its handler, to advance the sequence.
+    /// Replaces the sequence that is currently playing, if any.
+    pub fn start_sequence(&mut self, notes: &'static [Note]) {
+        self.sequence = notes;
+        self.index = 0;
+
+        match notes.first() {
+            Some(&note) => {
+                self.set_note(note);
+                self.remaining = note.periods();
+                self.timer.clear_irq(Event::Update);
+                self.timer.listen(Event::Update);
+            }
+            None => self.stop(),
+        }
+    }
+
+    /// Handles the update interrupt of the timer
+    ///
+    /// Moves on to the next note, once the current one has been played for
+    /// its duration. Returns `true` while the sequence is playing.
+    pub fn on_interrupt(&mut self) -> bool {
+        if !self.timer.is_pending(Event::Update) {
+            return self.is_playing();
+        }
+        self.timer.clear_irq(Event::Update);
+
+        if !self.is_playing() {
+            return false;
+        }
+
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining == 0 {
+            self.index += 1;
+            match self.sequence.get(self.index) {
+                Some(&note) => {
+                    self.set_note(note);
+                    self.remaining = note.periods();
+                }
+                None => {
+                    self.stop();
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Returns `true`, while a sequence started by [`Buzzer::start_sequence`]
+    /// is playing
+    pub fn is_playing(&self) -> bool {
+        self.index < self.sequence.len()
+    }
+
+    /// Stops playing and switches the output off
+    pub fn stop(&mut self) {
+        self.timer.unlisten(Event::Update);
+        self.channel.disable();
+        self.sequence = &[];
+        self.index = 0;
+        self.remaining = 0;
+    }
+
+    /// Releases the timer and the channel
+    pub fn free(mut self) -> (pwm::Timer<I>, Pwm<I, C, Assigned<P>>) {
+        self.stop();
+        (self.timer, self.channel)
+    }
+
+    fn set_note(&mut self, note: Note) {
+        let (psc, arr) = get_clock_config(note.timer_frequency(), self.clock);
+        self.timer.set_clock_config_buffered(psc, arr);
+
+        match note.tone() {
+            Some(_) => {
+                // The compare register is preloaded, so the new duty cycle
+                // takes effect together with the new frequency.
+                self.channel.set_duty(arr / 2);
+                self.channel.enable();
+            }
+            None => self.channel.disable(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periods_of_note() {
+        assert_eq!(Note::new(440, 500).periods(), 220);
+        assert_eq!(Note::new(1_000, 1).periods(), 1);
+    }
+
+    #[test]
+    fn periods_of_rest() {
+        assert_eq!(Note::rest(250).periods(), 250);
+    }
+
+    #[test]
+    fn zero_frequency_is_rest() {
+        let note = Note::new(0, 250);
+        assert_eq!(note, Note::rest(250));
+        assert_eq!(note.periods(), 250);
+
+        let note = Note {
+            frequency: Some(Hertz(0)),
+            duration: Milliseconds(250),
+        };
+        assert_eq!(note.tone(), None);
+        assert_eq!(note.periods(), 250);
+    }
+
+    #[test]
+    fn periods_saturate() {
+        assert_eq!(Note::new(u32::MAX, u32::MAX).periods(), u32::MAX);
+    }
+}
@@ -11,8 +11,17 @@
 //!
 //! Please check out the USB examples in the `examples/` directory to see how it
 //! fits together.
+//!
+//! The internal pull-up on D+ is enabled by `stm32-usbd` when the device is
+//! built. To delay the connection to the host, create the peripheral with
+//! [`USB::new_disconnected`] instead. [`UsbBusExt`] provides methods to control
+//! the pull-up, to connect to the host or to force the host to enumerate the
+//! device again.
+
+use core::marker::PhantomData;
 
 use crate::{
+    hal::blocking::delay::DelayMs,
     pac,
     rcc::{Enable, Reset, HSI48},
 };
@@ -22,19 +31,62 @@ use crate::gpio::gpioa::{PA11, PA12};
 use crate::gpio::Analog;
 pub use stm32_usbd::UsbBus;
 
-pub struct USB(());
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The D+ pull-up is enabled when the USB device is built.
+pub enum AutoConnect {}
+
+/// The D+ pull-up stays disabled until [`UsbBusExt::connect`] is called.
+pub enum ManualConnect {}
+
+impl sealed::Sealed for AutoConnect {}
+impl sealed::Sealed for ManualConnect {}
+
+/// Marker trait for the way the device connects to the host.
+pub trait ConnectMode: sealed::Sealed {
+    #[doc(hidden)]
+    const PULL_UP: bool;
+}
+
+impl ConnectMode for AutoConnect {
+    const PULL_UP: bool = true;
+}
+
+impl ConnectMode for ManualConnect {
+    const PULL_UP: bool = false;
+}
+
+pub struct USB<MODE = AutoConnect>(PhantomData<MODE>);
 
 impl USB {
+    /// Creates the USB peripheral
+    ///
+    /// The `HSI48` token can only be obtained from [`Rcc::enable_hsi48`], which
+    /// guarantees that the USB clock is running.
+    ///
+    /// [`Rcc::enable_hsi48`]: crate::rcc::Rcc::enable_hsi48
     pub fn new(_: pac::USB, _dm: PA11<Analog>, _dp: PA12<Analog>, _: HSI48) -> Self {
-        Self(())
+        USB(PhantomData)
+    }
+}
+
+impl USB<ManualConnect> {
+    /// Creates the USB peripheral, without connecting to the host
+    ///
+    /// The internal pull-up on D+ stays disabled when the device is built, so
+    /// the host doesn't see the device until [`UsbBusExt::connect`] is called.
+    pub fn new_disconnected(_: pac::USB, _dm: PA11<Analog>, _dp: PA12<Analog>, _: HSI48) -> Self {
+        USB(PhantomData)
     }
 }
 
-unsafe impl Sync for USB {}
+unsafe impl<MODE> Sync for USB<MODE> {}
 
-unsafe impl UsbPeripheral for USB {
+unsafe impl<MODE: ConnectMode + Send> UsbPeripheral for USB<MODE> {
     const REGISTERS: *const () = pac::USB::ptr() as *const ();
-    const DP_PULL_UP_FEATURE: bool = true;
+    const DP_PULL_UP_FEATURE: bool = MODE::PULL_UP;
     const EP_MEMORY: *const () = 0x4000_6000 as _;
     const EP_MEMORY_SIZE: usize = 1024;
     const EP_MEMORY_ACCESS_2X16: bool = true;
@@ -57,3 +109,51 @@ unsafe impl UsbPeripheral for USB {
 }
 
 pub type UsbBusType = UsbBus<USB>;
+
+/// Extension trait to control the connection of the USB bus to the host
+pub trait UsbBusExt {
+    /// Disables the internal pull-up on D+, disconnecting from the host
+    fn disconnect(&self);
+
+    /// Enables the internal pull-up on D+, connecting to the host
+    fn connect(&self);
+
+    /// Checks if the internal pull-up on D+ is enabled
+    fn is_connected(&self) -> bool;
+
+    /// Disconnects from the host for 10 ms and connects again
+    ///
+    /// This makes the host enumerate the device again, for example after a
+    /// firmware update or a change of the configuration.
+    fn force_reenumeration<D: DelayMs<u8>>(&self, delay: &mut D);
+}
+
+impl<MODE: ConnectMode + Send> UsbBusExt for UsbBus<USB<MODE>> {
+    fn disconnect(&self) {
+        set_pull_up(false);
+    }
+
+    fn connect(&self) {
+        set_pull_up(true);
+    }
+
+    fn is_connected(&self) -> bool {
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { (*pac::USB::ptr()).bcdr.read().dppu().bit_is_set() }
+    }
+
+    fn force_reenumeration<D: DelayMs<u8>>(&self, delay: &mut D) {
+        self.disconnect();
+        delay.delay_ms(10);
+        self.connect();
+    }
+}
+
+fn set_pull_up(enabled: bool) {
+    cortex_m::interrupt::free(|_| {
+        // NOTE(unsafe) read-modify-write in a critical section. `stm32-usbd`
+        // only touches BCDR while enabling the bus.
+        let usb = unsafe { &*pac::USB::ptr() };
+        usb.bcdr.modify(|_, w| w.dppu().bit(enabled));
+    });
+}
@@ -1,13 +1,71 @@
-use crate::hal::watchdog;
+use crate::hal::timer::CountDown;
+use crate::hal::watchdog::{self, Watchdog, WatchdogEnable};
+use crate::lptim::{self, LpTimer, OneShot};
 use crate::pac::{IWDG, WWDG};
 use crate::rcc::{Enable, Rcc};
+use crate::time::{IntoHertz, IntoMicroseconds};
+use crate::timer::DurationError;
+use core::cell::Cell;
+use cortex_m::interrupt::{self, Mutex};
+use embedded_time::duration::Microseconds;
 use embedded_time::rate::Hertz;
 
+/// Nominal frequency of the LSI clock, which drives the IWDG
+const LSI_CLOCK: u32 = 38_000_u32;
+
+/// Largest IWDG prescaler setting, dividing by 256
+const IWDG_PR_MAX: u8 = 6;
+
+/// Number of IWDG counter ticks with the largest reload value
+const IWDG_RELOAD_MAX: u64 = 0x1000;
+
+/// Hook called by [`handle_early_wakeup`]
+static EARLY_WAKEUP_HOOK: Mutex<Cell<Option<fn()>>> = Mutex::new(Cell::new(None));
+
 pub struct IndependedWatchdog {
     iwdg: IWDG,
+    lsi_freq: u32,
 }
 
 impl IndependedWatchdog {
+    /// Sets the frequency of the LSI clock, which is used to calculate
+    /// timeouts
+    ///
+    /// The LSI frequency varies considerably between devices and with
+    /// temperature, so the nominal 38 kHz can be far off. Pass the result of
+    /// [`Rcc::measure_lsi`] to make timeouts more accurate. Only affects
+    /// timeouts set afterwards.
+    pub fn set_lsi_frequency(&mut self, freq: impl IntoHertz) {
+        self.lsi_freq = freq.into_hertz().0;
+    }
+
+    /// Starts the watchdog, resetting the MCU if it isn't fed within
+    /// `timeout`
+    ///
+    /// The prescaler and reload value are calculated from the LSI frequency,
+    /// see [`IndependedWatchdog::set_lsi_frequency`]. The timeout is rounded
+    /// to the nearest possible value, which can be checked with
+    /// [`IndependedWatchdog::timeout`]. Timeouts from one tick of the counter
+    /// (about 0.1 ms) up to about 27 s are possible.
+    pub fn start_timeout<T>(&mut self, timeout: T) -> Result<(), DurationError>
+    where
+        T: IntoMicroseconds,
+    {
+        let (pre, reload) = iwdg_config(self.lsi_freq, timeout.into_microseconds().0)?;
+        self.set_config(pre, reload);
+        Ok(())
+    }
+
+    /// Returns the timeout the watchdog is configured for
+    ///
+    /// Calculated from the prescaler and reload registers and the LSI
+    /// frequency, see [`IndependedWatchdog::set_lsi_frequency`].
+    pub fn timeout(&self) -> Microseconds {
+        let pre = self.iwdg.pr.read().pr().bits();
+        let reload = self.iwdg.rlr.read().rl().bits();
+        Microseconds(iwdg_timeout_us(self.lsi_freq, pre, reload))
+    }
+
     pub fn set_config(&mut self, pre: u8, reload: u16) {
         self.iwdg.kr.write(|w| w.key().reset());
         self.iwdg.kr.write(|w| w.key().enable());
@@ -36,10 +94,8 @@ impl watchdog::WatchdogEnable for IndependedWatchdog {
     where
         T: Into<Hertz>,
     {
-        const LSI_CLOCK: u32 = 38_000_u32;
-
         let freq = period.into().0;
-        let mut timeout = LSI_CLOCK / freq / 4;
+        let mut timeout = self.lsi_freq / freq / 4;
         let mut pre = 0;
         let mut reload = 0;
         while pre < 7 {
@@ -60,8 +116,35 @@ pub trait IndependedWatchdogExt {
 
 impl IndependedWatchdogExt for IWDG {
     fn watchdog(self) -> IndependedWatchdog {
-        IndependedWatchdog { iwdg: self }
+        IndependedWatchdog {
+            iwdg: self,
+            lsi_freq: LSI_CLOCK,
+        }
+    }
+}
+
+/// Calculates the IWDG prescaler and reload values for `timeout_us`
+fn iwdg_config(lsi_freq: u32, timeout_us: u32) -> Result<(u8, u16), DurationError> {
+    let cycles = u64::from(lsi_freq) * u64::from(timeout_us) / 1_000_000;
+    for pre in 0..=IWDG_PR_MAX {
+        let divider = 4_u64 << pre;
+        let ticks = (cycles + divider / 2) / divider;
+        if ticks == 0 {
+            return Err(DurationError::TooShort);
+        }
+        if ticks <= IWDG_RELOAD_MAX {
+            return Ok((pre, (ticks - 1) as u16));
+        }
     }
+    Err(DurationError::TooLong)
+}
+
+/// Calculates the IWDG timeout resulting from the prescaler and reload values
+fn iwdg_timeout_us(lsi_freq: u32, pre: u8, reload: u16) -> u32 {
+    // Prescaler settings above the maximum also divide by 256
+    let divider = 4_u64 << pre.min(IWDG_PR_MAX);
+    let cycles = divider * (u64::from(reload) + 1);
+    (cycles * 1_000_000 / u64::from(lsi_freq)) as u32
 }
 
 pub struct WindowWatchdog {
@@ -103,8 +186,71 @@ impl WindowWatchdog {
             .write(|w| w.wdgtb().bits(pre).w().bits(window_bits));
     }
 
+    /// Returns the current value of the down-counter
+    ///
+    /// The MCU is reset when the counter drops below 0x40.
+    pub fn counter(&self) -> u8 {
+        self.wwdg.cr.read().t().bits()
+    }
+
+    /// Indicates whether the watchdog can be fed without causing a reset
+    ///
+    /// Feeding the watchdog is only allowed once the down-counter has dropped
+    /// below the window value set with [`WindowWatchdog::set_window`]. Feeding
+    /// it too early resets the MCU, just like feeding it too late.
+    pub fn is_in_window(&self) -> bool {
+        self.counter() < self.wwdg.cfr.read().w().bits()
+    }
+
+    /// Enables the early wakeup interrupt
+    ///
+    /// The early wakeup interrupt is triggered when the down-counter reaches
+    /// 0x40, one watchdog tick before the reset. Once enabled, it can only be
+    /// disabled by a reset.
     pub fn listen(&mut self) {
-        self.wwdg.cfr.write(|w| w.ewi().set_bit());
+        self.wwdg.cfr.modify(|_, w| w.ewi().set_bit());
+    }
+
+    /// Registers a hook that is called on the early wakeup interrupt and
+    /// enables that interrupt
+    ///
+    /// The hook is called by [`handle_early_wakeup`], which must be called from
+    /// the `WWDG` interrupt handler. The `WWDG` interrupt must also be unmasked
+    /// in the NVIC.
+    ///
+    /// This gives the application one watchdog tick (4096 * 2^WDGTB APB1 clock
+    /// cycles) to save its state before the reset, for example by writing it
+    /// to EEPROM using [`FLASH::start_write_word`].
+    ///
+    /// [`FLASH::start_write_word`]: crate::flash::FLASH::start_write_word
+    pub fn on_early_wakeup(&mut self, hook: fn()) {
+        interrupt::free(|cs| EARLY_WAKEUP_HOOK.borrow(cs).set(Some(hook)));
+        self.listen();
+    }
+
+    /// Returns `true`, if the early wakeup interrupt flag is set
+    pub fn is_early_wakeup(&self) -> bool {
+        self.wwdg.sr.read().ewif().bit_is_set()
+    }
+
+    /// Clears the early wakeup interrupt flag
+    pub fn clear_early_wakeup(&mut self) {
+        self.wwdg.sr.write(|w| w.ewif().clear_bit());
+    }
+}
+
+/// Handles the WWDG early wakeup interrupt
+///
+/// Clears the interrupt flag and calls the hook registered with
+/// [`WindowWatchdog::on_early_wakeup`], if any. Call this from the `WWDG`
+/// interrupt handler.
+pub fn handle_early_wakeup() {
+    // NOTE(unsafe) write to a stateless flag, the other bits of SR are reserved
+    let wwdg = unsafe { &*WWDG::ptr() };
+    wwdg.sr.write(|w| w.ewif().clear_bit());
+
+    if let Some(hook) = interrupt::free(|cs| EARLY_WAKEUP_HOOK.borrow(cs).get()) {
+        hook();
     }
 }
 
@@ -133,3 +279,150 @@ impl WindowWatchdogExt for WWDG {
         }
     }
 }
+
+/// Configuration for [`Supervisor`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SupervisionConfig {
+    /// Period within which the application must check in
+    ///
+    /// This is the coarse liveness check, timed by the LPTIM. Up to 256
+    /// seconds are possible, depending on the LPTIM clock.
+    pub checkin_period: Microseconds,
+
+    /// Frequency at which the IWDG must be fed
+    ///
+    /// This is the fine-grained check, like for
+    /// [`IndependedWatchdog::start`](WatchdogEnable::start).
+    pub watchdog_frequency: Hertz,
+}
+
+impl SupervisionConfig {
+    /// Creates a configuration from a check-in period and IWDG frequency
+    pub fn new(
+        checkin_period: impl Into<Microseconds>,
+        watchdog_frequency: impl Into<Hertz>,
+    ) -> Self {
+        SupervisionConfig {
+            checkin_period: checkin_period.into(),
+            watchdog_frequency: watchdog_frequency.into(),
+        }
+    }
+}
+
+/// Two-level supervision, using the IWDG and the LPTIM
+///
+/// The IWDG must be fed frequently using [`Supervisor::feed`], for example
+/// from the main loop or a periodic interrupt. This catches fine-grained
+/// hangs. On top of that, the application must call [`Supervisor::checkin`]
+/// at least once per check-in period, proving that it still makes progress.
+/// The check-in period is timed by the LPTIM in one-shot mode.
+///
+/// Once a check-in is missed, the supervisor stops feeding the IWDG, which
+/// then resets the MCU. This can't be undone.
+///
+/// A missed check-in is detected in `feed`. To detect it right away, unmask
+/// the `LPTIM1` interrupt and call [`Supervisor::on_interrupt`] from its
+/// handler.
+pub struct Supervisor {
+    watchdog: IndependedWatchdog,
+    timer: LpTimer<OneShot>,
+    checkin_period: Microseconds,
+    expired: bool,
+}
+
+impl Supervisor {
+    /// Starts the IWDG and the check-in period
+    pub fn new(
+        mut watchdog: IndependedWatchdog,
+        mut timer: LpTimer<OneShot>,
+        config: SupervisionConfig,
+    ) -> Self {
+        // Needs to happen before the timer is started, as it disables it
+        timer.listen(lptim::Event::AutoreloadMatch);
+        timer.start(config.checkin_period);
+        watchdog.start(config.watchdog_frequency);
+
+        Supervisor {
+            watchdog,
+            timer,
+            checkin_period: config.checkin_period,
+            expired: false,
+        }
+    }
+
+    /// Feeds the IWDG, unless a check-in has been missed
+    pub fn feed(&mut self) {
+        self.update();
+        if !self.expired {
+            self.watchdog.feed();
+        }
+    }
+
+    /// Checks in, starting a new check-in period
+    ///
+    /// Returns `false`, if a check-in has already been missed. The IWDG won't
+    /// be fed anymore in that case.
+    pub fn checkin(&mut self) -> bool {
+        self.update();
+        if self.expired {
+            return false;
+        }
+
+        self.timer.start(self.checkin_period);
+        true
+    }
+
+    /// Handles the LPTIM interrupt
+    ///
+    /// Returns `true`, if a check-in has been missed. The IWDG is not fed
+    /// anymore from this point on.
+    pub fn on_interrupt(&mut self) -> bool {
+        self.update();
+        self.expired
+    }
+
+    /// Checks if a check-in has been missed
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+
+    /// Returns the IWDG and the LPTIM
+    ///
+    /// The LPTIM interrupt stays enabled. The IWDG can't be stopped, so it
+    /// needs to be fed by other means afterwards.
+    pub fn free(self) -> (IndependedWatchdog, LpTimer<OneShot>) {
+        (self.watchdog, self.timer)
+    }
+
+    fn update(&mut self) {
+        if self.timer.is_pending(lptim::Event::AutoreloadMatch) {
+            self.timer.clear_irq(lptim::Event::AutoreloadMatch);
+            self.expired = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iwdg_timeout() {
+        assert_eq!(iwdg_config(38_000, 500_000), Ok((1, 2374)));
+        assert_eq!(iwdg_timeout_us(38_000, 1, 2374), 500_000);
+
+        // Rounded to the nearest tick
+        assert_eq!(iwdg_config(38_000, 1_000), Ok((0, 9)));
+        assert_eq!(iwdg_timeout_us(38_000, 0, 9), 1_052);
+
+        // Longest possible timeout
+        assert_eq!(iwdg_config(38_000, 27_000_000), Ok((6, 4007)));
+        assert_eq!(iwdg_timeout_us(38_000, 7, 4095), 27_594_105);
+    }
+
+    #[test]
+    fn iwdg_timeout_out_of_range() {
+        assert_eq!(iwdg_config(38_000, 10), Err(DurationError::TooShort));
+        assert_eq!(iwdg_config(38_000, 30_000_000), Err(DurationError::TooLong));
+    }
+}
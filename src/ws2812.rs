@@ -0,0 +1,158 @@
+//! WS2812/SK6812 addressable LED driver
+//!
+//! Requires the `ws2812` feature.
+//!
+//! The LEDs are driven by an SPI peripheral, using only its MOSI pin. Every
+//! bit sent to the LEDs is encoded as a pattern of 3 or 4 SPI bits, with a
+//! short (0) or long (1) high time. This keeps the timing exact, without any
+//! bit-banging, as long as there are no long gaps between bytes on the bus.
+//!
+//! The SPI peripheral must be configured for mode 0 and a frequency between
+//! 2.4 MHz and 4 MHz. Below 3.2 MHz, 3 SPI bits are used per LED bit,
+//! otherwise 4. As the SPI clock is derived from the APB clock by a power of
+//! two divider, 2 MHz or 4 MHz APB clocks, or multiples of them, work well.
+//!
+//! ``` ignore
+//! let spi = dp.SPI1.spi((sck, NoMiso, mosi), spi::MODE_0, 4.MHz(), &mut rcc);
+//! let mut leds = Ws2812::new(spi, 4.MHz());
+//! leds.write(&[RGB8::new(255, 0, 0), RGB8::new(0, 255, 0)])?;
+//! ```
+
+use crate::hal::blocking::spi::Write;
+use embedded_time::rate::Hertz;
+
+/// Maximum SPI frequency for the 3-bit encoding
+const THREE_BIT_MAX_FREQ: u32 = 3_200_000;
+
+/// Number of zero bytes sent after the data, to latch it
+///
+/// Newer LEDs require the line to be low for at least 280 µs, which are 140
+/// bytes at 4 MHz.
+const RESET_BYTES: usize = 140;
+
+/// An RGB color with 8 bits per channel
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RGB8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl RGB8 {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        RGB8 { r, g, b }
+    }
+}
+
+/// WS2812/SK6812 LED strip driver
+pub struct Ws2812<SPI> {
+    spi: SPI,
+    four_bit: bool,
+    gamma: bool,
+}
+
+impl<SPI, E> Ws2812<SPI>
+where
+    SPI: Write<u8, Error = E>,
+{
+    /// Creates a new driver, using `spi` running at `freq`
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `freq` is outside the range of 2.4 MHz to 4 MHz.
+    pub fn new(spi: SPI, freq: Hertz) -> Self {
+        assert!(freq.0 >= 2_400_000 && freq.0 <= 4_000_000);
+
+        Ws2812 {
+            spi,
+            four_bit: freq.0 > THREE_BIT_MAX_FREQ,
+            gamma: false,
+        }
+    }
+
+    /// Enables or disables gamma correction
+    ///
+    /// If enabled, the colors passed to `write` are corrected for the
+    /// non-linear brightness perception of the eye, which makes fades look
+    /// smoother.
+    pub fn set_gamma(&mut self, enabled: bool) {
+        self.gamma = enabled;
+    }
+
+    /// Sends `colors` to the LEDs, starting with the first LED of the strip
+    pub fn write(&mut self, colors: &[RGB8]) -> Result<(), E> {
+        for color in colors {
+            let color = if self.gamma {
+                RGB8::new(gamma(color.r), gamma(color.g), gamma(color.b))
+            } else {
+                *color
+            };
+
+            // The LEDs expect the colors in GRB order
+            let mut buffer = [0; 12];
+            let mut len = 0;
+            for &channel in &[color.g, color.r, color.b] {
+                len += self.encode(channel, &mut buffer[len..]);
+            }
+            self.spi.write(&buffer[..len])?;
+        }
+
+        // Latch the data
+        for _ in 0..RESET_BYTES / 4 {
+            self.spi.write(&[0; 4])?;
+        }
+
+        Ok(())
+    }
+
+    /// Releases the SPI peripheral
+    pub fn free(self) -> SPI {
+        self.spi
+    }
+
+    /// Encodes `byte` into `buffer`, returning the number of bytes written
+    fn encode(&self, byte: u8, buffer: &mut [u8]) -> usize {
+        let (zero, one, width) = if self.four_bit {
+            (0b1000, 0b1110, 4)
+        } else {
+            (0b100, 0b110, 3)
+        };
+
+        let mut bits: u32 = 0;
+        for i in (0..8).rev() {
+            let pattern = if byte & (1 << i) != 0 { one } else { zero };
+            bits = (bits << width) | pattern;
+        }
+
+        let len = width as usize;
+        for (i, out) in buffer[..len].iter_mut().enumerate() {
+            *out = (bits >> (8 * (len - 1 - i))) as u8;
+        }
+        len
+    }
+}
+
+/// Applies gamma correction (gamma 2.8) to a color channel
+pub fn gamma(value: u8) -> u8 {
+    GAMMA8[usize::from(value)]
+}
+
+#[rustfmt::skip]
+static GAMMA8: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2,
+    2, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5,
+    5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10,
+    10, 10, 11, 11, 11, 12, 12, 13, 13, 13, 14, 14, 15, 15, 16, 16,
+    17, 17, 18, 18, 19, 19, 20, 20, 21, 21, 22, 22, 23, 24, 24, 25,
+    25, 26, 27, 27, 28, 29, 29, 30, 31, 32, 32, 33, 34, 35, 35, 36,
+    37, 38, 39, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 50,
+    51, 52, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 66, 67, 68,
+    69, 70, 72, 73, 74, 75, 77, 78, 79, 81, 82, 83, 85, 86, 87, 89,
+    90, 92, 93, 95, 96, 98, 99, 101, 102, 104, 105, 107, 109, 110, 112, 114,
+    115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137, 138, 140, 142,
+    144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+    177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213,
+    215, 218, 220, 223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+];